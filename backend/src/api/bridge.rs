@@ -1,9 +1,21 @@
 // REST API Bridge for Frontend Integration
 // Wraps gRPC services with HTTP/JSON endpoints for Next.js compatibility
 
+use crate::api::{
+    ApiServer, BackupRequest, BackupResponse, CredentialRequest, EpochRequest, EpochResponse, FeeBalancesResponse,
+    FeeLedgerQuery, JurisdictionRequest, MerkleProofResponse, NullifierStatusRequest, NullifierStatusResponse,
+    ProofJobRequest, ProofJobsQuery, SanctionRequest, TokenStatusRequest, VerifyingKeyResponse,
+};
 use crate::error::CloakError;
+use crate::node::CloakNode;
+use crate::prover::keys::ALL_CIRCUITS;
+use crate::prover::{CircuitKind, ProofBundle};
+use crate::state::{
+    protocol_fee_account, AccreditationCredential, AuditReport, CorporateAction, DistributionReport, FeeLedgerEntry,
+    FeeSchedule, ProofJob, TokenInfo, TransferPolicy,
+};
 use axum::{
-    extract::{Json, State, WebSocketUpgrade},
+    extract::{Json, Path, Query, State, WebSocketUpgrade},
     http::{header, Method, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, post},
@@ -11,6 +23,7 @@ use axum::{
 };
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tower_http::cors::{Any, CorsLayer};
@@ -47,19 +60,65 @@ pub struct ZKProof {
     pub id: String,
     #[serde(rename = "type")]
     pub proof_type: String, // "balance" | "trade" | "compliance"
-    pub status: String, // "generating" | "complete" | "verified"
+    pub status: String, // "queued" | "generating" | "complete" | "verified" | "failed" | "cancelled"
     pub constraints: u64,
     pub prove_time: u64, // milliseconds
     pub proof_size: u64, // bytes
     pub timestamp: String, // ISO 8601
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ZKProof {
+    /// Reports a proving job of the node; metrics are zero until known
+    pub fn from_job(job: &ProofJob) -> Self {
+        let proof_type = match job.request.circuit() {
+            CircuitKind::Balance => "balance",
+            CircuitKind::Settlement => "trade",
+            CircuitKind::Compliance => "compliance",
+            CircuitKind::Batch => "batch",
+        };
+        Self {
+            id: format!("proof-{}", job.id),
+            proof_type: proof_type.to_string(),
+            status: job.status.as_str().to_string(),
+            constraints: job.constraints.unwrap_or_default(),
+            prove_time: job.prove_time_ms.unwrap_or_default(),
+            proof_size: job.proof_size.unwrap_or_default(),
+            timestamp: job.submitted_at.to_rfc3339(),
+            error: job.error.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SubmitProofRequest {
     pub user_sdkey: String,
     pub proof_data: String, // hex-encoded proof
-    pub public_inputs: Vec<String>,
+    pub public_inputs: Vec<String>, // hex-encoded 32-byte field elements
     pub signature: String, // ECDSA signature
+    #[serde(default)]
+    pub circuit: Option<CircuitKind>, // defaults to the balance circuit
+}
+
+impl SubmitProofRequest {
+    /// Decodes the hex proof and public inputs into a `ProofBundle`
+    pub fn to_bundle(&self) -> Result<ProofBundle, CloakError> {
+        let proof = hex::decode(self.proof_data.trim_start_matches("0x"))?;
+        let mut public_inputs = Vec::with_capacity(self.public_inputs.len());
+        for input in &self.public_inputs {
+            let bytes = hex::decode(input.trim_start_matches("0x"))?;
+            let input: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+                CloakError::invalid_input(format!("Public input must be 32 bytes, got {}", bytes.len()))
+            })?;
+            public_inputs.push(input);
+        }
+        Ok(ProofBundle {
+            circuit: self.circuit.unwrap_or(CircuitKind::Balance),
+            proof,
+            public_inputs,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,6 +133,11 @@ pub struct QueryStateRequest {
     pub user_sdkey: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateProofRequest {
+    pub user_sdkey: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QueryStateResponse {
     pub balances: Vec<Balance>,
@@ -106,6 +170,20 @@ pub struct AppState {
     pub positions: Arc<RwLock<Vec<Position>>>,
     pub proofs: Arc<RwLock<Vec<ZKProof>>>,
     pub psy_block_height: Arc<RwLock<u64>>,
+    /// Backing node, present when the bridge runs inside the full node
+    pub node: Option<Arc<CloakNode>>,
+    /// Directory admin backups are written to
+    pub backup_dir: PathBuf,
+}
+
+impl AppState {
+    /// Creates bridge state backed by a running Cloak node
+    pub fn with_node(node: Arc<CloakNode>) -> Self {
+        Self {
+            node: Some(node),
+            ..Self::default()
+        }
+    }
 }
 
 impl Default for AppState {
@@ -143,9 +221,12 @@ impl Default for AppState {
                     prove_time: 182,
                     proof_size: 288,
                     timestamp: chrono::Utc::now().to_rfc3339(),
+                    error: None,
                 },
             ])),
             psy_block_height: Arc::new(RwLock::new(0)),
+            node: None,
+            backup_dir: PathBuf::from(crate::CloakConfig::default().backup_dir),
         }
     }
 }
@@ -173,24 +254,36 @@ async fn submit_proof_handler(
         return Err(StatusCode::BAD_REQUEST);
     }
     
+    // Verify natively against the node's keys and current root when running inside a node;
+    // compliance proofs are recorded as attestations
+    if let Some(node) = state.node.as_ref() {
+        let bundle = req.to_bundle().map_err(|_| StatusCode::BAD_REQUEST)?;
+        let sdkey_hash = ApiServer::parse_sdkey_hash(&req.user_sdkey).map_err(|_| StatusCode::BAD_REQUEST)?;
+        node.accept_proof(sdkey_hash, &bundle).await.map_err(|e| {
+            tracing::warn!("Rejected {} proof from {}: {}", bundle.circuit, req.user_sdkey, e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+    }
+
     // TODO: Validate signature
-    // TODO: Verify proof using ZK verifier
     // TODO: Submit to Psy testnet
     
     let proof_id = format!("proof-{}", uuid::Uuid::new_v4());
     
-    // Add to proofs list
-    let new_proof = ZKProof {
-        id: proof_id.clone(),
-        proof_type: "trade".to_string(),
-        status: "generating".to_string(),
-        constraints: 1247392,
-        prove_time: 182,
-        proof_size: 288,
-        timestamp: chrono::Utc::now().to_rfc3339(),
-    };
-    
-    state.proofs.write().await.push(new_proof);
+    // Client proofs are not proving jobs; only the standalone bridge lists them
+    if state.node.is_none() {
+        let new_proof = ZKProof {
+            id: proof_id.clone(),
+            proof_type: "trade".to_string(),
+            status: "generating".to_string(),
+            constraints: 1247392,
+            prove_time: 182,
+            proof_size: 288,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            error: None,
+        };
+        state.proofs.write().await.push(new_proof);
+    }
     
     Ok(Json(SubmitProofResponse {
         proof_id,
@@ -219,6 +312,336 @@ async fn query_state_handler(
     })
 }
 
+// Merkle inclusion / non-inclusion proof for client-side witness generation
+async fn state_proof_handler(
+    State(state): State<AppState>,
+    Json(req): Json<StateProofRequest>,
+) -> Result<Json<MerkleProofResponse>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let sdkey_hash = ApiServer::parse_sdkey_hash(&req.user_sdkey)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let proof = node.state_manager.read().await
+        .generate_merkle_proof(sdkey_hash)
+        .map_err(|e| {
+            tracing::error!("Failed to generate Merkle proof: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(MerkleProofResponse::from_proof(proof)))
+}
+
+// Spent status of a nullifier, with a proof against the nullifier-set root
+async fn nullifier_status_handler(
+    State(state): State<AppState>,
+    Json(req): Json<NullifierStatusRequest>,
+) -> Result<Json<NullifierStatusResponse>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let nullifier = ApiServer::parse_sdkey_hash(&req.nullifier)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let proof = node.state_manager.read().await
+        .generate_nullifier_proof(nullifier)
+        .map_err(|e| {
+            tracing::error!("Failed to generate nullifier proof: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(NullifierStatusResponse::from_proof(proof)))
+}
+
+// Admin: consistent backup of the node's state database
+async fn backup_handler(
+    State(state): State<AppState>,
+    Json(req): Json<BackupRequest>,
+) -> Result<Json<BackupResponse>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let state_manager = node.state_manager.read().await;
+    let name = req.backup_name(state_manager.get_block_height())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let path = state.backup_dir.join(name);
+
+    let manifest = state_manager.create_backup(&path).map_err(|e| {
+        tracing::error!("Failed to create backup at {}: {}", path.display(), e);
+        match e {
+            CloakError::InvalidInput(_) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    })?;
+
+    Ok(Json(BackupResponse {
+        path: path.display().to_string(),
+        manifest,
+    }))
+}
+
+// Admin: settle trades in one epoch with a single batch proof submission
+async fn settle_epoch_handler(
+    State(state): State<AppState>,
+    Json(req): Json<EpochRequest>,
+) -> Result<Json<EpochResponse>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let receipt = node.settle_epoch(req.trades).await.map_err(|e| {
+        tracing::warn!("Rejected epoch: {}", e);
+        match e {
+            CloakError::InvalidInput(_)
+            | CloakError::InsufficientBalance { .. }
+            | CloakError::TransferRestricted { .. }
+            | CloakError::UserNotFound(_) => StatusCode::BAD_REQUEST,
+            // The root moved while proving; the caller may retry
+            CloakError::ProofVerification(_) => StatusCode::CONFLICT,
+            CloakError::Prover(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    })?;
+    Ok(Json(EpochResponse::from_receipt(&receipt)))
+}
+
+// Admin: recompute supply totals and the Merkle root from storage
+async fn audit_handler(State(state): State<AppState>) -> Result<Json<AuditReport>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let report = node.state_manager.read().await.audit().map_err(|e| {
+        tracing::error!("State audit failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(report))
+}
+
+// Token registry, so clients can list the accepted assets instead of hardcoding them
+async fn list_tokens_handler(State(state): State<AppState>) -> Result<Json<Vec<TokenInfo>>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(node.state_manager.read().await.list_tokens()))
+}
+
+async fn get_token_handler(
+    State(state): State<AppState>,
+    Path(token_id): Path<String>,
+) -> Result<Json<TokenInfo>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let token = node.state_manager.read().await.get_token(&token_id);
+    token.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// Admin: register a new token
+async fn register_token_handler(
+    State(state): State<AppState>,
+    Json(token): Json<TokenInfo>,
+) -> Result<Json<TokenInfo>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    node.state_manager.write().await.register_token(token.clone()).map_err(|e| {
+        tracing::warn!("Rejected token registration: {}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok(Json(token))
+}
+
+// Admin: freeze or reactivate a token
+async fn token_status_handler(
+    State(state): State<AppState>,
+    Path(token_id): Path<String>,
+    Json(req): Json<TokenStatusRequest>,
+) -> Result<Json<TokenInfo>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let mut state_manager = node.state_manager.write().await;
+    state_manager.set_token_status(&token_id, req.status).map_err(|_| StatusCode::NOT_FOUND)?;
+    state_manager.get_token(&token_id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// Admin: transfer restrictions of a token (unrestricted if none are set)
+async fn get_restrictions_handler(
+    State(state): State<AppState>,
+    Path(token_id): Path<String>,
+) -> Result<Json<TransferPolicy>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let state_manager = node.state_manager.read().await;
+    state_manager.get_token(&token_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(state_manager.get_transfer_policy(&token_id)))
+}
+
+// Admin: replace the transfer restrictions of a token
+async fn set_restrictions_handler(
+    State(state): State<AppState>,
+    Path(token_id): Path<String>,
+    Json(policy): Json<TransferPolicy>,
+) -> Result<Json<TransferPolicy>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let mut state_manager = node.state_manager.write().await;
+    state_manager.get_token(&token_id).ok_or(StatusCode::NOT_FOUND)?;
+    let policy = state_manager.set_transfer_policy(&token_id, policy).map_err(|e| {
+        tracing::warn!("Rejected transfer policy for {}: {}", token_id, e);
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok(Json(policy))
+}
+
+// Admin: record a user's jurisdiction (checked by jurisdiction blocks)
+async fn jurisdiction_handler(
+    State(state): State<AppState>,
+    Json(req): Json<JurisdictionRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let sdkey_hash: [u8; 32] = hex::decode(req.user_sdkey_hash.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    node.state_manager
+        .write()
+        .await
+        .set_user_jurisdiction(sdkey_hash, &req.jurisdiction)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Admin: add a jurisdiction to or remove it from the sanctions list
+async fn sanctions_handler(
+    State(state): State<AppState>,
+    Json(req): Json<SanctionRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    node.state_manager
+        .write()
+        .await
+        .set_sanctioned(&req.jurisdiction, req.sanctioned)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Admin: issue an accreditation credential (proven by compliance proofs)
+async fn credential_handler(
+    State(state): State<AppState>,
+    Json(req): Json<CredentialRequest>,
+) -> Result<Json<AccreditationCredential>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let sdkey_hash: [u8; 32] = hex::decode(req.user_sdkey_hash.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let credential = node
+        .state_manager
+        .write()
+        .await
+        .issue_credential(sdkey_hash, &req.jurisdiction, req.expires_at)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(Json(credential))
+}
+
+// Admin: pay a coupon or dividend to the holders of a token at a record height
+async fn distribution_handler(
+    State(state): State<AppState>,
+    Json(action): Json<CorporateAction>,
+) -> Result<Json<DistributionReport>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let report = node.state_manager.write().await.distribute(&action).map_err(|e| {
+        tracing::warn!("Rejected distribution of {} to {} holders: {}", action.payout_token_id, action.token_id, e);
+        match e {
+            CloakError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    })?;
+    Ok(Json(report))
+}
+
+// Committed distribution report, looked up by its hash
+async fn get_distribution_handler(
+    State(state): State<AppState>,
+    Path(report_hash): Path<String>,
+) -> Result<Json<DistributionReport>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let report_hash: [u8; 32] = hex::decode(report_hash.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let report = node.state_manager.read().await.get_distribution_report(&report_hash).map_err(|e| {
+        tracing::error!("Failed to read distribution report: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    report.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// Fee schedule of a token (all zero if none is set)
+async fn get_fee_schedule_handler(
+    State(state): State<AppState>,
+    Path(token_id): Path<String>,
+) -> Result<Json<FeeSchedule>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let state_manager = node.state_manager.read().await;
+    state_manager.get_token(&token_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(state_manager.get_fee_schedule(&token_id)))
+}
+
+// Admin: replace the fee schedule of a token
+async fn set_fee_schedule_handler(
+    State(state): State<AppState>,
+    Path(token_id): Path<String>,
+    Json(schedule): Json<FeeSchedule>,
+) -> Result<Json<FeeSchedule>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let mut state_manager = node.state_manager.write().await;
+    state_manager.get_token(&token_id).ok_or(StatusCode::NOT_FOUND)?;
+    state_manager.set_fee_schedule(&token_id, schedule).map_err(|e| {
+        tracing::warn!("Rejected fee schedule for {}: {}", token_id, e);
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok(Json(schedule))
+}
+
+// Balances collected by the protocol fee account
+async fn fee_balances_handler(State(state): State<AppState>) -> Result<Json<FeeBalancesResponse>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(FeeBalancesResponse {
+        fee_account: hex::encode(protocol_fee_account()),
+        balances: node.state_manager.read().await.get_fee_balances(),
+    }))
+}
+
+// Every fee collected, in the order it was charged
+async fn fee_ledger_handler(
+    State(state): State<AppState>,
+    Query(query): Query<FeeLedgerQuery>,
+) -> Result<Json<Vec<FeeLedgerEntry>>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let state_manager = node.state_manager.read().await;
+    let entries = state_manager
+        .get_fee_ledger(query.from_sequence, query.page_size())
+        .map_err(|e| {
+            tracing::error!("Failed to read fee ledger: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(entries))
+}
+
+// Verifying keys of every circuit, so clients and contracts can pin them
+async fn list_verifying_keys_handler(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<VerifyingKeyResponse>>, StatusCode> {
+    let mut keys = Vec::with_capacity(ALL_CIRCUITS.len());
+    for circuit in ALL_CIRCUITS {
+        keys.push(verifying_key_response(&state, circuit).await?);
+    }
+    Ok(Json(keys))
+}
+
+async fn get_verifying_key_handler(
+    State(state): State<AppState>,
+    Path(circuit): Path<CircuitKind>,
+) -> Result<Json<VerifyingKeyResponse>, StatusCode> {
+    verifying_key_response(&state, circuit).await.map(Json)
+}
+
+async fn verifying_key_response(state: &AppState, circuit: CircuitKind) -> Result<VerifyingKeyResponse, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let (bytes, source) = node
+        .verifying_key(circuit)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to encode the {} verifying key: {}", circuit, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let prover = node.prover_interface.read().await;
+    Ok(VerifyingKeyResponse::new(circuit, &bytes, source, prover.depth(), prover.batch_capacity()))
+}
+
 async fn get_orders_handler(State(state): State<AppState>) -> Json<Vec<Order>> {
     let orders = state.orders.read().await.clone();
     Json(orders)
@@ -229,9 +652,72 @@ async fn get_positions_handler(State(state): State<AppState>) -> Json<Vec<Positi
     Json(positions)
 }
 
-async fn get_proofs_handler(State(state): State<AppState>) -> Json<Vec<ZKProof>> {
-    let proofs = state.proofs.read().await.clone();
-    Json(proofs)
+// Proving jobs of the node, newest first (mock proofs without a node)
+async fn get_proofs_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ProofJobsQuery>,
+) -> Result<Json<Vec<ZKProof>>, StatusCode> {
+    current_proofs(&state, query.page_size()).await.map(Json)
+}
+
+async fn current_proofs(state: &AppState, limit: usize) -> Result<Vec<ZKProof>, StatusCode> {
+    let Some(node) = state.node.as_ref() else {
+        return Ok(state.proofs.read().await.clone());
+    };
+    let jobs = node.proof_jobs.list(limit).await.map_err(|e| {
+        tracing::error!("Failed to list proof jobs: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(jobs.iter().map(ZKProof::from_job).collect())
+}
+
+// Queue a proving job; poll its status with GET /api/proofs/:id
+async fn submit_proof_job_handler(
+    State(state): State<AppState>,
+    Json(req): Json<ProofJobRequest>,
+) -> Result<Json<ZKProof>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let job = node.proof_jobs.submit(req.request, req.priority).await.map_err(|e| {
+        tracing::warn!("Rejected proof job: {}", e);
+        match e {
+            CloakError::Prover(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    })?;
+    Ok(Json(ZKProof::from_job(&job)))
+}
+
+async fn get_proof_job_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ProofJob>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let id = parse_job_id(&id)?;
+    let job = node.proof_jobs.get(id).await.map_err(|e| {
+        tracing::error!("Failed to read proof job {}: {}", id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    job.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn cancel_proof_job_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ZKProof>, StatusCode> {
+    let node = state.node.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let id = parse_job_id(&id)?;
+    let known = node.proof_jobs.get(id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    known.ok_or(StatusCode::NOT_FOUND)?;
+    let job = node.proof_jobs.cancel(id).await.map_err(|e| match e {
+        CloakError::InvalidInput(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+    Ok(Json(ZKProof::from_job(&job)))
+}
+
+/// Parses a job ID as listed (`proof-7`) or bare (`7`)
+fn parse_job_id(id: &str) -> Result<u64, StatusCode> {
+    id.trim_start_matches("proof-").parse().map_err(|_| StatusCode::BAD_REQUEST)
 }
 
 // WebSocket handler for real-time updates
@@ -256,10 +742,9 @@ async fn handle_socket(
         tokio::select! {
             _ = interval.tick() => {
                 // Send real-time updates - clone only when sending
-                let proofs: Vec<ZKProof> = {
-                    let guard = state.proofs.read().await;
-                    guard.clone()
-                };
+                let proofs = current_proofs(&state, ProofJobsQuery { limit: None }.page_size())
+                    .await
+                    .unwrap_or_default();
                 let orders: Vec<Order> = {
                     let guard = state.orders.read().await;
                     guard.clone()
@@ -293,8 +778,14 @@ async fn handle_socket(
 // ============================================================================
 
 pub fn create_router() -> Router {
-    let state = AppState::default();
-    
+    build_router(AppState::default())
+}
+
+pub fn create_router_with_node(node: Arc<CloakNode>) -> Router {
+    build_router(AppState::with_node(node))
+}
+
+fn build_router(state: AppState) -> Router {
     // CORS configuration
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -305,16 +796,49 @@ pub fn create_router() -> Router {
         .route("/health", get(health_handler))
         .route("/api/proof/submit", post(submit_proof_handler))
         .route("/api/state/query", post(query_state_handler))
+        .route("/api/state/proof", post(state_proof_handler))
+        .route("/api/nullifier/status", post(nullifier_status_handler))
+        .route("/api/admin/backup", post(backup_handler))
+        .route("/api/admin/audit", get(audit_handler))
+        .route("/api/admin/epochs", post(settle_epoch_handler))
+        .route("/api/admin/tokens", post(register_token_handler))
+        .route("/api/admin/tokens/:token_id/status", post(token_status_handler))
+        .route(
+            "/api/admin/tokens/:token_id/restrictions",
+            get(get_restrictions_handler).post(set_restrictions_handler),
+        )
+        .route("/api/admin/jurisdictions", post(jurisdiction_handler))
+        .route("/api/admin/sanctions", post(sanctions_handler))
+        .route("/api/admin/credentials", post(credential_handler))
+        .route("/api/admin/distributions", post(distribution_handler))
+        .route("/api/admin/tokens/:token_id/fees", post(set_fee_schedule_handler))
+        .route("/api/tokens/:token_id/fees", get(get_fee_schedule_handler))
+        .route("/api/fees", get(fee_balances_handler))
+        .route("/api/fees/ledger", get(fee_ledger_handler))
+        .route("/api/distributions/:report_hash", get(get_distribution_handler))
+        .route("/api/keys", get(list_verifying_keys_handler))
+        .route("/api/keys/:circuit", get(get_verifying_key_handler))
+        .route("/api/tokens", get(list_tokens_handler))
+        .route("/api/tokens/:token_id", get(get_token_handler))
         .route("/api/orders", get(get_orders_handler))
         .route("/api/positions", get(get_positions_handler))
-        .route("/api/proofs", get(get_proofs_handler))
+        .route("/api/proofs", get(get_proofs_handler).post(submit_proof_job_handler))
+        .route("/api/proofs/:id", get(get_proof_job_handler))
+        .route("/api/proofs/:id/cancel", post(cancel_proof_job_handler))
         .route("/ws", get(ws_handler))
         .layer(cors)
         .with_state(state)
 }
 
 pub async fn run_server(port: u16) -> Result<(), CloakError> {
-    let app = create_router();
+    serve(port, create_router()).await
+}
+
+pub async fn run_server_with_node(port: u16, node: Arc<CloakNode>) -> Result<(), CloakError> {
+    serve(port, create_router_with_node(node)).await
+}
+
+async fn serve(port: u16, app: Router) -> Result<(), CloakError> {
     let addr = format!("0.0.0.0:{}", port);
     
     tracing::info!("REST API server listening on {}", addr);
@@ -8,6 +8,7 @@ pub mod bridge;
 
 pub use server::ApiServer;
 
+use crate::prover::CircuitKind;
 use serde::{Deserialize, Serialize};
 
 /// Request to submit a ZK proof for a private trade
@@ -16,9 +17,12 @@ pub struct SubmitProofRequest {
     /// The ZK proof data (serialized)
     pub proof_data: Vec<u8>,
 
-    /// Public inputs for the proof
+    /// Public inputs for the proof, concatenated 32-byte field elements
     pub public_inputs: Vec<u8>,
 
+    /// Circuit the proof was generated for
+    pub circuit: CircuitKind,
+
     /// User's SDKey hash
     pub user_sdkey_hash: String,
 
@@ -47,6 +51,10 @@ pub struct SubmitProofResponse {
 pub struct QueryStateRequest {
     /// User's SDKey hash
     pub user_sdkey_hash: String,
+
+    /// Optional block height for a point-in-time query (latest state if `None`)
+    #[serde(default)]
+    pub at_block: Option<u64>,
 }
 
 /// Response with user state information
@@ -68,6 +76,349 @@ pub struct QueryStateResponse {
     pub last_updated_block: u64,
 }
 
+/// Historical state root information
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateRootResponse {
+    /// Commit sequence number of the root
+    pub sequence: u64,
+
+    /// The state root
+    pub merkle_root: String,
+
+    /// Root the transitions were applied on top of
+    pub previous_root: String,
+
+    /// Nullifier-set root committed alongside the state root
+    pub nullifier_root: String,
+
+    /// Note-commitment root committed alongside the state root
+    pub note_root: String,
+
+    /// Block height the root was produced at
+    pub block_height: u64,
+
+    /// Transitions that produced the root
+    pub transitions: Vec<crate::state::StateTransition>,
+}
+
+impl StateRootResponse {
+    /// Builds a response from a state-layer root record
+    pub fn from_record(record: crate::state::RootRecord) -> Self {
+        Self {
+            sequence: record.sequence,
+            merkle_root: hex::encode(record.root),
+            previous_root: hex::encode(record.previous_root),
+            nullifier_root: hex::encode(record.nullifier_root),
+            note_root: hex::encode(record.note_root),
+            block_height: record.block_height,
+            transitions: record.transitions,
+        }
+    }
+}
+
+/// Request for a Merkle proof of a user's leaf
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProofRequest {
+    /// User's SDKey hash
+    pub user_sdkey_hash: String,
+}
+
+/// Merkle inclusion or non-inclusion proof, hex-encoded for clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProofResponse {
+    /// User's SDKey hash
+    pub user_sdkey_hash: String,
+
+    /// Whether the user is included in the tree
+    pub included: bool,
+
+    /// Leaf slot index derived from the SDKey hash
+    pub leaf_index: u64,
+
+    /// Leaf value at the slot
+    pub leaf: String,
+
+    /// Sibling hashes from the leaf up to the root (circuit `merkle_path`)
+    pub merkle_path: Vec<String>,
+
+    /// Direction bits for each level (circuit `merkle_path_indices`)
+    pub merkle_path_indices: Vec<bool>,
+
+    /// Root the proof authenticates against
+    pub merkle_root: String,
+
+    /// Full proof, for use with `state::verify_proof`
+    pub proof: crate::state::MerkleProof,
+}
+
+impl MerkleProofResponse {
+    /// Builds a response from a state-layer Merkle proof
+    pub fn from_proof(proof: crate::state::MerkleProof) -> Self {
+        Self {
+            user_sdkey_hash: hex::encode(proof.sdkey_hash),
+            included: proof.is_inclusion(),
+            leaf_index: proof.leaf_index,
+            leaf: hex::encode(proof.leaf),
+            merkle_path: proof.merkle_path.iter().map(hex::encode).collect(),
+            merkle_path_indices: proof.merkle_path_indices.clone(),
+            merkle_root: hex::encode(proof.root),
+            proof,
+        }
+    }
+}
+
+/// Request for the spent status of a nullifier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NullifierStatusRequest {
+    /// Nullifier, hex-encoded
+    pub nullifier: String,
+}
+
+/// Spent status of a nullifier with a (non-)membership proof
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NullifierStatusResponse {
+    /// Nullifier, hex-encoded
+    pub nullifier: String,
+
+    /// Whether the nullifier has already been spent
+    pub spent: bool,
+
+    /// Nullifier-set root the proof authenticates against
+    pub nullifier_root: String,
+
+    /// Membership proof if spent, non-membership proof otherwise
+    pub proof: crate::state::MerkleProof,
+}
+
+impl NullifierStatusResponse {
+    /// Builds a response from a nullifier-set proof
+    pub fn from_proof(proof: crate::state::MerkleProof) -> Self {
+        Self {
+            nullifier: hex::encode(proof.sdkey_hash),
+            spent: proof.is_inclusion(),
+            nullifier_root: hex::encode(proof.root),
+            proof,
+        }
+    }
+}
+
+/// Admin request to back up the state database
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackupRequest {
+    /// Name of the backup directory under the node's backup directory
+    /// (defaults to `cloak-<height>-<timestamp>`)
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl BackupRequest {
+    /// Resolves the backup directory name for a state at `block_height`
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the name is empty, starts with
+    /// a dot, or contains anything but ASCII letters, digits, `-`, `_`, `.`.
+    pub fn backup_name(&self, block_height: u64) -> crate::error::CloakResult<String> {
+        let Some(name) = &self.name else {
+            return Ok(format!("cloak-{}-{}", block_height, chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+        };
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(crate::error::CloakError::invalid_input(format!("Invalid backup name: {}", name)));
+        }
+        Ok(name.clone())
+    }
+}
+
+/// Location and manifest of a completed backup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupResponse {
+    /// Backup directory on the node's filesystem
+    pub path: String,
+
+    /// State captured by the backup
+    pub manifest: crate::state::BackupManifest,
+}
+
+/// Admin request to change a token's status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenStatusRequest {
+    /// New status of the token
+    pub status: crate::state::TokenStatus,
+}
+
+/// Balances collected by the protocol fee account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeBalancesResponse {
+    /// SDKey hash of the protocol fee account, hex-encoded
+    pub fee_account: String,
+
+    /// Collected balance per token
+    pub balances: std::collections::BTreeMap<String, u128>,
+}
+
+/// Page of the fee ledger
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeLedgerQuery {
+    /// First root sequence to include
+    #[serde(default)]
+    pub from_sequence: u64,
+
+    /// Maximum number of entries (default 100, at most 1000)
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl FeeLedgerQuery {
+    /// Gets the page size, capped at 1000 entries
+    pub fn page_size(&self) -> usize {
+        self.limit.unwrap_or(100).min(1_000)
+    }
+}
+
+/// Request to queue a proving job on the node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofJobRequest {
+    /// Circuit and inputs to prove
+    #[serde(flatten)]
+    pub request: crate::state::ProofRequest,
+
+    /// Scheduling priority (default normal)
+    #[serde(default)]
+    pub priority: crate::state::JobPriority,
+}
+
+/// Page of proving jobs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofJobsQuery {
+    /// Maximum number of jobs, newest first (default 50, at most 500)
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl ProofJobsQuery {
+    /// Gets the page size, capped at 500 jobs
+    pub fn page_size(&self) -> usize {
+        self.limit.unwrap_or(50).min(500)
+    }
+}
+
+/// Admin request to settle an epoch of trades with one batch proof
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpochRequest {
+    /// Trades in settlement order, without settlement proofs
+    pub trades: Vec<crate::state::StateTransition>,
+}
+
+/// A settled epoch and the transaction that submitted its batch proof
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpochResponse {
+    /// Psy transaction hash of the batch proof submission
+    pub tx_hash: String,
+
+    /// Number of trades settled
+    pub trade_count: usize,
+
+    /// Root sequence of the epoch's last trade
+    pub sequence: u64,
+
+    /// Root before the epoch, hex-encoded
+    pub merkle_root_old: String,
+
+    /// Root after the epoch, hex-encoded
+    pub merkle_root_new: String,
+}
+
+impl EpochResponse {
+    /// Builds the response from a node's epoch receipt
+    pub fn from_receipt(receipt: &crate::node::EpochReceipt) -> Self {
+        Self {
+            tx_hash: receipt.tx_hash.clone(),
+            trade_count: receipt.settlement.trade_count,
+            sequence: receipt.settlement.sequence,
+            merkle_root_old: hex::encode(receipt.settlement.merkle_root_old),
+            merkle_root_new: hex::encode(receipt.settlement.merkle_root_new),
+        }
+    }
+}
+
+/// Admin request to record a user's jurisdiction for transfer policies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JurisdictionRequest {
+    /// Hex-encoded SDKey hash of the user
+    pub user_sdkey_hash: String,
+
+    /// ISO 3166-1 alpha-2 country code
+    pub jurisdiction: String,
+}
+
+/// Admin request to add a jurisdiction to or remove it from the sanctions list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanctionRequest {
+    /// ISO 3166-1 alpha-2 country code
+    pub jurisdiction: String,
+
+    /// Whether the jurisdiction is sanctioned
+    pub sanctioned: bool,
+}
+
+/// Admin request to issue an accreditation credential for compliance proofs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialRequest {
+    /// Hex-encoded SDKey hash of the user
+    pub user_sdkey_hash: String,
+
+    /// ISO 3166-1 alpha-2 country code the user is accredited in
+    pub jurisdiction: String,
+
+    /// Block height from which the credential is no longer valid
+    pub expires_at: u64,
+}
+
+/// Verifying key of a circuit, for clients and contracts to pin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyingKeyResponse {
+    /// Circuit the key verifies
+    pub circuit: CircuitKind,
+
+    /// Compressed `ark_groth16::VerifyingKey`, hex-encoded
+    pub verifying_key: String,
+
+    /// Keccak-256 hash of the verifying key bytes, hex-encoded
+    pub hash: String,
+
+    /// Whether the node runs on development or pinned keys
+    pub source: crate::prover::KeySource,
+
+    /// Merkle tree depth the circuit is laid out for
+    pub tree_depth: usize,
+
+    /// Number of trades the batch circuit is laid out for (batch key only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_capacity: Option<usize>,
+}
+
+impl VerifyingKeyResponse {
+    /// Builds the response from the compressed key bytes and the prover's layout
+    pub fn new(
+        circuit: CircuitKind,
+        bytes: &[u8],
+        source: crate::prover::KeySource,
+        tree_depth: usize,
+        batch_capacity: usize,
+    ) -> Self {
+        Self {
+            circuit,
+            verifying_key: hex::encode(bytes),
+            hash: hex::encode(crate::prover::keys::key_hash(bytes)),
+            source,
+            tree_depth,
+            batch_capacity: (circuit == CircuitKind::Batch).then_some(batch_capacity),
+        }
+    }
+}
+
 /// Encrypted order intent for private trading
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderIntentMessage {
@@ -83,8 +434,8 @@ pub struct OrderIntentMessage {
     /// Signature for authentication
     pub signature: String,
 
-    /// TODO: Add order matching hints (encrypted)
-    /// TODO: Add liquidity provision parameters
+    // TODO: Add order matching hints (encrypted)
+    // TODO: Add liquidity provision parameters
 }
 
 /// Health check response
@@ -105,6 +456,12 @@ pub struct HealthCheckResponse {
     /// Current Merkle root
     pub merkle_root: String,
 
+    /// Current nullifier-set root
+    pub nullifier_root: String,
+
+    /// Fraction of user state lookups served from the in-memory cache
+    pub user_cache_hit_rate: f64,
+
     /// Version of the backend
     pub version: String,
 }
@@ -5,7 +5,12 @@
 
 use crate::error::{CloakError, CloakResult};
 use crate::node::CloakNode;
-use crate::api::{HealthCheckResponse, QueryStateRequest, QueryStateResponse, SubmitProofRequest, SubmitProofResponse};
+use crate::prover::{CircuitKind, ProofBundle};
+use crate::api::{
+    HealthCheckResponse, MerkleProofRequest, MerkleProofResponse, NullifierStatusRequest,
+    NullifierStatusResponse, QueryStateRequest, QueryStateResponse, StateRootResponse, SubmitProofRequest, SubmitProofResponse,
+    VerifyingKeyResponse,
+};
 use std::sync::Arc;
 use tracing::{debug, error, info, warn};
 
@@ -30,9 +35,9 @@ impl ApiServer {
     /// * `bind_addr` - Address to bind the server to (e.g., "127.0.0.1:50051")
     ///
     /// # Example
-    /// ```
+    /// ```no_run
     /// use std::sync::Arc;
-    /// use cloak_protocol::{ApiServer, CloakNode};
+    /// use cloak_backend::{ApiServer, CloakNode};
     ///
     /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
     /// let node = Arc::new(CloakNode::new("https://testnet-rpc.psy.xyz", "./db").await?);
@@ -141,6 +146,8 @@ impl ApiServer {
             block_height,
             active_users: node_status.active_users,
             merkle_root,
+            nullifier_root: node_status.nullifier_root,
+            user_cache_hit_rate: node_status.user_cache.hit_rate(),
             version: crate::VERSION.to_string(),
         })
     }
@@ -160,11 +167,12 @@ impl ApiServer {
     /// - User SDKey hash is invalid
     /// - Signature is empty
     /// - Proof data format is invalid
+    /// - Public inputs are not a whole number of 32-byte field elements
+    /// Returns `CloakError::ProofVerification` if the proof fails native verification (verified compliance proofs are recorded as attestations)
     /// Returns `CloakError::Network` if submission to Psy Protocol fails
     /// Returns `CloakError::PsyProtocol` if Psy Protocol returns an error
     ///
     /// # TODO for Part 2:
-    /// - Validate proof format
     /// - Verify user signature
     /// - Check nonce for replay protection
     /// - Submit to Psy verifier contract
@@ -193,7 +201,7 @@ impl ApiServer {
         }
 
         // Validate user SDKey hash format
-        let _sdkey_hash = Self::parse_sdkey_hash(&request.user_sdkey_hash)
+        let sdkey_hash = Self::parse_sdkey_hash(&request.user_sdkey_hash)
             .map_err(|e| {
                 error!("Invalid SDKey hash format: {}", e);
                 e
@@ -211,12 +219,17 @@ impl ApiServer {
             ));
         }
 
-        // TODO: Validate proof format
+        let bundle = Self::parse_proof_bundle(&request)?;
+        self.node.accept_proof(sdkey_hash, &bundle).await.map_err(|e| {
+            warn!("Rejected {} proof from {}: {}", bundle.circuit, request.user_sdkey_hash, e);
+            e
+        })?;
+
         // TODO: Verify user signature
         // TODO: Check nonce
 
         // Submit proof to Psy Protocol with error context
-        let tx_hash = self.node.submit_trade_proof(request.proof_data.clone()).await
+        let tx_hash = self.node.submit_trade_proof(request.proof_data.clone(), request.public_inputs.clone()).await
             .map_err(|e| {
                 error!("Failed to submit proof to Psy Protocol: {}", e);
                 match e {
@@ -241,6 +254,7 @@ impl ApiServer {
     ///
     /// # Arguments
     /// * `request` - The state query request containing the user's SDKey hash
+    ///   and an optional `at_block` height for point-in-time queries
     ///
     /// # Returns
     /// A `QueryStateResponse` with the user's state including:
@@ -273,7 +287,11 @@ impl ApiServer {
 
         // Get user state from state manager with error handling for poisoned locks
         let state_manager = self.node.state_manager.read().await;
-        let user_state = state_manager.get_user_state(sdkey_hash)
+        let user_state = match request.at_block {
+            Some(block_height) => state_manager.get_user_state_at_block(sdkey_hash, block_height)?,
+            None => state_manager.get_user_state(sdkey_hash),
+        };
+        let user_state = user_state
             .ok_or_else(|| {
                 let error = CloakError::user_not_found(&sdkey_hash);
                 debug!("User not found: {}", hex::encode(sdkey_hash));
@@ -378,6 +396,94 @@ impl ApiServer {
         Ok(encoded)
     }
 
+    /// Gets the state root in effect at a block height
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if no root had been produced at or below
+    /// the given height.
+    pub async fn get_state_root_at_block(&self, block_height: u64) -> CloakResult<StateRootResponse> {
+        let state_manager = self.node.state_manager.read().await;
+        let record = state_manager.get_root_at_block(block_height)?
+            .ok_or_else(|| CloakError::state(format!("No state root at or below block {}", block_height)))?;
+        Ok(StateRootResponse::from_record(record))
+    }
+
+    /// Looks up a historical state root by its hash
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the root is not valid hex.
+    /// Returns `CloakError::State` if the root was never produced by this node.
+    pub async fn get_state_root(&self, merkle_root: &str) -> CloakResult<StateRootResponse> {
+        // Roots share the 32-byte hex format of SDKey hashes
+        let root = Self::parse_sdkey_hash(merkle_root)
+            .map_err(|_| CloakError::invalid_input(format!("Invalid state root: {}", merkle_root)))?;
+        let state_manager = self.node.state_manager.read().await;
+        let record = state_manager.get_root_record(&root)?
+            .ok_or_else(|| CloakError::state(format!("Unknown state root: {}", hex::encode(root))))?;
+        Ok(StateRootResponse::from_record(record))
+    }
+
+    /// Generates a Merkle proof for a user against the current state root
+    ///
+    /// Wallets use the returned path as the `merkle_path` /
+    /// `merkle_path_indices` witness when proving client-side. Unregistered
+    /// users receive a non-membership proof instead of an error.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the SDKey hash format is invalid.
+    /// Returns `CloakError::State` if the proof cannot be generated.
+    pub async fn get_merkle_proof(
+        &self,
+        request: MerkleProofRequest,
+    ) -> CloakResult<MerkleProofResponse> {
+        debug!("Merkle proof requested for user: {}", request.user_sdkey_hash);
+
+        let sdkey_hash = Self::parse_sdkey_hash(&request.user_sdkey_hash)
+            .map_err(|e| {
+                error!("Invalid SDKey hash in get_merkle_proof: {}", e);
+                e
+            })?;
+
+        let state_manager = self.node.state_manager.read().await;
+        let proof = state_manager.generate_merkle_proof(sdkey_hash)?;
+
+        Ok(MerkleProofResponse::from_proof(proof))
+    }
+
+    /// Checks whether a nullifier has been spent
+    ///
+    /// Returns a membership proof for spent nullifiers and a non-membership
+    /// proof otherwise, both against the current nullifier-set root.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the nullifier is not valid hex.
+    /// Returns `CloakError::State` if the proof cannot be generated.
+    pub async fn get_nullifier_status(
+        &self,
+        request: NullifierStatusRequest,
+    ) -> CloakResult<NullifierStatusResponse> {
+        // Nullifiers share the 32-byte hex format of SDKey hashes
+        let nullifier = Self::parse_sdkey_hash(&request.nullifier)
+            .map_err(|_| CloakError::invalid_input(format!("Invalid nullifier: {}", request.nullifier)))?;
+
+        let state_manager = self.node.state_manager.read().await;
+        let proof = state_manager.generate_nullifier_proof(nullifier)?;
+
+        Ok(NullifierStatusResponse::from_proof(proof))
+    }
+
+    /// Gets the verifying key of a circuit, for clients and contracts to pin
+    ///
+    /// # Errors
+    /// Returns `CloakError::Prover` if no key is loaded for the circuit or
+    /// it cannot be serialized.
+    pub async fn get_verifying_key(&self, circuit: CircuitKind) -> CloakResult<VerifyingKeyResponse> {
+        let (bytes, source) = self.node.verifying_key(circuit).await?
+            .ok_or_else(|| CloakError::prover(format!("No verifying key loaded for the {} circuit", circuit)))?;
+        let prover = self.node.prover_interface.read().await;
+        Ok(VerifyingKeyResponse::new(circuit, &bytes, source, prover.depth(), prover.batch_capacity()))
+    }
+
     /// Gets the number of active users
     ///
     /// # Errors
@@ -401,7 +507,37 @@ impl ApiServer {
     /// - The hex string is empty
     /// - The hex string is invalid
     /// - The decoded bytes are not exactly 32 bytes
-    fn parse_sdkey_hash(hex_str: &str) -> CloakResult<[u8; SDKEY_HASH_LEN]> {
+    /// Splits a submitted proof into a `ProofBundle`
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the public inputs are not a
+    /// whole number of 32-byte field elements.
+    fn parse_proof_bundle(request: &SubmitProofRequest) -> CloakResult<ProofBundle> {
+        if request.public_inputs.len() % 32 != 0 {
+            return Err(CloakError::invalid_input(format!(
+                "Public inputs must be 32-byte field elements, got {} bytes",
+                request.public_inputs.len()
+            )));
+        }
+
+        let public_inputs = request
+            .public_inputs
+            .chunks(32)
+            .map(|chunk| {
+                let mut input = [0u8; 32];
+                input.copy_from_slice(chunk);
+                input
+            })
+            .collect();
+
+        Ok(ProofBundle {
+            circuit: request.circuit,
+            proof: request.proof_data.clone(),
+            public_inputs,
+        })
+    }
+
+    pub(crate) fn parse_sdkey_hash(hex_str: &str) -> CloakResult<[u8; SDKEY_HASH_LEN]> {
         // Validate input is not empty
         if hex_str.trim().is_empty() {
             return Err(CloakError::invalid_input("SDKey hash cannot be empty"));
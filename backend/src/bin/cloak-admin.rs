@@ -0,0 +1,102 @@
+// Cloak Protocol Admin - Operator commands for the state database
+//
+// Usage:
+//   cloak-admin backup [name]              Back up the running node's state
+//   cloak-admin restore <backup> <db_path> Restore a backup into a new database
+//   cloak-admin inspect <backup>           Print a backup's manifest
+//   cloak-admin audit                      Audit the running node's state
+//   cloak-admin keys <dir>                 Generate proving keys and print their pins
+//
+// `backup` and `audit` ask the running node over its REST API (ADMIN_URL,
+// default http://127.0.0.1:$API_PORT) so they can be scheduled without
+// stopping the node. `restore` works offline and must not target a live
+// database. `keys` runs a fresh setup for every circuit, the batch circuit
+// at BATCH_CAPACITY trades; its output is the KEY_PINS value for a node
+// started with KEY_DIR=<dir> and the same BATCH_CAPACITY.
+
+use cloak_backend::api::{BackupRequest, BackupResponse};
+use cloak_backend::prover::keys::ALL_CIRCUITS;
+use cloak_backend::prover::{KeyStore, Prover};
+use cloak_backend::state::TREE_DEPTH;
+use cloak_backend::state::{AuditReport, BackupManifest};
+use cloak_backend::{CloakConfig, CloakError, StateManager};
+use std::path::Path;
+
+const USAGE: &str = "usage: cloak-admin backup [name] | restore <backup> <db_path> | inspect <backup> | audit | keys <dir>";
+
+fn admin_url() -> String {
+    std::env::var("ADMIN_URL").unwrap_or_else(|_| {
+        let port = std::env::var("API_PORT").unwrap_or_else(|_| "8080".to_string());
+        format!("http://127.0.0.1:{}", port)
+    })
+}
+
+fn print_manifest(manifest: &BackupManifest) -> Result<(), CloakError> {
+    println!("{}", serde_json::to_string_pretty(manifest)?);
+    Ok(())
+}
+
+async fn backup(name: Option<String>) -> Result<(), CloakError> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/admin/backup", admin_url()))
+        .json(&BackupRequest { name })
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<BackupResponse>()
+        .await?;
+
+    println!("Backup written to {}", response.path);
+    print_manifest(&response.manifest)
+}
+
+async fn audit() -> Result<(), CloakError> {
+    let report = reqwest::get(format!("{}/api/admin/audit", admin_url()))
+        .await?
+        .error_for_status()?
+        .json::<AuditReport>()
+        .await?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if report.is_clean() {
+        Ok(())
+    } else {
+        Err(CloakError::state(format!("Audit found {} discrepancies", report.discrepancies.len())))
+    }
+}
+
+fn generate_keys(dir: &str) -> Result<(), CloakError> {
+    let store = KeyStore::new(dir);
+    let batch_capacity = CloakConfig::default().batch_capacity;
+    let mut pins = Vec::with_capacity(ALL_CIRCUITS.len());
+    for kind in ALL_CIRCUITS {
+        let key = Prover::generate_key(kind, TREE_DEPTH, batch_capacity, &mut rand::rngs::OsRng)?;
+        let hash = store.save(kind, &key)?;
+        println!("Wrote {}", store.path(kind).display());
+        pins.push(format!("{}={}", kind, hex::encode(hash)));
+    }
+    println!("KEY_PINS={}", pins.join(","));
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), CloakError> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["backup"] => backup(None).await,
+        ["backup", name] => backup(Some(name.to_string())).await,
+        ["restore", backup_dir, db_path] => {
+            let manifest = StateManager::restore_backup(Path::new(backup_dir), db_path)?;
+            println!("Restored into {}", db_path);
+            print_manifest(&manifest)
+        }
+        ["audit"] => audit().await,
+        ["keys", dir] => generate_keys(dir),
+        ["inspect", backup_dir] => print_manifest(&BackupManifest::read(Path::new(backup_dir))?),
+        _ => Err(CloakError::invalid_input(USAGE)),
+    }
+}
@@ -44,6 +44,22 @@ pub enum CloakError {
     #[error("Insufficient balance: required {required}, available {available}")]
     InsufficientBalance { required: u128, available: u128 },
 
+    /// Nullifier already spent (double-spend attempt)
+    #[error("Nullifier already spent: {0}")]
+    NullifierSpent(String),
+
+    /// Transfer rejected by a token's transfer policy
+    #[error("Transfer of {token_id} restricted by {rule} rule: {reason}")]
+    TransferRestricted {
+        token_id: String,
+        rule: crate::state::RestrictionRule,
+        reason: String,
+    },
+
+    /// Proof generation error
+    #[error("Proof generation failed: {0}")]
+    Prover(String),
+
     /// Proof verification error
     #[error("Proof verification failed: {0}")]
     ProofVerification(String),
@@ -84,9 +100,37 @@ impl CloakError {
         Self::InvalidInput(msg.into())
     }
 
+    /// Creates a new proof generation error
+    pub fn prover(msg: impl Into<String>) -> Self {
+        Self::Prover(msg.into())
+    }
+
+    /// Creates a new proof verification error
+    pub fn proof_verification(msg: impl Into<String>) -> Self {
+        Self::ProofVerification(msg.into())
+    }
+
     /// Creates a new user not found error
     pub fn user_not_found(sdkey_hash: &[u8; 32]) -> Self {
         Self::UserNotFound(hex::encode(sdkey_hash))
     }
+
+    /// Creates a new nullifier spent error
+    pub fn nullifier_spent(nullifier: &[u8; 32]) -> Self {
+        Self::NullifierSpent(hex::encode(nullifier))
+    }
+
+    /// Creates a new transfer restriction error
+    pub fn transfer_restricted(
+        token_id: impl Into<String>,
+        rule: crate::state::RestrictionRule,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self::TransferRestricted {
+            token_id: token_id.into(),
+            rule,
+            reason: reason.into(),
+        }
+    }
 }
 
@@ -10,6 +10,7 @@
 pub mod api;
 pub mod error;
 pub mod node;
+pub mod prover;
 pub mod psy_client;
 pub mod state;
 pub mod deploy;
@@ -36,6 +37,22 @@ pub struct CloakConfig {
     pub rest_api_port: u16,
     /// RocksDB database path
     pub db_path: String,
+    /// Number of Psy blocks after which state changes are final
+    pub finality_depth: u64,
+    /// Directory that admin-triggered state backups are written to
+    pub backup_dir: String,
+    /// Maximum number of user states kept in memory
+    pub user_cache_capacity: usize,
+    /// Directory of pinned proving keys; development keys are used if unset
+    pub key_dir: Option<String>,
+    /// Pinned key file hashes, as `circuit=hex,circuit=hex`
+    pub key_pins: String,
+    /// Refuse to start with development proving keys
+    pub production: bool,
+    /// Number of proofs generated concurrently
+    pub prover_workers: usize,
+    /// Number of trades one batch proof settles
+    pub batch_capacity: usize,
     /// Enable verbose logging
     pub verbose: bool,
 }
@@ -47,17 +64,73 @@ impl Default for CloakConfig {
             .unwrap_or_else(|_| "8080".to_string())
             .parse::<u16>()
             .unwrap_or(8080);
-        
+
+        let backup_dir = std::env::var("BACKUP_DIR").unwrap_or_else(|_| "./backups".to_string());
+
+        // Proving keys: production nodes must point KEY_DIR at pinned keys
+        let key_dir = std::env::var("KEY_DIR").ok();
+        let key_pins = std::env::var("KEY_PINS").unwrap_or_default();
+        let production = std::env::var("CLOAK_ENV").is_ok_and(|env| env == "production");
+
+        let prover_workers = std::env::var("PROVER_WORKERS")
+            .ok()
+            .and_then(|workers| workers.parse::<usize>().ok())
+            .unwrap_or(node::jobs::DEFAULT_PROVER_WORKERS);
+
+        let batch_capacity = std::env::var("BATCH_CAPACITY")
+            .ok()
+            .and_then(|capacity| capacity.parse::<usize>().ok())
+            .filter(|capacity| *capacity > 0)
+            .unwrap_or(prover::DEFAULT_BATCH_CAPACITY);
+
         Self {
             psy_rpc_url: "https://testnet-rpc.psy.xyz".to_string(),
             api_bind_addr: "127.0.0.1:50051".to_string(),
             rest_api_port,
             db_path: "./cloak_state.db".to_string(),
+            finality_depth: state::DEFAULT_FINALITY_DEPTH,
+            backup_dir,
+            user_cache_capacity: state::DEFAULT_USER_CACHE_CAPACITY,
+            key_dir,
+            key_pins,
+            production,
+            prover_workers,
+            batch_capacity,
             verbose: false,
         }
     }
 }
 
+impl CloakConfig {
+    /// Gets how the node obtains its proving keys
+    ///
+    /// # Errors
+    /// Returns `CloakError::Config` if the key pins are malformed.
+    pub fn key_config(&self) -> CloakResult<prover::KeyConfig> {
+        let store = match &self.key_dir {
+            Some(dir) => {
+                let pins = prover::keys::parse_pins(&self.key_pins)?;
+                Some(pins.into_iter().fold(prover::KeyStore::new(dir), |store, (kind, hash)| store.with_pin(kind, hash)))
+            }
+            None => None,
+        };
+        Ok(prover::KeyConfig {
+            store,
+            production: self.production,
+            batch_capacity: self.batch_capacity,
+            ..prover::KeyConfig::default()
+        })
+    }
+
+    /// Gets the limits of the node's proving job queue
+    pub fn proof_queue_config(&self) -> node::ProofQueueConfig {
+        node::ProofQueueConfig {
+            workers: self.prover_workers,
+            ..node::ProofQueueConfig::default()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -23,23 +23,41 @@ async fn main() -> Result<(), CloakError> {
     info!("  API Bind Address: {}", config.api_bind_addr);
     info!("  REST API Port: {}", config.rest_api_port);
     info!("  Database Path: {}", config.db_path);
+    info!("  Finality Depth: {}", config.finality_depth);
+    info!("  Backup Directory: {}", config.backup_dir);
+    info!("  User Cache Capacity: {}", config.user_cache_capacity);
+    info!("  Key Directory: {}", config.key_dir.as_deref().unwrap_or("(development keys)"));
+    info!("  Production Mode: {}", config.production);
+    info!("  Prover Workers: {}", config.prover_workers);
+    info!("  Batch Capacity: {}", config.batch_capacity);
 
     // Initialize the Cloak node
     let node = Arc::new(
-        CloakNode::new(&config.psy_rpc_url, &config.db_path).await
+        CloakNode::with_config(
+            &config.psy_rpc_url,
+            &config.db_path,
+            &config.key_config()?,
+            config.proof_queue_config(),
+        ).await
             .map_err(|e| {
                 error!("Failed to initialize Cloak node: {}", e);
                 e
             })?
     );
+    {
+        let mut state_manager = node.state_manager.write().await;
+        state_manager.set_finality_depth(config.finality_depth);
+        state_manager.set_user_cache_capacity(config.user_cache_capacity);
+    }
     info!("Cloak node initialized successfully");
 
     // Initialize the REST API bridge server (for frontend)
     info!("Starting REST API bridge server on port {}", config.rest_api_port);
     let bridge_handle = {
         let port = config.rest_api_port;
+        let node = node.clone();
         tokio::spawn(async move {
-            if let Err(e) = cloak_backend::api::bridge::run_server(port).await {
+            if let Err(e) = cloak_backend::api::bridge::run_server_with_node(port, node).await {
                 error!("REST API bridge server error: {}", e);
             }
         })
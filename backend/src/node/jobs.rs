@@ -0,0 +1,513 @@
+//! Proving Job Queue
+//!
+//! Runs proofs off the request path. Jobs are queued with a priority and
+//! picked up by a fixed number of workers, highest priority first and in
+//! submission order within a priority. A worker builds the witness against
+//! the state at the moment it starts, proves on a blocking thread, then
+//! verifies the proof natively, moving the job through
+//! `queued → generating → complete → verified` (or `failed`).
+//!
+//! Every status change is persisted (see `state::jobs`); jobs still queued
+//! or generating when the node stopped are queued again on startup.
+//! Cancelling a queued job drops it. Groth16 proving cannot be interrupted,
+//! so cancelling a generating job discards its proof once it finishes.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::{BalanceWitness, CircuitKind, ProofBundle, Prover};
+use crate::state::{JobPriority, JobStatus, ProofJob, ProofRequest, StateManager};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tokio::sync::{Notify, RwLock};
+use tracing::{info, warn};
+
+/// Default number of jobs proven concurrently
+pub const DEFAULT_PROVER_WORKERS: usize = 2;
+
+/// Default number of queued jobs before submissions are refused
+pub const DEFAULT_MAX_PENDING_JOBS: usize = 1_024;
+
+/// Draws a blinding for settlement leg commitments
+///
+/// The leading byte stays zero so the blinding is a canonical field element.
+pub fn draw_blinding() -> [u8; 32] {
+    let mut blinding = [0u8; 32];
+    OsRng.fill_bytes(&mut blinding[1..]);
+    blinding
+}
+
+/// Limits of the proving job queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofQueueConfig {
+    /// Number of jobs proven concurrently
+    pub workers: usize,
+
+    /// Number of queued jobs before submissions are refused
+    pub max_pending: usize,
+}
+
+impl Default for ProofQueueConfig {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_PROVER_WORKERS,
+            max_pending: DEFAULT_MAX_PENDING_JOBS,
+        }
+    }
+}
+
+/// In-memory view of the unfinished jobs
+#[derive(Debug, Default)]
+struct JobTable {
+    /// Unfinished jobs by ID
+    active: HashMap<u64, ProofJob>,
+
+    /// Queued job IDs, highest priority and then lowest ID first; entries of
+    /// cancelled jobs are skipped when popped
+    pending: BinaryHeap<(JobPriority, Reverse<u64>)>,
+
+    /// Generating jobs whose proof is to be discarded
+    cancelling: HashSet<u64>,
+
+    /// Number of queued jobs, counting submissions still being persisted
+    queued: usize,
+
+    /// ID of the next submitted job
+    next_id: u64,
+}
+
+struct QueueShared {
+    state_manager: Arc<RwLock<StateManager>>,
+    prover: Arc<RwLock<Prover>>,
+    config: ProofQueueConfig,
+    table: Mutex<JobTable>,
+
+    /// Wakes a worker when a job is queued
+    work: Notify,
+
+    /// Wakes `wait` callers when any job finishes
+    finished: Notify,
+
+    /// Constraint count per circuit, computed on first use
+    constraints: Mutex<HashMap<CircuitKind, u64>>,
+}
+
+/// Priority queue of proving jobs with bounded worker concurrency
+#[derive(Clone)]
+pub struct ProofJobQueue {
+    shared: Arc<QueueShared>,
+}
+
+impl ProofJobQueue {
+    /// Creates the queue, requeueing the jobs left unfinished by a previous run
+    ///
+    /// Workers are not started until `start` is called.
+    ///
+    /// # Errors
+    /// Returns `CloakError::Database` or `CloakError::Serialization` if the
+    /// job records cannot be read or rewritten.
+    pub async fn new(
+        state_manager: Arc<RwLock<StateManager>>,
+        prover: Arc<RwLock<Prover>>,
+        config: ProofQueueConfig,
+    ) -> CloakResult<Self> {
+        let mut table = JobTable::default();
+        {
+            let state = state_manager.read().await;
+            let (unfinished, next_id) = state.unfinished_proof_jobs()?;
+            table.next_id = next_id;
+            if !unfinished.is_empty() {
+                info!("Requeueing {} unfinished proof jobs", unfinished.len());
+            }
+            for mut job in unfinished {
+                job.status = JobStatus::Queued;
+                job.started_at = None;
+                state.put_proof_job(&job)?;
+                table.pending.push((job.priority, Reverse(job.id)));
+                table.active.insert(job.id, job);
+            }
+            table.queued = table.active.len();
+        }
+
+        Ok(Self {
+            shared: Arc::new(QueueShared {
+                state_manager,
+                prover,
+                config,
+                table: Mutex::new(table),
+                work: Notify::new(),
+                finished: Notify::new(),
+                constraints: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Spawns the workers
+    pub fn start(&self) {
+        let workers = self.shared.config.workers.max(1);
+        for _ in 0..workers {
+            let queue = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    let job = queue.next_job().await;
+                    queue.run(job).await;
+                }
+            });
+        }
+        info!("Started {} proof workers", workers);
+    }
+
+    /// Queues a proving job
+    ///
+    /// # Errors
+    /// Returns `CloakError::Prover` if the queue is full, and the database
+    /// errors of persisting the job.
+    pub async fn submit(&self, request: ProofRequest, priority: JobPriority) -> CloakResult<ProofJob> {
+        // Reserve the slot with the check, so concurrent submissions cannot
+        // overfill the queue while this one is persisted
+        let job = {
+            let mut table = self.table();
+            if table.queued >= self.shared.config.max_pending {
+                return Err(CloakError::prover(format!(
+                    "Proof queue is full ({} jobs pending)",
+                    table.queued
+                )));
+            }
+            let job = ProofJob::new(table.next_id, request, priority);
+            table.next_id += 1;
+            table.queued += 1;
+            job
+        };
+
+        // Persist before a worker can see the job, so its own updates land last
+        if let Err(e) = self.shared.state_manager.read().await.put_proof_job(&job) {
+            self.table().queued -= 1;
+            return Err(e);
+        }
+        {
+            let mut table = self.table();
+            table.pending.push((job.priority, Reverse(job.id)));
+            table.active.insert(job.id, job.clone());
+        }
+        self.shared.work.notify_one();
+        Ok(job)
+    }
+
+    /// Cancels a job
+    ///
+    /// A queued job is cancelled at once. A generating job is returned as
+    /// is and finishes as cancelled once its proof is done.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the job is unknown or already
+    /// finished.
+    pub async fn cancel(&self, id: u64) -> CloakResult<ProofJob> {
+        let cancelled = {
+            let mut table = self.table();
+            match table.active.get(&id).map(|job| job.status) {
+                Some(JobStatus::Queued) => {
+                    table.queued -= 1;
+                    table.active.remove(&id).map(|mut job| {
+                        job.finish(JobStatus::Cancelled, None);
+                        job
+                    })
+                }
+                Some(_) => {
+                    table.cancelling.insert(id);
+                    return Ok(table.active[&id].clone());
+                }
+                None => None,
+            }
+        };
+
+        match cancelled {
+            Some(job) => {
+                self.shared.state_manager.read().await.put_proof_job(&job)?;
+                self.shared.finished.notify_waiters();
+                info!("Cancelled proof job {}", id);
+                Ok(job)
+            }
+            None => match self.get(id).await? {
+                Some(job) => Err(CloakError::invalid_input(format!(
+                    "Proof job {} is already {}",
+                    id,
+                    job.status.as_str()
+                ))),
+                None => Err(CloakError::invalid_input(format!("Unknown proof job: {}", id))),
+            },
+        }
+    }
+
+    /// Gets the current record of a job
+    pub async fn get(&self, id: u64) -> CloakResult<Option<ProofJob>> {
+        if let Some(job) = self.table().active.get(&id) {
+            return Ok(Some(job.clone()));
+        }
+        self.shared.state_manager.read().await.get_proof_job(id)
+    }
+
+    /// Gets up to `limit` jobs, newest first
+    pub async fn list(&self, limit: usize) -> CloakResult<Vec<ProofJob>> {
+        self.shared.state_manager.read().await.list_proof_jobs(limit)
+    }
+
+    /// Waits until a job is finished and returns its final record
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the job is unknown.
+    pub async fn wait(&self, id: u64) -> CloakResult<ProofJob> {
+        loop {
+            let finished = self.shared.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+            match self.get(id).await? {
+                Some(job) if job.status.is_finished() => return Ok(job),
+                Some(_) => finished.await,
+                None => return Err(CloakError::invalid_input(format!("Unknown proof job: {}", id))),
+            }
+        }
+    }
+
+    fn table(&self) -> MutexGuard<'_, JobTable> {
+        self.shared.table.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Takes the next queued job, marking it generating
+    fn pop(&self) -> Option<ProofJob> {
+        let mut table = self.table();
+        while let Some((_, Reverse(id))) = table.pending.pop() {
+            if let Some(job) = table.active.get_mut(&id).filter(|job| job.status == JobStatus::Queued) {
+                job.status = JobStatus::Generating;
+                job.started_at = Some(chrono::Utc::now());
+                let job = job.clone();
+                table.queued -= 1;
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    /// Waits for the next queued job
+    async fn next_job(&self) -> ProofJob {
+        loop {
+            let queued = self.shared.work.notified();
+            if let Some(job) = self.pop() {
+                return job;
+            }
+            queued.await;
+        }
+    }
+
+    /// Records a job's new status
+    async fn update(&self, job: &ProofJob) {
+        if let Err(e) = self.shared.state_manager.read().await.put_proof_job(job) {
+            warn!("Failed to persist proof job {}: {}", job.id, e);
+        }
+        if let Some(active) = self.table().active.get_mut(&job.id) {
+            *active = job.clone();
+        }
+    }
+
+    /// Proves, verifies and records one job
+    async fn run(&self, mut job: ProofJob) {
+        self.update(&job).await;
+        let kind = job.request.circuit();
+        job.constraints = self.constraints(kind).await.ok();
+
+        let started = Instant::now();
+        let outcome = self.prove(&job.request).await;
+        job.prove_time_ms = Some(started.elapsed().as_millis() as u64);
+
+        let cancelled = self.table().cancelling.remove(&job.id);
+        match outcome {
+            Err(e) => job.finish(JobStatus::Failed, Some(e.to_string())),
+            Ok(_) if cancelled => job.finish(JobStatus::Cancelled, None),
+            Ok((bundle, blinding)) => {
+                job.status = JobStatus::Complete;
+                job.proof_size = Some(bundle.proof.len() as u64);
+                job.proof = Some(bundle.clone());
+                job.blinding = blinding;
+                self.update(&job).await;
+
+                match self.shared.state_manager.read().await.verify_proof(&bundle) {
+                    Ok(()) => job.finish(JobStatus::Verified, None),
+                    Err(e) => job.finish(JobStatus::Failed, Some(e.to_string())),
+                }
+            }
+        }
+
+        self.update(&job).await;
+        self.table().active.remove(&job.id);
+        self.shared.finished.notify_waiters();
+        match &job.error {
+            Some(error) => warn!("Proof job {} ({}) {}: {}", job.id, kind, job.status.as_str(), error),
+            None => info!(
+                "Proof job {} ({}) {} in {} ms",
+                job.id,
+                kind,
+                job.status.as_str(),
+                job.prove_time_ms.unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Builds the witness of a request and proves it
+    async fn prove(&self, request: &ProofRequest) -> CloakResult<(ProofBundle, Option<[u8; 32]>)> {
+        let state_manager = &self.shared.state_manager;
+        match request {
+            ProofRequest::Balance {
+                user_sdkey_hash,
+                token_id,
+                trade_amount,
+                received_amount,
+            } => {
+                let witness = {
+                    let state = state_manager.read().await;
+                    BalanceWitness::from_state(&state, *user_sdkey_hash, token_id, *trade_amount, *received_amount)?
+                };
+                let bundle = self.on_prover(move |prover| prover.prove_balance(&witness, &mut OsRng)).await?;
+                Ok((bundle, None))
+            }
+            ProofRequest::Settlement { transition } => {
+                let blinding = draw_blinding();
+                let witness = state_manager.read().await.settlement_witness(transition, blinding)?;
+                let bundle = self.on_prover(move |prover| prover.prove_settlement(&witness, &mut OsRng)).await?;
+                Ok((bundle, Some(blinding)))
+            }
+            ProofRequest::Compliance { user_sdkey_hash } => {
+                let witness = state_manager.read().await.compliance_witness(*user_sdkey_hash)?;
+                let bundle = self.on_prover(move |prover| prover.prove_compliance(&witness, &mut OsRng)).await?;
+                Ok((bundle, None))
+            }
+        }
+    }
+
+    /// Gets the constraint count of a circuit
+    async fn constraints(&self, kind: CircuitKind) -> CloakResult<u64> {
+        let cached = self
+            .shared
+            .constraints
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&kind)
+            .copied();
+        if let Some(constraints) = cached {
+            return Ok(constraints);
+        }
+        let constraints = self.on_prover(move |prover| prover.num_constraints(kind)).await? as u64;
+        self.shared
+            .constraints
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(kind, constraints);
+        Ok(constraints)
+    }
+
+    /// Runs CPU-bound prover work on a blocking thread
+    async fn on_prover<T, F>(&self, work: F) -> CloakResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Prover) -> CloakResult<T> + Send + 'static,
+    {
+        let prover = self.shared.prover.clone();
+        tokio::task::spawn_blocking(move || work(&prover.blocking_read()))
+            .await
+            .map_err(|e| CloakError::prover(format!("Proving task failed: {}", e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::{empty_manager, keyed_prover, test_token};
+    use crate::state::{AssetClass, StateTransition, TREE_DEPTH};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn balance_request(user: [u8; 32], trade_amount: u128) -> ProofRequest {
+        ProofRequest::Balance {
+            user_sdkey_hash: user,
+            token_id: "USDC".to_string(),
+            trade_amount,
+            received_amount: 0,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_jobs_run_by_priority_persist_and_report_real_metrics() {
+        let (mut state, path) = empty_manager();
+        let user = [5u8; 32];
+        state.register_user(user).unwrap();
+        state.register_token(test_token("USDC", AssetClass::Treasury)).unwrap();
+        state
+            .apply_transition(StateTransition::Deposit {
+                user_sdkey_hash: user,
+                token_id: "USDC".to_string(),
+                amount: 500,
+            })
+            .unwrap();
+
+        let mut rng = StdRng::seed_from_u64(24);
+        let prover = keyed_prover(&mut state, Prover::new(TREE_DEPTH), &[CircuitKind::Balance], &mut rng);
+        let state = Arc::new(RwLock::new(state));
+        let prover = Arc::new(RwLock::new(prover));
+        let config = ProofQueueConfig { workers: 1, max_pending: 3 };
+
+        // Higher priorities run first, then submission order
+        let queue = ProofJobQueue::new(state.clone(), prover.clone(), config).await.unwrap();
+        let low = queue.submit(balance_request(user, 1), JobPriority::Low).await.unwrap();
+        let normal = queue.submit(balance_request(user, 2), JobPriority::Normal).await.unwrap();
+        let high = queue.submit(balance_request(user, 3), JobPriority::High).await.unwrap();
+        assert!(queue.submit(balance_request(user, 4), JobPriority::High).await.is_err());
+        assert_eq!(queue.pop().map(|job| job.id), Some(high.id));
+        assert_eq!(queue.pop().map(|job| job.id), Some(normal.id));
+
+        // Cancelling a queued job drops it; a finished job cannot be cancelled
+        let cancelled = queue.cancel(low.id).await.unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert!(queue.pop().is_none());
+        assert!(queue.cancel(low.id).await.is_err());
+
+        // Jobs left queued or generating are picked up again by the next queue
+        let overdraw = queue.submit(balance_request(user, 900), JobPriority::Normal).await.unwrap();
+        drop(queue);
+        let queue = ProofJobQueue::new(state.clone(), prover.clone(), config).await.unwrap();
+        queue.start();
+        let job = queue.wait(high.id).await.unwrap();
+        assert_eq!(job.status, JobStatus::Verified, "{:?}", job.error);
+        assert!(job.constraints.unwrap() > 0);
+        assert!(job.prove_time_ms.is_some());
+        assert_eq!(job.proof_size, Some(job.proof.as_ref().unwrap().proof.len() as u64));
+        assert_eq!(queue.wait(normal.id).await.unwrap().status, JobStatus::Verified);
+
+        let failed = queue.wait(overdraw.id).await.unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert!(failed.error.is_some() && failed.proof.is_none());
+
+        let listed: Vec<_> = queue.list(10).await.unwrap().iter().map(|job| (job.id, job.status)).collect();
+        assert_eq!(
+            listed,
+            vec![
+                (overdraw.id, JobStatus::Failed),
+                (high.id, JobStatus::Verified),
+                (normal.id, JobStatus::Verified),
+                (low.id, JobStatus::Cancelled),
+            ]
+        );
+
+        // Concurrent submissions cannot overfill the queue
+        let idle = ProofJobQueue::new(state.clone(), prover, config).await.unwrap();
+        let submitted = futures_util::future::join_all(
+            (1..=5).map(|amount| idle.submit(balance_request(user, amount), JobPriority::Normal)),
+        )
+        .await;
+        assert_eq!(submitted.iter().filter(|result| result.is_ok()).count(), 3);
+        idle.cancel(submitted[0].as_ref().unwrap().id).await.unwrap();
+        assert!(idle.submit(balance_request(user, 6), JobPriority::Normal).await.is_ok());
+        assert!(idle.submit(balance_request(user, 7), JobPriority::Normal).await.is_err());
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
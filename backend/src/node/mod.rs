@@ -3,9 +3,18 @@
 //! Defines the `CloakNode` struct and event loop for managing the Cloak Protocol backend.
 //! The node coordinates between state management, proof generation, order relay, and Psy integration.
 
+pub mod jobs;
+
+pub use jobs::{ProofJobQueue, ProofQueueConfig};
+
 use crate::error::{CloakError, CloakResult};
+use crate::prover::keys::{self, ALL_CIRCUITS};
+use crate::prover::{
+    BalanceWitness, BatchProof, CircuitKind, KeyConfig, KeySource, ProofBundle, Prover, SettlementProof,
+};
 use crate::psy_client::PsyClient;
-use crate::state::StateManager;
+use crate::state::{CacheStats, EpochSettlement, StateManager, StateTransition, TREE_DEPTH};
+use rand::rngs::OsRng;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
@@ -16,9 +25,11 @@ pub struct CloakNode {
     /// Manages the private state and Merkle tree
     pub state_manager: Arc<RwLock<StateManager>>,
 
-    /// Interface to the ZK prover system
-    /// TODO: Implement full ProverInterface with arkworks circuits
-    pub prover_interface: Arc<RwLock<ProverStub>>,
+    /// Groth16 prover for the protocol circuits
+    pub prover_interface: Arc<RwLock<Prover>>,
+
+    /// Background proving jobs run with `prover_interface`
+    pub proof_jobs: ProofJobQueue,
 
     /// Relay for broadcasting encrypted order intents
     /// TODO: Implement full OrderRelay with P2P networking
@@ -28,15 +39,6 @@ pub struct CloakNode {
     pub psy_client: Arc<PsyClient>,
 }
 
-/// Stub for the ZK prover system (to be implemented in Part 2)
-#[derive(Debug, Clone)]
-pub struct ProverStub {
-    /// TODO: Add circuit compilation cache
-    /// TODO: Add witness generation pipeline
-    /// TODO: Add proof batching mechanism
-    pub initialized: bool,
-}
-
 /// Stub for the order relay system (to be implemented in Part 2)
 #[derive(Debug, Clone)]
 pub struct OrderRelayStub {
@@ -47,7 +49,7 @@ pub struct OrderRelayStub {
 }
 
 impl CloakNode {
-    /// Creates a new CloakNode with Psy testnet connection
+    /// Creates a new CloakNode with Psy testnet connection and development keys
     ///
     /// # Arguments
     /// * `psy_rpc_url` - The Psy Protocol testnet RPC endpoint
@@ -56,8 +58,30 @@ impl CloakNode {
     /// # Returns
     /// A new CloakNode instance or an error if initialization fails
     pub async fn new(psy_rpc_url: &str, db_path: &str) -> CloakResult<Self> {
+        Self::with_config(psy_rpc_url, db_path, &KeyConfig::default(), ProofQueueConfig::default()).await
+    }
+
+    /// Creates a new CloakNode with proving keys obtained as `keys` describes
+    /// and a proving job queue limited by `queue`
+    ///
+    /// # Errors
+    /// Returns `CloakError::Config` if the keys cannot be loaded or are
+    /// development keys in production mode, besides the errors of `new`.
+    pub async fn with_config(
+        psy_rpc_url: &str,
+        db_path: &str,
+        keys: &KeyConfig,
+        queue: ProofQueueConfig,
+    ) -> CloakResult<Self> {
         info!("Initializing Cloak Protocol node with Psy testnet: {}", psy_rpc_url);
 
+        // Load the proving keys first, so a misconfigured node fails before
+        // it connects anywhere
+        let prover = keys.build_prover(TREE_DEPTH)?;
+        if prover.uses_development_keys() {
+            warn!("Prover is using development keys; proofs can be forged by anyone with the seed");
+        }
+
         // Initialize Psy client with WebSocket connection
         let psy_client = Arc::new(PsyClient::new(psy_rpc_url).await?);
         info!("Connected to Psy Protocol testnet");
@@ -66,8 +90,18 @@ impl CloakNode {
         let state_manager = Arc::new(RwLock::new(StateManager::new(db_path)?));
         info!("State manager initialized with database at: {}", db_path);
 
-        // Initialize prover interface stub
-        let prover_interface = Arc::new(RwLock::new(ProverStub { initialized: true }));
+        // Hand the verifying keys matching the proving keys to the state manager
+        for kind in ALL_CIRCUITS {
+            if let Some(key) = prover.verifying_key(kind) {
+                state_manager.write().await.register_verifying_key(kind, key);
+            }
+        }
+        let prover_interface = Arc::new(RwLock::new(prover));
+        info!("Prover initialized for Merkle depth {}", TREE_DEPTH);
+
+        // Start the proving workers, picking up jobs left by a previous run
+        let proof_jobs = ProofJobQueue::new(state_manager.clone(), prover_interface.clone(), queue).await?;
+        proof_jobs.start();
 
         // Initialize order relay stub
         let order_relay = Arc::new(RwLock::new(OrderRelayStub { initialized: true }));
@@ -75,6 +109,7 @@ impl CloakNode {
         Ok(Self {
             state_manager,
             prover_interface,
+            proof_jobs,
             order_relay,
             psy_client,
         })
@@ -85,13 +120,12 @@ impl CloakNode {
     /// This loop:
     /// 1. Subscribes to Psy block headers
     /// 2. Processes incoming order intents
-    /// 3. Generates batch settlement proofs
+    /// 3. Settles matched trades in epochs (see `settle_epoch`)
     /// 4. Submits proofs to the Psy verifier contract
     ///
     /// # TODO for Part 2:
     /// - Implement full block subscription and processing
     /// - Add order matching and aggregation
-    /// - Implement batch proof generation
     /// - Add error recovery and retry logic
     pub async fn start_event_loop(&self) -> CloakResult<()> {
         info!("Starting Cloak node event loop");
@@ -113,7 +147,9 @@ impl CloakNode {
 
     /// Processes a new block from the Psy chain
     /// TODO: Implement full block processing logic
-    async fn process_block(&self, _block_number: u64) -> CloakResult<()> {
+    async fn process_block(&self, block_number: u64) -> CloakResult<()> {
+        self.state_manager.write().await.set_block_height(block_number);
+
         // TODO: Extract state transitions from block
         // TODO: Validate transitions against ZK proofs
         // TODO: Update local state
@@ -121,14 +157,126 @@ impl CloakNode {
         Ok(())
     }
 
+    /// Proves a balance update for a user against the current state root
+    ///
+    /// Builds the witness from the user's state and Merkle path, then proves
+    /// it with the balance circuit. The state itself is not modified.
+    ///
+    /// # Errors
+    /// Returns the witness errors of `BalanceWitness::from_state`, and
+    /// `CloakError::Prover` if proving fails.
+    pub async fn prove_balance(
+        &self,
+        sdkey_hash: [u8; 32],
+        token_id: &str,
+        trade_amount: u128,
+        received_amount: u128,
+    ) -> CloakResult<ProofBundle> {
+        let witness = {
+            let state = self.state_manager.read().await;
+            BalanceWitness::from_state(&state, sdkey_hash, token_id, trade_amount, received_amount)?
+        };
+        self.prover_interface.read().await.prove_balance(&witness, &mut OsRng)
+    }
+
+    /// Proves the settlement of a trade against the current state root
+    ///
+    /// Draws a fresh blinding for the leg commitments and returns the proof
+    /// to attach to the trade before applying it. The state itself is not
+    /// modified, so the proof goes stale if another transition lands first.
+    ///
+    /// # Errors
+    /// Returns the errors of `StateManager::settlement_witness`, and
+    /// `CloakError::Prover` if proving fails.
+    pub async fn prove_settlement(&self, transition: &StateTransition) -> CloakResult<SettlementProof> {
+        let blinding = jobs::draw_blinding();
+        let witness = self.state_manager.read().await.settlement_witness(transition, blinding)?;
+        let proof = self.prover_interface.read().await.prove_settlement(&witness, &mut OsRng)?;
+        Ok(SettlementProof { proof, blinding })
+    }
+
+    /// Settles an epoch of trades with one batch proof and one submission
+    ///
+    /// Proves the trades against the current root under a fresh blinding,
+    /// applies them all-or-nothing, then submits the batch proof to the Psy
+    /// verifier contract once. If another transition lands while proving,
+    /// the proof goes stale and the epoch is refused.
+    ///
+    /// # Errors
+    /// Returns the errors of `StateManager::batch_witness` and
+    /// `StateManager::apply_epoch`, `CloakError::Prover` if proving fails,
+    /// and any error submitting the proof, in which case the epoch stays
+    /// applied.
+    pub async fn settle_epoch(&self, trades: Vec<StateTransition>) -> CloakResult<EpochReceipt> {
+        let blinding = jobs::draw_blinding();
+        let witness = self.state_manager.read().await.batch_witness(&trades, blinding)?;
+        let proof = self.prover_interface.read().await.prove_batch(&witness, &mut OsRng)?;
+        let batch = BatchProof { proof, blinding };
+        let settlement = self.state_manager.write().await.apply_epoch(trades, &batch)?;
+
+        let tx_hash = self
+            .submit_trade_proof(batch.proof.proof.clone(), batch.proof.public_inputs.concat())
+            .await?;
+        info!("Submitted epoch of {} trades in {}", settlement.trade_count, tx_hash);
+        Ok(EpochReceipt {
+            settlement,
+            batch,
+            tx_hash,
+        })
+    }
+
+    /// Proves a user's compliance at the current block height
+    ///
+    /// # Errors
+    /// Returns the errors of `StateManager::compliance_witness`, and
+    /// `CloakError::Prover` if proving fails.
+    pub async fn prove_compliance(&self, sdkey_hash: [u8; 32]) -> CloakResult<ProofBundle> {
+        let witness = self.state_manager.read().await.compliance_witness(sdkey_hash)?;
+        self.prover_interface.read().await.prove_compliance(&witness, &mut OsRng)
+    }
+
+    /// Verifies a proof submitted by a user, recording compliance proofs as
+    /// the user's attestation
+    ///
+    /// # Errors
+    /// Returns `CloakError::ProofVerification` if the proof is refused by
+    /// `verify_proof`, or by `StateManager::submit_compliance_proof` for a
+    /// compliance proof.
+    pub async fn accept_proof(&self, sdkey_hash: [u8; 32], bundle: &ProofBundle) -> CloakResult<()> {
+        if bundle.circuit == CircuitKind::Compliance {
+            self.state_manager.write().await.submit_compliance_proof(sdkey_hash, bundle)?;
+            return Ok(());
+        }
+        self.verify_proof(bundle).await
+    }
+
+    /// Gets the compressed verifying key for `kind` and where its proving key came from
+    ///
+    /// # Errors
+    /// Returns `CloakError::Prover` if the key cannot be serialized.
+    pub async fn verifying_key(&self, kind: CircuitKind) -> CloakResult<Option<(Vec<u8>, KeySource)>> {
+        let prover = self.prover_interface.read().await;
+        match (prover.verifying_key(kind), prover.key_source(kind)) {
+            (Some(key), Some(source)) => Ok(Some((keys::encode_verifying_key(key)?, source))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Verifies a proof natively against the current state root
+    ///
+    /// # Errors
+    /// Returns `CloakError::ProofVerification` if the proof does not verify
+    /// against the stored verifying key for its circuit, or was made against
+    /// a different root.
+    pub async fn verify_proof(&self, bundle: &ProofBundle) -> CloakResult<()> {
+        self.state_manager.read().await.verify_proof(bundle)
+    }
+
     /// Submits a private trade proof to the Psy verifier contract
     /// TODO: Implement full proof submission with gas estimation
-    pub async fn submit_trade_proof(&self, _proof_data: Vec<u8>) -> CloakResult<String> {
-        // TODO: Serialize proof to Psy contract format
-        // TODO: Estimate gas costs
-        // TODO: Submit transaction
-        // TODO: Return transaction hash
-        Ok("0x0000000000000000000000000000000000000000000000000000000000000000".to_string())
+    pub async fn submit_trade_proof(&self, proof_data: Vec<u8>, public_inputs: Vec<u8>) -> CloakResult<String> {
+        let submission = self.psy_client.submit_proof(proof_data, public_inputs).await?;
+        Ok(submission.tx_hash)
     }
 
     /// Gets the current state of the node
@@ -138,7 +286,9 @@ impl CloakNode {
             is_running: true,
             psy_connected: self.psy_client.is_connected().await,
             state_root: hex::encode(state.get_merkle_root()),
+            nullifier_root: hex::encode(state.get_nullifier_root()),
             active_users: state.get_user_count(),
+            user_cache: state.get_user_cache_stats(),
         }
     }
 }
@@ -149,7 +299,17 @@ pub struct NodeStatus {
     pub is_running: bool,
     pub psy_connected: bool,
     pub state_root: String,
+    pub nullifier_root: String,
     pub active_users: usize,
+    pub user_cache: CacheStats,
+}
+
+/// An epoch settled by `settle_epoch`
+#[derive(Debug, Clone)]
+pub struct EpochReceipt {
+    pub settlement: EpochSettlement,
+    pub batch: BatchProof,
+    pub tx_hash: String,
 }
 
 #[cfg(test)]
@@ -157,9 +317,10 @@ mod tests {
     use super::*;
 
     #[tokio::test]
-    async fn test_prover_stub_initialization() {
-        let prover = ProverStub { initialized: true };
-        assert!(prover.initialized);
+    async fn test_prover_starts_without_keys() {
+        let prover = Prover::new(TREE_DEPTH);
+        assert_eq!(prover.depth(), TREE_DEPTH);
+        assert!(!prover.is_ready(CircuitKind::Balance));
     }
 
     #[tokio::test]
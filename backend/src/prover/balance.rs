@@ -0,0 +1,271 @@
+//! Balance Proof Circuit
+//!
+//! Proves that a user's leaf moves from `merkle_root_old` to
+//! `merkle_root_new` by debiting `trade_amount` of one token and crediting
+//! `received_amount` of the same token, without revealing the balance:
+//!
+//! 1. `old_balance >= trade_amount` (range checks)
+//! 2. `new_balance = old_balance - trade_amount + received_amount`
+//! 3. the old leaf authenticates against `merkle_root_old`
+//! 4. the updated leaf, at the same slot with the nonce bumped, authenticates
+//!    against `merkle_root_new`
+//!
+//! A leaf commits to every balance the user holds, folded in token-ID order.
+//! The witness splits that fold around the proven token: the balances sorted
+//! before it are carried as a single digest, and the ones sorted after it are
+//! refolded in-circuit from up to `MAX_TRAILING_BALANCES` padded slots.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::gadgets::{self, AMOUNT_BITS};
+use crate::state::{poseidon, StateManager};
+use ark_bls12_381::Fr;
+use ark_ff::Zero;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use serde::{Deserialize, Serialize};
+
+/// Number of balances sorted after the proven token that a witness can carry
+pub const MAX_TRAILING_BALANCES: usize = 8;
+
+/// Private and public inputs of the balance circuit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceWitness {
+    /// SDKey hash of the user whose leaf is updated (public)
+    pub sdkey_hash: [u8; 32],
+
+    /// Token whose balance changes (public)
+    pub token_id: String,
+
+    /// Amount debited from the balance (public)
+    pub trade_amount: u128,
+
+    /// Balance before the update
+    pub old_balance: u128,
+
+    /// Amount credited to the balance
+    pub received_amount: u128,
+
+    /// Leaf nonce before the update
+    pub nonce: u64,
+
+    /// Balances digest folded over the tokens sorted before `token_id`
+    pub preceding_digest: [u8; 32],
+
+    /// Non-zero balances sorted after `token_id`, in token-ID order
+    pub trailing_balances: Vec<(String, u128)>,
+
+    /// Sibling hashes from the leaf level up to just below the root
+    pub merkle_path: Vec<[u8; 32]>,
+
+    /// Direction bits for each level of `merkle_path`
+    pub merkle_path_indices: Vec<bool>,
+
+    /// Root before the update (public)
+    pub merkle_root_old: [u8; 32],
+
+    /// Root after the update (public)
+    pub merkle_root_new: [u8; 32],
+}
+
+impl BalanceWitness {
+    /// Builds a witness for updating one of a user's balances in the current state
+    ///
+    /// `merkle_root_new` is the root the tree would have after the update; the
+    /// state itself is not modified.
+    ///
+    /// # Errors
+    /// Returns `CloakError::UserNotFound` if the user has no leaf,
+    /// `CloakError::State` if the user shares its leaf slot with another key,
+    /// `CloakError::InsufficientBalance` if `trade_amount` exceeds the balance,
+    /// and `CloakError::InvalidInput` if the new balance overflows or the user
+    /// holds more than `MAX_TRAILING_BALANCES` tokens sorted after `token_id`.
+    pub fn from_state(
+        state: &StateManager,
+        sdkey_hash: [u8; 32],
+        token_id: &str,
+        trade_amount: u128,
+        received_amount: u128,
+    ) -> CloakResult<Self> {
+        let user = state
+            .get_user_state(sdkey_hash)
+            .ok_or_else(|| CloakError::user_not_found(&sdkey_hash))?;
+        let proof = state.generate_merkle_proof(sdkey_hash)?;
+        if !proof.is_inclusion() {
+            return Err(CloakError::user_not_found(&sdkey_hash));
+        }
+        if !proof.slot_leaves.is_empty() {
+            return Err(CloakError::state(format!(
+                "User {} shares its leaf slot with another key, which the circuit cannot prove",
+                hex::encode(sdkey_hash)
+            )));
+        }
+
+        let old_balance = user.get_balance(token_id);
+        let new_balance = old_balance
+            .checked_sub(trade_amount)
+            .ok_or(CloakError::InsufficientBalance {
+                required: trade_amount,
+                available: old_balance,
+            })?
+            .checked_add(received_amount)
+            .ok_or_else(|| CloakError::invalid_input("New balance overflows"))?;
+
+        let mut sorted: Vec<(&String, &u128)> = user
+            .balances
+            .iter()
+            .filter(|(token, amount)| **amount > 0 && token.as_str() != token_id)
+            .collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut preceding_digest = Fr::zero();
+        let mut trailing_balances = Vec::new();
+        for (token, amount) in sorted {
+            if token.as_str() < token_id {
+                preceding_digest = poseidon::hash(&[
+                    preceding_digest,
+                    poseidon::string_to_field(token),
+                    Fr::from(*amount),
+                ]);
+            } else {
+                trailing_balances.push((token.clone(), *amount));
+            }
+        }
+        if trailing_balances.len() > MAX_TRAILING_BALANCES {
+            return Err(CloakError::invalid_input(format!(
+                "User holds {} tokens sorted after {}, the balance circuit supports {}",
+                trailing_balances.len(),
+                token_id,
+                MAX_TRAILING_BALANCES
+            )));
+        }
+
+        let mut updated = user.clone();
+        updated.update_balance(token_id.to_string(), new_balance);
+        let mut new_proof = proof.clone();
+        new_proof.leaf = poseidon::field_to_bytes(&updated.leaf_hash());
+
+        Ok(Self {
+            sdkey_hash,
+            token_id: token_id.to_string(),
+            trade_amount,
+            old_balance,
+            received_amount,
+            nonce: user.nonce,
+            preceding_digest: poseidon::field_to_bytes(&preceding_digest),
+            trailing_balances,
+            merkle_path: proof.merkle_path,
+            merkle_path_indices: proof.merkle_path_indices,
+            merkle_root_old: proof.root,
+            merkle_root_new: poseidon::field_to_bytes(&new_proof.compute_root()),
+        })
+    }
+
+    /// Creates a placeholder witness with the shape of a tree of `depth` levels
+    ///
+    /// Used for key generation, where only the circuit layout matters.
+    pub fn blank(depth: usize) -> Self {
+        Self {
+            sdkey_hash: [0u8; 32],
+            token_id: String::new(),
+            trade_amount: 0,
+            old_balance: 0,
+            received_amount: 0,
+            nonce: 0,
+            preceding_digest: [0u8; 32],
+            trailing_balances: Vec::new(),
+            merkle_path: vec![[0u8; 32]; depth],
+            merkle_path_indices: vec![false; depth],
+            merkle_root_old: [0u8; 32],
+            merkle_root_new: [0u8; 32],
+        }
+    }
+
+    /// Gets the public inputs in circuit order
+    ///
+    /// `[merkle_root_old, merkle_root_new, token_id, trade_amount, user_sdkey_hash]`
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        vec![
+            poseidon::bytes_to_field(&self.merkle_root_old),
+            poseidon::bytes_to_field(&self.merkle_root_new),
+            poseidon::string_to_field(&self.token_id),
+            Fr::from(self.trade_amount),
+            poseidon::bytes_to_field(&self.sdkey_hash),
+        ]
+    }
+}
+
+/// R1CS circuit for a single balance update, see the module docs
+#[derive(Debug, Clone)]
+pub struct BalanceProofCircuit {
+    witness: BalanceWitness,
+}
+
+impl BalanceProofCircuit {
+    /// Creates the circuit for a witness
+    pub fn new(witness: BalanceWitness) -> Self {
+        Self { witness }
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for BalanceProofCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let w = self.witness;
+        let input = |value: Fr| FpVar::new_input(cs.clone(), || Ok(value));
+        let private = |value: Fr| FpVar::new_witness(cs.clone(), || Ok(value));
+
+        let public = w.public_inputs();
+        let root_old = input(public[0])?;
+        let root_new = input(public[1])?;
+        let token = input(public[2])?;
+        let trade_amount = input(public[3])?;
+        let sdkey_hash = input(public[4])?;
+
+        let old_balance = private(Fr::from(w.old_balance))?;
+        let received_amount = private(Fr::from(w.received_amount))?;
+        let nonce = private(Fr::from(w.nonce))?;
+        let preceding = private(poseidon::bytes_to_field(&w.preceding_digest))?;
+
+        let mut trailing = Vec::with_capacity(MAX_TRAILING_BALANCES);
+        for slot in 0..MAX_TRAILING_BALANCES {
+            let (slot_token, slot_amount) = match w.trailing_balances.get(slot) {
+                Some((token_id, amount)) => (poseidon::string_to_field(token_id), Fr::from(*amount)),
+                None => (Fr::zero(), Fr::zero()),
+            };
+            trailing.push((private(slot_token)?, private(slot_amount)?));
+        }
+
+        let mut path = Vec::with_capacity(w.merkle_path.len());
+        for sibling in &w.merkle_path {
+            path.push(private(poseidon::bytes_to_field(sibling))?);
+        }
+        let mut indices = Vec::with_capacity(w.merkle_path_indices.len());
+        for is_right in &w.merkle_path_indices {
+            indices.push(Boolean::new_witness(cs.clone(), || Ok(*is_right))?);
+        }
+
+        // Range checks: no amount wraps around the field, and the debit
+        // leaves a non-negative remainder
+        let remainder = &old_balance - &trade_amount;
+        let new_balance = &remainder + &received_amount;
+        for amount in [&old_balance, &trade_amount, &received_amount, &remainder, &new_balance] {
+            gadgets::enforce_range(cs.clone(), amount, AMOUNT_BITS)?;
+        }
+
+        // Old and new leaves share every balance except the proven one
+        let digest = |balance: &FpVar<Fr>| -> Result<FpVar<Fr>, SynthesisError> {
+            let mut digest = gadgets::fold_balance(cs.clone(), &preceding, &token, balance)?;
+            for (slot_token, slot_amount) in &trailing {
+                digest = gadgets::fold_balance(cs.clone(), &digest, slot_token, slot_amount)?;
+            }
+            Ok(digest)
+        };
+        let old_leaf = gadgets::user_leaf(cs.clone(), &sdkey_hash, &digest(&old_balance)?, &nonce)?;
+        let new_nonce = &nonce + FpVar::one();
+        let new_leaf = gadgets::user_leaf(cs.clone(), &sdkey_hash, &digest(&new_balance)?, &new_nonce)?;
+
+        gadgets::merkle_root(cs.clone(), &old_leaf, &path, &indices)?.enforce_equal(&root_old)?;
+        gadgets::merkle_root(cs, &new_leaf, &path, &indices)?.enforce_equal(&root_new)
+    }
+}
+
@@ -0,0 +1,193 @@
+//! Batch Settlement Circuit
+//!
+//! Proves an epoch of trades in one proof, so a single on-chain submission
+//! settles all of them. The circuit lays out `capacity` settlement steps
+//! (see `settlement::enforce_settlement`); step `i` starts from the root the
+//! previous step left, and only the first `trade_count` steps are enabled,
+//! the rest passing the root through unchanged.
+//!
+//! Public inputs are `[merkle_root_old, merkle_root_new, trade_count,
+//! legs_digest]`. The legs digest chains every enabled step's leg
+//! commitments, `d_i = H(d_{i-1} || commitment_a || commitment_b)` from
+//! `d_0 = 0`, so the proof is bound to the exact trades of the epoch and
+//! their order without revealing amounts or counterparties. All legs share
+//! the epoch's blinding.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::gadgets;
+use crate::prover::settlement::{enforce_settlement, leg_commitment, SettlementWitness};
+use crate::prover::ProofBundle;
+use crate::state::poseidon;
+use ark_bls12_381::Fr;
+use ark_ff::Zero;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use serde::{Deserialize, Serialize};
+
+/// Default number of trades a batch proof can settle
+pub const DEFAULT_BATCH_CAPACITY: usize = 8;
+
+/// Chains one trade's leg commitments into the epoch's legs digest
+pub fn fold_legs(digest: Fr, commitment_a: Fr, commitment_b: Fr) -> Fr {
+    poseidon::hash(&[digest, commitment_a, commitment_b])
+}
+
+/// A batch proof settling an epoch, with the blinding that opens its legs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchProof {
+    /// Groth16 proof of the batch circuit
+    pub proof: ProofBundle,
+
+    /// Blinding of every leg commitment in the epoch
+    pub blinding: [u8; 32],
+}
+
+/// Private and public inputs of the batch circuit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchWitness {
+    /// Settlement of each trade, in epoch order, each against the root the
+    /// previous one left
+    pub steps: Vec<SettlementWitness>,
+
+    /// Blinding of every leg commitment
+    pub blinding: [u8; 32],
+
+    /// Root before the epoch (public)
+    pub merkle_root_old: [u8; 32],
+
+    /// Root after the epoch (public)
+    pub merkle_root_new: [u8; 32],
+}
+
+impl BatchWitness {
+    /// Creates a placeholder witness with no trades
+    pub fn blank() -> Self {
+        Self {
+            steps: Vec::new(),
+            blinding: [0u8; 32],
+            merkle_root_old: [0u8; 32],
+            merkle_root_new: [0u8; 32],
+        }
+    }
+
+    /// Checks every step's authentication paths have `depth` levels
+    pub fn has_depth(&self, depth: usize) -> bool {
+        self.steps.iter().all(|step| step.depth() == Some(depth))
+    }
+
+    /// Computes the legs digest of the epoch
+    pub fn legs_digest(&self) -> Fr {
+        self.steps.iter().fold(Fr::zero(), |digest, step| {
+            fold_legs(
+                digest,
+                leg_commitment(&step.user_a.sdkey_hash, &step.token_a_id, step.amount_a, &self.blinding),
+                leg_commitment(&step.user_b.sdkey_hash, &step.token_b_id, step.amount_b, &self.blinding),
+            )
+        })
+    }
+
+    /// Gets the public inputs in circuit order
+    ///
+    /// `[merkle_root_old, merkle_root_new, trade_count, legs_digest]`
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        vec![
+            poseidon::bytes_to_field(&self.merkle_root_old),
+            poseidon::bytes_to_field(&self.merkle_root_new),
+            Fr::from(self.steps.len() as u64),
+            self.legs_digest(),
+        ]
+    }
+}
+
+/// One leg of a trade: `(sdkey_hash, token_id, amount)`
+pub type TradeLeg<'a> = (&'a [u8; 32], &'a str, u128);
+
+/// Checks a batch proof's count and legs digest match the given trades
+///
+/// Each entry is the maker's and taker's leg of one trade, in epoch order.
+///
+/// # Errors
+/// Returns `CloakError::ProofVerification` if the proof does not have the
+/// batch layout, settles a different number of trades, or its digest does
+/// not open to the legs.
+pub fn check_legs(batch: &BatchProof, legs: &[(TradeLeg<'_>, TradeLeg<'_>)]) -> CloakResult<()> {
+    let inputs = &batch.proof.public_inputs;
+    if inputs.len() != 4 {
+        return Err(CloakError::proof_verification(format!(
+            "Batch proof has {} public inputs, expected 4",
+            inputs.len()
+        )));
+    }
+    if inputs[2] != poseidon::field_to_bytes(&Fr::from(legs.len() as u64)) {
+        return Err(CloakError::proof_verification(format!(
+            "Batch proof does not settle {} trades",
+            legs.len()
+        )));
+    }
+    let digest = legs.iter().fold(Fr::zero(), |digest, ((sdkey_a, token_a, amount_a), (sdkey_b, token_b, amount_b))| {
+        fold_legs(
+            digest,
+            leg_commitment(sdkey_a, token_a, *amount_a, &batch.blinding),
+            leg_commitment(sdkey_b, token_b, *amount_b, &batch.blinding),
+        )
+    });
+    if inputs[3] != poseidon::field_to_bytes(&digest) {
+        return Err(CloakError::proof_verification("Batch legs digest does not open to the epoch's trades"));
+    }
+    Ok(())
+}
+
+/// R1CS circuit settling up to `capacity` trades, see the module docs
+#[derive(Debug, Clone)]
+pub struct BatchCircuit {
+    witness: BatchWitness,
+    depth: usize,
+    capacity: usize,
+}
+
+impl BatchCircuit {
+    /// Creates the circuit for a witness, padded to `capacity` steps of
+    /// trees of `depth` levels
+    pub fn new(witness: BatchWitness, depth: usize, capacity: usize) -> Self {
+        Self { witness, depth, capacity }
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for BatchCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let w = self.witness;
+        let input = |value: Fr| FpVar::new_input(cs.clone(), || Ok(value));
+
+        let public = w.public_inputs();
+        let root_old = input(public[0])?;
+        let root_new = input(public[1])?;
+        let trade_count = input(public[2])?;
+        let legs_digest = input(public[3])?;
+
+        let padding = SettlementWitness::blank(self.depth);
+        let mut root = root_old;
+        let mut digest = FpVar::zero();
+        let mut count = FpVar::zero();
+        let mut previous = Boolean::TRUE;
+        for index in 0..self.capacity {
+            let step = w.steps.get(index).unwrap_or(&padding);
+            let enabled = Boolean::new_witness(cs.clone(), || Ok(index < w.steps.len()))?;
+
+            // Enabled steps form a prefix, so `trade_count` fixes which ones
+            enabled.and(&previous.not())?.enforce_equal(&Boolean::FALSE)?;
+            count += FpVar::from(enabled.clone());
+
+            let settled = enforce_settlement(cs.clone(), step, &root, &enabled)?;
+            let folded =
+                gadgets::poseidon_hash(cs.clone(), &[digest.clone(), settled.commitment_a, settled.commitment_b])?;
+            digest = FpVar::conditionally_select(&enabled, &folded, &digest)?;
+            root = settled.root;
+            previous = enabled;
+        }
+
+        count.enforce_equal(&trade_count)?;
+        digest.enforce_equal(&legs_digest)?;
+        root.enforce_equal(&root_new)
+    }
+}
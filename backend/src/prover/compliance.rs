@@ -0,0 +1,169 @@
+//! Compliance Circuit
+//!
+//! Proves a user may trade regulated assets without revealing who they are
+//! or where they are:
+//!
+//! - the user holds an accreditation credential
+//!   `H(sdkey_hash || jurisdiction_hash || expires_at)` in the committed
+//!   credential registry, and it has not expired at `block_height`
+//! - the credential's jurisdiction is not in the committed sanctions list
+//!
+//! The sanctions list is a sparse Merkle tree keyed by jurisdiction hash
+//! (see `state::compliance`). Non-membership opens the jurisdiction's own
+//! slot, whose path bits are derived from the hash in-circuit, and shows it
+//! does not hold the jurisdiction's sanction leaf `H(jurisdiction_hash)`.
+//!
+//! Public inputs are `[sanctions_root, credentials_root, user_sdkey_hash,
+//! block_height]`.
+
+use crate::prover::gadgets;
+use crate::state::poseidon;
+use crate::state::merkle::MAX_TREE_DEPTH;
+use ark_bls12_381::Fr;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use serde::{Deserialize, Serialize};
+
+/// Bits of block heights and credential expiries
+const HEIGHT_BITS: usize = 64;
+
+/// Bits of a field element's 32-byte big-endian encoding
+const ENCODING_BITS: usize = 256;
+
+/// Private and public inputs of the compliance circuit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComplianceWitness {
+    /// SDKey hash the credential was issued to (public)
+    pub sdkey_hash: [u8; 32],
+
+    /// Hash of the credential's jurisdiction code
+    pub jurisdiction_hash: [u8; 32],
+
+    /// Block height the credential expires at
+    pub expires_at: u64,
+
+    /// Credential leaf path in the registry, from the leaf level up
+    pub credential_path: Vec<[u8; 32]>,
+
+    /// Direction bits for each level of `credential_path`
+    pub credential_path_indices: Vec<bool>,
+
+    /// Leaf currently in the jurisdiction's slot of the sanctions tree
+    pub sanctions_slot_leaf: [u8; 32],
+
+    /// Path of the jurisdiction's slot in the sanctions tree
+    pub sanctions_path: Vec<[u8; 32]>,
+
+    /// Root of the sanctions tree (public)
+    pub sanctions_root: [u8; 32],
+
+    /// Root of the credential registry (public)
+    pub credentials_root: [u8; 32],
+
+    /// Block height the proof is made at (public)
+    pub block_height: u64,
+}
+
+impl ComplianceWitness {
+    /// Creates a placeholder witness with the shape of trees of `depth` levels
+    pub fn blank(depth: usize) -> Self {
+        Self {
+            sdkey_hash: [0u8; 32],
+            jurisdiction_hash: [0u8; 32],
+            expires_at: 0,
+            credential_path: vec![[0u8; 32]; depth],
+            credential_path_indices: vec![false; depth],
+            sanctions_slot_leaf: [0u8; 32],
+            sanctions_path: vec![[0u8; 32]; depth],
+            sanctions_root: [0u8; 32],
+            credentials_root: [0u8; 32],
+            block_height: 0,
+        }
+    }
+
+    /// Gets the depth of both trees, if the paths agree
+    pub fn depth(&self) -> Option<usize> {
+        let depth = self.credential_path.len();
+        (self.credential_path_indices.len() == depth && self.sanctions_path.len() == depth).then_some(depth)
+    }
+
+    /// Gets the public inputs in circuit order
+    ///
+    /// `[sanctions_root, credentials_root, user_sdkey_hash, block_height]`
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        vec![
+            poseidon::bytes_to_field(&self.sanctions_root),
+            poseidon::bytes_to_field(&self.credentials_root),
+            poseidon::bytes_to_field(&self.sdkey_hash),
+            Fr::from(self.block_height),
+        ]
+    }
+}
+
+/// R1CS circuit for sanctions non-membership and accreditation, see the module docs
+#[derive(Debug, Clone)]
+pub struct ComplianceCircuit {
+    witness: ComplianceWitness,
+}
+
+impl ComplianceCircuit {
+    /// Creates the circuit for a witness
+    pub fn new(witness: ComplianceWitness) -> Self {
+        Self { witness }
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for ComplianceCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let w = self.witness;
+        let depth = w.credential_path.len();
+        if depth == 0 || depth > MAX_TREE_DEPTH || w.depth() != Some(depth) {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        let input = |value: Fr| FpVar::new_input(cs.clone(), || Ok(value));
+        let private = |value: Fr| FpVar::new_witness(cs.clone(), || Ok(value));
+
+        let public = w.public_inputs();
+        let sanctions_root = input(public[0])?;
+        let credentials_root = input(public[1])?;
+        let sdkey_hash = input(public[2])?;
+        let block_height = input(public[3])?;
+
+        let jurisdiction = private(poseidon::bytes_to_field(&w.jurisdiction_hash))?;
+        let expires_at = private(Fr::from(w.expires_at))?;
+        let mut credential_path = Vec::with_capacity(depth);
+        for sibling in &w.credential_path {
+            credential_path.push(private(poseidon::bytes_to_field(sibling))?);
+        }
+        let mut credential_indices = Vec::with_capacity(depth);
+        for is_right in &w.credential_path_indices {
+            credential_indices.push(Boolean::new_witness(cs.clone(), || Ok(*is_right))?);
+        }
+        let slot_leaf = private(poseidon::bytes_to_field(&w.sanctions_slot_leaf))?;
+        let mut sanctions_path = Vec::with_capacity(depth);
+        for sibling in &w.sanctions_path {
+            sanctions_path.push(private(poseidon::bytes_to_field(sibling))?);
+        }
+
+        // The credential is in the registry and outlives the proof's height
+        let credential = gadgets::poseidon_hash(cs.clone(), &[sdkey_hash, jurisdiction.clone(), expires_at.clone()])?;
+        gadgets::merkle_root(cs.clone(), &credential, &credential_path, &credential_indices)?
+            .enforce_equal(&credentials_root)?;
+        gadgets::enforce_range(cs.clone(), &block_height, HEIGHT_BITS)?;
+        gadgets::enforce_range(cs.clone(), &expires_at, HEIGHT_BITS)?;
+        let remaining = &expires_at - &block_height - FpVar::one();
+        gadgets::enforce_range(cs.clone(), &remaining, HEIGHT_BITS)?;
+
+        // The slot is the one the tree assigns the jurisdiction: the leading
+        // `depth` bits of its big-endian encoding, the lowest level first
+        let bits = jurisdiction.to_bits_le()?;
+        let sanctions_indices: Vec<Boolean<Fr>> = (ENCODING_BITS - depth..ENCODING_BITS)
+            .map(|position| bits.get(position).cloned().unwrap_or(Boolean::FALSE))
+            .collect();
+        gadgets::merkle_root(cs.clone(), &slot_leaf, &sanctions_path, &sanctions_indices)?
+            .enforce_equal(&sanctions_root)?;
+        let sanctioned = gadgets::poseidon_hash(cs, &[jurisdiction])?;
+        slot_leaf.enforce_not_equal(&sanctioned)
+    }
+}
@@ -0,0 +1,155 @@
+//! R1CS Gadgets
+//!
+//! In-circuit counterparts of the native commitments in `state::poseidon` and
+//! `state::merkle`. Every gadget here must produce exactly the value its
+//! native twin computes, otherwise proofs built from real state will not
+//! satisfy the circuits.
+
+use crate::state::poseidon::poseidon_config;
+use ark_bls12_381::Fr;
+use ark_crypto_primitives::sponge::constraints::CryptographicSpongeVar;
+use ark_crypto_primitives::sponge::poseidon::constraints::PoseidonSpongeVar;
+use ark_ff::{BigInteger, PrimeField};
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError};
+
+/// Bit width of range-checked amounts; balances are `u128` in the state
+pub const AMOUNT_BITS: usize = 128;
+
+/// Hashes field elements with the shared Poseidon configuration
+///
+/// Mirrors `poseidon::hash`, including the input length absorbed first.
+pub fn poseidon_hash(
+    cs: ConstraintSystemRef<Fr>,
+    inputs: &[FpVar<Fr>],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let mut sponge = PoseidonSpongeVar::new(cs, poseidon_config());
+    sponge.absorb(&FpVar::constant(Fr::from(inputs.len() as u64)))?;
+    sponge.absorb(&inputs)?;
+    Ok(sponge.squeeze_field_elements(1)?.remove(0))
+}
+
+/// Enforces that `value` fits in `bits` bits
+///
+/// The value is decomposed into freshly allocated witness bits and
+/// recomposed, so a field element that wrapped around the modulus cannot
+/// satisfy the check.
+pub fn enforce_range(
+    cs: ConstraintSystemRef<Fr>,
+    value: &FpVar<Fr>,
+    bits: usize,
+) -> Result<(), SynthesisError> {
+    let mut decomposition = Vec::with_capacity(bits);
+    for i in 0..bits {
+        decomposition.push(Boolean::new_witness(cs.clone(), || {
+            Ok(value.value()?.into_bigint().get_bit(i))
+        })?);
+    }
+    Boolean::le_bits_to_fp_var(&decomposition)?.enforce_equal(value)
+}
+
+/// Folds one `(token, amount)` pair into a running balances digest
+///
+/// Zero amounts leave the digest unchanged, matching `merkle::leaf_body`,
+/// which skips empty balances.
+pub fn fold_balance(
+    cs: ConstraintSystemRef<Fr>,
+    digest: &FpVar<Fr>,
+    token: &FpVar<Fr>,
+    amount: &FpVar<Fr>,
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let folded = poseidon_hash(cs, &[digest.clone(), token.clone(), amount.clone()])?;
+    FpVar::conditionally_select(&amount.is_zero()?, digest, &folded)
+}
+
+/// Computes a user leaf `H(sdkey_hash || H(digest || nonce))`
+///
+/// Mirrors `merkle::user_leaf` given the folded balances digest.
+pub fn user_leaf(
+    cs: ConstraintSystemRef<Fr>,
+    sdkey_hash: &FpVar<Fr>,
+    digest: &FpVar<Fr>,
+    nonce: &FpVar<Fr>,
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let body = poseidon_hash(cs.clone(), &[digest.clone(), nonce.clone()])?;
+    poseidon_hash(cs, &[sdkey_hash.clone(), body])
+}
+
+/// Hashes a leaf up its authentication path to a root
+///
+/// Mirrors `MerkleProof::compute_root`: an index of `true` means the running
+/// node is the right child at that level.
+pub fn merkle_root(
+    cs: ConstraintSystemRef<Fr>,
+    leaf: &FpVar<Fr>,
+    path: &[FpVar<Fr>],
+    indices: &[Boolean<Fr>],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let mut current = leaf.clone();
+    for (sibling, is_right) in path.iter().zip(indices) {
+        let left = FpVar::conditionally_select(is_right, sibling, &current)?;
+        let right = FpVar::conditionally_select(is_right, &current, sibling)?;
+        current = poseidon_hash(cs.clone(), &[left, right])?;
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::merkle::SparseMerkleTree;
+    use crate::state::poseidon;
+    use ark_relations::r1cs::ConstraintSystem;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_gadgets_match_native_commitments() {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let sdkey = [7u8; 32];
+        let mut balances = HashMap::new();
+        balances.insert("USDC".to_string(), 500u128);
+        balances.insert("RWA".to_string(), 0u128);
+
+        let mut tree = SparseMerkleTree::with_depth(4);
+        tree.update(&sdkey, crate::state::merkle::user_leaf(&sdkey, &balances, 3));
+        let proof = tree.prove_path(&sdkey);
+
+        let witness = |value: Fr| FpVar::new_witness(cs.clone(), || Ok(value)).unwrap();
+        let mut digest = witness(Fr::from(0u64));
+        for (token, amount) in [("RWA", 0u128), ("USDC", 500)] {
+            let token = witness(poseidon::string_to_field(token));
+            digest = fold_balance(cs.clone(), &digest, &token, &witness(Fr::from(amount))).unwrap();
+        }
+        let leaf = user_leaf(
+            cs.clone(),
+            &witness(poseidon::bytes_to_field(&sdkey)),
+            &digest,
+            &witness(Fr::from(3u64)),
+        )
+        .unwrap();
+        let path: Vec<_> = proof.merkle_path.iter().map(|s| witness(poseidon::bytes_to_field(s))).collect();
+        let indices: Vec<_> = proof
+            .merkle_path_indices
+            .iter()
+            .map(|bit| Boolean::new_witness(cs.clone(), || Ok(*bit)).unwrap())
+            .collect();
+        let root = merkle_root(cs.clone(), &leaf, &path, &indices).unwrap();
+
+        assert_eq!(poseidon::field_to_bytes(&root.value().unwrap()), tree.get_root());
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    fn test_range_check_rejects_wrapped_values() {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let value = FpVar::new_witness(cs.clone(), || Ok(Fr::from(255u64))).unwrap();
+        enforce_range(cs.clone(), &value, 8).unwrap();
+        assert!(cs.is_satisfied().unwrap());
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let negative = FpVar::new_witness(cs.clone(), || Ok(-Fr::from(1u64))).unwrap();
+        enforce_range(cs.clone(), &negative, AMOUNT_BITS).unwrap();
+        assert!(!cs.is_satisfied().unwrap());
+    }
+}
@@ -0,0 +1,427 @@
+//! Key Store
+//!
+//! Groth16 keys are circuit-specific, so a node needs one proving key per
+//! circuit kind, generated for its Merkle tree depth and, for the batch
+//! circuit, its batch capacity. The key store keeps them on disk as
+//! `<dir>/<circuit>.pk`, the compressed `ark-serialize` encoding of
+//! `ProvingKey<Bls12_381>` (which embeds the verifying key).
+//!
+//! Every file is checked against a pinned Keccak-256 hash before it is
+//! deserialized, so a swapped or corrupted key is refused at startup instead
+//! of producing proofs the verifier contract rejects.
+//!
+//! Development keys are derived deterministically from a seed so tests and
+//! local nodes agree on them without a trusted setup. Their setup is slow at
+//! full tree depth, so they are cached on disk and reused across runs. Anyone who knows the
+//! seed can forge proofs, so a node in production mode refuses to start
+//! with them, including when a key store pins them.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::{CircuitKind, Prover, DEFAULT_BATCH_CAPACITY};
+use ark_bls12_381::Bls12_381;
+use ark_groth16::{ProvingKey, VerifyingKey};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use ethers::utils::keccak256;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Seed of the development keys used when no key directory is configured
+pub const DEV_KEY_SEED: u64 = 0xC10A_0000_0000_0001;
+
+/// Every circuit a node proves and verifies
+pub const ALL_CIRCUITS: [CircuitKind; 4] = [
+    CircuitKind::Balance,
+    CircuitKind::Settlement,
+    CircuitKind::Compliance,
+    CircuitKind::Batch,
+];
+
+/// Where a loaded proving key came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// Generated by the node itself, from a seed or local randomness
+    Development,
+
+    /// Loaded from the key store and matched against its pinned hash
+    Pinned,
+}
+
+/// Hashes a serialized key for pinning
+pub fn key_hash(bytes: &[u8]) -> [u8; 32] {
+    keccak256(bytes)
+}
+
+/// Serializes a proving key in the key store's on-disk encoding
+///
+/// # Errors
+/// Returns `CloakError::Prover` if serialization fails.
+pub fn encode_proving_key(key: &ProvingKey<Bls12_381>) -> CloakResult<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(key.compressed_size());
+    key.serialize_compressed(&mut bytes)
+        .map_err(|e| CloakError::prover(format!("Failed to serialize proving key: {}", e)))?;
+    Ok(bytes)
+}
+
+/// Serializes a verifying key in the encoding clients and contracts pin
+///
+/// # Errors
+/// Returns `CloakError::Prover` if serialization fails.
+pub fn encode_verifying_key(key: &VerifyingKey<Bls12_381>) -> CloakResult<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(key.compressed_size());
+    key.serialize_compressed(&mut bytes)
+        .map_err(|e| CloakError::prover(format!("Failed to serialize verifying key: {}", e)))?;
+    Ok(bytes)
+}
+
+/// Generates the development proving key of `kind` for trees of `depth`
+/// levels and batches of `batch_capacity` trades
+///
+/// The same seed always yields the same key, and each circuit (and batch
+/// capacity) draws from its own stream so no two keys share setup randomness.
+///
+/// # Errors
+/// Returns `CloakError::Prover` if the circuit cannot be synthesized.
+pub fn dev_proving_key(
+    kind: CircuitKind,
+    depth: usize,
+    batch_capacity: usize,
+    seed: u64,
+) -> CloakResult<ProvingKey<Bls12_381>> {
+    let label = dev_key_label(kind, batch_capacity);
+    let mut rng = StdRng::from_seed(keccak256(format!("cloak-dev-keys:{}:{}:{}", seed, label, depth)));
+    Prover::generate_key(kind, depth, batch_capacity, &mut rng)
+}
+
+/// Gets a development proving key from the cache in `dir`, generating and
+/// caching it on a miss
+///
+/// A cached file that does not decode is regenerated. The file is written
+/// under a temporary name and renamed, so concurrent nodes never read a
+/// partial key.
+///
+/// # Errors
+/// Returns the errors of `dev_proving_key`, and `CloakError::Io` if the
+/// cache cannot be written.
+pub fn cached_dev_proving_key(
+    kind: CircuitKind,
+    depth: usize,
+    batch_capacity: usize,
+    seed: u64,
+    dir: &Path,
+) -> CloakResult<ProvingKey<Bls12_381>> {
+    let path = dir.join(format!("{}-{}-{:016x}.pk", dev_key_label(kind, batch_capacity), depth, seed));
+    if let Ok(bytes) = std::fs::read(&path) {
+        if let Ok(key) = ProvingKey::deserialize_compressed(&bytes[..]) {
+            return Ok(key);
+        }
+    }
+
+    let key = dev_proving_key(kind, depth, batch_capacity, seed)?;
+    std::fs::create_dir_all(dir)?;
+    let partial = path.with_extension(format!("pk.{}", uuid::Uuid::new_v4()));
+    std::fs::write(&partial, encode_proving_key(&key)?)?;
+    std::fs::rename(&partial, &path)?;
+    Ok(key)
+}
+
+/// Names the development key of `kind`, telling batch capacities apart
+fn dev_key_label(kind: CircuitKind, batch_capacity: usize) -> String {
+    match kind {
+        CircuitKind::Batch => format!("{}{}", kind, batch_capacity),
+        _ => kind.to_string(),
+    }
+}
+
+/// Proving keys on disk, each pinned to the hash of its file
+#[derive(Debug, Clone)]
+pub struct KeyStore {
+    /// Directory holding one `<circuit>.pk` file per circuit
+    dir: PathBuf,
+
+    /// Expected hash of each circuit's key file
+    pins: BTreeMap<CircuitKind, [u8; 32]>,
+}
+
+impl KeyStore {
+    /// Creates a key store over `dir` with no pinned hashes
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            pins: BTreeMap::new(),
+        }
+    }
+
+    /// Pins the expected hash of the key file for `kind`
+    pub fn with_pin(mut self, kind: CircuitKind, hash: [u8; 32]) -> Self {
+        self.pins.insert(kind, hash);
+        self
+    }
+
+    /// Gets the key directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Gets the pinned hash of the key file for `kind`, if any
+    pub fn pin(&self, kind: CircuitKind) -> Option<[u8; 32]> {
+        self.pins.get(&kind).copied()
+    }
+
+    /// Gets the path of the key file for `kind`
+    pub fn path(&self, kind: CircuitKind) -> PathBuf {
+        self.dir.join(format!("{}.pk", kind))
+    }
+
+    /// Writes the proving key for `kind` and returns the hash to pin
+    ///
+    /// # Errors
+    /// Returns `CloakError::Io` if the file cannot be written, and
+    /// `CloakError::Prover` if the key cannot be serialized.
+    pub fn save(&self, kind: CircuitKind, key: &ProvingKey<Bls12_381>) -> CloakResult<[u8; 32]> {
+        let bytes = encode_proving_key(key)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(kind), &bytes)?;
+        Ok(key_hash(&bytes))
+    }
+
+    /// Reads the proving key for `kind`, checking it against its pin
+    ///
+    /// # Errors
+    /// Returns `CloakError::Config` if no hash is pinned for `kind` or the
+    /// file does not match it, `CloakError::Io` if the file cannot be read,
+    /// and `CloakError::Prover` if it does not decode as a proving key.
+    pub fn load(&self, kind: CircuitKind) -> CloakResult<ProvingKey<Bls12_381>> {
+        let pin = self
+            .pin(kind)
+            .ok_or_else(|| CloakError::Config(format!("No pinned hash for the {} proving key", kind)))?;
+        let bytes = std::fs::read(self.path(kind))?;
+        let hash = key_hash(&bytes);
+        if hash != pin {
+            return Err(CloakError::Config(format!(
+                "The {} proving key at {} hashes to 0x{}, pinned 0x{}",
+                kind,
+                self.path(kind).display(),
+                hex::encode(hash),
+                hex::encode(pin)
+            )));
+        }
+        ProvingKey::deserialize_compressed(&bytes[..])
+            .map_err(|e| CloakError::prover(format!("Failed to deserialize the {} proving key: {}", kind, e)))
+    }
+
+    /// Loads every circuit's pinned key into `prover`
+    ///
+    /// # Errors
+    /// Returns the errors of `load`, and of `Prover::insert_key` if a key
+    /// was generated for another tree depth.
+    pub fn load_into(&self, prover: &mut Prover) -> CloakResult<()> {
+        for kind in ALL_CIRCUITS {
+            prover.insert_key(kind, self.load(kind)?, KeySource::Pinned)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a node obtains its proving keys
+#[derive(Debug, Clone)]
+pub struct KeyConfig {
+    /// Pinned key store to load from; development keys are used if unset
+    pub store: Option<KeyStore>,
+
+    /// Seed of the development keys
+    pub dev_seed: u64,
+
+    /// Directory development keys are cached in; they are regenerated on
+    /// every start if unset
+    pub dev_key_cache: Option<PathBuf>,
+
+    /// Refuse to run with development keys
+    pub production: bool,
+
+    /// Number of trades the batch circuit is laid out for
+    pub batch_capacity: usize,
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        Self {
+            store: None,
+            dev_seed: DEV_KEY_SEED,
+            dev_key_cache: Some(std::env::temp_dir().join("cloak-dev-keys")),
+            production: false,
+            batch_capacity: DEFAULT_BATCH_CAPACITY,
+        }
+    }
+}
+
+impl KeyConfig {
+    /// Builds a prover for trees of `depth` levels with keys for every circuit
+    ///
+    /// # Errors
+    /// Returns `CloakError::Config` in production mode without a key store
+    /// or with a store holding development keys, and the errors of
+    /// `KeyStore::load_into` or `dev_proving_key`.
+    pub fn build_prover(&self, depth: usize) -> CloakResult<Prover> {
+        let mut prover = Prover::new(depth).with_batch_capacity(self.batch_capacity);
+        match &self.store {
+            Some(store) => {
+                store.load_into(&mut prover)?;
+                if self.production {
+                    self.refuse_development_keys(&prover, depth)?;
+                }
+            }
+            None if self.production => {
+                return Err(CloakError::Config(
+                    "Refusing to start in production mode with development proving keys".to_string(),
+                ))
+            }
+            None => {
+                for kind in ALL_CIRCUITS {
+                    let key = match &self.dev_key_cache {
+                        Some(dir) => cached_dev_proving_key(kind, depth, self.batch_capacity, self.dev_seed, dir)?,
+                        None => dev_proving_key(kind, depth, self.batch_capacity, self.dev_seed)?,
+                    };
+                    prover.insert_key(kind, key, KeySource::Development)?;
+                }
+            }
+        }
+        Ok(prover)
+    }
+
+    /// Checks that no loaded key is the development key of the public seed
+    /// or of the configured one
+    ///
+    /// The development keys are derived afresh rather than read from any
+    /// cache, so a tampered cache cannot hide a match.
+    fn refuse_development_keys(&self, prover: &Prover, depth: usize) -> CloakResult<()> {
+        let mut seeds = vec![DEV_KEY_SEED];
+        if self.dev_seed != DEV_KEY_SEED {
+            seeds.push(self.dev_seed);
+        }
+        for kind in ALL_CIRCUITS {
+            for seed in &seeds {
+                let dev_key = dev_proving_key(kind, depth, self.batch_capacity, *seed)?;
+                if prover.verifying_key(kind) == Some(&dev_key.vk) {
+                    return Err(CloakError::Config(format!(
+                        "Refusing to start in production mode: the pinned {} proving key is a development key",
+                        kind
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses pinned key hashes written as `circuit=hex,circuit=hex`
+///
+/// # Errors
+/// Returns `CloakError::Config` for an unknown circuit, a malformed entry or
+/// a hash that is not 32 bytes.
+pub fn parse_pins(pins: &str) -> CloakResult<BTreeMap<CircuitKind, [u8; 32]>> {
+    let mut parsed = BTreeMap::new();
+    for entry in pins.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (circuit, hash) = entry
+            .split_once('=')
+            .ok_or_else(|| CloakError::Config(format!("Key pin '{}' is not circuit=hash", entry)))?;
+        let kind = ALL_CIRCUITS
+            .into_iter()
+            .find(|kind| kind.as_str() == circuit.trim())
+            .ok_or_else(|| CloakError::Config(format!("Unknown circuit '{}' in key pins", circuit.trim())))?;
+        let hash: [u8; 32] = hex::decode(hash.trim().trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| CloakError::Config(format!("Pinned hash of the {} key must be 32 hex bytes", kind)))?;
+        parsed.insert(kind, hash);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPTH: usize = 4;
+
+    #[test]
+    fn test_dev_keys_are_deterministic_and_pinned_on_load() {
+        let key = dev_proving_key(CircuitKind::Balance, DEPTH, 1, 42).unwrap();
+        let again = dev_proving_key(CircuitKind::Balance, DEPTH, 1, 42).unwrap();
+        let other = dev_proving_key(CircuitKind::Balance, DEPTH, 1, 43).unwrap();
+        assert_eq!(encode_proving_key(&key).unwrap(), encode_proving_key(&again).unwrap());
+        assert_ne!(encode_verifying_key(&key.vk).unwrap(), encode_verifying_key(&other.vk).unwrap());
+
+        let dir = std::env::temp_dir().join(format!("cloak-keys-test-{}", uuid::Uuid::new_v4()));
+        let store = KeyStore::new(&dir);
+        let hash = store.save(CircuitKind::Balance, &key).unwrap();
+
+        // Unpinned and mispinned keys are refused
+        assert!(matches!(store.load(CircuitKind::Balance), Err(CloakError::Config(_))));
+        let wrong = store.clone().with_pin(CircuitKind::Balance, [1u8; 32]);
+        assert!(matches!(wrong.load(CircuitKind::Balance), Err(CloakError::Config(_))));
+
+        let store = store.with_pin(CircuitKind::Balance, hash);
+        let loaded = store.load(CircuitKind::Balance).unwrap();
+        assert_eq!(loaded.vk, key.vk);
+
+        // A key for another depth does not fit the prover
+        let mut prover = Prover::new(DEPTH + 1);
+        assert!(prover.insert_key(CircuitKind::Balance, loaded.clone(), KeySource::Pinned).is_err());
+        let mut prover = Prover::new(DEPTH);
+        prover.insert_key(CircuitKind::Balance, loaded, KeySource::Pinned).unwrap();
+        assert_eq!(prover.key_source(CircuitKind::Balance), Some(KeySource::Pinned));
+        assert!(!prover.uses_development_keys());
+
+        // Cached development keys are the generated ones, and a corrupted
+        // cache file is regenerated
+        let cache = dir.join("cache");
+        let cached = cached_dev_proving_key(CircuitKind::Balance, DEPTH, 1, 42, &cache).unwrap();
+        assert_eq!(cached.vk, key.vk);
+        let file = std::fs::read_dir(&cache).unwrap().next().unwrap().unwrap().path();
+        std::fs::write(&file, b"corrupted").unwrap();
+        assert_eq!(cached_dev_proving_key(CircuitKind::Balance, DEPTH, 1, 42, &cache).unwrap().vk, key.vk);
+        assert_eq!(cached_dev_proving_key(CircuitKind::Balance, DEPTH, 1, 42, &cache).unwrap().vk, key.vk);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_production_mode_refuses_development_keys() {
+        let config = KeyConfig {
+            production: true,
+            ..KeyConfig::default()
+        };
+        assert!(matches!(config.build_prover(DEPTH), Err(CloakError::Config(_))));
+
+        // Pinning the development keys in a store does not get them past it
+        let dir = std::env::temp_dir().join(format!("cloak-keys-test-{}", uuid::Uuid::new_v4()));
+        let mut store = KeyStore::new(&dir);
+        for kind in ALL_CIRCUITS {
+            let hash = store.save(kind, &dev_proving_key(kind, DEPTH, 1, DEV_KEY_SEED).unwrap()).unwrap();
+            store = store.with_pin(kind, hash);
+        }
+        let config = KeyConfig {
+            store: Some(store),
+            production: true,
+            batch_capacity: 1,
+            ..KeyConfig::default()
+        };
+        assert!(matches!(config.build_prover(DEPTH), Err(CloakError::Config(_))));
+        let development = KeyConfig {
+            production: false,
+            ..config
+        };
+        assert!(development.build_prover(DEPTH).unwrap().verifying_key(CircuitKind::Balance).is_some());
+        let _ = std::fs::remove_dir_all(dir);
+
+        let pins = parse_pins(&format!("balance=0x{}, compliance={}", "ab".repeat(32), "cd".repeat(32))).unwrap();
+        assert_eq!(pins.get(&CircuitKind::Balance), Some(&[0xab; 32]));
+        assert_eq!(pins.get(&CircuitKind::Compliance), Some(&[0xcd; 32]));
+        assert!(parse_pins("balance=abcd").is_err());
+        assert!(parse_pins(&format!("ledger={}", "ab".repeat(32))).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Leaf Updates
+//!
+//! Witness and gadget for moving one user leaf through a set of balance
+//! changes, shared by the circuits that touch several balances at once.
+//!
+//! A leaf folds its balances in token-ID order, so changing one balance
+//! changes every fold step after it. The witness carries the fold over the
+//! tokens sorted before the first touched token as a single digest, and the
+//! remaining tokens as up to `MAX_LEAF_SLOTS` slots holding the old and new
+//! amount. The gadget checks each change lands in exactly one slot, recomputes
+//! both leaves and hashes them up the same path, so the new root differs from
+//! the old one only at this leaf.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::gadgets::{self, AMOUNT_BITS};
+use crate::state::{poseidon, SparseMerkleTree, UserState};
+use ark_bls12_381::Fr;
+use ark_ff::Zero;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Number of balances from the first touched token on that a leaf update can carry
+pub const MAX_LEAF_SLOTS: usize = 8;
+
+/// One balance of a leaf before and after an update
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceSlot {
+    pub token_id: String,
+    pub old_amount: u128,
+    pub new_amount: u128,
+}
+
+/// Witness for updating one leaf of the state tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeafUpdate {
+    /// SDKey hash owning the leaf
+    pub sdkey_hash: [u8; 32],
+
+    /// Whether the leaf existed before the update (otherwise its slot was empty)
+    pub exists: bool,
+
+    /// Leaf nonce before the update
+    pub nonce: u64,
+
+    /// Balances digest folded over the tokens sorted before the first slot
+    pub preceding_digest: [u8; 32],
+
+    /// Balances from the first touched token on, in token-ID order
+    pub slots: Vec<BalanceSlot>,
+
+    /// Sibling hashes from the leaf level up, against the root before this update
+    pub merkle_path: Vec<[u8; 32]>,
+
+    /// Direction bits for each level of `merkle_path`
+    pub merkle_path_indices: Vec<bool>,
+}
+
+impl LeafUpdate {
+    /// Builds the witness for replacing `old` with `new` in `tree`, then applies it
+    ///
+    /// `touched` lists the tokens the circuit will change; the slots start at
+    /// the smallest of them even if its amount ends up unchanged. The tree is
+    /// updated so the next leaf update is proven against the intermediate root.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if more than `MAX_LEAF_SLOTS`
+    /// balances sort from the first touched token on, and `CloakError::State`
+    /// if the leaf slot holds another key.
+    pub fn apply(
+        tree: &mut SparseMerkleTree,
+        old: Option<&UserState>,
+        new: &UserState,
+        touched: &[&str],
+    ) -> CloakResult<Self> {
+        let empty = UserState::new(new.sdkey_hash);
+        let before = old.unwrap_or(&empty);
+        let first = touched.iter().min().copied().unwrap_or_default();
+
+        let mut tokens: BTreeMap<&str, (u128, u128)> = BTreeMap::new();
+        for token in touched {
+            tokens.entry(token).or_default();
+        }
+        for (token, amount) in before.balances.iter().filter(|(_, amount)| **amount > 0) {
+            tokens.entry(token.as_str()).or_default().0 = *amount;
+        }
+        for (token, amount) in new.balances.iter().filter(|(_, amount)| **amount > 0) {
+            tokens.entry(token.as_str()).or_default().1 = *amount;
+        }
+
+        let mut preceding_digest = Fr::zero();
+        let mut slots = Vec::new();
+        for (token, (old_amount, new_amount)) in tokens {
+            if token < first {
+                preceding_digest = poseidon::hash(&[
+                    preceding_digest,
+                    poseidon::string_to_field(token),
+                    Fr::from(old_amount),
+                ]);
+            } else {
+                slots.push(BalanceSlot {
+                    token_id: token.to_string(),
+                    old_amount,
+                    new_amount,
+                });
+            }
+        }
+        if slots.len() > MAX_LEAF_SLOTS {
+            return Err(CloakError::invalid_input(format!(
+                "User {} holds {} tokens from {} on, a leaf update supports {}",
+                hex::encode(new.sdkey_hash),
+                slots.len(),
+                first,
+                MAX_LEAF_SLOTS
+            )));
+        }
+
+        // The circuits take one leaf per slot
+        if tree.is_slot_shared(&new.sdkey_hash) {
+            return Err(CloakError::state(format!(
+                "User {} shares its leaf slot with another key, which the circuit cannot prove",
+                hex::encode(new.sdkey_hash)
+            )));
+        }
+        let proof = tree.prove_path(&new.sdkey_hash);
+        tree.update(&new.sdkey_hash, new.leaf_hash());
+
+        Ok(Self {
+            sdkey_hash: new.sdkey_hash,
+            exists: old.is_some(),
+            nonce: before.nonce,
+            preceding_digest: poseidon::field_to_bytes(&preceding_digest),
+            slots,
+            merkle_path: proof.merkle_path,
+            merkle_path_indices: proof.merkle_path_indices,
+        })
+    }
+
+    /// Creates a placeholder update with the shape of a tree of `depth` levels
+    pub fn blank(depth: usize) -> Self {
+        Self {
+            sdkey_hash: [0u8; 32],
+            exists: false,
+            nonce: 0,
+            preceding_digest: [0u8; 32],
+            slots: Vec::new(),
+            merkle_path: vec![[0u8; 32]; depth],
+            merkle_path_indices: vec![false; depth],
+        }
+    }
+
+    /// Gets the number of levels in the authentication path
+    pub fn depth(&self) -> usize {
+        self.merkle_path.len()
+    }
+}
+
+/// A balance change applied by `enforce_leaf_update`
+pub struct BalanceChange<'a> {
+    pub token: &'a FpVar<Fr>,
+    pub amount: &'a FpVar<Fr>,
+    /// Whether `amount` is added to (rather than taken from) the balance
+    pub credit: bool,
+}
+
+/// Allocates a leaf update and enforces it, returning the root after it
+///
+/// The old leaf (zero when `exists` is false) must hash up to `root_in`, and
+/// every change must match exactly one slot. Each slot's new amount is its
+/// old amount plus the matching credits minus the matching debits, range
+/// checked so a debit cannot exceed the balance. The nonce grows by
+/// `nonce_increment`. All checks are skipped when `enabled` is false, so a
+/// circuit can lay out an update it does not always perform.
+#[allow(clippy::too_many_arguments)]
+pub fn enforce_leaf_update(
+    cs: ConstraintSystemRef<Fr>,
+    update: &LeafUpdate,
+    sdkey_hash: &FpVar<Fr>,
+    exists: &Boolean<Fr>,
+    changes: &[BalanceChange<'_>],
+    nonce_increment: u64,
+    root_in: &FpVar<Fr>,
+    enabled: &Boolean<Fr>,
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let private = |value: Fr| FpVar::new_witness(cs.clone(), || Ok(value));
+
+    let nonce = private(Fr::from(update.nonce))?;
+    let preceding = private(poseidon::bytes_to_field(&update.preceding_digest))?;
+    let mut slots = Vec::with_capacity(MAX_LEAF_SLOTS);
+    for index in 0..MAX_LEAF_SLOTS {
+        let (token, old_amount, new_amount) = match update.slots.get(index) {
+            Some(slot) => (
+                poseidon::string_to_field(&slot.token_id),
+                Fr::from(slot.old_amount),
+                Fr::from(slot.new_amount),
+            ),
+            None => (Fr::zero(), Fr::zero(), Fr::zero()),
+        };
+        slots.push((private(token)?, private(old_amount)?, private(new_amount)?));
+    }
+    let mut path = Vec::with_capacity(update.merkle_path.len());
+    for sibling in &update.merkle_path {
+        path.push(private(poseidon::bytes_to_field(sibling))?);
+    }
+    let mut indices = Vec::with_capacity(update.merkle_path_indices.len());
+    for is_right in &update.merkle_path_indices {
+        indices.push(Boolean::new_witness(cs.clone(), || Ok(*is_right))?);
+    }
+
+    // Each change lands in exactly one slot and moves its amount
+    let mut expected: Vec<FpVar<Fr>> = slots.iter().map(|(_, old, _)| old.clone()).collect();
+    for change in changes {
+        let mut matches = FpVar::zero();
+        for ((token, _, _), expected) in slots.iter().zip(expected.iter_mut()) {
+            let is_match = token.is_eq(change.token)?;
+            let moved = FpVar::conditionally_select(&is_match, change.amount, &FpVar::zero())?;
+            *expected = if change.credit { &*expected + &moved } else { &*expected - &moved };
+            matches += FpVar::from(is_match);
+        }
+        matches.conditional_enforce_equal(&FpVar::one(), enabled)?;
+    }
+    for ((_, _, new_amount), expected) in slots.iter().zip(&expected) {
+        gadgets::enforce_range(cs.clone(), new_amount, AMOUNT_BITS)?;
+        new_amount.conditional_enforce_equal(expected, enabled)?;
+    }
+
+    // A leaf that did not exist starts from an empty state
+    let fresh = enabled.and(&exists.not())?;
+    nonce.conditional_enforce_equal(&FpVar::zero(), &fresh)?;
+    preceding.conditional_enforce_equal(&FpVar::zero(), &fresh)?;
+    for (_, old_amount, _) in &slots {
+        old_amount.conditional_enforce_equal(&FpVar::zero(), &fresh)?;
+    }
+
+    let mut old_digest = preceding.clone();
+    let mut new_digest = preceding;
+    for (token, old_amount, new_amount) in &slots {
+        old_digest = gadgets::fold_balance(cs.clone(), &old_digest, token, old_amount)?;
+        new_digest = gadgets::fold_balance(cs.clone(), &new_digest, token, new_amount)?;
+    }
+
+    let old_leaf = gadgets::user_leaf(cs.clone(), sdkey_hash, &old_digest, &nonce)?;
+    let old_leaf = FpVar::conditionally_select(exists, &old_leaf, &FpVar::zero())?;
+    let new_nonce = &nonce + FpVar::constant(Fr::from(nonce_increment));
+    let new_leaf = gadgets::user_leaf(cs.clone(), sdkey_hash, &new_digest, &new_nonce)?;
+
+    gadgets::merkle_root(cs.clone(), &old_leaf, &path, &indices)?.conditional_enforce_equal(root_in, enabled)?;
+    gadgets::merkle_root(cs, &new_leaf, &path, &indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_relations::r1cs::ConstraintSystem;
+
+    fn user(sdkey: u8, balances: &[(&str, u128)], nonce: u64) -> UserState {
+        let mut user_state = UserState::new([sdkey; 32]);
+        for (token, amount) in balances {
+            user_state.balances.insert(token.to_string(), *amount);
+        }
+        user_state.nonce = nonce;
+        user_state
+    }
+
+    /// Enforces `update` moving `amount` of AAA to BBB and returns whether it held
+    fn check(tree: &mut SparseMerkleTree, old: Option<&UserState>, new: &UserState, amount: u128) -> bool {
+        let root_old = poseidon::bytes_to_field(&tree.get_root());
+        let update = LeafUpdate::apply(tree, old, new, &["AAA", "BBB"]).unwrap();
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let var = |value: Fr| FpVar::new_witness(cs.clone(), || Ok(value)).unwrap();
+        let (aaa, bbb, amount) = (
+            var(poseidon::string_to_field("AAA")),
+            var(poseidon::string_to_field("BBB")),
+            var(Fr::from(amount)),
+        );
+        let changes = [
+            BalanceChange { token: &aaa, amount: &amount, credit: false },
+            BalanceChange { token: &bbb, amount: &amount, credit: true },
+        ];
+        let root_new = enforce_leaf_update(
+            cs.clone(),
+            &update,
+            &var(poseidon::bytes_to_field(&new.sdkey_hash)),
+            &Boolean::new_witness(cs.clone(), || Ok(old.is_some())).unwrap(),
+            &changes,
+            2,
+            &var(root_old),
+            &Boolean::TRUE,
+        )
+        .unwrap();
+        cs.is_satisfied().unwrap() && root_new.value().unwrap() == poseidon::bytes_to_field(&tree.get_root())
+    }
+
+    #[test]
+    fn test_leaf_update_gadget_matches_native_tree() {
+        let mut tree = SparseMerkleTree::new();
+        let neighbour = user(1, &[("AAA", 5)], 3);
+        tree.update(&neighbour.sdkey_hash, neighbour.leaf_hash());
+
+        // Balances sorted before the first touched token fold into the digest
+        let old = user(2, &[("000", 9), ("AAA", 100), ("ZZZ", 1)], 4);
+        tree.update(&old.sdkey_hash, old.leaf_hash());
+        let new = user(2, &[("000", 9), ("AAA", 60), ("BBB", 40), ("ZZZ", 1)], 6);
+        assert!(check(&mut tree.clone(), Some(&old), &new, 40));
+
+        // Claiming a different amount than the leaves moved is unsatisfiable
+        assert!(!check(&mut tree.clone(), Some(&old), &new, 30));
+
+        // A fresh leaf starts empty
+        let fresh = user(3, &[("AAA", 0), ("BBB", 0)], 2);
+        assert!(check(&mut tree, None, &fresh, 0));
+    }
+}
@@ -0,0 +1,500 @@
+//! Zero-Knowledge Prover
+//!
+//! Groth16 proving over BLS12-381 for the circuits described in
+//! TECHNICAL.md. Circuits are written against arkworks R1CS in their own
+//! submodules and share the Poseidon and Merkle gadgets in `gadgets`, which
+//! mirror the native commitments in `state`.
+//!
+//! The `Prover` holds one proving key per circuit kind. Keys are generated by
+//! a circuit-specific setup for a fixed Merkle tree depth (and, for the
+//! batch circuit, a fixed number of trades), so they only accept witnesses
+//! of that shape. `keys` loads them from pinned files or derives
+//! development keys from a seed.
+
+pub mod balance;
+pub mod batch;
+pub mod compliance;
+pub mod gadgets;
+pub mod keys;
+pub mod leaf;
+pub mod settlement;
+pub mod verifier;
+
+pub use balance::{BalanceProofCircuit, BalanceWitness, MAX_TRAILING_BALANCES};
+pub use batch::{BatchCircuit, BatchProof, BatchWitness, DEFAULT_BATCH_CAPACITY};
+pub use compliance::{ComplianceCircuit, ComplianceWitness};
+pub use keys::{KeyConfig, KeySource, KeyStore};
+pub use leaf::{LeafUpdate, MAX_LEAF_SLOTS};
+pub use settlement::{SettlementCircuit, SettlementProof, SettlementWitness};
+pub use verifier::Verifier;
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::poseidon;
+use ark_bls12_381::{Bls12_381, Fr};
+use ark_groth16::{Groth16, ProvingKey, VerifyingKey};
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem, ConstraintSystemRef, SynthesisMode};
+use ark_serialize::CanonicalSerialize;
+use ark_snark::SNARK;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Circuits the prover can generate keys and proofs for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitKind {
+    /// Single balance update, see `balance`
+    Balance,
+
+    /// Two-party trade settlement, see `settlement`
+    Settlement,
+
+    /// Sanctions non-membership and accreditation, see `compliance`
+    Compliance,
+
+    /// Settlement of an epoch of trades, see `batch`
+    Batch,
+}
+
+impl CircuitKind {
+    /// Gets the identifier used in APIs and key files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitKind::Balance => "balance",
+            CircuitKind::Settlement => "settlement",
+            CircuitKind::Compliance => "compliance",
+            CircuitKind::Batch => "batch",
+        }
+    }
+
+    /// Gets the position of the public input that must equal the current
+    /// state root for a proof to be accepted, if the circuit has one
+    pub fn state_root_input(&self) -> Option<usize> {
+        match self {
+            CircuitKind::Balance | CircuitKind::Settlement | CircuitKind::Batch => Some(0),
+            CircuitKind::Compliance => None,
+        }
+    }
+}
+
+impl fmt::Display for CircuitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A serialized Groth16 proof together with the public inputs it proves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofBundle {
+    /// Circuit the proof was generated for
+    pub circuit: CircuitKind,
+
+    /// Compressed `ark_groth16::Proof` (A, B, C)
+    pub proof: Vec<u8>,
+
+    /// Public inputs in circuit order, as 32-byte big-endian field elements
+    pub public_inputs: Vec<[u8; 32]>,
+}
+
+/// Groth16 prover holding a proving key per circuit kind
+pub struct Prover {
+    /// Merkle tree depth the circuits are laid out for
+    depth: usize,
+
+    /// Number of trades the batch circuit is laid out for
+    batch_capacity: usize,
+
+    /// Proving keys by circuit; each embeds its verifying key
+    proving_keys: HashMap<CircuitKind, ProvingKey<Bls12_381>>,
+
+    /// Where each proving key came from
+    key_sources: HashMap<CircuitKind, KeySource>,
+}
+
+impl Prover {
+    /// Creates a prover for trees of `depth` levels with no keys yet
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            batch_capacity: DEFAULT_BATCH_CAPACITY,
+            proving_keys: HashMap::new(),
+            key_sources: HashMap::new(),
+        }
+    }
+
+    /// Sets the number of trades the batch circuit is laid out for
+    ///
+    /// Batch keys only fit the capacity they were generated for, so set it
+    /// before loading or generating them.
+    pub fn with_batch_capacity(mut self, capacity: usize) -> Self {
+        self.batch_capacity = capacity;
+        self
+    }
+
+    /// Gets the Merkle tree depth the circuits are laid out for
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Gets the number of trades the batch circuit is laid out for
+    pub fn batch_capacity(&self) -> usize {
+        self.batch_capacity
+    }
+
+    /// Runs the circuit-specific setup for `kind`, replacing any existing key
+    ///
+    /// Keys made here are development keys: whoever holds `rng` knows the
+    /// setup trapdoor.
+    ///
+    /// # Errors
+    /// Returns `CloakError::Prover` if the circuit cannot be synthesized.
+    pub fn setup<R: RngCore + CryptoRng>(&mut self, kind: CircuitKind, rng: &mut R) -> CloakResult<()> {
+        let proving_key = Self::generate_key(kind, self.depth, self.batch_capacity, rng)?;
+        self.proving_keys.insert(kind, proving_key);
+        self.key_sources.insert(kind, KeySource::Development);
+        Ok(())
+    }
+
+    /// Runs the circuit-specific setup for `kind` at `depth`, with batch
+    /// keys laid out for `batch_capacity` trades
+    ///
+    /// # Errors
+    /// Returns `CloakError::Prover` if the circuit cannot be synthesized.
+    pub fn generate_key<R: RngCore + CryptoRng>(
+        kind: CircuitKind,
+        depth: usize,
+        batch_capacity: usize,
+        rng: &mut R,
+    ) -> CloakResult<ProvingKey<Bls12_381>> {
+        let setup = match kind {
+            CircuitKind::Balance => Groth16::<Bls12_381>::circuit_specific_setup(
+                BalanceProofCircuit::new(BalanceWitness::blank(depth)),
+                rng,
+            ),
+            CircuitKind::Settlement => Groth16::<Bls12_381>::circuit_specific_setup(
+                SettlementCircuit::new(SettlementWitness::blank(depth)),
+                rng,
+            ),
+            CircuitKind::Compliance => Groth16::<Bls12_381>::circuit_specific_setup(
+                ComplianceCircuit::new(ComplianceWitness::blank(depth)),
+                rng,
+            ),
+            CircuitKind::Batch => Groth16::<Bls12_381>::circuit_specific_setup(
+                BatchCircuit::new(BatchWitness::blank(), depth, batch_capacity),
+                rng,
+            ),
+        };
+        let (proving_key, _) =
+            setup.map_err(|e| CloakError::prover(format!("{} circuit setup failed: {}", kind, e)))?;
+        Ok(proving_key)
+    }
+
+    /// Installs a proving key generated elsewhere, replacing any existing key
+    ///
+    /// # Errors
+    /// Returns `CloakError::Config` if the key does not have the shape of
+    /// the `kind` circuit at the prover's depth and batch capacity, and
+    /// `CloakError::Prover` if the circuit cannot be synthesized to compare
+    /// against.
+    pub fn insert_key(&mut self, kind: CircuitKind, key: ProvingKey<Bls12_381>, source: KeySource) -> CloakResult<()> {
+        let cs = self.blank_constraint_system(kind)?;
+        let instances = cs.num_instance_variables();
+        let variables = instances + cs.num_witness_variables();
+        if key.vk.gamma_abc_g1.len() != instances || key.a_query.len() != variables {
+            return Err(CloakError::Config(format!(
+                "The {} proving key was not generated for a tree of depth {} (batch capacity {})",
+                kind, self.depth, self.batch_capacity
+            )));
+        }
+        self.proving_keys.insert(kind, key);
+        self.key_sources.insert(kind, source);
+        Ok(())
+    }
+
+    /// Gets where the proving key for `kind` came from, if one is loaded
+    pub fn key_source(&self, kind: CircuitKind) -> Option<KeySource> {
+        self.key_sources.get(&kind).copied()
+    }
+
+    /// Checks whether any loaded proving key is a development key
+    pub fn uses_development_keys(&self) -> bool {
+        self.key_sources.values().any(|source| *source == KeySource::Development)
+    }
+
+    /// Counts the constraints of the `kind` circuit at the prover's depth
+    ///
+    /// # Errors
+    /// Returns `CloakError::Prover` if the circuit cannot be synthesized.
+    pub fn num_constraints(&self, kind: CircuitKind) -> CloakResult<usize> {
+        Ok(self.blank_constraint_system(kind)?.num_constraints())
+    }
+
+    /// Synthesizes the `kind` circuit at the prover's depth in setup mode
+    fn blank_constraint_system(&self, kind: CircuitKind) -> CloakResult<ConstraintSystemRef<Fr>> {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        cs.set_mode(SynthesisMode::Setup);
+        let synthesized = match kind {
+            CircuitKind::Balance => {
+                BalanceProofCircuit::new(BalanceWitness::blank(self.depth)).generate_constraints(cs.clone())
+            }
+            CircuitKind::Settlement => {
+                SettlementCircuit::new(SettlementWitness::blank(self.depth)).generate_constraints(cs.clone())
+            }
+            CircuitKind::Compliance => {
+                ComplianceCircuit::new(ComplianceWitness::blank(self.depth)).generate_constraints(cs.clone())
+            }
+            CircuitKind::Batch => BatchCircuit::new(BatchWitness::blank(), self.depth, self.batch_capacity)
+                .generate_constraints(cs.clone()),
+        };
+        synthesized.map_err(|e| CloakError::prover(format!("{} circuit synthesis failed: {}", kind, e)))?;
+        cs.finalize();
+        Ok(cs)
+    }
+
+    /// Checks whether a proving key is loaded for `kind`
+    pub fn is_ready(&self, kind: CircuitKind) -> bool {
+        self.proving_keys.contains_key(&kind)
+    }
+
+    /// Gets the verifying key matching the proving key for `kind`
+    pub fn verifying_key(&self, kind: CircuitKind) -> Option<&VerifyingKey<Bls12_381>> {
+        self.proving_keys.get(&kind).map(|key| &key.vk)
+    }
+
+    /// Proves a balance update
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the witness path does not match
+    /// the prover's depth, and `CloakError::Prover` if no balance key is loaded
+    /// or proving fails (e.g. the witness does not satisfy the circuit).
+    pub fn prove_balance<R: RngCore + CryptoRng>(
+        &self,
+        witness: &BalanceWitness,
+        rng: &mut R,
+    ) -> CloakResult<ProofBundle> {
+        if witness.merkle_path.len() != self.depth || witness.merkle_path_indices.len() != self.depth {
+            return Err(CloakError::invalid_input(format!(
+                "Balance witness has a Merkle path of {} levels, expected {}",
+                witness.merkle_path.len(),
+                self.depth
+            )));
+        }
+
+        let public_inputs = witness.public_inputs();
+        self.prove(CircuitKind::Balance, BalanceProofCircuit::new(witness.clone()), &public_inputs, rng)
+    }
+
+    /// Proves a trade settlement
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if any leaf path does not match the
+    /// prover's depth, and `CloakError::Prover` if no settlement key is loaded
+    /// or proving fails.
+    pub fn prove_settlement<R: RngCore + CryptoRng>(
+        &self,
+        witness: &SettlementWitness,
+        rng: &mut R,
+    ) -> CloakResult<ProofBundle> {
+        if witness.depth() != Some(self.depth) {
+            return Err(CloakError::invalid_input(format!(
+                "Settlement witness Merkle paths do not all have {} levels",
+                self.depth
+            )));
+        }
+
+        let public_inputs = witness.public_inputs();
+        self.prove(CircuitKind::Settlement, SettlementCircuit::new(witness.clone()), &public_inputs, rng)
+    }
+
+    /// Proves a user's compliance
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if either tree path does not match
+    /// the prover's depth, and `CloakError::Prover` if no compliance key is
+    /// loaded or proving fails.
+    pub fn prove_compliance<R: RngCore + CryptoRng>(
+        &self,
+        witness: &ComplianceWitness,
+        rng: &mut R,
+    ) -> CloakResult<ProofBundle> {
+        if witness.depth() != Some(self.depth) {
+            return Err(CloakError::invalid_input(format!(
+                "Compliance witness Merkle paths do not all have {} levels",
+                self.depth
+            )));
+        }
+
+        let public_inputs = witness.public_inputs();
+        self.prove(CircuitKind::Compliance, ComplianceCircuit::new(witness.clone()), &public_inputs, rng)
+    }
+
+    /// Proves the settlement of an epoch of trades
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the epoch is empty, holds more
+    /// trades than the batch capacity, or any leaf path does not match the
+    /// prover's depth, and `CloakError::Prover` if no batch key is loaded or
+    /// proving fails.
+    pub fn prove_batch<R: RngCore + CryptoRng>(&self, witness: &BatchWitness, rng: &mut R) -> CloakResult<ProofBundle> {
+        if witness.steps.is_empty() || witness.steps.len() > self.batch_capacity {
+            return Err(CloakError::invalid_input(format!(
+                "Batch of {} trades, expected 1 to {}",
+                witness.steps.len(),
+                self.batch_capacity
+            )));
+        }
+        if !witness.has_depth(self.depth) {
+            return Err(CloakError::invalid_input(format!(
+                "Batch witness Merkle paths do not all have {} levels",
+                self.depth
+            )));
+        }
+
+        let public_inputs = witness.public_inputs();
+        let circuit = BatchCircuit::new(witness.clone(), self.depth, self.batch_capacity);
+        self.prove(CircuitKind::Batch, circuit, &public_inputs, rng)
+    }
+
+    /// Proves a circuit with the key for `kind` and packages the result
+    fn prove<C, R>(&self, kind: CircuitKind, circuit: C, public_inputs: &[Fr], rng: &mut R) -> CloakResult<ProofBundle>
+    where
+        C: ConstraintSynthesizer<Fr> + Clone,
+        R: RngCore + CryptoRng,
+    {
+        let proving_key = self
+            .proving_keys
+            .get(&kind)
+            .ok_or_else(|| CloakError::prover(format!("No proving key loaded for the {} circuit", kind)))?;
+
+        // Groth16 happily proves an unsatisfied circuit, and the result only
+        // fails at verification, so check the witness up front
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        circuit
+            .clone()
+            .generate_constraints(cs.clone())
+            .map_err(|e| CloakError::prover(format!("{} circuit synthesis failed: {}", kind, e)))?;
+        let satisfied = cs
+            .is_satisfied()
+            .map_err(|e| CloakError::prover(format!("{} circuit synthesis failed: {}", kind, e)))?;
+        if !satisfied {
+            return Err(CloakError::prover(format!(
+                "Witness does not satisfy the {} circuit",
+                kind
+            )));
+        }
+
+        let proof = Groth16::<Bls12_381>::prove(proving_key, circuit, rng)
+            .map_err(|e| CloakError::prover(format!("{} proof generation failed: {}", kind, e)))?;
+
+        let mut bytes = Vec::with_capacity(proof.compressed_size());
+        proof
+            .serialize_compressed(&mut bytes)
+            .map_err(|e| CloakError::prover(format!("Failed to serialize {} proof: {}", kind, e)))?;
+
+        Ok(ProofBundle {
+            circuit: kind,
+            proof: bytes,
+            public_inputs: public_inputs.iter().map(poseidon::field_to_bytes).collect(),
+        })
+    }
+}
+
+impl fmt::Debug for Prover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ready: Vec<_> = self.proving_keys.keys().collect();
+        ready.sort();
+        f.debug_struct("Prover")
+            .field("depth", &self.depth)
+            .field("batch_capacity", &self.batch_capacity)
+            .field("proving_keys", &ready)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::{empty_manager, test_token};
+    use crate::state::{AssetClass, StateManager, StateTransition, TREE_DEPTH};
+    use ark_groth16::Proof;
+    use ark_serialize::CanonicalDeserialize;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn deposit(state: &mut StateManager, user: [u8; 32], token: &str, amount: u128) {
+        if state.get_token(token).is_none() {
+            state.register_token(test_token(token, AssetClass::Treasury)).unwrap();
+        }
+        state
+            .apply_transition(StateTransition::Deposit {
+                user_sdkey_hash: user,
+                token_id: token.to_string(),
+                amount,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_balance_proof_roundtrip_against_state() {
+        let (mut state, path) = empty_manager();
+        let user = [3u8; 32];
+        state.register_user(user).unwrap();
+        deposit(&mut state, user, "AAA", 10);
+        deposit(&mut state, user, "USDC", 1_000);
+        deposit(&mut state, user, "ZZZ", 7);
+
+        let witness = BalanceWitness::from_state(&state, user, "USDC", 400, 25).unwrap();
+        assert_eq!(witness.merkle_root_old, state.get_merkle_root());
+        assert_eq!(witness.trailing_balances, vec![("ZZZ".to_string(), 7)]);
+
+        let mut rng = StdRng::seed_from_u64(19);
+        let mut prover = Prover::new(TREE_DEPTH);
+        prover.setup(CircuitKind::Balance, &mut rng).unwrap();
+        let bundle = prover.prove_balance(&witness, &mut rng).unwrap();
+        assert_eq!(bundle.public_inputs.len(), 5);
+        assert_eq!(bundle.public_inputs[0], state.get_merkle_root());
+
+        let proof = Proof::<Bls12_381>::deserialize_compressed(&bundle.proof[..]).unwrap();
+        let inputs: Vec<Fr> = bundle.public_inputs.iter().map(poseidon::bytes_to_field).collect();
+        let vk = prover.verifying_key(CircuitKind::Balance).unwrap();
+        assert!(Groth16::<Bls12_381>::verify(vk, &inputs, &proof).unwrap());
+
+        // The new root is the one the state reaches after the same update
+        state
+            .apply_transition(StateTransition::Withdrawal {
+                user_sdkey_hash: user,
+                token_id: "USDC".to_string(),
+                amount: 375,
+                nullifier: None,
+                fee: 0,
+                proof: None,
+            })
+            .unwrap();
+        assert_eq!(witness.merkle_root_new, state.get_merkle_root());
+
+        // Overdrawing or forging a root is refused before a proof leaves the prover
+        let mut forged = witness.clone();
+        forged.trade_amount = 2_000;
+        assert!(prover.prove_balance(&forged, &mut rng).is_err());
+        let mut forged = witness;
+        forged.merkle_root_new = [9u8; 32];
+        assert!(prover.prove_balance(&forged, &mut rng).is_err());
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_prover_requires_setup_and_matching_depth() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let prover = Prover::new(4);
+        assert!(!prover.is_ready(CircuitKind::Balance));
+        assert!(prover.prove_balance(&BalanceWitness::blank(4), &mut rng).is_err());
+        assert!(prover.prove_balance(&BalanceWitness::blank(3), &mut rng).is_err());
+        assert!(prover.prove_settlement(&SettlementWitness::blank(4), &mut rng).is_err());
+
+        let mut mixed = SettlementWitness::blank(4);
+        mixed.user_b = LeafUpdate::blank(3);
+        assert_eq!(mixed.depth(), None);
+    }
+}
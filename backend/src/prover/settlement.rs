@@ -0,0 +1,278 @@
+//! Trade Settlement Circuit
+//!
+//! Proves both legs of a `StateTransition::Trade` in one proof. The maker
+//! (A) gives `amount_a` of token A and receives `amount_b - maker_fee` of
+//! token B; the taker (B) gives `amount_b` of token B and receives
+//! `amount_a - taker_fee` of token A; the protocol fee account receives both
+//! fees. The three leaves are updated in that order, each against the root
+//! left by the previous one, and every leaf's nonce grows by two (one debit
+//! or fee credit per token).
+//!
+//! Public inputs are `[merkle_root_old, merkle_root_new, commitment_a,
+//! commitment_b]`, where each leg commitment is
+//! `H(sdkey_hash || token_id || amount || blinding)`. Amounts, tokens, fees
+//! and the counterparties stay private; whoever holds the blinding can open
+//! the commitments against the trade.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::gadgets::{self, AMOUNT_BITS};
+use crate::prover::leaf::{enforce_leaf_update, BalanceChange, LeafUpdate};
+use crate::prover::ProofBundle;
+use crate::state::{poseidon, protocol_fee_account};
+use ark_bls12_381::Fr;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use serde::{Deserialize, Serialize};
+
+/// Nonce increment of every leaf a trade touches
+const TRADE_NONCE_INCREMENT: u64 = 2;
+
+/// Computes the commitment to one leg of a trade
+pub fn leg_commitment(sdkey_hash: &[u8; 32], token_id: &str, amount: u128, blinding: &[u8; 32]) -> Fr {
+    poseidon::hash(&[
+        poseidon::bytes_to_field(sdkey_hash),
+        poseidon::string_to_field(token_id),
+        Fr::from(amount),
+        poseidon::bytes_to_field(blinding),
+    ])
+}
+
+/// A settlement proof attached to a trade, with the blinding that opens its commitments
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SettlementProof {
+    /// Groth16 proof of the settlement circuit
+    pub proof: ProofBundle,
+
+    /// Blinding of both leg commitments
+    pub blinding: [u8; 32],
+}
+
+/// Private and public inputs of the settlement circuit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SettlementWitness {
+    pub token_a_id: String,
+    pub token_b_id: String,
+    pub amount_a: u128,
+    pub amount_b: u128,
+    pub maker_fee: u128,
+    pub taker_fee: u128,
+
+    /// Blinding of both leg commitments
+    pub blinding: [u8; 32],
+
+    /// Maker leaf update, against `merkle_root_old`
+    pub user_a: LeafUpdate,
+
+    /// Taker leaf update, against the root after the maker's
+    pub user_b: LeafUpdate,
+
+    /// Fee account leaf update, against the root after the taker's, when
+    /// the trade charges fees
+    pub fee_account: Option<LeafUpdate>,
+
+    /// Root before the trade (public)
+    pub merkle_root_old: [u8; 32],
+
+    /// Root after the trade (public)
+    pub merkle_root_new: [u8; 32],
+}
+
+impl SettlementWitness {
+    /// Creates a placeholder witness with the shape of a tree of `depth` levels
+    pub fn blank(depth: usize) -> Self {
+        Self {
+            token_a_id: String::new(),
+            token_b_id: String::new(),
+            amount_a: 0,
+            amount_b: 0,
+            maker_fee: 0,
+            taker_fee: 0,
+            blinding: [0u8; 32],
+            user_a: LeafUpdate::blank(depth),
+            user_b: LeafUpdate::blank(depth),
+            fee_account: None,
+            merkle_root_old: [0u8; 32],
+            merkle_root_new: [0u8; 32],
+        }
+    }
+
+    /// Gets the depth of every authentication path, if they agree
+    pub fn depth(&self) -> Option<usize> {
+        let depth = self.user_a.depth();
+        let uniform = [Some(&self.user_b), self.fee_account.as_ref()]
+            .into_iter()
+            .flatten()
+            .all(|update| update.depth() == depth && update.merkle_path_indices.len() == depth);
+        (uniform && self.user_a.merkle_path_indices.len() == depth).then_some(depth)
+    }
+
+    /// Gets the public inputs in circuit order
+    ///
+    /// `[merkle_root_old, merkle_root_new, commitment_a, commitment_b]`
+    pub fn public_inputs(&self) -> Vec<Fr> {
+        vec![
+            poseidon::bytes_to_field(&self.merkle_root_old),
+            poseidon::bytes_to_field(&self.merkle_root_new),
+            leg_commitment(&self.user_a.sdkey_hash, &self.token_a_id, self.amount_a, &self.blinding),
+            leg_commitment(&self.user_b.sdkey_hash, &self.token_b_id, self.amount_b, &self.blinding),
+        ]
+    }
+}
+
+/// Checks a settlement proof's commitments open to the given trade legs
+///
+/// # Errors
+/// Returns `CloakError::ProofVerification` if the proof does not have the
+/// settlement layout or either commitment does not match.
+pub fn check_commitments(
+    settlement: &SettlementProof,
+    leg_a: (&[u8; 32], &str, u128),
+    leg_b: (&[u8; 32], &str, u128),
+) -> CloakResult<()> {
+    let inputs = &settlement.proof.public_inputs;
+    if inputs.len() != 4 {
+        return Err(CloakError::proof_verification(format!(
+            "Settlement proof has {} public inputs, expected 4",
+            inputs.len()
+        )));
+    }
+    for (index, (sdkey_hash, token_id, amount)) in [(2, leg_a), (3, leg_b)] {
+        let expected = leg_commitment(sdkey_hash, token_id, amount, &settlement.blinding);
+        if inputs[index] != poseidon::field_to_bytes(&expected) {
+            return Err(CloakError::proof_verification(format!(
+                "Settlement commitment {} does not open to the trade",
+                index - 1
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// R1CS circuit settling both legs of a trade, see the module docs
+#[derive(Debug, Clone)]
+pub struct SettlementCircuit {
+    witness: SettlementWitness,
+}
+
+impl SettlementCircuit {
+    /// Creates the circuit for a witness
+    pub fn new(witness: SettlementWitness) -> Self {
+        Self { witness }
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for SettlementCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let public = self.witness.public_inputs();
+        let input = |value: Fr| FpVar::new_input(cs.clone(), || Ok(value));
+        let root_old = input(public[0])?;
+        let root_new = input(public[1])?;
+        let commitment_a = input(public[2])?;
+        let commitment_b = input(public[3])?;
+
+        let settled = enforce_settlement(cs, &self.witness, &root_old, &Boolean::TRUE)?;
+        settled.commitment_a.enforce_equal(&commitment_a)?;
+        settled.commitment_b.enforce_equal(&commitment_b)?;
+        settled.root.enforce_equal(&root_new)
+    }
+}
+
+/// Outputs of `enforce_settlement`
+pub struct SettledTrade {
+    /// Root after the trade, or the input root when disabled
+    pub root: FpVar<Fr>,
+
+    /// Commitment to the maker's leg
+    pub commitment_a: FpVar<Fr>,
+
+    /// Commitment to the taker's leg
+    pub commitment_b: FpVar<Fr>,
+}
+
+/// Allocates a trade's witness and enforces its settlement from `root_in`
+///
+/// Shared by the settlement circuit and the batch circuit, which chains one
+/// trade per step. When `enabled` is false the leaf updates are laid out but
+/// not checked, and the root passes through unchanged.
+pub fn enforce_settlement(
+    cs: ConstraintSystemRef<Fr>,
+    w: &SettlementWitness,
+    root_in: &FpVar<Fr>,
+    enabled: &Boolean<Fr>,
+) -> Result<SettledTrade, SynthesisError> {
+    let private = |value: Fr| FpVar::new_witness(cs.clone(), || Ok(value));
+
+    let token_a = private(poseidon::string_to_field(&w.token_a_id))?;
+    let token_b = private(poseidon::string_to_field(&w.token_b_id))?;
+    let amount_a = private(Fr::from(w.amount_a))?;
+    let amount_b = private(Fr::from(w.amount_b))?;
+    let maker_fee = private(Fr::from(w.maker_fee))?;
+    let taker_fee = private(Fr::from(w.taker_fee))?;
+    let blinding = private(poseidon::bytes_to_field(&w.blinding))?;
+    let sdkey_a = private(poseidon::bytes_to_field(&w.user_a.sdkey_hash))?;
+    let sdkey_b = private(poseidon::bytes_to_field(&w.user_b.sdkey_hash))?;
+    let charges_fees = Boolean::new_witness(cs.clone(), || Ok(w.fee_account.is_some()))?;
+    let fee_account_exists =
+        Boolean::new_witness(cs.clone(), || Ok(w.fee_account.as_ref().is_some_and(|update| update.exists)))?;
+
+    // Fees come out of the traded amounts, and only when fees are charged
+    let net_a = &amount_a - &taker_fee;
+    let net_b = &amount_b - &maker_fee;
+    for amount in [&amount_a, &amount_b, &maker_fee, &taker_fee, &net_a, &net_b] {
+        gadgets::enforce_range(cs.clone(), amount, AMOUNT_BITS)?;
+    }
+    let no_fees = charges_fees.not();
+    maker_fee.conditional_enforce_equal(&FpVar::zero(), &no_fees)?;
+    taker_fee.conditional_enforce_equal(&FpVar::zero(), &no_fees)?;
+
+    // The commitments open to the private legs
+    let commitment_a =
+        gadgets::poseidon_hash(cs.clone(), &[sdkey_a.clone(), token_a.clone(), amount_a.clone(), blinding.clone()])?;
+    let commitment_b =
+        gadgets::poseidon_hash(cs.clone(), &[sdkey_b.clone(), token_b.clone(), amount_b.clone(), blinding])?;
+
+    let debit = |token, amount| BalanceChange { token, amount, credit: false };
+    let credit = |token, amount| BalanceChange { token, amount, credit: true };
+
+    let after_a = enforce_leaf_update(
+        cs.clone(),
+        &w.user_a,
+        &sdkey_a,
+        &Boolean::TRUE,
+        &[debit(&token_a, &amount_a), credit(&token_b, &net_b)],
+        TRADE_NONCE_INCREMENT,
+        root_in,
+        enabled,
+    )?;
+    let after_b = enforce_leaf_update(
+        cs.clone(),
+        &w.user_b,
+        &sdkey_b,
+        &Boolean::TRUE,
+        &[debit(&token_b, &amount_b), credit(&token_a, &net_a)],
+        TRADE_NONCE_INCREMENT,
+        &after_a,
+        enabled,
+    )?;
+
+    let fee_update = w.fee_account.clone().unwrap_or_else(|| LeafUpdate::blank(w.user_a.depth()));
+    let fee_sdkey = FpVar::constant(poseidon::bytes_to_field(&protocol_fee_account()));
+    let after_fees = enforce_leaf_update(
+        cs.clone(),
+        &fee_update,
+        &fee_sdkey,
+        &fee_account_exists,
+        &[credit(&token_a, &taker_fee), credit(&token_b, &maker_fee)],
+        TRADE_NONCE_INCREMENT,
+        &after_b,
+        &charges_fees.and(enabled)?,
+    )?;
+
+    let settled = FpVar::conditionally_select(&charges_fees, &after_fees, &after_b)?;
+    Ok(SettledTrade {
+        root: FpVar::conditionally_select(enabled, &settled, root_in)?,
+        commitment_a,
+        commitment_b,
+    })
+}
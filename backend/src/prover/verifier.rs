@@ -0,0 +1,99 @@
+//! Groth16 Verifier
+//!
+//! Checks serialized proofs natively against prepared verifying keys, one per
+//! circuit kind. Every failure, including a missing key or malformed proof
+//! bytes, surfaces as `CloakError::ProofVerification` so callers can refuse
+//! the proof without distinguishing why.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::{CircuitKind, ProofBundle};
+use crate::state::poseidon;
+use ark_bls12_381::{Bls12_381, Fr};
+use ark_groth16::{prepare_verifying_key, Groth16, PreparedVerifyingKey, Proof, VerifyingKey};
+use ark_serialize::CanonicalDeserialize;
+use ark_snark::SNARK;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Prepared verifying keys by circuit kind
+#[derive(Default)]
+pub struct Verifier {
+    keys: BTreeMap<CircuitKind, PreparedVerifyingKey<Bls12_381>>,
+}
+
+impl Verifier {
+    /// Creates a verifier with no keys
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the verifying key for `kind`, replacing any existing key
+    pub fn insert(&mut self, kind: CircuitKind, key: &VerifyingKey<Bls12_381>) {
+        self.keys.insert(kind, prepare_verifying_key(key));
+    }
+
+    /// Checks whether a verifying key is stored for `kind`
+    pub fn has_key(&self, kind: CircuitKind) -> bool {
+        self.keys.contains_key(&kind)
+    }
+
+    /// Gets the circuit kinds that have a verifying key
+    pub fn circuits(&self) -> Vec<CircuitKind> {
+        self.keys.keys().copied().collect()
+    }
+
+    /// Verifies a proof bundle against the key for its circuit
+    ///
+    /// # Errors
+    /// Returns `CloakError::ProofVerification` if there is no key for the
+    /// circuit, the proof bytes do not decode, the number of public inputs is
+    /// wrong, an input is not a canonical field encoding, or the pairing check
+    /// fails.
+    pub fn verify(&self, bundle: &ProofBundle) -> CloakResult<()> {
+        let key = self.keys.get(&bundle.circuit).ok_or_else(|| {
+            CloakError::proof_verification(format!("No verifying key for the {} circuit", bundle.circuit))
+        })?;
+
+        let expected_inputs = key.vk.gamma_abc_g1.len() - 1;
+        if bundle.public_inputs.len() != expected_inputs {
+            return Err(CloakError::proof_verification(format!(
+                "{} proof has {} public inputs, expected {}",
+                bundle.circuit,
+                bundle.public_inputs.len(),
+                expected_inputs
+            )));
+        }
+
+        let proof = Proof::<Bls12_381>::deserialize_compressed(&bundle.proof[..]).map_err(|e| {
+            CloakError::proof_verification(format!("Malformed {} proof: {}", bundle.circuit, e))
+        })?;
+        let mut inputs = Vec::with_capacity(bundle.public_inputs.len());
+        for (index, bytes) in bundle.public_inputs.iter().enumerate() {
+            let input: Fr = poseidon::bytes_to_field(bytes);
+            if poseidon::field_to_bytes(&input) != *bytes {
+                return Err(CloakError::proof_verification(format!(
+                    "{} proof public input {} is not a canonical field element",
+                    bundle.circuit, index
+                )));
+            }
+            inputs.push(input);
+        }
+
+        let valid = Groth16::<Bls12_381>::verify_with_processed_vk(key, &inputs, &proof).map_err(|e| {
+            CloakError::proof_verification(format!("{} proof could not be checked: {}", bundle.circuit, e))
+        })?;
+        if !valid {
+            return Err(CloakError::proof_verification(format!(
+                "{} proof does not verify against its public inputs",
+                bundle.circuit
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Verifier").field("circuits", &self.circuits()).finish()
+    }
+}
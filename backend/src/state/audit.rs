@@ -0,0 +1,153 @@
+//! State Self-Audit
+//!
+//! Recomputes the global invariants from the stored records instead of
+//! trusting incrementally maintained state:
+//! - per token, the sum of every user's balance equals the public supply
+//!   implied by the supply counters (including distributions)
+//! - the Merkle root rebuilt from the stored user leaves equals the live
+//!   root and the root recorded for the current sequence
+//!
+//! The audit reports discrepancies rather than failing, so an operator can
+//! see everything that is wrong at once. It runs at startup and on demand.
+
+use crate::error::CloakResult;
+use crate::state::{SparseMerkleTree, StateManager};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{error, info};
+
+/// Audit result for one token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenAudit {
+    pub token_id: String,
+    /// Public supply implied by the counters (`None` if they underflow)
+    pub expected: Option<u128>,
+    /// Sum of all stored user balances
+    pub actual: u128,
+}
+
+impl TokenAudit {
+    /// Checks whether the balances match the counters
+    pub fn is_balanced(&self) -> bool {
+        self.expected == Some(self.actual)
+    }
+}
+
+/// Outcome of a full state audit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditReport {
+    /// Number of stored user records scanned
+    pub users_scanned: usize,
+    /// Live Merkle root, hex-encoded
+    pub state_root: String,
+    /// Merkle root rebuilt from the stored user records, hex-encoded
+    pub recomputed_root: String,
+    /// Root recorded in the history for the current sequence, hex-encoded
+    pub recorded_root: Option<String>,
+    /// Per-token supply checks
+    pub tokens: Vec<TokenAudit>,
+    /// Human-readable description of every discrepancy found
+    pub discrepancies: Vec<String>,
+}
+
+impl AuditReport {
+    /// Checks whether the audit found no discrepancies
+    pub fn is_clean(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+impl StateManager {
+    /// Recomputes supply totals and the Merkle root from storage
+    ///
+    /// # Errors
+    /// Only fails if the stored records cannot be read; discrepancies are
+    /// reported in the returned `AuditReport`.
+    pub fn audit(&self) -> CloakResult<AuditReport> {
+        let mut tree = SparseMerkleTree::with_depth(self.merkle_tree.depth());
+        let mut totals: BTreeMap<String, u128> = BTreeMap::new();
+        let mut discrepancies = Vec::new();
+        let mut users_scanned = 0;
+
+        self.for_each_stored_user(|user_state| {
+            tree.update(&user_state.sdkey_hash, user_state.leaf_hash());
+            for (token_id, amount) in &user_state.balances {
+                let total = totals.entry(token_id.clone()).or_default();
+                match total.checked_add(*amount) {
+                    Some(sum) => *total = sum,
+                    None => discrepancies.push(format!("Balances of {} overflow u128", token_id)),
+                }
+            }
+            users_scanned += 1;
+            Ok(())
+        })?;
+
+        let token_ids: BTreeSet<&String> = totals.keys().chain(self.supply.iter().map(|(id, _)| id)).collect();
+        let tokens: Vec<TokenAudit> = token_ids
+            .into_iter()
+            .map(|token_id| TokenAudit {
+                token_id: token_id.clone(),
+                expected: self.supply.get(token_id).public_supply(),
+                actual: totals.get(token_id).copied().unwrap_or(0),
+            })
+            .collect();
+        for token in tokens.iter().filter(|token| !token.is_balanced()) {
+            discrepancies.push(match token.expected {
+                Some(expected) => format!(
+                    "Token {}: balances sum to {} but supply counters imply {}",
+                    token.token_id, token.actual, expected
+                ),
+                None => format!("Token {}: supply counters underflow", token.token_id),
+            });
+        }
+        for (token_id, supply) in self.supply.iter() {
+            if supply.shielded_supply().is_none() {
+                discrepancies.push(format!("Token {}: more unshielded than was ever shielded", token_id));
+            }
+        }
+
+        let state_root = self.merkle_tree.get_root();
+        let recomputed_root = tree.get_root();
+        if recomputed_root != state_root {
+            discrepancies.push(format!(
+                "Merkle root rebuilt from {} stored users is {}, live root is {}",
+                users_scanned,
+                hex::encode(recomputed_root),
+                hex::encode(state_root)
+            ));
+        }
+        let recorded_root = self.get_root_record_by_sequence(self.root_sequence)?.map(|record| record.root);
+        if let Some(recorded) = recorded_root {
+            if recorded != state_root {
+                discrepancies.push(format!(
+                    "Root recorded for sequence {} is {}, live root is {}",
+                    self.root_sequence,
+                    hex::encode(recorded),
+                    hex::encode(state_root)
+                ));
+            }
+        }
+
+        let report = AuditReport {
+            users_scanned,
+            state_root: hex::encode(state_root),
+            recomputed_root: hex::encode(recomputed_root),
+            recorded_root: recorded_root.map(hex::encode),
+            tokens,
+            discrepancies,
+        };
+        if report.is_clean() {
+            info!(
+                "State audit passed: {} users, {} tokens, root {}",
+                report.users_scanned,
+                report.tokens.len(),
+                report.state_root
+            );
+        } else {
+            for discrepancy in &report.discrepancies {
+                error!("State audit: {}", discrepancy);
+            }
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,277 @@
+//! State Backups
+//!
+//! Consistent on-disk copies of a running node's state database. A backup
+//! is a RocksDB checkpoint (hard-linked SST files plus a copied WAL, so it
+//! is cheap and does not block writers for long) next to a manifest that
+//! tags it with the state root and height it was taken at:
+//!
+//! ```text
+//! <backup>/manifest.json
+//! <backup>/db/...
+//! ```
+//!
+//! Restoring copies the checkpoint into a fresh database directory, loads
+//! it, and only moves it into place once the loaded roots match the
+//! manifest and the journal replay agrees with the stored snapshot.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{schema, StateManager};
+use rocksdb::checkpoint::Checkpoint;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// File name of the manifest inside a backup directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Directory holding the RocksDB checkpoint inside a backup directory
+pub const CHECKPOINT_DIR: &str = "db";
+
+/// Describes the state captured by a backup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Hex-encoded state Merkle root
+    pub state_root: String,
+    /// Hex-encoded nullifier-set root
+    pub nullifier_root: String,
+    /// Hex-encoded note commitment root
+    pub note_root: String,
+    /// Psy block height of the state
+    pub block_height: u64,
+    /// Last applied root sequence
+    pub root_sequence: u64,
+    /// Number of registered users
+    pub user_count: usize,
+    /// Storage schema version of the checkpoint
+    pub schema_version: u32,
+    /// Creation time (RFC 3339)
+    pub created_at: String,
+}
+
+impl BackupManifest {
+    /// Reads the manifest of a backup directory
+    pub fn read(backup_dir: &Path) -> CloakResult<Self> {
+        let bytes = fs::read(backup_dir.join(MANIFEST_FILE))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Checks a loaded state against this manifest
+    fn check(&self, manager: &StateManager) -> CloakResult<()> {
+        let loaded = manager.backup_manifest()?;
+        let mismatch = |field: &str, expected: &dyn std::fmt::Display, actual: &dyn std::fmt::Display| {
+            CloakError::state(format!(
+                "Restored {} {} does not match backup manifest {}",
+                field, actual, expected
+            ))
+        };
+
+        if loaded.state_root != self.state_root {
+            return Err(mismatch("state root", &self.state_root, &loaded.state_root));
+        }
+        if loaded.nullifier_root != self.nullifier_root {
+            return Err(mismatch("nullifier root", &self.nullifier_root, &loaded.nullifier_root));
+        }
+        if loaded.note_root != self.note_root {
+            return Err(mismatch("note root", &self.note_root, &loaded.note_root));
+        }
+        if loaded.root_sequence != self.root_sequence {
+            return Err(mismatch("root sequence", &self.root_sequence, &loaded.root_sequence));
+        }
+        if loaded.block_height != self.block_height {
+            return Err(mismatch("block height", &self.block_height, &loaded.block_height));
+        }
+        Ok(())
+    }
+}
+
+/// Copies every file of a checkpoint directory into `dest`
+fn copy_checkpoint(src: &Path, dest: &Path) -> CloakResult<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), dest.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether a path is missing or an empty directory
+fn is_vacant(path: &Path) -> CloakResult<bool> {
+    match fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl StateManager {
+    /// Describes the current state as a backup manifest
+    pub fn backup_manifest(&self) -> CloakResult<BackupManifest> {
+        Ok(BackupManifest {
+            state_root: hex::encode(self.merkle_tree.get_root()),
+            nullifier_root: hex::encode(self.nullifiers.get_root()),
+            note_root: hex::encode(self.note_tree.get_root()),
+            block_height: self.block_height,
+            root_sequence: self.root_sequence,
+            user_count: self.get_user_count(),
+            schema_version: self.get_schema_version()?.unwrap_or(schema::SCHEMA_VERSION),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        })
+    }
+
+    /// Writes a consistent backup of the state database to `backup_dir`
+    ///
+    /// Holding `&self` keeps transitions out while the checkpoint is taken,
+    /// so the manifest always describes exactly the checkpointed state.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if `backup_dir` already exists and
+    /// is not empty.
+    pub fn create_backup(&self, backup_dir: &Path) -> CloakResult<BackupManifest> {
+        if !is_vacant(backup_dir)? {
+            return Err(CloakError::invalid_input(format!(
+                "Backup directory is not empty: {}",
+                backup_dir.display()
+            )));
+        }
+        fs::create_dir_all(backup_dir)?;
+
+        let manifest = self.backup_manifest()?;
+        Checkpoint::new(&self.db)?.create_checkpoint(backup_dir.join(CHECKPOINT_DIR))?;
+        fs::write(backup_dir.join(MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?)?;
+
+        info!(
+            "Created backup at {} (root {}, height {}, sequence {})",
+            backup_dir.display(),
+            manifest.state_root,
+            manifest.block_height,
+            manifest.root_sequence
+        );
+        Ok(manifest)
+    }
+
+    /// Restores a backup into a new database at `db_path`
+    ///
+    /// The checkpoint is copied to a staging directory next to `db_path`,
+    /// opened and verified there, and only then renamed into place. The
+    /// node must not be running against `db_path`.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if `db_path` already holds data,
+    /// and `CloakError::State` if the restored state does not match the
+    /// manifest or fails journal verification.
+    pub fn restore_backup(backup_dir: &Path, db_path: &str) -> CloakResult<BackupManifest> {
+        let target = Path::new(db_path);
+        if !is_vacant(target)? {
+            return Err(CloakError::invalid_input(format!(
+                "Refusing to restore over existing database: {}",
+                db_path
+            )));
+        }
+
+        let manifest = BackupManifest::read(backup_dir)?;
+        let staging = PathBuf::from(format!("{}.restoring", db_path));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        copy_checkpoint(&backup_dir.join(CHECKPOINT_DIR), &staging)?;
+
+        let verified = StateManager::new(&staging.to_string_lossy()).and_then(|manager| {
+            manifest.check(&manager)?;
+            manager.verify_snapshot()?;
+            Ok(())
+        });
+        if let Err(e) = verified {
+            fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+
+        if target.exists() {
+            fs::remove_dir(target)?;
+        }
+        fs::rename(&staging, target)?;
+
+        info!(
+            "Restored backup {} into {} (root {}, height {})",
+            backup_dir.display(),
+            db_path,
+            manifest.state_root,
+            manifest.block_height
+        );
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_token;
+    use crate::state::{AssetClass, StateTransition};
+
+    fn temp_path(label: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cloak-backup-{}-{}", label, uuid::Uuid::new_v4()))
+    }
+
+    fn funded_manager(path: &Path) -> StateManager {
+        let mut manager = StateManager::new(path.to_str().unwrap()).unwrap();
+        manager.register_token(test_token("USDC", AssetClass::Treasury)).unwrap();
+        manager.set_block_height(12);
+        manager.register_user([1u8; 32]).unwrap();
+        manager
+            .apply_transition(StateTransition::Deposit {
+                user_sdkey_hash: [1u8; 32],
+                token_id: "USDC".to_string(),
+                amount: 500,
+            })
+            .unwrap();
+        manager
+    }
+
+    #[test]
+    fn test_backup_restores_tagged_state() {
+        let (db, backup, restored) = (temp_path("db"), temp_path("dir"), temp_path("restored"));
+        let mut manager = funded_manager(&db);
+        let manifest = manager.create_backup(&backup).unwrap();
+        assert_eq!(manifest.state_root, hex::encode(manager.get_merkle_root()));
+        assert_eq!(manifest.block_height, 12);
+
+        // Later writes are not part of the backup
+        manager.register_user([2u8; 32]).unwrap();
+        assert!(manager.create_backup(&backup).is_err());
+
+        let restored_manifest = StateManager::restore_backup(&backup, restored.to_str().unwrap()).unwrap();
+        assert_eq!(restored_manifest, manifest);
+        let reopened = StateManager::new(restored.to_str().unwrap()).unwrap();
+        assert_eq!(hex::encode(reopened.get_merkle_root()), manifest.state_root);
+        assert_eq!(reopened.get_user_count(), 1);
+        assert_eq!(reopened.get_user_state([1u8; 32]).unwrap().get_balance("USDC"), 500);
+
+        // Restoring over a live database is refused
+        assert!(StateManager::restore_backup(&backup, restored.to_str().unwrap()).is_err());
+
+        drop((manager, reopened));
+        for path in [db, backup, restored] {
+            fs::remove_dir_all(path).ok();
+        }
+    }
+
+    #[test]
+    fn test_restore_rejects_manifest_mismatch() {
+        let (db, backup, restored) = (temp_path("db"), temp_path("dir"), temp_path("restored"));
+        let manager = funded_manager(&db);
+        let mut manifest = manager.create_backup(&backup).unwrap();
+        manifest.state_root = hex::encode([9u8; 32]);
+        fs::write(backup.join(MANIFEST_FILE), serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+        let result = StateManager::restore_backup(&backup, restored.to_str().unwrap());
+        assert!(matches!(result, Err(CloakError::State(_))));
+        assert!(!restored.exists());
+
+        drop(manager);
+        for path in [db, backup] {
+            fs::remove_dir_all(path).ok();
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! User State Cache
+//!
+//! RocksDB's `users` column family is the source of truth for user states.
+//! `StateManager` keeps only a bounded LRU of recently used states in
+//! memory and reads through to the database on a miss. Committed
+//! transitions write the new states into the cache after the batch lands,
+//! so the cache never holds a state the database does not.
+
+use crate::error::CloakResult;
+use crate::state::{schema, StateManager, StateTransition, UserState};
+use lru::LruCache;
+use rocksdb::IteratorMode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Default number of user states kept in memory
+pub const DEFAULT_USER_CACHE_CAPACITY: usize = 100_000;
+
+/// Hit-rate metrics of the user state cache
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Maximum number of cached states
+    pub capacity: usize,
+    /// Number of states currently cached
+    pub len: usize,
+    /// Lookups served from memory
+    pub hits: u64,
+    /// Lookups that went to RocksDB
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Gets the fraction of lookups served from memory (0 with no lookups)
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Bounded LRU of user states with hit/miss counters
+///
+/// Lookups take `&self` so read-only queries can fill the cache while the
+/// state manager is shared behind a read lock.
+#[derive(Debug)]
+pub struct UserCache {
+    entries: Mutex<LruCache<[u8; 32], UserState>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl UserCache {
+    /// Creates an empty cache holding at most `capacity` states (minimum 1)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(Self::non_zero(capacity))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn non_zero(capacity: usize) -> NonZeroUsize {
+        NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN)
+    }
+
+    fn entries(&self) -> MutexGuard<'_, LruCache<[u8; 32], UserState>> {
+        // The cache only ever holds copies of committed states, so a panic
+        // mid-update cannot leave it inconsistent with the database
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Gets a cached state, recording a hit or a miss
+    pub fn get(&self, sdkey_hash: &[u8; 32]) -> Option<UserState> {
+        let found = self.entries().get(sdkey_hash).cloned();
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Caches a state, evicting the least recently used one if full
+    pub fn put(&self, user_state: UserState) {
+        self.entries().put(user_state.sdkey_hash, user_state);
+    }
+
+    /// Drops a state from the cache
+    pub fn remove(&self, sdkey_hash: &[u8; 32]) {
+        self.entries().pop(sdkey_hash);
+    }
+
+    /// Drops every cached state
+    pub fn clear(&self) {
+        self.entries().clear();
+    }
+
+    /// Changes the capacity, evicting the oldest states if it shrinks
+    pub fn resize(&self, capacity: usize) {
+        self.entries().resize(Self::non_zero(capacity));
+    }
+
+    /// Gets the current hit-rate metrics
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries();
+        CacheStats {
+            capacity: entries.cap().get(),
+            len: entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl StateManager {
+    /// Gets the user state cache metrics
+    pub fn get_user_cache_stats(&self) -> CacheStats {
+        self.user_cache.stats()
+    }
+
+    /// Sets how many user states are kept in memory
+    pub fn set_user_cache_capacity(&mut self, capacity: usize) {
+        self.user_cache.resize(capacity);
+    }
+
+    /// Reads a user state from RocksDB, bypassing the cache
+    pub(super) fn fetch_user(&self, sdkey_hash: &[u8; 32]) -> CloakResult<Option<UserState>> {
+        match self.db.get_cf(schema::users_cf(&self.db)?, sdkey_hash)? {
+            Some(value) => Ok(Some(UserState::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a user state through the cache, filling it on a miss
+    pub(super) fn load_user(&self, sdkey_hash: &[u8; 32]) -> CloakResult<Option<UserState>> {
+        if let Some(user_state) = self.user_cache.get(sdkey_hash) {
+            return Ok(Some(user_state));
+        }
+        let user_state = self.fetch_user(sdkey_hash)?;
+        if let Some(user_state) = &user_state {
+            self.user_cache.put(user_state.clone());
+        }
+        Ok(user_state)
+    }
+
+    /// Loads every registered user a transition touches
+    pub(super) fn load_affected_users(
+        &self,
+        transition: &StateTransition,
+    ) -> CloakResult<HashMap<[u8; 32], UserState>> {
+        let mut loaded = HashMap::new();
+        for sdkey_hash in transition.affected_users() {
+            if let Some(user_state) = self.load_user(&sdkey_hash)? {
+                loaded.insert(sdkey_hash, user_state);
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Streams every stored user state through `f` without caching them
+    pub(super) fn for_each_stored_user<F>(&self, mut f: F) -> CloakResult<()>
+    where
+        F: FnMut(UserState) -> CloakResult<()>,
+    {
+        for item in self.db.iterator_cf(schema::users_cf(&self.db)?, IteratorMode::Start) {
+            let (_, value) = item?;
+            f(UserState::decode(&value)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_evicts_least_recently_used_and_counts_hits() {
+        let cache = UserCache::new(2);
+        for byte in [1u8, 2, 3] {
+            cache.put(UserState::new([byte; 32]));
+        }
+
+        assert!(cache.get(&[1u8; 32]).is_none());
+        assert!(cache.get(&[2u8; 32]).is_some());
+        assert!(cache.get(&[3u8; 32]).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.capacity, stats.len), (2, 2));
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert!((stats.hit_rate() - 2.0 / 3.0).abs() < f64::EPSILON);
+
+        cache.resize(1);
+        assert_eq!(cache.stats().len, 1);
+    }
+}
@@ -0,0 +1,479 @@
+//! Compliance Attestations
+//!
+//! Tokens can require their traders to hold a fresh compliance proof (see
+//! `prover::compliance`). The state manager keeps the two trees the proof is
+//! made against, both sparse Merkle trees of the state tree's depth:
+//!
+//! - the sanctions list, keyed by jurisdiction hash, each sanctioned
+//!   jurisdiction holding the leaf `H(jurisdiction_hash)`
+//! - the credential registry, keyed by SDKey hash, each accredited user
+//!   holding `H(sdkey_hash || jurisdiction_hash || expires_at)`
+//!
+//! A verified compliance proof is recorded as an attestation at the block
+//! height it was made for. A transition crediting or debiting a token whose
+//! policy sets `compliance_window_blocks` needs every party to hold an attestation no
+//! older than the window, made against the current sanctions list. Adding a
+//! sanction therefore voids every attestation at once, while a revoked
+//! credential lapses with the window.
+//!
+//! Records live in the `restrictions` column family:
+//!
+//! | Key                          | Value                           |
+//! |------------------------------|---------------------------------|
+//! | `sanctioned:<code>`          | empty                           |
+//! | `credential:<sdkey hex>`     | JSON `AccreditationCredential`  |
+//! | `attestation:<sdkey hex>`    | JSON `ComplianceAttestation`    |
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::{CircuitKind, ComplianceWitness, ProofBundle};
+use crate::state::restrictions::normalize_jurisdiction;
+use crate::state::{poseidon, schema, SparseMerkleTree, StateManager};
+use ark_bls12_381::Fr;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Key prefix of sanctioned jurisdictions
+const SANCTIONED_PREFIX: &str = "sanctioned:";
+
+/// Key prefix of accreditation credentials
+const CREDENTIAL_PREFIX: &str = "credential:";
+
+/// Key prefix of compliance attestations
+const ATTESTATION_PREFIX: &str = "attestation:";
+
+/// Accreditation credential issued to a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccreditationCredential {
+    /// ISO 3166-1 alpha-2 jurisdiction the user was accredited in
+    pub jurisdiction: String,
+    /// Block height from which the credential is no longer valid
+    pub expires_at: u64,
+}
+
+/// Record of a verified compliance proof
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComplianceAttestation {
+    /// Block height the proof was made for
+    pub block_height: u64,
+    /// Sanctions list root the proof was made against
+    pub sanctions_root: [u8; 32],
+    /// Credential registry root the proof was made against
+    pub credentials_root: [u8; 32],
+}
+
+/// Sanctions list and credential registry the compliance circuit proves against
+#[derive(Debug, Default)]
+pub struct ComplianceRegistry {
+    sanctions: SparseMerkleTree,
+    credentials: SparseMerkleTree,
+    issued: BTreeMap<[u8; 32], AccreditationCredential>,
+}
+
+/// Gets the sanctions tree key of a jurisdiction code
+pub fn jurisdiction_hash(code: &str) -> [u8; 32] {
+    poseidon::field_to_bytes(&poseidon::string_to_field(&code.to_ascii_uppercase()))
+}
+
+/// Computes the sanctions tree leaf of a sanctioned jurisdiction
+pub fn sanction_leaf(jurisdiction_hash: &[u8; 32]) -> Fr {
+    poseidon::hash(&[poseidon::bytes_to_field(jurisdiction_hash)])
+}
+
+/// Computes the registry leaf of a credential
+pub fn credential_leaf(sdkey_hash: &[u8; 32], credential: &AccreditationCredential) -> Fr {
+    poseidon::hash(&[
+        poseidon::bytes_to_field(sdkey_hash),
+        poseidon::bytes_to_field(&jurisdiction_hash(&credential.jurisdiction)),
+        Fr::from(credential.expires_at),
+    ])
+}
+
+fn sanctioned_key(code: &str) -> String {
+    format!("{}{}", SANCTIONED_PREFIX, code)
+}
+
+fn credential_key(sdkey_hash: &[u8; 32]) -> String {
+    format!("{}{}", CREDENTIAL_PREFIX, hex::encode(sdkey_hash))
+}
+
+fn attestation_key(sdkey_hash: &[u8; 32]) -> String {
+    format!("{}{}", ATTESTATION_PREFIX, hex::encode(sdkey_hash))
+}
+
+/// Decodes a public input that must be a u64
+fn input_to_u64(input: &[u8; 32]) -> Option<u64> {
+    let (high, low) = input.split_at(24);
+    high.iter().all(|byte| *byte == 0).then(|| u64::from_be_bytes(low.try_into().expect("8 bytes")))
+}
+
+impl StateManager {
+    /// Adds a jurisdiction to the sanctions list, or removes it
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for a malformed jurisdiction code,
+    /// and `CloakError::State` if its hash collides with another sanctioned
+    /// jurisdiction's slot.
+    pub fn set_sanctioned(&mut self, code: &str, sanctioned: bool) -> CloakResult<()> {
+        let code = normalize_jurisdiction(code)?;
+        let key = jurisdiction_hash(&code);
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        if sanctioned {
+            // The circuit checks the slot's leaf, which a shared slot would hide
+            if self.compliance.sanctions.is_slot_shared(&key) {
+                return Err(CloakError::state(format!(
+                    "Jurisdiction {} shares its sanctions slot with another sanctioned jurisdiction",
+                    code
+                )));
+            }
+            self.compliance.sanctions.update(&key, sanction_leaf(&key));
+            self.db.put_cf(cf, sanctioned_key(&code), [])?;
+        } else {
+            self.compliance.sanctions.remove(&key);
+            self.db.delete_cf(cf, sanctioned_key(&code))?;
+        }
+        info!(
+            "Jurisdiction {} {} the sanctions list, root {}",
+            code,
+            if sanctioned { "added to" } else { "removed from" },
+            hex::encode(self.sanctions_root())
+        );
+        Ok(())
+    }
+
+    /// Checks whether a jurisdiction is on the sanctions list
+    pub fn is_sanctioned(&self, code: &str) -> bool {
+        self.compliance.sanctions.contains(&jurisdiction_hash(code))
+    }
+
+    /// Gets the root of the sanctions list
+    pub fn sanctions_root(&self) -> [u8; 32] {
+        self.compliance.sanctions.get_root()
+    }
+
+    /// Issues an accreditation credential to a user, replacing any existing one
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for a malformed jurisdiction code.
+    pub fn issue_credential(
+        &mut self,
+        sdkey_hash: [u8; 32],
+        jurisdiction: &str,
+        expires_at: u64,
+    ) -> CloakResult<AccreditationCredential> {
+        let credential = AccreditationCredential {
+            jurisdiction: normalize_jurisdiction(jurisdiction)?,
+            expires_at,
+        };
+        self.compliance.credentials.update(&sdkey_hash, credential_leaf(&sdkey_hash, &credential));
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        self.db.put_cf(cf, credential_key(&sdkey_hash), serde_json::to_vec(&credential)?)?;
+        info!(
+            "Issued credential to {} in {} until block {}",
+            hex::encode(sdkey_hash),
+            credential.jurisdiction,
+            expires_at
+        );
+        self.compliance.issued.insert(sdkey_hash, credential.clone());
+        Ok(credential)
+    }
+
+    /// Revokes a user's accreditation credential, returning whether one existed
+    pub fn revoke_credential(&mut self, sdkey_hash: [u8; 32]) -> CloakResult<bool> {
+        if self.compliance.issued.remove(&sdkey_hash).is_none() {
+            return Ok(false);
+        }
+        self.compliance.credentials.remove(&sdkey_hash);
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        self.db.delete_cf(cf, credential_key(&sdkey_hash))?;
+        info!("Revoked credential of {}", hex::encode(sdkey_hash));
+        Ok(true)
+    }
+
+    /// Gets a user's accreditation credential
+    pub fn get_credential(&self, sdkey_hash: &[u8; 32]) -> Option<&AccreditationCredential> {
+        self.compliance.issued.get(sdkey_hash)
+    }
+
+    /// Gets the root of the credential registry
+    pub fn credentials_root(&self) -> [u8; 32] {
+        self.compliance.credentials.get_root()
+    }
+
+    /// Builds the compliance circuit witness for a user at the current height
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the user holds no credential,
+    /// it has expired, or its jurisdiction is sanctioned, and
+    /// `CloakError::State` if the credential shares its registry slot with
+    /// another user's, which the circuit cannot prove.
+    pub fn compliance_witness(&self, sdkey_hash: [u8; 32]) -> CloakResult<ComplianceWitness> {
+        let credential = self.get_credential(&sdkey_hash).ok_or_else(|| {
+            CloakError::invalid_input(format!("User {} holds no credential", hex::encode(sdkey_hash)))
+        })?;
+        if credential.expires_at <= self.block_height {
+            return Err(CloakError::invalid_input(format!(
+                "Credential of {} expired at block {}",
+                hex::encode(sdkey_hash),
+                credential.expires_at
+            )));
+        }
+        if self.is_sanctioned(&credential.jurisdiction) {
+            return Err(CloakError::invalid_input(format!(
+                "Jurisdiction {} is sanctioned",
+                credential.jurisdiction
+            )));
+        }
+        if self.compliance.credentials.is_slot_shared(&sdkey_hash) {
+            return Err(CloakError::state(format!(
+                "Credential of {} shares its registry slot with another user's",
+                hex::encode(sdkey_hash)
+            )));
+        }
+
+        let key = jurisdiction_hash(&credential.jurisdiction);
+        let credential_proof = self.compliance.credentials.prove_path(&sdkey_hash);
+        let sanctions_proof = self.compliance.sanctions.prove_path(&key);
+        Ok(ComplianceWitness {
+            sdkey_hash,
+            jurisdiction_hash: key,
+            expires_at: credential.expires_at,
+            credential_path: credential_proof.merkle_path,
+            credential_path_indices: credential_proof.merkle_path_indices,
+            sanctions_slot_leaf: sanctions_proof.leaf,
+            sanctions_path: sanctions_proof.merkle_path,
+            sanctions_root: self.sanctions_root(),
+            credentials_root: self.credentials_root(),
+            block_height: self.block_height,
+        })
+    }
+
+    /// Verifies a user's compliance proof and records it as their attestation
+    ///
+    /// The proof must be made against the current sanctions list and
+    /// credential registry, for a block height no later than the current one.
+    /// Its user input is `sdkey_hash` reduced into the field, so the
+    /// attestation is keyed by the raw `sdkey_hash` the gates look up, and
+    /// the user must hold a credential so no other key reducing to the same
+    /// input can claim the proof.
+    ///
+    /// # Errors
+    /// Returns `CloakError::ProofVerification` if the proof is not a valid
+    /// compliance proof of `sdkey_hash` against the current trees.
+    pub fn submit_compliance_proof(
+        &mut self,
+        sdkey_hash: [u8; 32],
+        bundle: &ProofBundle,
+    ) -> CloakResult<ComplianceAttestation> {
+        if bundle.circuit != CircuitKind::Compliance {
+            return Err(CloakError::proof_verification(format!(
+                "Expected a compliance proof, got a {} proof",
+                bundle.circuit
+            )));
+        }
+        self.verify_proof(bundle)?;
+
+        let inputs = &bundle.public_inputs;
+        if inputs[0] != self.sanctions_root() {
+            return Err(CloakError::proof_verification("Compliance proof is against a stale sanctions list"));
+        }
+        if inputs[1] != self.credentials_root() {
+            return Err(CloakError::proof_verification(
+                "Compliance proof is against a stale credential registry",
+            ));
+        }
+        let block_height = input_to_u64(&inputs[3])
+            .filter(|height| *height <= self.block_height)
+            .ok_or_else(|| CloakError::proof_verification("Compliance proof is for a future block"))?;
+
+        if inputs[2] != poseidon::field_to_bytes(&poseidon::bytes_to_field(&sdkey_hash)) {
+            return Err(CloakError::proof_verification(format!(
+                "Compliance proof is not for user {}",
+                hex::encode(sdkey_hash)
+            )));
+        }
+        if self.get_credential(&sdkey_hash).is_none() {
+            return Err(CloakError::proof_verification(format!(
+                "User {} holds no credential",
+                hex::encode(sdkey_hash)
+            )));
+        }
+
+        let attestation = ComplianceAttestation {
+            block_height,
+            sanctions_root: inputs[0],
+            credentials_root: inputs[1],
+        };
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        self.db.put_cf(cf, attestation_key(&sdkey_hash), serde_json::to_vec(&attestation)?)?;
+        info!("User {} attested compliance at block {}", hex::encode(sdkey_hash), block_height);
+        Ok(attestation)
+    }
+
+    /// Gets a user's latest compliance attestation
+    pub fn get_compliance_attestation(&self, sdkey_hash: &[u8; 32]) -> CloakResult<Option<ComplianceAttestation>> {
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        match self.db.get_cf(cf, attestation_key(sdkey_hash))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Explains why a user has no fresh attestation within `window` blocks
+    pub(super) fn compliance_lapse(&self, sdkey_hash: &[u8; 32], window: u64) -> CloakResult<Option<String>> {
+        let user = hex::encode(sdkey_hash);
+        let Some(attestation) = self.get_compliance_attestation(sdkey_hash)? else {
+            return Ok(Some(format!("{} has no compliance proof", user)));
+        };
+        if attestation.sanctions_root != self.sanctions_root() {
+            return Ok(Some(format!("{}'s compliance proof predates the sanctions list", user)));
+        }
+        let fresh_until = attestation.block_height.saturating_add(window);
+        if self.block_height > fresh_until {
+            return Ok(Some(format!("{}'s compliance proof expired at block {}", user, fresh_until)));
+        }
+        Ok(None)
+    }
+
+    /// Rebuilds the sanctions list and credential registry from RocksDB
+    pub(super) fn load_compliance(&mut self) -> CloakResult<()> {
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        let mut registry = ComplianceRegistry::default();
+        for item in self.db.prefix_iterator_cf(cf, SANCTIONED_PREFIX) {
+            let (key, _) = item?;
+            let Some(code) = key.strip_prefix(SANCTIONED_PREFIX.as_bytes()) else {
+                break;
+            };
+            let key = jurisdiction_hash(&String::from_utf8_lossy(code));
+            registry.sanctions.update(&key, sanction_leaf(&key));
+        }
+        for item in self.db.prefix_iterator_cf(cf, CREDENTIAL_PREFIX) {
+            let (key, value) = item?;
+            let Some(sdkey_hex) = key.strip_prefix(CREDENTIAL_PREFIX.as_bytes()) else {
+                break;
+            };
+            let sdkey_hash: [u8; 32] = hex::decode(sdkey_hex)?
+                .try_into()
+                .map_err(|_| CloakError::state("Corrupt credential key"))?;
+            let credential: AccreditationCredential = serde_json::from_slice(&value)?;
+            registry.credentials.update(&sdkey_hash, credential_leaf(&sdkey_hash, &credential));
+            registry.issued.insert(sdkey_hash, credential);
+        }
+        self.compliance = registry;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prover::Prover;
+    use crate::state::testing::{empty_manager, keyed_prover, test_token};
+    use crate::state::{AssetClass, RestrictionRule, StateTransition, TransferPolicy, TREE_DEPTH};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_compliance_proofs_attest_accredited_unsanctioned_users() {
+        let (mut manager, path) = empty_manager();
+        let (user, other) = ([8u8; 32], [9u8; 32]);
+        manager.set_block_height(10);
+        manager.set_sanctioned("kp", true).unwrap();
+        manager.set_sanctioned("IR", true).unwrap();
+        manager.issue_credential(user, "us", 100).unwrap();
+        manager.issue_credential(other, "KP", 100).unwrap();
+
+        // Sanctioned or expired credentials cannot be proven
+        assert!(manager.compliance_witness(other).is_err());
+        manager.issue_credential(other, "DE", 5).unwrap();
+        assert!(manager.compliance_witness(other).is_err());
+
+        let mut rng = StdRng::seed_from_u64(22);
+        let prover = keyed_prover(&mut manager, Prover::new(TREE_DEPTH), &[CircuitKind::Compliance], &mut rng);
+
+        let witness = manager.compliance_witness(user).unwrap();
+        let proof = prover.prove_compliance(&witness, &mut rng).unwrap();
+
+        // A witness claiming a sanctioned jurisdiction or an expired credential does not satisfy the circuit
+        let mut forged = witness.clone();
+        forged.jurisdiction_hash = jurisdiction_hash("KP");
+        assert!(prover.prove_compliance(&forged, &mut rng).is_err());
+        let mut forged = witness.clone();
+        forged.block_height = 100;
+        assert!(prover.prove_compliance(&forged, &mut rng).is_err());
+
+        // Deposits of a token with a compliance window need an attestation
+        manager.register_token(test_token("RWA-FUND", AssetClass::Credit)).unwrap();
+        let policy = TransferPolicy {
+            compliance_window_blocks: Some(5),
+            ..TransferPolicy::default()
+        };
+        manager.set_transfer_policy("RWA-FUND", policy).unwrap();
+        manager.register_user(user).unwrap();
+        let deposit = StateTransition::Deposit {
+            user_sdkey_hash: user,
+            token_id: "RWA-FUND".to_string(),
+            amount: 10,
+        };
+        assert!(matches!(
+            manager.apply_transition(deposit.clone()),
+            Err(CloakError::TransferRestricted { rule: RestrictionRule::Compliance, .. })
+        ));
+
+        assert!(matches!(
+            manager.submit_compliance_proof(other, &proof),
+            Err(CloakError::ProofVerification(_))
+        ));
+        let attestation = manager.submit_compliance_proof(user, &proof).unwrap();
+        manager.apply_transition(deposit).unwrap();
+        assert_eq!(attestation.block_height, 10);
+        assert_eq!(manager.get_compliance_attestation(&user).unwrap(), Some(attestation));
+        assert_eq!(manager.compliance_lapse(&user, 5).unwrap(), None);
+        manager.set_block_height(16);
+        assert!(manager.compliance_lapse(&user, 5).unwrap().is_some());
+        assert_eq!(manager.compliance_lapse(&user, 6).unwrap(), None);
+
+        // A new sanction voids the attestation and the proof
+        manager.set_sanctioned("SY", true).unwrap();
+        assert!(manager.compliance_lapse(&user, 6).unwrap().is_some());
+        assert!(matches!(
+            manager.submit_compliance_proof(user, &proof),
+            Err(CloakError::ProofVerification(_))
+        ));
+
+        // The trees are rebuilt on restart
+        let (sanctions_root, credentials_root) = (manager.sanctions_root(), manager.credentials_root());
+        drop(manager);
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.sanctions_root(), sanctions_root);
+        assert_eq!(reopened.credentials_root(), credentials_root);
+        assert!(reopened.is_sanctioned("sy"));
+        assert_eq!(reopened.get_credential(&user).map(|c| c.expires_at), Some(100));
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_attestations_keyed_by_raw_sdkey_above_field_modulus() {
+        let (mut manager, path) = empty_manager();
+        // Leading byte above the modulus' 0x73, so the user input is reduced
+        let user = [0xF0u8; 32];
+        let reduced = poseidon::field_to_bytes(&poseidon::bytes_to_field(&user));
+        assert_ne!(reduced, user);
+        manager.set_block_height(10);
+        manager.issue_credential(user, "US", 100).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(22);
+        let prover = keyed_prover(&mut manager, Prover::new(TREE_DEPTH), &[CircuitKind::Compliance], &mut rng);
+        let proof = prover.prove_compliance(&manager.compliance_witness(user).unwrap(), &mut rng).unwrap();
+        assert_eq!(proof.public_inputs[2], reduced);
+
+        // The reduced key holds no credential and cannot take the proof
+        assert!(manager.submit_compliance_proof(reduced, &proof).is_err());
+        manager.submit_compliance_proof(user, &proof).unwrap();
+        assert!(manager.get_compliance_attestation(&user).unwrap().is_some());
+        assert_eq!(manager.compliance_lapse(&user, 5).unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
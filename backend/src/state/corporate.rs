@@ -0,0 +1,348 @@
+//! Corporate Actions
+//!
+//! Coupon and dividend distributions to the holders of a token. A
+//! distribution snapshots the holders of a token at a record height from
+//! the versioned user history, splits a payout pro rata to their balances,
+//! and credits the payout token to every holder in a single
+//! `StateTransition::Distribution`, without exporting holder balances.
+//!
+//! Entitlements are computed in the payout token's base units as
+//! `balance * total_payout / eligible_supply` with an exact 256-bit
+//! intermediate product, then rounded by the action's `RoundingPolicy`.
+//!
+//! The resulting `DistributionReport` is committed by its Poseidon hash:
+//! the report travels inside the journaled transition, and the node indexes
+//! it by hash in the `distributions` column family in the same batch. When
+//! applied, a report is recomputed from the snapshot and must match
+//! exactly, so a report cannot be altered and the same action cannot be
+//! paid twice.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{poseidon, schema, StateManager, StateTransition};
+use ark_bls12_381::Fr;
+use rocksdb::{WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+/// Low 64 bits of a `u128`
+const LOW_64: u128 = u64::MAX as u128;
+
+/// How the fractional parts of entitlements are settled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingPolicy {
+    /// Every entitlement is rounded down; the undistributed remainder is
+    /// reported and stays with the issuer
+    Floor,
+    /// Entitlements are rounded down, then the remainder is paid one unit
+    /// each to the holders with the largest fractional parts (ties go to
+    /// the lower SDKey hash), so the full payout is distributed
+    LargestRemainder,
+}
+
+impl RoundingPolicy {
+    /// Gets the policy's tag in the report hash
+    fn tag(&self) -> u64 {
+        match self {
+            RoundingPolicy::Floor => 0,
+            RoundingPolicy::LargestRemainder => 1,
+        }
+    }
+}
+
+/// A coupon or dividend to distribute
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorporateAction {
+    /// Token whose holders are entitled
+    pub token_id: String,
+    /// Token the payout is made in
+    pub payout_token_id: String,
+    /// Holders are taken from the state in effect at this block height
+    pub record_height: u64,
+    /// Total payout in base units of the payout token
+    pub total_payout: u128,
+    pub rounding: RoundingPolicy,
+}
+
+/// One holder's share of a distribution
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entitlement {
+    pub sdkey_hash: [u8; 32],
+    /// Balance of the entitled token at the record height
+    pub balance: u128,
+    /// Payout credited, in base units of the payout token
+    pub amount: u128,
+}
+
+/// Auditable record of a distribution, committed by `report_hash`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistributionReport {
+    pub action: CorporateAction,
+    /// Sequence of the state the holders were taken from
+    pub record_sequence: u64,
+    /// State root the holders were taken from
+    pub record_root: [u8; 32],
+    /// Sum of all entitled balances at the record height
+    pub eligible_supply: u128,
+    /// Per-holder entitlements, ordered by SDKey hash
+    pub entitlements: Vec<Entitlement>,
+    /// Total credited to holders
+    pub distributed: u128,
+    /// Part of the payout left undistributed by rounding
+    pub remainder: u128,
+    /// Poseidon hash of every field above
+    pub report_hash: [u8; 32],
+}
+
+impl DistributionReport {
+    /// Computes the Poseidon commitment to the report's contents
+    pub fn compute_hash(&self) -> [u8; 32] {
+        let mut digest = poseidon::hash(&[
+            poseidon::string_to_field(&self.action.token_id),
+            poseidon::string_to_field(&self.action.payout_token_id),
+            Fr::from(self.action.record_height),
+            Fr::from(self.action.total_payout),
+            Fr::from(self.action.rounding.tag()),
+            Fr::from(self.record_sequence),
+            poseidon::bytes_to_field(&self.record_root),
+            Fr::from(self.eligible_supply),
+        ]);
+        for entitlement in &self.entitlements {
+            digest = poseidon::hash(&[
+                digest,
+                poseidon::bytes_to_field(&entitlement.sdkey_hash),
+                Fr::from(entitlement.balance),
+                Fr::from(entitlement.amount),
+            ]);
+        }
+        let digest = poseidon::hash(&[digest, Fr::from(self.distributed), Fr::from(self.remainder)]);
+        poseidon::field_to_bytes(&digest)
+    }
+
+    /// Gets the hex-encoded report hash
+    pub fn id(&self) -> String {
+        hex::encode(self.report_hash)
+    }
+}
+
+/// Computes `floor(a * b / c)` and its remainder with a 256-bit intermediate
+///
+/// Returns `None` if `c` is zero or the quotient does not fit in a `u128`.
+pub fn mul_div(a: u128, b: u128, c: u128) -> Option<(u128, u128)> {
+    if c == 0 {
+        return None;
+    }
+    let (a_hi, a_lo) = (a >> 64, a & LOW_64);
+    let (b_hi, b_lo) = (b >> 64, b & LOW_64);
+    let (ll, lh, hl, hh) = (a_lo * b_lo, a_lo * b_hi, a_hi * b_lo, a_hi * b_hi);
+    let mid = (ll >> 64) + (lh & LOW_64) + (hl & LOW_64);
+    let lo = (ll & LOW_64) | (mid << 64);
+    let hi = hh + (lh >> 64) + (hl >> 64) + (mid >> 64);
+    if hi >= c {
+        return None;
+    }
+
+    // Shift-subtract division; `rem < c` holds before every step
+    let (mut quotient, mut rem) = (0u128, hi);
+    for bit in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || rem >= c {
+            rem = rem.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    Some((quotient, rem))
+}
+
+/// Splits a payout pro rata over `(holder, balance)` pairs
+///
+/// Holders must be ordered by SDKey hash and have non-zero balances.
+/// Returns the entitlements and the undistributed remainder.
+fn allocate(
+    holders: &[([u8; 32], u128)],
+    eligible_supply: u128,
+    total_payout: u128,
+    rounding: RoundingPolicy,
+) -> CloakResult<(Vec<Entitlement>, u128)> {
+    let mut entitlements = Vec::with_capacity(holders.len());
+    let mut fractions = Vec::with_capacity(holders.len());
+    let mut distributed: u128 = 0;
+    for (index, (sdkey_hash, balance)) in holders.iter().enumerate() {
+        let (amount, fraction) = mul_div(*balance, total_payout, eligible_supply)
+            .ok_or_else(|| CloakError::state("Entitlement exceeds the payout"))?;
+        distributed += amount;
+        fractions.push((Reverse(fraction), index));
+        entitlements.push(Entitlement {
+            sdkey_hash: *sdkey_hash,
+            balance: *balance,
+            amount,
+        });
+    }
+
+    let mut remainder = total_payout - distributed;
+    if rounding == RoundingPolicy::LargestRemainder {
+        // The floors lose less than one unit per holder, so one pass suffices
+        fractions.sort();
+        for (_, index) in fractions.into_iter().take(remainder as usize) {
+            entitlements[index].amount += 1;
+        }
+        remainder = 0;
+    }
+    Ok((entitlements, remainder))
+}
+
+/// Gets the distributions column family handle
+fn distributions_cf(db: &DB) -> CloakResult<&rocksdb::ColumnFamily> {
+    schema::column_family(db, schema::DISTRIBUTIONS_CF)
+}
+
+impl StateManager {
+    /// Computes the report of a corporate action without applying it
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the token is not registered,
+    /// the payout is zero, the record height is in the future, or nobody
+    /// held the token at the record height.
+    pub fn compute_distribution(&self, action: &CorporateAction) -> CloakResult<DistributionReport> {
+        if self.tokens.get(&action.token_id).is_none() {
+            return Err(CloakError::invalid_input(format!("Unknown token: {}", action.token_id)));
+        }
+        if action.total_payout == 0 {
+            return Err(CloakError::invalid_input("Distribution payout must be positive"));
+        }
+        if action.record_height > self.block_height {
+            return Err(CloakError::invalid_input(format!(
+                "Record height {} is after the current block {}",
+                action.record_height, self.block_height
+            )));
+        }
+        let record = self.get_root_at_block(action.record_height)?.ok_or_else(|| {
+            CloakError::invalid_input(format!("No state at record height {}", action.record_height))
+        })?;
+
+        let mut holders: Vec<([u8; 32], u128)> = self
+            .user_states_at_sequence(record.sequence)?
+            .into_values()
+            .map(|user_state| (user_state.sdkey_hash, user_state.get_balance(&action.token_id)))
+            .filter(|(_, balance)| *balance > 0)
+            .collect();
+        holders.sort();
+        let eligible_supply = holders
+            .iter()
+            .try_fold(0u128, |sum, (_, balance)| sum.checked_add(*balance))
+            .ok_or_else(|| CloakError::state(format!("Balances of {} overflow u128", action.token_id)))?;
+        if eligible_supply == 0 {
+            return Err(CloakError::invalid_input(format!(
+                "No holders of {} at record height {}",
+                action.token_id, action.record_height
+            )));
+        }
+
+        let (entitlements, remainder) = allocate(&holders, eligible_supply, action.total_payout, action.rounding)?;
+        let mut report = DistributionReport {
+            action: action.clone(),
+            record_sequence: record.sequence,
+            record_root: record.root,
+            eligible_supply,
+            entitlements,
+            distributed: action.total_payout - remainder,
+            remainder,
+            report_hash: [0u8; 32],
+        };
+        report.report_hash = report.compute_hash();
+        Ok(report)
+    }
+
+    /// Computes a corporate action and credits every holder's entitlement
+    pub fn distribute(&mut self, action: &CorporateAction) -> CloakResult<DistributionReport> {
+        let report = self.compute_distribution(action)?;
+        self.apply_transition(StateTransition::Distribution { report: report.clone() })?;
+        Ok(report)
+    }
+
+    /// Gets a committed distribution report by its hash
+    pub fn get_distribution_report(&self, report_hash: &[u8; 32]) -> CloakResult<Option<DistributionReport>> {
+        match self.db.get_cf(distributions_cf(&self.db)?, report_hash)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Rejects a distribution whose report does not match its snapshot
+    ///
+    /// Recomputes the report from the action and the stored history, so
+    /// altered entitlements or a replay of a paid action are refused.
+    pub(super) fn check_distribution(&self, transition: &StateTransition) -> CloakResult<()> {
+        let StateTransition::Distribution { report } = transition else {
+            return Ok(());
+        };
+        if self.get_distribution_report(&report.report_hash)?.is_some() {
+            return Err(CloakError::invalid_input(format!("Distribution already paid: {}", report.id())));
+        }
+        let expected = self.compute_distribution(&report.action)?;
+        if expected != *report {
+            return Err(CloakError::invalid_input(format!(
+                "Distribution report {} does not match the snapshot at height {} (expected {})",
+                report.id(),
+                report.action.record_height,
+                expected.id()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Adds the report index of a distribution to a batch
+    pub(super) fn stage_distribution(db: &DB, batch: &mut WriteBatch, transition: &StateTransition) -> CloakResult<()> {
+        if let StateTransition::Distribution { report } = transition {
+            batch.put_cf(distributions_cf(db)?, report.report_hash, serde_json::to_vec(report)?);
+        }
+        Ok(())
+    }
+
+    /// Adds the removal of an orphaned distribution's report index to a batch
+    pub(super) fn stage_distribution_removal(
+        db: &DB,
+        batch: &mut WriteBatch,
+        transition: &StateTransition,
+    ) -> CloakResult<()> {
+        if let StateTransition::Distribution { report } = transition {
+            batch.delete_cf(distributions_cf(db)?, report.report_hash);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mul_div_is_exact_beyond_u128_products() {
+        assert_eq!(mul_div(7, 10, 3), Some((23, 1)));
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), Some((u128::MAX, 0)));
+        assert_eq!(mul_div(u128::MAX, 3, u128::MAX - 1), Some((3, 3)));
+        assert_eq!(mul_div(1 << 100, 1 << 100, 1 << 90), Some((1 << 110, 0)));
+        assert_eq!(mul_div(u128::MAX, 2, 1), None);
+        assert_eq!(mul_div(1, 1, 0), None);
+    }
+
+    #[test]
+    fn test_rounding_policies_split_remainder() {
+        let holders = [([1u8; 32], 1), ([2u8; 32], 1), ([3u8; 32], 1)];
+
+        let (floor, remainder) = allocate(&holders, 3, 100, RoundingPolicy::Floor).unwrap();
+        assert_eq!(floor.iter().map(|e| e.amount).collect::<Vec<_>>(), vec![33, 33, 33]);
+        assert_eq!(remainder, 1);
+
+        let (largest, remainder) = allocate(&holders, 3, 100, RoundingPolicy::LargestRemainder).unwrap();
+        assert_eq!(largest.iter().map(|e| e.amount).collect::<Vec<_>>(), vec![34, 33, 33]);
+        assert_eq!(remainder, 0);
+
+        // The larger fractional part wins, not the larger balance
+        let holders = [([1u8; 32], 1), ([2u8; 32], 2)];
+        let (largest, _) = allocate(&holders, 3, 2, RoundingPolicy::LargestRemainder).unwrap();
+        assert_eq!(largest.iter().map(|e| e.amount).collect::<Vec<_>>(), vec![1, 1]);
+    }
+}
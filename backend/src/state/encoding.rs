@@ -0,0 +1,231 @@
+//! Persisted UserState Encoding
+//!
+//! Deterministic, versioned binary encoding for `UserState` records. The
+//! same state always encodes to the same bytes, so records can be hashed or
+//! compared byte-for-byte. Layout (integers big-endian):
+//!
+//! ```text
+//! version: u8 | sdkey_hash: [u8; 32] | merkle_root: [u8; 32]
+//! nonce: u64 | last_updated_block: u64 | balance_count: u32
+//! balance_count x (token_id_len: u16 | token_id: utf-8 | amount: u128)
+//! ```
+//!
+//! Balances are written in token-ID order. Records written before the
+//! binary encoding existed are JSON objects; they are still readable and are
+//! rewritten by `migrate_legacy_user_records` on startup.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{history, schema, StateManager, UserState};
+use rocksdb::{Direction, IteratorMode, WriteBatch};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Current version of the binary `UserState` encoding
+pub const USER_STATE_VERSION: u8 = 1;
+
+/// First byte of a legacy JSON record (`{`), never a valid version
+const LEGACY_JSON_MARKER: u8 = b'{';
+
+/// Cursor over an encoded record
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> CloakResult<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(CloakError::state("Truncated user state record"));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> CloakResult<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+}
+
+impl UserState {
+    /// Encodes this state with the current binary encoding
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if a token ID or the number of
+    /// balances exceeds the encoding's length prefixes.
+    pub fn encode(&self) -> CloakResult<Vec<u8>> {
+        let mut balances: Vec<(&String, &u128)> = self.balances.iter().collect();
+        balances.sort_by(|a, b| a.0.cmp(b.0));
+        let count = u32::try_from(balances.len())
+            .map_err(|_| CloakError::invalid_input("Too many balances to encode"))?;
+
+        let mut out = Vec::with_capacity(85 + balances.len() * 32);
+        out.push(USER_STATE_VERSION);
+        out.extend_from_slice(&self.sdkey_hash);
+        out.extend_from_slice(&self.merkle_root);
+        out.extend_from_slice(&self.nonce.to_be_bytes());
+        out.extend_from_slice(&self.last_updated_block.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        for (token_id, amount) in balances {
+            let len = u16::try_from(token_id.len())
+                .map_err(|_| CloakError::invalid_input(format!("Token ID too long to encode: {}", token_id)))?;
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(token_id.as_bytes());
+            out.extend_from_slice(&amount.to_be_bytes());
+        }
+        Ok(out)
+    }
+
+    /// Decodes a persisted state record
+    ///
+    /// Accepts both the binary encoding and legacy JSON records.
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` for an unknown version, truncated or
+    /// trailing bytes, unsorted balances, or a non-UTF-8 token ID.
+    pub fn decode(bytes: &[u8]) -> CloakResult<Self> {
+        match bytes.first() {
+            Some(&LEGACY_JSON_MARKER) => return Ok(serde_json::from_slice(bytes)?),
+            Some(&USER_STATE_VERSION) => {}
+            Some(version) => {
+                return Err(CloakError::state(format!("Unsupported user state version: {}", version)))
+            }
+            None => return Err(CloakError::state("Empty user state record")),
+        }
+
+        let mut reader = Reader { bytes: &bytes[1..] };
+        let sdkey_hash = reader.array::<32>()?;
+        let merkle_root = reader.array::<32>()?;
+        let nonce = u64::from_be_bytes(reader.array()?);
+        let last_updated_block = u64::from_be_bytes(reader.array()?);
+        let count = u32::from_be_bytes(reader.array()?);
+
+        let mut balances = HashMap::new();
+        let mut previous: Option<String> = None;
+        for _ in 0..count {
+            let len = u16::from_be_bytes(reader.array()?) as usize;
+            let token_id = String::from_utf8(reader.take(len)?.to_vec())
+                .map_err(|_| CloakError::state("Token ID is not valid UTF-8"))?;
+            let amount = u128::from_be_bytes(reader.array()?);
+            // Strictly increasing order keeps the encoding canonical
+            if previous.as_ref().is_some_and(|prev| *prev >= token_id) {
+                return Err(CloakError::state("User state balances are not sorted"));
+            }
+            previous = Some(token_id.clone());
+            balances.insert(token_id, amount);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(CloakError::state("Trailing bytes after user state record"));
+        }
+
+        Ok(Self {
+            sdkey_hash,
+            merkle_root,
+            balances,
+            nonce,
+            last_updated_block,
+        })
+    }
+}
+
+/// Checks whether a persisted record uses the legacy JSON encoding
+pub fn is_legacy_encoding(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&LEGACY_JSON_MARKER)
+}
+
+impl StateManager {
+    /// Rewrites legacy JSON user records with the binary encoding
+    ///
+    /// Covers both the latest user records and the versioned history
+    /// snapshots, and writes every rewrite in a single batch. Returns the
+    /// number of records migrated.
+    pub(super) fn migrate_legacy_user_records(&self) -> CloakResult<usize> {
+        let mut batch = WriteBatch::default();
+        let mut migrated = 0;
+
+        let keyspaces = [
+            (schema::users_cf(&self.db)?, ""),
+            (schema::roots_cf(&self.db)?, history::USER_HISTORY_PREFIX),
+        ];
+        for (cf, prefix) in keyspaces {
+            let iter = self.db.iterator_cf(cf, IteratorMode::From(prefix.as_bytes(), Direction::Forward));
+            for item in iter {
+                let (key, value) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                if is_legacy_encoding(&value) {
+                    let user_state = UserState::decode(&value)?;
+                    batch.put_cf(cf, &key, user_state.encode()?);
+                    migrated += 1;
+                }
+            }
+        }
+
+        if migrated > 0 {
+            self.db.write(batch)?;
+            info!("Migrated {} legacy JSON user records to binary encoding", migrated);
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> UserState {
+        let mut user_state = UserState::new([3u8; 32]);
+        user_state.merkle_root = [4u8; 32];
+        user_state.update_balance("USDC".to_string(), 1_000);
+        user_state.update_balance("RWA-CREDIT".to_string(), 0);
+        user_state.update_balance("ETH".to_string(), u128::MAX);
+        user_state.last_updated_block = 77;
+        user_state
+    }
+
+    #[test]
+    fn test_encoding_roundtrip_is_deterministic() {
+        let user_state = sample();
+        let encoded = user_state.encode().unwrap();
+        assert_eq!(encoded[0], USER_STATE_VERSION);
+
+        let decoded = UserState::decode(&encoded).unwrap();
+        assert_eq!(decoded.balances, user_state.balances);
+        assert_eq!(decoded.nonce, user_state.nonce);
+        assert_eq!(decoded.last_updated_block, 77);
+
+        // Rebuilding the map in a different order gives identical bytes
+        let mut reordered = UserState::new([3u8; 32]);
+        reordered.merkle_root = [4u8; 32];
+        for (token_id, amount) in [("ETH", u128::MAX), ("USDC", 1_000), ("RWA-CREDIT", 0)] {
+            reordered.balances.insert(token_id.to_string(), amount);
+        }
+        reordered.nonce = user_state.nonce;
+        reordered.last_updated_block = 77;
+        assert_eq!(reordered.encode().unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_records() {
+        let encoded = sample().encode().unwrap();
+        assert!(UserState::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(UserState::decode(&trailing).is_err());
+
+        let mut future = encoded;
+        future[0] = USER_STATE_VERSION + 1;
+        assert!(UserState::decode(&future).is_err());
+    }
+
+    #[test]
+    fn test_decode_reads_legacy_json() {
+        let user_state = sample();
+        let legacy = serde_json::to_vec(&user_state).unwrap();
+        assert!(is_legacy_encoding(&legacy));
+        assert_eq!(UserState::decode(&legacy).unwrap().balances, user_state.balances);
+    }
+}
@@ -0,0 +1,260 @@
+//! Epoch Settlement
+//!
+//! An epoch is an ordered list of trades settled by a single batch proof
+//! instead of one settlement proof each. The witness is built by staging the
+//! trades one after another on a copy of the tree, exactly as applying them
+//! in order would, with every leg committed under the epoch's blinding.
+//!
+//! Applying an epoch checks the batch proof against the current root and
+//! the epoch's legs before anything is written, then applies the trades in
+//! order. If any trade fails, or the trades end at a root other than the
+//! proof's new root, every trade already applied is rewound so the epoch
+//! lands all-or-nothing. Should that rewind fail too, the epoch is left
+//! partially applied and the error names both failures.
+
+use super::{StateManager, StateTransition};
+use crate::error::{CloakError, CloakResult};
+use crate::prover::batch::{check_legs, TradeLeg};
+use crate::prover::{BatchProof, BatchWitness, CircuitKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{error, info, warn};
+
+/// Outcome of applying an epoch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochSettlement {
+    /// Number of trades settled
+    pub trade_count: usize,
+
+    /// Root sequence of the epoch's last trade
+    pub sequence: u64,
+
+    /// Root before the epoch
+    pub merkle_root_old: [u8; 32],
+
+    /// Root after the epoch
+    pub merkle_root_new: [u8; 32],
+}
+
+impl StateManager {
+    /// Builds the batch circuit witness for an epoch at the current root
+    ///
+    /// Each trade is staged on top of the ones before it, so the steps chain
+    /// from the current root to the root the whole epoch reaches.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the epoch is empty or holds a
+    /// transition other than a trade, and any error `settlement_witness`
+    /// would raise for one of the trades.
+    pub fn batch_witness(&self, trades: &[StateTransition], blinding: [u8; 32]) -> CloakResult<BatchWitness> {
+        if trades.is_empty() {
+            return Err(CloakError::invalid_input("An epoch needs at least one trade"));
+        }
+
+        let mut tree = self.merkle_tree.clone();
+        let mut staged_users = HashMap::new();
+        let merkle_root_old = tree.get_root();
+        let steps = trades
+            .iter()
+            .map(|trade| self.stage_settlement(trade, blinding, &mut tree, &mut staged_users))
+            .collect::<CloakResult<Vec<_>>>()?;
+
+        Ok(BatchWitness {
+            steps,
+            blinding,
+            merkle_root_old,
+            merkle_root_new: tree.get_root(),
+        })
+    }
+
+    /// Applies an epoch of trades settled by a batch proof
+    ///
+    /// The trades must not carry settlement proofs of their own. Fees are
+    /// assessed from the current schedules, as they were when the witness
+    /// was built.
+    ///
+    /// # Errors
+    /// Returns `CloakError::ProofVerification` if the proof is not a batch
+    /// proof, does not verify against the current root, or does not settle
+    /// exactly these trades in this order, or if the trades end at another
+    /// root. Returns `CloakError::InvalidInput` for an epoch holding other
+    /// transitions, and any error applying a trade raises. On error the
+    /// state is left as it was, unless rewinding the applied trades fails,
+    /// in which case `CloakError::State` reports both the original error
+    /// and the rewind error.
+    pub fn apply_epoch(&mut self, trades: Vec<StateTransition>, batch: &BatchProof) -> CloakResult<EpochSettlement> {
+        if batch.proof.circuit != CircuitKind::Batch {
+            return Err(CloakError::proof_verification(format!(
+                "Epoch carries a {} proof, expected a batch proof",
+                batch.proof.circuit
+            )));
+        }
+        self.verify_proof(&batch.proof)?;
+
+        let legs = trades
+            .iter()
+            .map(|transition| match transition {
+                StateTransition::Trade { settlement: Some(_), .. } => Err(CloakError::invalid_input(
+                    "Trades in an epoch are settled by the batch proof, not their own",
+                )),
+                StateTransition::Trade {
+                    user_a_sdkey_hash,
+                    user_b_sdkey_hash,
+                    token_a_id,
+                    token_b_id,
+                    amount_a,
+                    amount_b,
+                    ..
+                } => {
+                    let leg_a: TradeLeg<'_> = (user_a_sdkey_hash, token_a_id, *amount_a);
+                    let leg_b: TradeLeg<'_> = (user_b_sdkey_hash, token_b_id, *amount_b);
+                    Ok((leg_a, leg_b))
+                }
+                _ => Err(CloakError::invalid_input("An epoch holds only trades")),
+            })
+            .collect::<CloakResult<Vec<_>>>()?;
+        check_legs(batch, &legs)?;
+
+        let merkle_root_old = self.get_merkle_root();
+        let start_sequence = self.root_sequence;
+        let trade_count = trades.len();
+        let mut result = trades
+            .into_iter()
+            .try_for_each(|trade| self.apply_checked(trade, true));
+        if result.is_ok() && batch.proof.public_inputs[1] != self.get_merkle_root() {
+            result = Err(CloakError::proof_verification(format!(
+                "Batch proof does not end at the new root {}",
+                hex::encode(self.get_merkle_root())
+            )));
+        }
+        if let Err(e) = result {
+            if self.root_sequence > start_sequence {
+                warn!(
+                    "Rewinding {} trades of a failed epoch: {}",
+                    self.root_sequence - start_sequence,
+                    e
+                );
+                if let Err(rewind) = self.rewind_to_sequence(start_sequence) {
+                    error!(
+                        "Epoch left partially applied past sequence {}: {}",
+                        start_sequence, rewind
+                    );
+                    return Err(CloakError::state(format!(
+                        "Epoch failed ({}) and rewinding its trades to sequence {} failed: {}",
+                        e, start_sequence, rewind
+                    )));
+                }
+            }
+            return Err(e);
+        }
+
+        let settlement = EpochSettlement {
+            trade_count,
+            sequence: self.root_sequence,
+            merkle_root_old,
+            merkle_root_new: self.get_merkle_root(),
+        };
+        info!(
+            "Epoch: settled {} trades, root {} -> {}",
+            trade_count,
+            hex::encode(settlement.merkle_root_old),
+            hex::encode(settlement.merkle_root_new)
+        );
+        Ok(settlement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prover::Prover;
+    use crate::state::testing::{empty_manager, keyed_prover, test_token};
+    use crate::state::{protocol_fee_account, AssetClass, FeeSchedule, TREE_DEPTH};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn trade(a: [u8; 32], b: [u8; 32], amount_a: u128, amount_b: u128) -> StateTransition {
+        StateTransition::Trade {
+            user_a_sdkey_hash: a,
+            user_b_sdkey_hash: b,
+            token_a_id: "USDC".to_string(),
+            token_b_id: "RWA-BOND".to_string(),
+            amount_a,
+            amount_b,
+            nullifiers: Vec::new(),
+            maker_fee: 0,
+            taker_fee: 0,
+            settlement: None,
+        }
+    }
+
+    fn is_rejected<T>(result: CloakResult<T>) -> bool {
+        matches!(result, Err(CloakError::ProofVerification(_)))
+    }
+
+    #[test]
+    fn test_epoch_settles_all_trades_with_one_batch_proof() {
+        let (mut manager, path) = empty_manager();
+        manager.register_token(test_token("USDC", AssetClass::Treasury)).unwrap();
+        manager.register_token(test_token("RWA-BOND", AssetClass::Treasury)).unwrap();
+        manager
+            .set_fee_schedule("USDC", FeeSchedule { maker_bps: 0, taker_bps: 100, withdrawal_bps: 0 })
+            .unwrap();
+        let (a, b) = ([5u8; 32], [6u8; 32]);
+        for (user, token_id) in [(a, "USDC"), (b, "RWA-BOND")] {
+            manager.register_user(user).unwrap();
+            manager
+                .apply_transition(StateTransition::Deposit {
+                    user_sdkey_hash: user,
+                    token_id: token_id.to_string(),
+                    amount: 1_000,
+                })
+                .unwrap();
+        }
+
+        let mut rng = StdRng::seed_from_u64(25);
+        let prover = Prover::new(TREE_DEPTH).with_batch_capacity(2);
+        let prover = keyed_prover(&mut manager, prover, &[CircuitKind::Settlement, CircuitKind::Batch], &mut rng);
+
+        // The second trade pays into the fee account the first one created
+        let trades = vec![trade(a, b, 400, 50), trade(a, b, 200, 30)];
+        let blinding = [9u8; 32];
+        let witness = manager.batch_witness(&trades, blinding).unwrap();
+        assert_eq!(witness.steps[1].merkle_root_old, witness.steps[0].merkle_root_new);
+        let batch = BatchProof {
+            proof: prover.prove_batch(&witness, &mut rng).unwrap(),
+            blinding,
+        };
+
+        // The proof is bound to these trades in this order
+        let root_before = manager.get_merkle_root();
+        assert!(is_rejected(manager.apply_epoch(vec![trades[1].clone(), trades[0].clone()], &batch)));
+        assert!(is_rejected(manager.apply_epoch(vec![trades[0].clone()], &batch)));
+        assert!(is_rejected(manager.apply_epoch(vec![trade(a, b, 400, 50), trade(a, b, 200, 31)], &batch)));
+
+        // Trades ending at another root are rewound
+        manager
+            .set_fee_schedule("USDC", FeeSchedule { maker_bps: 0, taker_bps: 200, withdrawal_bps: 0 })
+            .unwrap();
+        assert!(is_rejected(manager.apply_epoch(trades.clone(), &batch)));
+        assert_eq!(manager.get_merkle_root(), root_before);
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("USDC"), 1_000);
+        manager
+            .set_fee_schedule("USDC", FeeSchedule { maker_bps: 0, taker_bps: 100, withdrawal_bps: 0 })
+            .unwrap();
+
+        let settlement = manager.apply_epoch(trades.clone(), &batch).unwrap();
+        assert_eq!(settlement.trade_count, 2);
+        assert_eq!(settlement.merkle_root_old, root_before);
+        assert_eq!(settlement.merkle_root_new, witness.merkle_root_new);
+        assert_eq!(manager.get_merkle_root(), witness.merkle_root_new);
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("RWA-BOND"), 80);
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 594);
+        assert_eq!(manager.get_user_state(protocol_fee_account()).unwrap().get_balance("USDC"), 6);
+
+        // Replaying the epoch against the new root fails
+        assert!(is_rejected(manager.apply_epoch(trades, &batch)));
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
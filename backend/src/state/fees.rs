@@ -0,0 +1,329 @@
+//! Fee Accounting
+//!
+//! Per-token fee schedules for trades and withdrawals. Fees are charged in
+//! basis points, rounded up to the next base unit, and moved to the
+//! protocol fee account, an ordinary leaf of the state tree under a
+//! reserved SDKey hash, inside the same atomic transition:
+//!
+//! - Trade: `user_a` is the maker and `user_b` the taker. Each side pays
+//!   its fee in the token it receives, by that token's schedule, so the
+//!   maker receives `amount_b - maker_fee` and the taker `amount_a - taker_fee`.
+//! - Withdrawal: the fee is charged in the withdrawn token on top of the
+//!   withdrawn amount.
+//!
+//! `StateManager::apply_transition` fills in the fees from the current
+//! schedules before staging, so the journal records exactly what was
+//! charged and replay does not depend on later schedule changes. Every
+//! collected fee is also written to the fee ledger in the `fees` column
+//! family, keyed by the sequence that charged it.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::corporate::mul_div;
+use crate::state::{poseidon, schema, StateManager, StateTransition};
+use once_cell::sync::Lazy;
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Basis points in one whole
+pub const BPS_DENOMINATOR: u128 = 10_000;
+
+/// Highest fee rate a schedule may set (10%)
+pub const MAX_FEE_BPS: u16 = 1_000;
+
+/// Key prefix of fee schedules
+const SCHEDULE_PREFIX: &str = "schedule:";
+
+/// Key prefix of fee ledger entries
+const LEDGER_PREFIX: &str = "ledger:";
+
+/// SDKey hash of the protocol fee account, derived from a fixed label
+static PROTOCOL_FEE_ACCOUNT: Lazy<[u8; 32]> =
+    Lazy::new(|| poseidon::field_to_bytes(&poseidon::string_to_field("cloak-protocol-fee-account")));
+
+/// Gets the SDKey hash of the protocol fee account
+pub fn protocol_fee_account() -> [u8; 32] {
+    *PROTOCOL_FEE_ACCOUNT
+}
+
+/// Fee rates of one token, in basis points of the charged amount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    /// Charged to the maker (`user_a`) on the amount of this token received
+    #[serde(default)]
+    pub maker_bps: u16,
+    /// Charged to the taker (`user_b`) on the amount of this token received
+    #[serde(default)]
+    pub taker_bps: u16,
+    /// Charged on withdrawals of this token, on top of the amount
+    #[serde(default)]
+    pub withdrawal_bps: u16,
+}
+
+impl FeeSchedule {
+    /// Checks that no rate exceeds `MAX_FEE_BPS`
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` naming the first rate that does.
+    pub fn validate(&self) -> CloakResult<()> {
+        for (name, bps) in [
+            ("maker", self.maker_bps),
+            ("taker", self.taker_bps),
+            ("withdrawal", self.withdrawal_bps),
+        ] {
+            if bps > MAX_FEE_BPS {
+                return Err(CloakError::invalid_input(format!(
+                    "{} fee of {} bps exceeds the maximum of {} bps",
+                    name, bps, MAX_FEE_BPS
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Computes a fee of `bps` basis points on `amount`, rounded up
+pub fn fee_for(amount: u128, bps: u16) -> u128 {
+    // `bps <= BPS_DENOMINATOR`, so the quotient always fits
+    let (fee, remainder) = mul_div(amount, u128::from(bps), BPS_DENOMINATOR).unwrap_or((amount, 0));
+    if remainder > 0 {
+        fee + 1
+    } else {
+        fee
+    }
+}
+
+/// What a fee was charged for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeKind {
+    Maker,
+    Taker,
+    Withdrawal,
+}
+
+/// One fee collected by the protocol
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeLedgerEntry {
+    /// Root sequence of the transition that charged the fee
+    pub sequence: u64,
+    pub block_height: u64,
+    pub kind: FeeKind,
+    /// SDKey hash of the user who paid, hex-encoded
+    pub payer: String,
+    pub token_id: String,
+    pub amount: u128,
+}
+
+/// Gets the fees a transition charges as `(kind, payer, token, amount)`
+fn charged_fees(transition: &StateTransition) -> Vec<(FeeKind, [u8; 32], &str, u128)> {
+    let fees = match transition {
+        StateTransition::Trade {
+            user_a_sdkey_hash,
+            user_b_sdkey_hash,
+            token_a_id,
+            token_b_id,
+            maker_fee,
+            taker_fee,
+            ..
+        } => vec![
+            (FeeKind::Maker, *user_a_sdkey_hash, token_b_id.as_str(), *maker_fee),
+            (FeeKind::Taker, *user_b_sdkey_hash, token_a_id.as_str(), *taker_fee),
+        ],
+        StateTransition::Withdrawal { user_sdkey_hash, token_id, fee, .. } => {
+            vec![(FeeKind::Withdrawal, *user_sdkey_hash, token_id.as_str(), *fee)]
+        }
+        _ => Vec::new(),
+    };
+    fees.into_iter().filter(|(_, _, _, amount)| *amount > 0).collect()
+}
+
+fn schedule_key(token_id: &str) -> String {
+    format!("{}{}", SCHEDULE_PREFIX, token_id)
+}
+
+/// Ledger key: prefix, sequence (u64 BE), index within the transition
+fn ledger_key(sequence: u64, index: u8) -> Vec<u8> {
+    let mut key = LEDGER_PREFIX.as_bytes().to_vec();
+    key.extend_from_slice(&sequence.to_be_bytes());
+    key.push(index);
+    key
+}
+
+/// Gets the fees column family handle
+fn fees_cf(db: &DB) -> CloakResult<&rocksdb::ColumnFamily> {
+    schema::column_family(db, schema::FEES_CF)
+}
+
+impl StateTransition {
+    /// Checks whether this transition charges any fee
+    pub fn charges_fees(&self) -> bool {
+        !charged_fees(self).is_empty()
+    }
+}
+
+impl StateManager {
+    /// Sets the fee schedule of a registered token
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the token is not registered or
+    /// a rate is too high.
+    pub fn set_fee_schedule(&mut self, token_id: &str, schedule: FeeSchedule) -> CloakResult<()> {
+        if self.tokens.get(token_id).is_none() {
+            return Err(CloakError::invalid_input(format!("Unknown token: {}", token_id)));
+        }
+        schedule.validate()?;
+        self.db.put_cf(fees_cf(&self.db)?, schedule_key(token_id), serde_json::to_vec(&schedule)?)?;
+        info!(
+            "Fee schedule for {}: maker {} bps, taker {} bps, withdrawal {} bps",
+            token_id, schedule.maker_bps, schedule.taker_bps, schedule.withdrawal_bps
+        );
+        self.fee_schedules.insert(token_id.to_string(), schedule);
+        Ok(())
+    }
+
+    /// Gets the fee schedule of a token (no fees if none is set)
+    pub fn get_fee_schedule(&self, token_id: &str) -> FeeSchedule {
+        self.fee_schedules.get(token_id).copied().unwrap_or_default()
+    }
+
+    /// Gets the balances held by the protocol fee account
+    pub fn get_fee_balances(&self) -> BTreeMap<String, u128> {
+        self.get_user_state(protocol_fee_account())
+            .map(|user_state| user_state.balances.into_iter().filter(|(_, amount)| *amount > 0).collect())
+            .unwrap_or_default()
+    }
+
+    /// Gets up to `limit` fee ledger entries starting at a root sequence
+    pub fn get_fee_ledger(&self, from_sequence: u64, limit: usize) -> CloakResult<Vec<FeeLedgerEntry>> {
+        let start = ledger_key(from_sequence, 0);
+        let iter = self
+            .db
+            .iterator_cf(fees_cf(&self.db)?, IteratorMode::From(start.as_slice(), Direction::Forward));
+        let mut entries = Vec::new();
+        for item in iter {
+            if entries.len() >= limit {
+                break;
+            }
+            let (key, value) = item?;
+            if !key.starts_with(LEDGER_PREFIX.as_bytes()) {
+                break;
+            }
+            entries.push(serde_json::from_slice(&value)?);
+        }
+        Ok(entries)
+    }
+
+    /// Loads the fee schedules from RocksDB
+    pub(super) fn load_fee_schedules(&mut self) -> CloakResult<()> {
+        let mut schedules = BTreeMap::new();
+        for item in self.db.prefix_iterator_cf(fees_cf(&self.db)?, SCHEDULE_PREFIX) {
+            let (key, value) = item?;
+            let Some(token_id) = key.strip_prefix(SCHEDULE_PREFIX.as_bytes()) else {
+                break;
+            };
+            let token_id = String::from_utf8(token_id.to_vec()).map_err(|e| CloakError::state(e.to_string()))?;
+            schedules.insert(token_id, serde_json::from_slice(&value)?);
+        }
+        self.fee_schedules = schedules;
+        Ok(())
+    }
+
+    /// Fills in the fees of a Trade or Withdrawal from the current schedules
+    ///
+    /// Fees already set on the transition are replaced, so callers cannot
+    /// choose what they pay. The fee account itself pays no fees.
+    pub(super) fn assess_fees(&self, mut transition: StateTransition) -> StateTransition {
+        let fee_account = protocol_fee_account();
+        match &mut transition {
+            StateTransition::Trade {
+                user_a_sdkey_hash,
+                user_b_sdkey_hash,
+                token_a_id,
+                token_b_id,
+                amount_a,
+                amount_b,
+                maker_fee,
+                taker_fee,
+                ..
+            } => {
+                *maker_fee = if *user_a_sdkey_hash == fee_account {
+                    0
+                } else {
+                    fee_for(*amount_b, self.get_fee_schedule(token_b_id).maker_bps)
+                };
+                *taker_fee = if *user_b_sdkey_hash == fee_account {
+                    0
+                } else {
+                    fee_for(*amount_a, self.get_fee_schedule(token_a_id).taker_bps)
+                };
+            }
+            StateTransition::Withdrawal { user_sdkey_hash, token_id, amount, fee, .. } => {
+                *fee = if *user_sdkey_hash == fee_account {
+                    0
+                } else {
+                    fee_for(*amount, self.get_fee_schedule(token_id).withdrawal_bps)
+                };
+            }
+            _ => {}
+        }
+        transition
+    }
+
+    /// Adds the ledger entries of every fee a transition charges to a batch
+    pub(super) fn stage_fee_ledger(
+        db: &DB,
+        batch: &mut WriteBatch,
+        transition: &StateTransition,
+        sequence: u64,
+        block_height: u64,
+    ) -> CloakResult<()> {
+        for (index, (kind, payer, token_id, amount)) in charged_fees(transition).into_iter().enumerate() {
+            let entry = FeeLedgerEntry {
+                sequence,
+                block_height,
+                kind,
+                payer: hex::encode(payer),
+                token_id: token_id.to_string(),
+                amount,
+            };
+            batch.put_cf(fees_cf(db)?, ledger_key(sequence, index as u8), serde_json::to_vec(&entry)?);
+        }
+        Ok(())
+    }
+
+    /// Adds the removal of an orphaned transition's ledger entries to a batch
+    pub(super) fn stage_fee_ledger_removal(
+        db: &DB,
+        batch: &mut WriteBatch,
+        transition: &StateTransition,
+        sequence: u64,
+    ) -> CloakResult<()> {
+        for index in 0..charged_fees(transition).len() {
+            batch.delete_cf(fees_cf(db)?, ledger_key(sequence, index as u8));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fees_round_up_to_base_units() {
+        assert_eq!(fee_for(10_000, 30), 30);
+        assert_eq!(fee_for(10_001, 30), 31);
+        assert_eq!(fee_for(1, 1), 1);
+        assert_eq!(fee_for(0, 30), 0);
+        assert_eq!(fee_for(1_000, 0), 0);
+        assert_eq!(fee_for(u128::MAX, MAX_FEE_BPS), u128::MAX / 10 + 1);
+    }
+
+    #[test]
+    fn test_schedule_rejects_excessive_rates() {
+        assert!(FeeSchedule { maker_bps: 10, taker_bps: 30, withdrawal_bps: MAX_FEE_BPS }.validate().is_ok());
+        assert!(FeeSchedule { taker_bps: MAX_FEE_BPS + 1, ..FeeSchedule::default() }.validate().is_err());
+    }
+}
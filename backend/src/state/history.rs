@@ -0,0 +1,344 @@
+//! State Root History
+//!
+//! Records every root the state manager produces, together with the block
+//! height and the transitions that produced it. Each commit also stores a
+//! versioned snapshot of every user it touched, so balances can be read back
+//! at any historical root for disputes and audits.
+
+use crate::error::CloakResult;
+use crate::state::schema::roots_cf;
+use crate::state::{StateManager, StateTransition, UserState};
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// A state root produced by the state manager
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootRecord {
+    /// Monotonic commit sequence number (starts at 1)
+    pub sequence: u64,
+
+    /// Merkle root after the transitions were applied
+    pub root: [u8; 32],
+
+    /// Merkle root before the transitions were applied
+    pub previous_root: [u8; 32],
+
+    /// Nullifier-set root after the transitions were applied
+    #[serde(default)]
+    pub nullifier_root: [u8; 32],
+
+    /// Note-commitment root after the transitions were applied
+    #[serde(default)]
+    pub note_root: [u8; 32],
+
+    /// Psy block height the root was produced at
+    pub block_height: u64,
+
+    /// Transitions that moved the state from `previous_root` to `root`
+    pub transitions: Vec<StateTransition>,
+}
+
+impl RootRecord {
+    /// Checks whether the record's transitions appended note commitments
+    fn appends_notes(&self) -> bool {
+        self.transitions.iter().any(|transition| !transition.note_commitments().is_empty())
+    }
+}
+
+/// Key prefix for root records, ordered by sequence
+const ROOT_PREFIX: &str = "root:";
+
+/// Key prefix for the root hash -> sequence index
+const ROOT_HASH_PREFIX: &str = "root_hash:";
+
+/// Key prefix for the block height -> sequence index
+const ROOT_HEIGHT_PREFIX: &str = "root_height:";
+
+/// Key prefix for the note root -> sequence index of valid spend anchors
+const NOTE_ANCHOR_PREFIX: &str = "note_anchor:";
+
+/// Key prefix for versioned user snapshots
+pub(super) const USER_HISTORY_PREFIX: &str = "user_history:";
+
+/// Every key prefix stored in the root history column family
+pub(super) const HISTORY_PREFIXES: [&str; 5] = [
+    ROOT_PREFIX,
+    ROOT_HASH_PREFIX,
+    ROOT_HEIGHT_PREFIX,
+    NOTE_ANCHOR_PREFIX,
+    USER_HISTORY_PREFIX,
+];
+
+fn record_key(sequence: u64) -> String {
+    format!("{}{:020}", ROOT_PREFIX, sequence)
+}
+
+fn root_hash_key(root: &[u8; 32]) -> String {
+    format!("{}{}", ROOT_HASH_PREFIX, hex::encode(root))
+}
+
+fn note_anchor_key(note_root: &[u8; 32]) -> String {
+    format!("{}{}", NOTE_ANCHOR_PREFIX, hex::encode(note_root))
+}
+
+fn height_key(block_height: u64, sequence: u64) -> String {
+    format!("{}{:020}:{:020}", ROOT_HEIGHT_PREFIX, block_height, sequence)
+}
+
+fn user_history_prefix(sdkey_hash: &[u8; 32]) -> String {
+    format!("{}{}:", USER_HISTORY_PREFIX, hex::encode(sdkey_hash))
+}
+
+fn user_history_key(sdkey_hash: &[u8; 32], sequence: u64) -> String {
+    format!("{}{:020}", user_history_prefix(sdkey_hash), sequence)
+}
+
+/// Parses the trailing `:{sequence}` segment of an index key
+fn trailing_sequence(key: &str) -> Option<u64> {
+    key.rsplit(':').next()?.parse().ok()
+}
+
+impl StateManager {
+    /// Adds a root record, its indexes and the touched user snapshots to a batch
+    pub(super) fn stage_root_record(
+        db: &DB,
+        batch: &mut WriteBatch,
+        record: &RootRecord,
+        touched: &[UserState],
+    ) -> CloakResult<()> {
+        let cf = roots_cf(db)?;
+        batch.put_cf(cf, record_key(record.sequence), serde_json::to_vec(record)?);
+        batch.put_cf(cf, root_hash_key(&record.root), record.sequence.to_be_bytes());
+        batch.put_cf(cf, height_key(record.block_height, record.sequence), record.sequence.to_be_bytes());
+        if record.appends_notes() {
+            batch.put_cf(cf, note_anchor_key(&record.note_root), record.sequence.to_be_bytes());
+        }
+        for user_state in touched {
+            batch.put_cf(
+                cf,
+                user_history_key(&user_state.sdkey_hash, record.sequence),
+                user_state.encode()?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds the removal of root records after `target_sequence` to a batch
+    ///
+    /// Removes each record, its height index and its user snapshots. A root
+    /// hash or note anchor index pointing at a removed record is pointed back
+    /// at the latest surviving record with the same root or anchor, or
+    /// removed if there is none.
+    pub(super) fn stage_root_records_removal(
+        &self,
+        batch: &mut WriteBatch,
+        records: &[RootRecord],
+        target_sequence: u64,
+    ) -> CloakResult<()> {
+        let cf = roots_cf(&self.db)?;
+        let mut roots = HashSet::new();
+        let mut anchors = HashSet::new();
+        for record in records {
+            batch.delete_cf(cf, record_key(record.sequence));
+            batch.delete_cf(cf, height_key(record.block_height, record.sequence));
+            if self.root_sequence_of(&record.root)?.is_some_and(|sequence| sequence > target_sequence) {
+                roots.insert(record.root);
+            }
+            if self.note_anchor_sequence_of(&record.note_root)?.is_some_and(|sequence| sequence > target_sequence) {
+                anchors.insert(record.note_root);
+            }
+            for transition in &record.transitions {
+                for sdkey_hash in transition.affected_users() {
+                    batch.delete_cf(cf, user_history_key(&sdkey_hash, record.sequence));
+                }
+            }
+        }
+
+        // Walk the surviving records newest first for earlier producers
+        if !roots.is_empty() || !anchors.is_empty() {
+            let upper = record_key(target_sequence);
+            let iter = self.db.iterator_cf(cf, IteratorMode::From(upper.as_bytes(), Direction::Reverse));
+            for item in iter {
+                let (key, value) = item?;
+                if !key.starts_with(ROOT_PREFIX.as_bytes()) || (roots.is_empty() && anchors.is_empty()) {
+                    break;
+                }
+                let record: RootRecord = serde_json::from_slice(&value)?;
+                if roots.remove(&record.root) {
+                    batch.put_cf(cf, root_hash_key(&record.root), record.sequence.to_be_bytes());
+                }
+                if record.appends_notes() && anchors.remove(&record.note_root) {
+                    batch.put_cf(cf, note_anchor_key(&record.note_root), record.sequence.to_be_bytes());
+                }
+            }
+        }
+        for root in roots {
+            batch.delete_cf(cf, root_hash_key(&root));
+        }
+        for note_root in anchors {
+            batch.delete_cf(cf, note_anchor_key(&note_root));
+        }
+        Ok(())
+    }
+
+    /// Finds the sequence number of the most recent root record
+    pub(super) fn load_root_sequence(&self) -> CloakResult<u64> {
+        let upper = format!("{}~", ROOT_PREFIX);
+        let mut iter = self.db.iterator_cf(
+            roots_cf(&self.db)?,
+            IteratorMode::From(upper.as_bytes(), Direction::Reverse),
+        );
+        if let Some(item) = iter.next() {
+            let (key, _) = item?;
+            if let Some(sequence) = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|key| key.strip_prefix(ROOT_PREFIX))
+                .and_then(|sequence| sequence.parse().ok())
+            {
+                return Ok(sequence);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Gets the root record with a given sequence number
+    pub fn get_root_record_by_sequence(&self, sequence: u64) -> CloakResult<Option<RootRecord>> {
+        match self.db.get_cf(roots_cf(&self.db)?, record_key(sequence))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the most recent record that produced a given root
+    pub fn get_root_record(&self, root: &[u8; 32]) -> CloakResult<Option<RootRecord>> {
+        match self.root_sequence_of(root)? {
+            Some(sequence) => self.get_root_record_by_sequence(sequence),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the state root in effect at a block height
+    ///
+    /// Returns the last root produced at or below `block_height`, or `None`
+    /// if no root had been produced yet.
+    pub fn get_root_at_block(&self, block_height: u64) -> CloakResult<Option<RootRecord>> {
+        match self.sequence_at_block(block_height)? {
+            Some(sequence) => self.get_root_record_by_sequence(sequence),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks whether a note-commitment root was produced by this node
+    ///
+    /// Only roots that some commit appended notes to are valid anchors, so
+    /// the empty tree root never is.
+    pub fn is_known_note_anchor(&self, note_root: &[u8; 32]) -> CloakResult<bool> {
+        Ok(self.db.get_cf(roots_cf(&self.db)?, note_anchor_key(note_root))?.is_some())
+    }
+
+    /// Gets a user's state as of a historical root
+    pub fn get_user_state_at_root(
+        &self,
+        sdkey_hash: [u8; 32],
+        root: &[u8; 32],
+    ) -> CloakResult<Option<UserState>> {
+        match self.root_sequence_of(root)? {
+            Some(sequence) => self.user_state_at_sequence(&sdkey_hash, sequence),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a user's state as of a block height
+    pub fn get_user_state_at_block(
+        &self,
+        sdkey_hash: [u8; 32],
+        block_height: u64,
+    ) -> CloakResult<Option<UserState>> {
+        match self.sequence_at_block(block_height)? {
+            Some(sequence) => self.user_state_at_sequence(&sdkey_hash, sequence),
+            None => Ok(None),
+        }
+    }
+
+    /// Reconstructs every user's state as of a sequence number
+    ///
+    /// Walks the versioned snapshots and keeps, per user, the latest one at
+    /// or before `sequence`. Users registered later are omitted.
+    pub(super) fn user_states_at_sequence(&self, sequence: u64) -> CloakResult<HashMap<[u8; 32], UserState>> {
+        let iter = self.db.iterator_cf(
+            roots_cf(&self.db)?,
+            IteratorMode::From(USER_HISTORY_PREFIX.as_bytes(), Direction::Forward),
+        );
+        let mut user_states = HashMap::new();
+
+        for item in iter {
+            let (key, value) = item?;
+            let key = match std::str::from_utf8(&key) {
+                Ok(key) if key.starts_with(USER_HISTORY_PREFIX) => key,
+                _ => break,
+            };
+            if trailing_sequence(key).is_some_and(|version| version <= sequence) {
+                let user_state = UserState::decode(&value)?;
+                user_states.insert(user_state.sdkey_hash, user_state);
+            }
+        }
+
+        Ok(user_states)
+    }
+
+    /// Looks up the sequence number that produced a root
+    fn root_sequence_of(&self, root: &[u8; 32]) -> CloakResult<Option<u64>> {
+        Ok(self
+            .db
+            .get_cf(roots_cf(&self.db)?, root_hash_key(root))?
+            .and_then(|value| value.as_slice().try_into().ok().map(u64::from_be_bytes)))
+    }
+
+    /// Looks up the sequence number that made a note root a spend anchor
+    fn note_anchor_sequence_of(&self, note_root: &[u8; 32]) -> CloakResult<Option<u64>> {
+        Ok(self
+            .db
+            .get_cf(roots_cf(&self.db)?, note_anchor_key(note_root))?
+            .and_then(|value| value.as_slice().try_into().ok().map(u64::from_be_bytes)))
+    }
+
+    /// Finds the last sequence number committed at or below a block height
+    fn sequence_at_block(&self, block_height: u64) -> CloakResult<Option<u64>> {
+        let upper = format!("{}{:020}:~", ROOT_HEIGHT_PREFIX, block_height);
+        let mut iter = self.db.iterator_cf(
+            roots_cf(&self.db)?,
+            IteratorMode::From(upper.as_bytes(), Direction::Reverse),
+        );
+        if let Some(item) = iter.next() {
+            let (key, _) = item?;
+            if let Ok(key) = std::str::from_utf8(&key) {
+                if key.starts_with(ROOT_HEIGHT_PREFIX) {
+                    return Ok(trailing_sequence(key));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the latest snapshot of a user at or before a sequence number
+    fn user_state_at_sequence(
+        &self,
+        sdkey_hash: &[u8; 32],
+        sequence: u64,
+    ) -> CloakResult<Option<UserState>> {
+        let prefix = user_history_prefix(sdkey_hash);
+        let upper = user_history_key(sdkey_hash, sequence);
+        let mut iter = self.db.iterator_cf(
+            roots_cf(&self.db)?,
+            IteratorMode::From(upper.as_bytes(), Direction::Reverse),
+        );
+        if let Some(item) = iter.next() {
+            let (key, value) = item?;
+            if key.starts_with(prefix.as_bytes()) {
+                return Ok(Some(UserState::decode(&value)?));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,207 @@
+//! Proof Job Records
+//!
+//! Persistent records of the node's proving jobs (see `node::jobs`). Each
+//! job is stored as JSON in the `proof_jobs` column family under its ID
+//! (u64 BE), and rewritten on every status change, so `/api/proofs` can list
+//! jobs in submission order and unfinished jobs are picked up again after a
+//! restart.
+
+use crate::error::{CloakError, CloakResult};
+use crate::prover::{CircuitKind, ProofBundle};
+use crate::state::{schema, StateManager, StateTransition};
+use chrono::{DateTime, Utc};
+use rocksdb::{IteratorMode, DB};
+use serde::{Deserialize, Serialize};
+
+/// What a proving job proves; witnesses are built when a worker picks it up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "circuit", rename_all = "snake_case")]
+pub enum ProofRequest {
+    /// Balance update of one user, see `BalanceWitness::from_state`
+    Balance {
+        user_sdkey_hash: [u8; 32],
+        token_id: String,
+        trade_amount: u128,
+        received_amount: u128,
+    },
+
+    /// Settlement of a trade, see `StateManager::settlement_witness`
+    Settlement { transition: StateTransition },
+
+    /// Compliance of one user, see `StateManager::compliance_witness`
+    Compliance { user_sdkey_hash: [u8; 32] },
+}
+
+impl ProofRequest {
+    /// Gets the circuit the request is proven with
+    pub fn circuit(&self) -> CircuitKind {
+        match self {
+            ProofRequest::Balance { .. } => CircuitKind::Balance,
+            ProofRequest::Settlement { .. } => CircuitKind::Settlement,
+            ProofRequest::Compliance { .. } => CircuitKind::Compliance,
+        }
+    }
+}
+
+/// Scheduling priority of a proving job; higher priorities run first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Lifecycle of a proving job
+///
+/// `Queued → Generating → Complete → Verified`, or `Failed` / `Cancelled`
+/// from any unfinished status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for a worker
+    Queued,
+
+    /// Witness generation and proving in progress
+    Generating,
+
+    /// Proof generated, native verification pending
+    Complete,
+
+    /// Proof verified against the node's verifying key and current root
+    Verified,
+
+    /// Witness generation, proving or verification failed
+    Failed,
+
+    /// Cancelled before a proof was delivered
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Gets the identifier used in APIs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Generating => "generating",
+            JobStatus::Complete => "complete",
+            JobStatus::Verified => "verified",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Checks whether the job will not change status again
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Verified | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+/// A proving job and, once finished, its outcome
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofJob {
+    /// Sequential job ID, in submission order
+    pub id: u64,
+
+    pub request: ProofRequest,
+    pub priority: JobPriority,
+    pub status: JobStatus,
+
+    /// Constraints of the circuit proven
+    pub constraints: Option<u64>,
+
+    /// Wall time of witness generation and proving, in milliseconds
+    pub prove_time_ms: Option<u64>,
+
+    /// Size of the compressed proof in bytes
+    pub proof_size: Option<u64>,
+
+    /// Generated proof
+    pub proof: Option<ProofBundle>,
+
+    /// Blinding of the leg commitments, for settlement proofs
+    pub blinding: Option<[u8; 32]>,
+
+    /// Why the job failed
+    pub error: Option<String>,
+
+    pub submitted_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl ProofJob {
+    /// Creates a queued job
+    pub fn new(id: u64, request: ProofRequest, priority: JobPriority) -> Self {
+        Self {
+            id,
+            request,
+            priority,
+            status: JobStatus::Queued,
+            constraints: None,
+            prove_time_ms: None,
+            proof_size: None,
+            proof: None,
+            blinding: None,
+            error: None,
+            submitted_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+        }
+    }
+
+    /// Moves the job to a finished status
+    pub fn finish(&mut self, status: JobStatus, error: Option<String>) {
+        self.status = status;
+        self.error = error;
+        self.finished_at = Some(Utc::now());
+    }
+}
+
+/// Gets the proof jobs column family handle
+fn jobs_cf(db: &DB) -> CloakResult<&rocksdb::ColumnFamily> {
+    schema::column_family(db, schema::PROOF_JOBS_CF)
+}
+
+impl StateManager {
+    /// Writes the current record of a proving job
+    pub fn put_proof_job(&self, job: &ProofJob) -> CloakResult<()> {
+        self.db.put_cf(jobs_cf(&self.db)?, job.id.to_be_bytes(), serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    /// Gets the record of a proving job
+    pub fn get_proof_job(&self, id: u64) -> CloakResult<Option<ProofJob>> {
+        self.db
+            .get_cf(jobs_cf(&self.db)?, id.to_be_bytes())?
+            .map(|bytes| serde_json::from_slice(&bytes).map_err(CloakError::from))
+            .transpose()
+    }
+
+    /// Gets up to `limit` proving jobs, newest first
+    pub fn list_proof_jobs(&self, limit: usize) -> CloakResult<Vec<ProofJob>> {
+        let mut jobs = Vec::new();
+        for item in self.db.iterator_cf(jobs_cf(&self.db)?, IteratorMode::End).take(limit) {
+            let (_, value) = item?;
+            jobs.push(serde_json::from_slice(&value)?);
+        }
+        Ok(jobs)
+    }
+
+    /// Gets every unfinished proving job, oldest first, and the next free job ID
+    pub fn unfinished_proof_jobs(&self) -> CloakResult<(Vec<ProofJob>, u64)> {
+        let cf = jobs_cf(&self.db)?;
+        let mut unfinished = Vec::new();
+        let mut next_id = 0;
+        for item in self.db.iterator_cf(cf, IteratorMode::Start) {
+            let (_, value) = item?;
+            let job: ProofJob = serde_json::from_slice(&value)?;
+            next_id = job.id + 1;
+            if !job.status.is_finished() {
+                unfinished.push(job);
+            }
+        }
+        Ok((unfinished, next_id))
+    }
+}
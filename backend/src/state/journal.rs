@@ -0,0 +1,319 @@
+//! Transition Journal
+//!
+//! Append-only, sequence-numbered log of every applied `StateTransition`,
+//! stored in its own RocksDB column family and written in the same batch as
+//! the state update it describes. Replaying the journal from genesis or from
+//! a checkpoint rebuilds the user states independently of the stored user
+//! records, which lets the node verify, and if necessary repair, its stored
+//! state after a crash.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{schema, SparseMerkleTree, StateManager, StateTransition, UserState};
+use rocksdb::{ColumnFamily, Direction, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// Key of the latest journal checkpoint in the metadata column family
+pub(super) const CHECKPOINT_KEY: &str = "journal_checkpoint";
+
+/// A single applied transition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Sequence number, shared with the root history
+    pub sequence: u64,
+
+    /// Block height the transition was applied at
+    pub block_height: u64,
+
+    /// The transition itself
+    pub transition: StateTransition,
+
+    /// Merkle root after the transition was applied
+    pub root_after: [u8; 32],
+}
+
+/// A verified point in the journal that replay can start from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalCheckpoint {
+    /// Last sequence number covered by the checkpoint
+    pub sequence: u64,
+
+    /// Merkle root at that sequence
+    pub root: [u8; 32],
+}
+
+/// Result of replaying the journal
+#[derive(Debug, Clone)]
+pub struct ReplayOutcome {
+    /// Sequence number of the last replayed entry
+    pub last_sequence: u64,
+
+    /// Merkle root after replay
+    pub root: [u8; 32],
+
+    /// Rebuilt user states
+    pub user_states: HashMap<[u8; 32], UserState>,
+
+    /// Number of entries applied on top of the starting point
+    pub entries_replayed: usize,
+}
+
+/// Gets the journal column family handle
+pub(super) fn journal_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    schema::column_family(db, schema::JOURNAL_CF)
+}
+
+impl StateManager {
+    /// Adds a journal entry to a batch
+    pub(super) fn stage_journal_entry(
+        db: &DB,
+        batch: &mut WriteBatch,
+        entry: &JournalEntry,
+    ) -> CloakResult<()> {
+        batch.put_cf(journal_cf(db)?, entry.sequence.to_be_bytes(), serde_json::to_vec(entry)?);
+        Ok(())
+    }
+
+    /// Gets journal entries starting at a sequence number, in order
+    pub fn get_journal_entries(&self, from_sequence: u64, limit: usize) -> CloakResult<Vec<JournalEntry>> {
+        let start = from_sequence.to_be_bytes();
+        let iter = self.db.iterator_cf(
+            journal_cf(&self.db)?,
+            IteratorMode::From(&start, Direction::Forward),
+        );
+
+        let mut entries = Vec::new();
+        for item in iter.take(limit) {
+            let (_, value) = item?;
+            entries.push(serde_json::from_slice(&value)?);
+        }
+        Ok(entries)
+    }
+
+    /// Gets the most recent journal entry
+    pub fn last_journal_entry(&self) -> CloakResult<Option<JournalEntry>> {
+        let mut iter = self.db.iterator_cf(journal_cf(&self.db)?, IteratorMode::End);
+        match iter.next() {
+            Some(item) => {
+                let (_, value) = item?;
+                Ok(Some(serde_json::from_slice(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Records a checkpoint at the current sequence and root
+    pub fn checkpoint_journal(&self) -> CloakResult<JournalCheckpoint> {
+        let checkpoint = JournalCheckpoint {
+            sequence: self.root_sequence,
+            root: self.merkle_tree.get_root(),
+        };
+        self.db.put_cf(schema::metadata_cf(&self.db)?, CHECKPOINT_KEY, serde_json::to_vec(&checkpoint)?)?;
+        info!("Journal checkpoint at sequence {}", checkpoint.sequence);
+        Ok(checkpoint)
+    }
+
+    /// Adds the removal of the journal checkpoint to a batch
+    pub(super) fn stage_checkpoint_removal(db: &DB, batch: &mut WriteBatch) -> CloakResult<()> {
+        batch.delete_cf(schema::metadata_cf(db)?, CHECKPOINT_KEY);
+        Ok(())
+    }
+
+    /// Gets the latest journal checkpoint, if one was recorded
+    pub fn get_journal_checkpoint(&self) -> CloakResult<Option<JournalCheckpoint>> {
+        match self.db.get_cf(schema::metadata_cf(&self.db)?, CHECKPOINT_KEY)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replays the journal to rebuild user states
+    ///
+    /// Starts from an empty state, or from the user snapshots recorded at
+    /// `checkpoint`, and re-applies every later entry with the same staging
+    /// rules as `apply_transition`. The root after each entry must match the
+    /// root recorded in the journal.
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if the journal has a gap or the replayed
+    /// root diverges from the recorded one.
+    pub fn replay_journal(&self, checkpoint: Option<&JournalCheckpoint>) -> CloakResult<ReplayOutcome> {
+        let mut tree = SparseMerkleTree::with_depth(self.merkle_tree.depth());
+        let (mut user_states, start_sequence) = match checkpoint {
+            Some(checkpoint) => {
+                let user_states = self.user_states_at_sequence(checkpoint.sequence)?;
+                for user_state in user_states.values() {
+                    tree.update(&user_state.sdkey_hash, user_state.leaf_hash());
+                }
+                if tree.get_root() != checkpoint.root {
+                    return Err(CloakError::state(format!(
+                        "Checkpoint root mismatch at sequence {}",
+                        checkpoint.sequence
+                    )));
+                }
+                (user_states, checkpoint.sequence)
+            }
+            None => (HashMap::new(), 0),
+        };
+
+        let start = (start_sequence + 1).to_be_bytes();
+        let iter = self.db.iterator_cf(
+            journal_cf(&self.db)?,
+            IteratorMode::From(&start, Direction::Forward),
+        );
+
+        let mut last_sequence = start_sequence;
+        let mut entries_replayed = 0;
+        for item in iter {
+            let (_, value) = item?;
+            let entry: JournalEntry = serde_json::from_slice(&value)?;
+            if entry.sequence != last_sequence + 1 {
+                return Err(CloakError::state(format!(
+                    "Journal gap: expected sequence {}, found {}",
+                    last_sequence + 1,
+                    entry.sequence
+                )));
+            }
+
+            let staged = Self::stage_with(&entry.transition, |sdkey_hash| user_states.get(sdkey_hash).cloned())?;
+            for user_state in &staged {
+                tree.update(&user_state.sdkey_hash, user_state.leaf_hash());
+            }
+
+            let root = tree.get_root();
+            if root != entry.root_after {
+                return Err(CloakError::state(format!(
+                    "Journal replay diverged at sequence {}: expected root {}, got {}",
+                    entry.sequence,
+                    hex::encode(entry.root_after),
+                    hex::encode(root)
+                )));
+            }
+
+            for mut user_state in staged {
+                user_state.merkle_root = root;
+                user_state.last_updated_block = entry.block_height;
+                user_states.insert(user_state.sdkey_hash, user_state);
+            }
+            last_sequence = entry.sequence;
+            entries_replayed += 1;
+        }
+
+        Ok(ReplayOutcome {
+            last_sequence,
+            root: tree.get_root(),
+            user_states,
+            entries_replayed,
+        })
+    }
+
+    /// Verifies the stored snapshot against a journal replay
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` describing the first divergence found.
+    pub fn verify_snapshot(&self) -> CloakResult<ReplayOutcome> {
+        let checkpoint = self.get_journal_checkpoint()?;
+        let outcome = self.replay_journal(checkpoint.as_ref())?;
+
+        if outcome.last_sequence != self.root_sequence {
+            return Err(CloakError::state(format!(
+                "Snapshot at sequence {} but journal ends at {}",
+                self.root_sequence, outcome.last_sequence
+            )));
+        }
+        if outcome.root != self.merkle_tree.get_root() {
+            return Err(CloakError::state(format!(
+                "Snapshot root {} does not match journal root {}",
+                hex::encode(self.merkle_tree.get_root()),
+                hex::encode(outcome.root)
+            )));
+        }
+        if outcome.user_states.len() != self.get_user_count() {
+            return Err(CloakError::state(format!(
+                "Snapshot has {} users but journal has {}",
+                self.get_user_count(),
+                outcome.user_states.len()
+            )));
+        }
+        for (sdkey_hash, replayed) in &outcome.user_states {
+            let matches = self
+                .fetch_user(sdkey_hash)?
+                .map(|stored| stored.balances == replayed.balances && stored.nonce == replayed.nonce)
+                .unwrap_or(false);
+            if !matches {
+                return Err(CloakError::state(format!(
+                    "Snapshot diverges from journal for user {}",
+                    hex::encode(sdkey_hash)
+                )));
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Rebuilds the stored snapshot from the journal
+    ///
+    /// Rewrites every user record and the stored Merkle tree from a journal
+    /// replay, drops records the journal does not know about, and reloads
+    /// the in-memory state and tree.
+    pub fn recover_from_journal(&mut self) -> CloakResult<usize> {
+        let checkpoint = self.get_journal_checkpoint()?;
+        let outcome = self.replay_journal(checkpoint.as_ref())?;
+
+        let mut tree = SparseMerkleTree::with_depth(self.merkle_tree.depth());
+        for user_state in outcome.user_states.values() {
+            tree.update(&user_state.sdkey_hash, user_state.leaf_hash());
+        }
+
+        let users_cf = schema::users_cf(&self.db)?;
+        let mut batch = WriteBatch::default();
+        self.for_each_stored_user(|user_state| {
+            if !outcome.user_states.contains_key(&user_state.sdkey_hash) {
+                batch.delete_cf(users_cf, user_state.sdkey_hash);
+            }
+            Ok(())
+        })?;
+        for user_state in outcome.user_states.values() {
+            batch.put_cf(users_cf, user_state.sdkey_hash, user_state.encode()?);
+        }
+        Self::stage_merkle_rewrite(&self.db, &tree, &mut batch)?;
+        self.db.write(batch)?;
+
+        self.merkle_tree = tree;
+        self.user_cache.clear();
+        self.root_sequence = outcome.last_sequence;
+        self.recount_holders()?;
+
+        info!(
+            "Recovered {} user states from journal at sequence {}",
+            self.get_user_count(),
+            self.root_sequence
+        );
+        Ok(outcome.entries_replayed)
+    }
+
+    /// Checks the loaded snapshot against the journal tail on startup
+    ///
+    /// Repairs the snapshot from the journal if the two disagree.
+    pub(super) fn check_journal_on_startup(&mut self) -> CloakResult<()> {
+        let last = match self.last_journal_entry()? {
+            Some(last) => last,
+            None => return Ok(()),
+        };
+
+        if last.sequence == self.root_sequence && last.root_after == self.merkle_tree.get_root() {
+            return Ok(());
+        }
+
+        warn!(
+            "Stored snapshot (sequence {}, root {}) disagrees with journal (sequence {}, root {}); replaying",
+            self.root_sequence,
+            hex::encode(self.merkle_tree.get_root()),
+            last.sequence,
+            hex::encode(last.root_after)
+        );
+        self.recover_from_journal()?;
+        Ok(())
+    }
+}
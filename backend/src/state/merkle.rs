@@ -0,0 +1,713 @@
+//! Sparse Merkle Tree
+//!
+//! Fixed-depth binary sparse Merkle tree over the BLS12-381 scalar field,
+//! hashed with Poseidon. Leaves are keyed by `sdkey_hash`: the leading
+//! `depth` bits of the hash select the leaf slot. Only non-empty nodes are
+//! stored, and every update rehashes just the path from the touched leaf to
+//! the root.
+//!
+//! Keys whose leading bits collide share their slot, which then holds
+//! `H(tag || leaf_1 || ... || leaf_n)` over their leaves in key order, so no
+//! key is ever refused a slot. The circuits take a single leaf per slot, so
+//! only keys alone in their slot can be proven in-circuit.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::poseidon;
+use ark_bls12_381::Fr;
+use ark_ff::Zero;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Default tree depth (2^32 leaf slots), as specified in TECHNICAL.md
+pub const TREE_DEPTH: usize = 32;
+
+/// Maximum supported depth; leaf indices are stored as `u64`
+pub const MAX_TREE_DEPTH: usize = 64;
+
+/// Domain tag absorbed ahead of the leaves of a shared slot
+const SHARED_SLOT_TAG: u64 = u64::MAX;
+
+/// Computes the value of a slot shared by several keys from their leaves
+///
+/// `leaves` must be ordered by the keys' SDKey hashes.
+pub fn shared_slot_leaf<I>(leaves: I) -> Fr
+where
+    I: IntoIterator<Item = Fr>,
+{
+    let inputs: Vec<Fr> = std::iter::once(Fr::from(SHARED_SLOT_TAG)).chain(leaves).collect();
+    poseidon::hash(&inputs)
+}
+
+/// Computes the leaf commitment `H(sdkey_hash || H(balances || nonce))`
+///
+/// Balances are folded in token-ID order and zero balances are skipped, so
+/// the leaf only depends on the logical contents of the user state.
+pub fn user_leaf<'a, I>(sdkey_hash: &[u8; 32], balances: I, nonce: u64) -> Fr
+where
+    I: IntoIterator<Item = (&'a String, &'a u128)>,
+{
+    poseidon::hash_two(poseidon::bytes_to_field(sdkey_hash), leaf_body(balances, nonce))
+}
+
+/// Computes the inner `H(balances || nonce)` part of a user leaf
+pub fn leaf_body<'a, I>(balances: I, nonce: u64) -> Fr
+where
+    I: IntoIterator<Item = (&'a String, &'a u128)>,
+{
+    let mut sorted: Vec<(&String, &u128)> = balances
+        .into_iter()
+        .filter(|(_, amount)| **amount > 0)
+        .collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut digest = Fr::zero();
+    for (token_id, amount) in sorted {
+        digest = poseidon::hash(&[digest, poseidon::string_to_field(token_id), Fr::from(*amount)]);
+    }
+
+    poseidon::hash_two(digest, Fr::from(nonce))
+}
+
+/// What a Merkle proof shows about the queried SDKey hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProofKind {
+    /// The key is in its leaf slot and `leaf` is the slot's current value
+    Inclusion,
+
+    /// The key's leaf slot is empty (`leaf` is zero)
+    EmptySlot,
+
+    /// The key's leaf slot holds only other keys
+    ///
+    /// Every occupant's key and leaf body let a verifier recompute the slot
+    /// without learning the occupants' balances.
+    OccupiedSlot { occupants: Vec<SlotOccupant> },
+}
+
+/// Key and leaf body of a key holding a slot, so its leaf
+/// `H(sdkey_hash || leaf_body)` can be recomputed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotOccupant {
+    /// SDKey hash of the occupant
+    pub sdkey_hash: [u8; 32],
+
+    /// Inner part of the occupant's leaf
+    pub leaf_body: [u8; 32],
+}
+
+/// Authentication path for an SDKey hash against a specific root
+///
+/// `merkle_path` and `merkle_path_indices` are ordered from the leaf up and
+/// match the witness layout of the balance circuit: an index of `true` means
+/// the running node is the right child at that level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// SDKey hash the proof was generated for
+    pub sdkey_hash: [u8; 32],
+
+    /// Leaf slot index derived from the SDKey hash
+    pub leaf_index: u64,
+
+    /// Leaf value at the slot
+    pub leaf: [u8; 32],
+
+    /// Leaves of the keys sharing the slot, in key order, if there are
+    /// several (`leaf` is then their `shared_slot_leaf`)
+    #[serde(default)]
+    pub slot_leaves: Vec<[u8; 32]>,
+
+    /// Sibling hashes from the leaf level up to just below the root
+    pub merkle_path: Vec<[u8; 32]>,
+
+    /// Direction bits for each level of `merkle_path`
+    pub merkle_path_indices: Vec<bool>,
+
+    /// Root the proof authenticates against
+    pub root: [u8; 32],
+
+    /// Whether this proves membership or non-membership
+    pub kind: ProofKind,
+}
+
+impl MerkleProof {
+    /// Checks whether this proves the key is a member of the tree
+    pub fn is_inclusion(&self) -> bool {
+        self.kind == ProofKind::Inclusion
+    }
+
+    /// Recomputes the root from the leaf and authentication path
+    pub fn compute_root(&self) -> Fr {
+        let mut current = poseidon::bytes_to_field(&self.leaf);
+        for (sibling, is_right) in self.merkle_path.iter().zip(&self.merkle_path_indices) {
+            let sibling = poseidon::bytes_to_field(sibling);
+            current = if *is_right {
+                poseidon::hash_two(sibling, current)
+            } else {
+                poseidon::hash_two(current, sibling)
+            };
+        }
+        current
+    }
+}
+
+/// Verifies a Merkle proof against an expected root
+///
+/// Checks that the path is well-formed for the key's slot, that the leaf is
+/// consistent with the claimed proof kind and the slot's shared leaves, and
+/// that it hashes up to `root`. An occupied-slot proof must rebuild the
+/// slot from all of its occupants, none of which may be the queried key. For inclusion proofs the caller should
+/// additionally look for the leaf it expects (e.g. `UserState::leaf_hash`)
+/// in `proof.leaf`, or in `proof.slot_leaves` if the slot is shared.
+pub fn verify_proof(proof: &MerkleProof, root: &[u8; 32]) -> bool {
+    let depth = proof.merkle_path.len();
+    if depth == 0 || depth > MAX_TREE_DEPTH || proof.merkle_path_indices.len() != depth {
+        return false;
+    }
+    if proof.root != *root {
+        return false;
+    }
+
+    let slot_of = |sdkey_hash: &[u8; 32]| SparseMerkleTree::slot_for_depth(sdkey_hash, depth);
+    if slot_of(&proof.sdkey_hash) != proof.leaf_index {
+        return false;
+    }
+    let indices_match = proof
+        .merkle_path_indices
+        .iter()
+        .enumerate()
+        .all(|(level, is_right)| ((proof.leaf_index >> level) & 1 == 1) == *is_right);
+    if !indices_match {
+        return false;
+    }
+
+    let leaf = poseidon::bytes_to_field(&proof.leaf);
+    let slot_ok = match proof.slot_leaves.len() {
+        0 => true,
+        1 => false,
+        _ => shared_slot_leaf(proof.slot_leaves.iter().map(poseidon::bytes_to_field)) == leaf,
+    };
+    let kind_ok = match &proof.kind {
+        ProofKind::Inclusion => !leaf.is_zero(),
+        ProofKind::EmptySlot => leaf.is_zero() && proof.slot_leaves.is_empty(),
+        ProofKind::OccupiedSlot { occupants } => {
+            // The occupants must be exactly the slot's keys, in key order,
+            // and the queried key must not be one of them
+            let leaves: Vec<[u8; 32]> = occupants
+                .iter()
+                .map(|occupant| {
+                    poseidon::field_to_bytes(&poseidon::hash_two(
+                        poseidon::bytes_to_field(&occupant.sdkey_hash),
+                        poseidon::bytes_to_field(&occupant.leaf_body),
+                    ))
+                })
+                .collect();
+            let slot_leaves = match leaves.len() {
+                1 => std::slice::from_ref(&proof.leaf),
+                _ => proof.slot_leaves.as_slice(),
+            };
+            !leaves.is_empty()
+                && leaves == slot_leaves
+                && occupants.windows(2).all(|pair| pair[0].sdkey_hash < pair[1].sdkey_hash)
+                && occupants.iter().all(|occupant| {
+                    occupant.sdkey_hash != proof.sdkey_hash && slot_of(&occupant.sdkey_hash) == proof.leaf_index
+                })
+        }
+    };
+
+    slot_ok && kind_ok && poseidon::field_to_bytes(&proof.compute_root()) == *root
+}
+
+/// Poseidon sparse Merkle tree keyed by SDKey hash
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree {
+    /// Number of levels between the leaves and the root
+    depth: usize,
+
+    /// Roots of empty subtrees, indexed by height (0 = leaf)
+    empty_hashes: Vec<Fr>,
+
+    /// Non-empty nodes keyed by (height, index within level)
+    nodes: HashMap<(usize, u64), Fr>,
+
+    /// Keys in each occupied leaf slot with their leaves, ordered by key
+    occupants: HashMap<u64, BTreeMap<[u8; 32], Fr>>,
+}
+
+impl SparseMerkleTree {
+    /// Creates an empty tree with the default depth
+    pub fn new() -> Self {
+        Self::with_depth(TREE_DEPTH)
+    }
+
+    /// Creates an empty tree with a custom depth
+    ///
+    /// # Panics
+    /// Panics if `depth` is zero or greater than `MAX_TREE_DEPTH`.
+    pub fn with_depth(depth: usize) -> Self {
+        assert!(
+            depth > 0 && depth <= MAX_TREE_DEPTH,
+            "tree depth must be between 1 and {}",
+            MAX_TREE_DEPTH
+        );
+
+        let mut empty_hashes = Vec::with_capacity(depth + 1);
+        empty_hashes.push(Fr::zero());
+        for height in 0..depth {
+            let child = empty_hashes[height];
+            empty_hashes.push(poseidon::hash_two(child, child));
+        }
+
+        Self {
+            depth,
+            empty_hashes,
+            nodes: HashMap::new(),
+            occupants: HashMap::new(),
+        }
+    }
+
+    /// Gets the depth of the tree
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Gets the leaf slot index for an SDKey hash
+    pub fn leaf_index(&self, sdkey_hash: &[u8; 32]) -> u64 {
+        Self::slot_for_depth(sdkey_hash, self.depth)
+    }
+
+    /// Maps an SDKey hash to its leaf slot in a tree of the given depth
+    fn slot_for_depth(sdkey_hash: &[u8; 32], depth: usize) -> u64 {
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&sdkey_hash[..8]);
+        let prefix = u64::from_be_bytes(prefix);
+        if depth == MAX_TREE_DEPTH {
+            prefix
+        } else {
+            prefix >> (MAX_TREE_DEPTH - depth)
+        }
+    }
+
+    /// Sets the leaf for an SDKey hash and rehashes its path to the root
+    ///
+    /// A key whose slot already holds other keys joins them in the slot.
+    pub fn update(&mut self, sdkey_hash: &[u8; 32], leaf: Fr) {
+        let index = self.leaf_index(sdkey_hash);
+        self.occupants.entry(index).or_default().insert(*sdkey_hash, leaf);
+        self.set_path(index, self.slot_value(index));
+    }
+
+    /// Clears the leaf for an SDKey hash, returning whether it was present
+    pub fn remove(&mut self, sdkey_hash: &[u8; 32]) -> bool {
+        let index = self.leaf_index(sdkey_hash);
+        let Some(slot) = self.occupants.get_mut(&index) else {
+            return false;
+        };
+        if slot.remove(sdkey_hash).is_none() {
+            return false;
+        }
+        if slot.is_empty() {
+            self.occupants.remove(&index);
+        }
+
+        self.set_path(index, self.slot_value(index));
+        true
+    }
+
+    /// Gets the current leaf for an SDKey hash, if it is in the tree
+    pub fn get_leaf(&self, sdkey_hash: &[u8; 32]) -> Option<Fr> {
+        let index = self.leaf_index(sdkey_hash);
+        self.occupants.get(&index)?.get(sdkey_hash).copied()
+    }
+
+    /// Checks whether an SDKey hash's leaf slot holds any other key
+    pub fn is_slot_shared(&self, sdkey_hash: &[u8; 32]) -> bool {
+        let index = self.leaf_index(sdkey_hash);
+        self.occupants
+            .get(&index)
+            .is_some_and(|slot| slot.keys().any(|occupant| occupant != sdkey_hash))
+    }
+
+    /// Checks whether an SDKey hash has a leaf in the tree
+    pub fn contains(&self, sdkey_hash: &[u8; 32]) -> bool {
+        self.get_leaf(sdkey_hash).is_some()
+    }
+
+    /// Gets the current root as a field element
+    pub fn root(&self) -> Fr {
+        self.node(self.depth, 0)
+    }
+
+    /// Gets the current root hash
+    pub fn get_root(&self) -> [u8; 32] {
+        poseidon::field_to_bytes(&self.root())
+    }
+
+    /// Generates an inclusion or non-inclusion proof for an SDKey hash
+    ///
+    /// `leaf_body` resolves the `H(balances || nonce)` part of another key's
+    /// leaf, and is only consulted when the key's slot is held by others.
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if `leaf_body` cannot resolve an occupant.
+    pub fn prove<F>(&self, sdkey_hash: &[u8; 32], leaf_body: F) -> CloakResult<MerkleProof>
+    where
+        F: Fn(&[u8; 32]) -> Option<Fr>,
+    {
+        let mut proof = self.prove_path(sdkey_hash);
+        let index = proof.leaf_index;
+        if let (ProofKind::OccupiedSlot { occupants }, Some(slot)) = (&mut proof.kind, self.occupants.get(&index)) {
+            for occupant in slot.keys() {
+                let body = leaf_body(occupant).ok_or_else(|| {
+                    CloakError::state(format!("Missing leaf body for slot occupant {}", hex::encode(occupant)))
+                })?;
+                occupants.push(SlotOccupant {
+                    sdkey_hash: *occupant,
+                    leaf_body: poseidon::field_to_bytes(&body),
+                });
+            }
+        }
+        Ok(proof)
+    }
+
+    /// Generates the authentication path for an SDKey hash's slot
+    ///
+    /// This is the circuit witness `prove` builds on. An occupied-slot proof
+    /// from here lists no occupants, so it does not verify as non-membership.
+    pub fn prove_path(&self, sdkey_hash: &[u8; 32]) -> MerkleProof {
+        let index = self.leaf_index(sdkey_hash);
+        let slot = self.occupants.get(&index);
+        let kind = match slot {
+            Some(slot) if slot.contains_key(sdkey_hash) => ProofKind::Inclusion,
+            Some(_) => ProofKind::OccupiedSlot { occupants: Vec::new() },
+            None => ProofKind::EmptySlot,
+        };
+        let slot_leaves = match slot {
+            Some(slot) if slot.len() > 1 => slot.values().map(poseidon::field_to_bytes).collect(),
+            _ => Vec::new(),
+        };
+
+        let mut merkle_path = Vec::with_capacity(self.depth);
+        let mut merkle_path_indices = Vec::with_capacity(self.depth);
+        let mut node_index = index;
+        for height in 0..self.depth {
+            merkle_path.push(poseidon::field_to_bytes(&self.node(height, node_index ^ 1)));
+            merkle_path_indices.push(node_index & 1 == 1);
+            node_index >>= 1;
+        }
+
+        MerkleProof {
+            sdkey_hash: *sdkey_hash,
+            leaf_index: index,
+            leaf: poseidon::field_to_bytes(&self.node(0, index)),
+            slot_leaves,
+            merkle_path,
+            merkle_path_indices,
+            root: self.get_root(),
+            kind,
+        }
+    }
+
+    /// Rebuilds a tree from persisted nodes and the leaves of its keys
+    ///
+    /// No hashing is done; callers should check the result with
+    /// `slots_match_nodes` and against the expected root.
+    pub fn from_nodes<'a, I>(depth: usize, nodes: HashMap<(usize, u64), Fr>, leaves: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a [u8; 32], Fr)>,
+    {
+        let mut tree = Self::with_depth(depth);
+        for (sdkey_hash, leaf) in leaves {
+            let index = tree.leaf_index(sdkey_hash);
+            tree.occupants.entry(index).or_default().insert(*sdkey_hash, leaf);
+        }
+        tree.nodes = nodes;
+        tree
+    }
+
+    /// Checks that every occupied slot's node holds its keys' leaves
+    pub fn slots_match_nodes(&self) -> bool {
+        self.occupants
+            .keys()
+            .all(|index| self.node(0, *index) == self.slot_value(*index))
+    }
+
+    /// Gets every node on the path from a key's leaf to the root
+    ///
+    /// Empty nodes are returned as `None`, so callers persisting the tree can
+    /// delete them.
+    pub fn path_nodes(&self, sdkey_hash: &[u8; 32]) -> Vec<((usize, u64), Option<Fr>)> {
+        let mut index = self.leaf_index(sdkey_hash);
+        let mut path = Vec::with_capacity(self.depth + 1);
+        for height in 0..=self.depth {
+            path.push(((height, index), self.nodes.get(&(height, index)).copied()));
+            index >>= 1;
+        }
+        path
+    }
+
+    /// Iterates over every non-empty node
+    pub fn nodes(&self) -> impl Iterator<Item = (&(usize, u64), &Fr)> {
+        self.nodes.iter()
+    }
+
+    /// Gets the number of keys in the tree
+    pub fn len(&self) -> usize {
+        self.occupants.values().map(BTreeMap::len).sum()
+    }
+
+    /// Checks if the tree is empty
+    pub fn is_empty(&self) -> bool {
+        self.occupants.is_empty()
+    }
+
+    /// Gets a node by height and index, falling back to the empty subtree hash
+    fn node(&self, height: usize, index: u64) -> Fr {
+        self.nodes
+            .get(&(height, index))
+            .copied()
+            .unwrap_or(self.empty_hashes[height])
+    }
+
+    /// Computes a slot's leaf from the keys in it
+    fn slot_value(&self, index: u64) -> Fr {
+        let leaves: Vec<Fr> = self
+            .occupants
+            .get(&index)
+            .map(|slot| slot.values().copied().collect())
+            .unwrap_or_default();
+        match leaves.as_slice() {
+            [] => self.empty_hashes[0],
+            [leaf] => *leaf,
+            _ => shared_slot_leaf(leaves),
+        }
+    }
+
+    /// Stores a node, dropping it from the map when it equals the empty hash
+    fn set_node(&mut self, height: usize, index: u64, value: Fr) {
+        if value == self.empty_hashes[height] {
+            self.nodes.remove(&(height, index));
+        } else {
+            self.nodes.insert((height, index), value);
+        }
+    }
+
+    /// Writes a leaf and recomputes every ancestor up to the root
+    fn set_path(&mut self, index: u64, leaf: Fr) {
+        self.set_node(0, index, leaf);
+
+        let mut index = index;
+        let mut current = leaf;
+        for height in 0..self.depth {
+            let sibling = self.node(height, index ^ 1);
+            current = if index & 1 == 0 {
+                poseidon::hash_two(current, sibling)
+            } else {
+                poseidon::hash_two(sibling, current)
+            };
+            index >>= 1;
+            self.set_node(height + 1, index, current);
+        }
+    }
+}
+
+impl Default for SparseMerkleTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(first: u8) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key[0] = first;
+        key
+    }
+
+    #[test]
+    fn test_identical_leaves_do_not_cancel() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        let empty_root = tree.get_root();
+        let leaf = Fr::from(42u64);
+
+        tree.update(&key(1), leaf);
+        tree.update(&key(2), leaf);
+        assert_ne!(tree.get_root(), empty_root);
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn test_incremental_root_matches_insertion_order() {
+        let mut a = SparseMerkleTree::with_depth(8);
+        a.update(&key(1), Fr::from(1u64));
+        a.update(&key(200), Fr::from(2u64));
+
+        let mut b = SparseMerkleTree::with_depth(8);
+        b.update(&key(200), Fr::from(2u64));
+        b.update(&key(1), Fr::from(1u64));
+
+        assert_eq!(a.root(), b.root());
+    }
+
+    #[test]
+    fn test_remove_restores_empty_root() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        let empty_root = tree.root();
+
+        tree.update(&key(9), Fr::from(5u64));
+        assert!(tree.remove(&key(9)));
+        assert_eq!(tree.root(), empty_root);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_colliding_keys_share_a_slot() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        let empty_root = tree.root();
+        let mut other = key(3);
+        other[31] = 1;
+
+        tree.update(&key(3), Fr::from(1u64));
+        let alone = tree.root();
+        tree.update(&other, Fr::from(2u64));
+        assert_eq!(tree.len(), 2);
+        assert!(tree.is_slot_shared(&other));
+        assert_eq!(tree.get_leaf(&key(3)), Some(Fr::from(1u64)));
+        assert_eq!(tree.get_leaf(&other), Some(Fr::from(2u64)));
+
+        let proof = tree.prove_path(&other);
+        assert!(proof.is_inclusion());
+        let leaves = [Fr::from(1u64), Fr::from(2u64)];
+        assert_eq!(proof.slot_leaves, leaves.iter().map(poseidon::field_to_bytes).collect::<Vec<_>>());
+        assert_eq!(proof.leaf, poseidon::field_to_bytes(&shared_slot_leaf(leaves)));
+        assert!(verify_proof(&proof, &tree.get_root()));
+
+        // The shared leaves must be the ones hashed into the slot
+        let mut tampered = proof.clone();
+        tampered.slot_leaves.reverse();
+        assert!(!verify_proof(&tampered, &tree.get_root()));
+
+        assert!(tree.remove(&other));
+        assert_eq!(tree.root(), alone);
+        assert!(!tree.is_slot_shared(&key(3)));
+        assert!(tree.remove(&key(3)));
+        assert_eq!(tree.root(), empty_root);
+    }
+
+    #[test]
+    fn test_inclusion_proof_verifies() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        tree.update(&key(1), Fr::from(10u64));
+        tree.update(&key(77), Fr::from(20u64));
+
+        let proof = tree.prove(&key(77), |_| None).unwrap();
+        assert!(proof.is_inclusion());
+        assert_eq!(proof.merkle_path.len(), 8);
+        assert_eq!(proof.leaf, poseidon::field_to_bytes(&Fr::from(20u64)));
+        assert!(verify_proof(&proof, &tree.get_root()));
+
+        let mut tampered = proof.clone();
+        tampered.leaf = poseidon::field_to_bytes(&Fr::from(21u64));
+        assert!(!verify_proof(&tampered, &tree.get_root()));
+    }
+
+    #[test]
+    fn test_non_inclusion_proofs_verify() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        let occupant = key(5);
+        let body = Fr::from(99u64);
+        let leaf = poseidon::hash_two(poseidon::bytes_to_field(&occupant), body);
+        tree.update(&occupant, leaf);
+        let leaf_body = |sdkey_hash: &[u8; 32]| (*sdkey_hash == occupant).then_some(body);
+
+        let empty = tree.prove(&key(6), leaf_body).unwrap();
+        assert_eq!(empty.kind, ProofKind::EmptySlot);
+        assert!(verify_proof(&empty, &tree.get_root()));
+
+        let mut same_slot = key(5);
+        same_slot[31] = 1;
+        let occupied = tree.prove(&same_slot, leaf_body).unwrap();
+        let occupants = vec![SlotOccupant {
+            sdkey_hash: occupant,
+            leaf_body: poseidon::field_to_bytes(&body),
+        }];
+        assert_eq!(occupied.kind, ProofKind::OccupiedSlot { occupants });
+        assert_eq!(occupied.leaf, poseidon::field_to_bytes(&leaf));
+        assert!(verify_proof(&occupied, &tree.get_root()));
+        assert!(tree.prove(&same_slot, |_| None).is_err());
+        assert!(!verify_proof(&tree.prove_path(&same_slot), &tree.get_root()));
+
+        let mut forged = empty.clone();
+        forged.kind = ProofKind::Inclusion;
+        assert!(!verify_proof(&forged, &tree.get_root()));
+    }
+
+    #[test]
+    fn test_relabeled_inclusion_proof_is_not_non_membership() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        let member = key(5);
+        let body = Fr::from(99u64);
+        tree.update(&member, poseidon::hash_two(poseidon::bytes_to_field(&member), body));
+        let inclusion = tree.prove(&member, |_| None).unwrap();
+        assert!(verify_proof(&inclusion, &tree.get_root()));
+
+        let mut relabeled = inclusion.clone();
+        relabeled.kind = ProofKind::OccupiedSlot { occupants: Vec::new() };
+        assert!(!verify_proof(&relabeled, &tree.get_root()));
+
+        // Naming the member itself as the occupant does not help either
+        relabeled.kind = ProofKind::OccupiedSlot {
+            occupants: vec![SlotOccupant {
+                sdkey_hash: member,
+                leaf_body: poseidon::field_to_bytes(&body),
+            }],
+        };
+        assert!(!verify_proof(&relabeled, &tree.get_root()));
+
+        // Nor does leaving a colliding member out of a shared slot
+        let mut other = key(5);
+        other[31] = 2;
+        tree.update(&other, poseidon::hash_two(poseidon::bytes_to_field(&other), body));
+        let mut partial = tree.prove_path(&member);
+        partial.kind = ProofKind::OccupiedSlot {
+            occupants: vec![SlotOccupant {
+                sdkey_hash: other,
+                leaf_body: poseidon::field_to_bytes(&body),
+            }],
+        };
+        assert!(!verify_proof(&partial, &tree.get_root()));
+    }
+
+    #[test]
+    fn test_proof_rejected_against_stale_root() {
+        let mut tree = SparseMerkleTree::with_depth(8);
+        tree.update(&key(1), Fr::from(1u64));
+        let proof = tree.prove(&key(1), |_| None).unwrap();
+        let old_root = tree.get_root();
+
+        tree.update(&key(1), Fr::from(2u64));
+        assert!(verify_proof(&proof, &old_root));
+        assert!(!verify_proof(&proof, &tree.get_root()));
+    }
+
+    #[test]
+    fn test_user_leaf_ignores_zero_balances_and_map_order() {
+        let mut balances = HashMap::new();
+        balances.insert("USDC".to_string(), 100u128);
+        balances.insert("RWA-CREDIT".to_string(), 5u128);
+        let with_zero = {
+            let mut b = balances.clone();
+            b.insert("EMPTY".to_string(), 0u128);
+            b
+        };
+
+        let sdkey_hash = key(7);
+        assert_eq!(
+            user_leaf(&sdkey_hash, &balances, 3),
+            user_leaf(&sdkey_hash, &with_zero, 3)
+        );
+        assert_ne!(user_leaf(&sdkey_hash, &balances, 3), user_leaf(&sdkey_hash, &balances, 4));
+    }
+}
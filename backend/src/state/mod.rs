@@ -5,9 +5,53 @@
 //! - State transitions (Deposit, Trade, Withdrawal)
 //! - RocksDB persistence layer for local state caching
 
+pub mod audit;
+pub mod backup;
+pub mod cache;
+pub mod compliance;
+pub mod corporate;
+pub mod encoding;
+pub mod epochs;
+pub mod fees;
+pub mod history;
+pub mod jobs;
+pub mod journal;
+pub mod merkle;
+pub mod notes;
+pub mod nullifier;
+pub mod poseidon;
+pub mod proofs;
+pub mod reorg;
+pub mod restrictions;
+pub mod schema;
+pub mod supply;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tokens;
+
+pub use audit::{AuditReport, TokenAudit};
+pub use backup::BackupManifest;
+pub use cache::{CacheStats, UserCache, DEFAULT_USER_CACHE_CAPACITY};
+pub use compliance::{AccreditationCredential, ComplianceAttestation, ComplianceRegistry};
+pub use corporate::{CorporateAction, DistributionReport, Entitlement, RoundingPolicy};
+pub use epochs::EpochSettlement;
+pub use fees::{protocol_fee_account, FeeKind, FeeLedgerEntry, FeeSchedule};
+pub use history::RootRecord;
+pub use jobs::{JobPriority, JobStatus, ProofJob, ProofRequest};
+pub use journal::{JournalCheckpoint, JournalEntry, ReplayOutcome};
+pub use reorg::DEFAULT_FINALITY_DEPTH;
+pub use restrictions::{RestrictionRule, TransferPolicy};
+pub use merkle::{verify_proof, MerkleProof, ProofKind, SlotOccupant, SparseMerkleTree, TREE_DEPTH};
+pub use notes::{CommitmentTree, Note, NoteProof};
+pub use nullifier::NullifierSet;
+pub use supply::{SupplyCounters, TokenSupply};
+pub use tokens::{AssetClass, TokenInfo, TokenRegistry, TokenStatus};
+
 use crate::error::{CloakError, CloakResult};
-use std::collections::HashMap;
-use rocksdb::{DB, Options};
+use crate::prover::{ProofBundle, SettlementProof, Verifier};
+use ark_bls12_381::Fr;
+use std::collections::{BTreeMap, HashMap};
+use rocksdb::{WriteBatch, DB};
 use serde::{Deserialize, Serialize};
 use tracing::{info, warn, error};
 
@@ -17,8 +61,7 @@ pub struct UserState {
     /// Hash of the user's SDKey (programmable identity)
     pub sdkey_hash: [u8; 32],
 
-    /// Current Merkle tree root commitment
-    /// TODO: Replace with actual Poseidon hash field element
+    /// Global Merkle root at which this user's leaf was last committed
     pub merkle_root: [u8; 32],
 
     /// User's asset balances (token_id -> amount)
@@ -53,11 +96,26 @@ impl UserState {
     pub fn get_balance(&self, token_id: &str) -> u128 {
         self.balances.get(token_id).copied().unwrap_or(0)
     }
+
+    /// Computes this user's Merkle leaf: `H(sdkey_hash || balances || nonce)`
+    pub fn leaf_hash(&self) -> Fr {
+        merkle::user_leaf(&self.sdkey_hash, &self.balances, self.nonce)
+    }
+
+    /// Computes the `H(balances || nonce)` part of this user's leaf
+    pub fn leaf_body(&self) -> Fr {
+        merkle::leaf_body(&self.balances, self.nonce)
+    }
 }
 
 /// Represents a state transition in the Cloak Protocol
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StateTransition {
+    /// New user joins the private state with an empty leaf
+    Register {
+        user_sdkey_hash: [u8; 32],
+    },
+
     /// User deposits assets into the private state
     Deposit {
         user_sdkey_hash: [u8; 32],
@@ -73,6 +131,18 @@ pub enum StateTransition {
         token_b_id: String,
         amount_a: u128,
         amount_b: u128,
+        /// Nullifiers of the notes spent by either leg
+        #[serde(default)]
+        nullifiers: Vec<[u8; 32]>,
+        /// Fee in token B the maker (A) pays out of `amount_b`, set by the node
+        #[serde(default)]
+        maker_fee: u128,
+        /// Fee in token A the taker (B) pays out of `amount_a`, set by the node
+        #[serde(default)]
+        taker_fee: u128,
+        /// Proof that both legs settle against the current root
+        #[serde(default)]
+        settlement: Option<SettlementProof>,
     },
 
     /// User withdraws assets from the private state
@@ -80,83 +150,100 @@ pub enum StateTransition {
         user_sdkey_hash: [u8; 32],
         token_id: String,
         amount: u128,
+        /// Nullifier of the note being withdrawn, if any
+        #[serde(default)]
+        nullifier: Option<[u8; 32]>,
+        /// Fee charged on top of `amount`, set by the node
+        #[serde(default)]
+        fee: u128,
+        /// Balance proof that the user can cover `amount + fee`, if any
+        #[serde(default)]
+        proof: Option<ProofBundle>,
     },
-}
 
-/// Simple Merkle tree structure for state commitments
-/// TODO: Replace with full Poseidon-based Merkle tree implementation
-#[derive(Debug, Clone)]
-pub struct SimpleMerkleTree {
-    /// Leaf nodes (user state hashes)
-    leaves: Vec<[u8; 32]>,
+    /// User moves part of an account balance into a new shielded note
+    Shield {
+        user_sdkey_hash: [u8; 32],
+        token_id: String,
+        amount: u128,
+        /// Commitment of the note receiving `amount` of `token_id`
+        commitment: [u8; 32],
+        /// Opening of `commitment`, which must hold `amount` of `token_id`
+        #[serde(default)]
+        opening: Option<Note>,
+    },
+
+    /// Shielded notes are spent and new ones created, revealing only nullifiers
+    PrivateTransfer {
+        /// Nullifiers of the spent notes
+        nullifiers: Vec<[u8; 32]>,
+        /// Commitments of the newly created notes
+        output_commitments: Vec<[u8; 32]>,
+        /// Note-commitment root the spent notes were proven against
+        anchor: [u8; 32],
+    },
 
-    /// Current root hash
-    root: [u8; 32],
+    /// A corporate action credits each holder's entitlement in the payout token
+    Distribution {
+        /// Report the entitlements are taken from, committed by its hash
+        report: DistributionReport,
+    },
+
+    /// A shielded note is spent back into a user's account balance
+    Unshield {
+        user_sdkey_hash: [u8; 32],
+        token_id: String,
+        amount: u128,
+        /// Nullifier of the spent note
+        nullifier: [u8; 32],
+        /// Note-commitment root the spent note was proven against
+        anchor: [u8; 32],
+    },
 }
 
-impl SimpleMerkleTree {
-    /// Creates a new empty Merkle tree
-    pub fn new() -> Self {
-        Self {
-            leaves: Vec::new(),
-            root: [0u8; 32],
-        }
-    }
+/// State manager that handles persistence and state transitions
+pub struct StateManager {
+    /// Bounded cache of recently used user states (RocksDB is authoritative)
+    user_cache: UserCache,
 
-    /// Adds a leaf to the tree and updates the root
-    /// TODO: Implement proper Poseidon hashing for tree updates
-    pub fn add_leaf(&mut self, leaf: [u8; 32]) {
-        self.leaves.push(leaf);
-        self.update_root();
-    }
-
-    /// Updates the root hash (placeholder implementation)
-    fn update_root(&mut self) {
-        // TODO: Implement proper Merkle tree root computation using Poseidon
-        if self.leaves.is_empty() {
-            self.root = [0u8; 32];
-        } else {
-            // Temporary: XOR all leaves as a placeholder
-            // This is a simple hash that will be replaced with Poseidon
-            let mut root = [0u8; 32];
-            for leaf in &self.leaves {
-                for (i, byte) in leaf.iter().enumerate() {
-                    root[i] ^= byte;
-                }
-            }
-            self.root = root;
-        }
-    }
+    /// Poseidon sparse Merkle tree for state commitments
+    merkle_tree: SparseMerkleTree,
 
-    /// Gets the current root hash
-    pub fn get_root(&self) -> [u8; 32] {
-        self.root
-    }
+    /// Spent nullifiers and their Merkle tree
+    nullifiers: NullifierSet,
 
-    /// Gets the number of leaves in the tree
-    pub fn len(&self) -> usize {
-        self.leaves.len()
-    }
+    /// Append-only tree of shielded note commitments
+    note_tree: CommitmentTree,
 
-    /// Checks if the tree is empty
-    pub fn is_empty(&self) -> bool {
-        self.leaves.is_empty()
-    }
-}
+    /// Per-token supply counters, updated atomically with every transition
+    supply: SupplyCounters,
 
-impl Default for SimpleMerkleTree {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+    /// Tokens accepted in transitions
+    tokens: TokenRegistry,
 
-/// State manager that handles persistence and state transitions
-pub struct StateManager {
-    /// In-memory cache of user states
-    user_states: HashMap<[u8; 32], UserState>,
+    /// Trade and withdrawal fee rates, keyed by token ID
+    fee_schedules: BTreeMap<String, FeeSchedule>,
+
+    /// Transfer policies of restricted tokens, keyed by token ID
+    restrictions: BTreeMap<String, TransferPolicy>,
+
+    /// Number of accounts holding a non-zero balance, keyed by token ID
+    holders: BTreeMap<String, u64>,
+
+    /// Sanctions list and credential registry for compliance proofs
+    compliance: ComplianceRegistry,
 
-    /// Merkle tree for state commitments
-    merkle_tree: SimpleMerkleTree,
+    /// Verifying keys that attached proofs are checked against
+    verifier: Verifier,
+
+    /// Current Psy block height, stamped onto updated user states
+    block_height: u64,
+
+    /// Sequence number of the last recorded state root
+    root_sequence: u64,
+
+    /// Number of blocks after which applied transitions can no longer be rolled back
+    finality_depth: u64,
 
     /// RocksDB instance for persistence
     db: DB,
@@ -174,97 +261,296 @@ impl Drop for StateManager {
 impl StateManager {
     /// Creates a new state manager with RocksDB persistence
     pub fn new(db_path: &str) -> CloakResult<Self> {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.set_max_open_files(1000);
-        opts.set_write_buffer_size(64 * 1024 * 1024); // 64MB write buffer
-
-        let db = DB::open(&opts, db_path)?;
+        let db = schema::open_db(db_path)?;
         info!("Opened RocksDB at: {}", db_path);
 
         let mut manager = Self {
-            user_states: HashMap::new(),
-            merkle_tree: SimpleMerkleTree::new(),
+            user_cache: UserCache::new(DEFAULT_USER_CACHE_CAPACITY),
+            merkle_tree: SparseMerkleTree::new(),
+            nullifiers: NullifierSet::new(),
+            note_tree: CommitmentTree::new(),
+            supply: SupplyCounters::default(),
+            tokens: TokenRegistry::default(),
+            fee_schedules: BTreeMap::new(),
+            restrictions: BTreeMap::new(),
+            holders: BTreeMap::new(),
+            compliance: ComplianceRegistry::default(),
+            verifier: Verifier::new(),
+            block_height: 0,
+            root_sequence: 0,
+            finality_depth: reorg::DEFAULT_FINALITY_DEPTH,
             db,
         };
 
-        // Load existing state from database
+        // Load existing state from database and check it against the journal
+        manager.check_schema()?;
+        manager.migrate_legacy_user_records()?;
         manager.load_state_from_db()?;
+        manager.load_nullifiers()?;
+        manager.load_notes()?;
+        manager.load_supply()?;
+        manager.load_tokens()?;
+        manager.load_restrictions()?;
+        manager.load_compliance()?;
+        manager.load_fee_schedules()?;
+        manager.check_journal_on_startup()?;
+        manager.audit()?;
 
         Ok(manager)
     }
 
-    /// Loads state from RocksDB into memory
+    /// Loads the state Merkle tree, counters and holder counts from RocksDB
+    ///
+    /// User states are scanned once for their leaves and stay on disk; they
+    /// are read into the cache on first use.
     fn load_state_from_db(&mut self) -> CloakResult<()> {
-        let iter = self.db.iterator(rocksdb::IteratorMode::Start);
-        let mut loaded_count = 0;
-
-        for item in iter {
-            let (key, value) = item?;
-            if let Some(key_str) = std::str::from_utf8(&key).ok() {
-                if key_str.starts_with("user:") {
-                    let user_state: UserState = serde_json::from_slice(&value)?;
-                    self.user_states.insert(user_state.sdkey_hash, user_state);
-                    loaded_count += 1;
-                }
+        let mut leaves = Vec::new();
+        let mut block_height = self.block_height;
+        let mut holders = BTreeMap::new();
+        self.for_each_stored_user(|user_state| {
+            block_height = block_height.max(user_state.last_updated_block);
+            leaves.push((user_state.sdkey_hash, user_state.leaf_hash()));
+            restrictions::count_holdings(&mut holders, &user_state);
+            Ok(())
+        })?;
+        self.block_height = block_height;
+        self.holders = holders;
+
+        self.root_sequence = self.load_root_sequence()?;
+
+        self.load_merkle_tree(&leaves)?;
+
+        info!(
+            "Indexed {} user states from database, Merkle root: {}",
+            leaves.len(),
+            hex::encode(self.merkle_tree.get_root())
+        );
+        Ok(())
+    }
+
+    /// Registers a new user in the state
+    pub fn register_user(&mut self, sdkey_hash: [u8; 32]) -> CloakResult<()> {
+        self.apply_transition(StateTransition::Register {
+            user_sdkey_hash: sdkey_hash,
+        })
+    }
+
+    /// Applies a state transition atomically
+    ///
+    /// All affected users are staged as copies and every leg is validated
+    /// before anything is written. The staged states are then committed in a
+    /// single RocksDB `WriteBatch` and only afterwards swapped into memory, so
+    /// a failed transition leaves both the cache and the database untouched.
+    /// Deposits and Trades must also pass the transfer policies of the
+    /// tokens they move, checked against the staged holder set. Trade and
+    /// withdrawal fees are set from the current fee schedules first, and any
+    /// attached proof must verify against the current root. Every Trade needs
+    /// a settlement proof, whose new root must be the root the trade actually
+    /// reaches; trades settled by a batch proof go through `apply_epoch`.
+    pub fn apply_transition(&mut self, transition: StateTransition) -> CloakResult<()> {
+        self.apply_checked(transition, false)
+    }
+
+    /// Applies a transition, waiving the settlement proof of a trade when
+    /// `batch_settled` because an epoch's batch proof covers it instead
+    fn apply_checked(&mut self, transition: StateTransition, batch_settled: bool) -> CloakResult<()> {
+        let transition = self.assess_fees(transition);
+        self.check_tokens(&transition)?;
+        self.check_proofs(&transition, batch_settled)?;
+        self.check_nullifiers(&transition)?;
+        self.check_notes(&transition)?;
+        self.check_distribution(&transition)?;
+        let previous = self.load_affected_users(&transition)?;
+        let staged = Self::stage_with(&transition, |sdkey_hash| previous.get(sdkey_hash).cloned())?;
+        let holder_changes = restrictions::holder_changes(&previous, &staged);
+        self.check_restrictions(&transition, &holder_changes)?;
+        self.commit_staged(&transition, staged)?;
+        self.apply_holder_changes(&holder_changes);
+
+        match &transition {
+            StateTransition::Register { user_sdkey_hash } => {
+                info!("Registered user: {}", hex::encode(user_sdkey_hash))
             }
+            StateTransition::Deposit { user_sdkey_hash, token_id, amount } => info!(
+                "Deposit: user {} deposited {} of {}",
+                hex::encode(user_sdkey_hash),
+                amount,
+                token_id
+            ),
+            StateTransition::Trade { user_a_sdkey_hash, user_b_sdkey_hash, .. } => info!(
+                "Trade: user {} and {} executed trade",
+                hex::encode(user_a_sdkey_hash),
+                hex::encode(user_b_sdkey_hash)
+            ),
+            StateTransition::Withdrawal { user_sdkey_hash, token_id, amount, .. } => info!(
+                "Withdrawal: user {} withdrew {} of {}",
+                hex::encode(user_sdkey_hash),
+                amount,
+                token_id
+            ),
+            StateTransition::Shield { user_sdkey_hash, token_id, amount, .. } => info!(
+                "Shield: user {} shielded {} of {}",
+                hex::encode(user_sdkey_hash),
+                amount,
+                token_id
+            ),
+            StateTransition::PrivateTransfer { nullifiers, output_commitments, .. } => info!(
+                "Private transfer: {} notes spent, {} notes created",
+                nullifiers.len(),
+                output_commitments.len()
+            ),
+            StateTransition::Unshield { user_sdkey_hash, token_id, amount, .. } => info!(
+                "Unshield: user {} unshielded {} of {}",
+                hex::encode(user_sdkey_hash),
+                amount,
+                token_id
+            ),
+            StateTransition::Distribution { report } => info!(
+                "Distribution {}: {} of {} paid to {} holders of {}",
+                report.id(),
+                report.distributed,
+                report.action.payout_token_id,
+                report.entitlements.len(),
+                report.action.token_id
+            ),
         }
 
-        info!("Loaded {} user states from database", loaded_count);
         Ok(())
     }
 
-    /// Persists a user state to RocksDB
-    fn persist_user_state(&self, sdkey_hash: [u8; 32], user_state: &UserState) -> CloakResult<()> {
-        let key = format!("user:{}", hex::encode(sdkey_hash));
-        let value = serde_json::to_vec(user_state)?;
-        self.db.put(key, value)?;
+    /// Commits staged user states to the Merkle tree, RocksDB and memory
+    ///
+    /// Leaves are updated first so the new global root can be stamped onto
+    /// every staged user before the batch is written. The batch also records
+    /// the new root in the root history, any spent nullifiers, any new note
+    /// commitments, the updated supply counters and lockup acquisition
+    /// heights. If the tree update or the write fails, the touched leaves are
+    /// restored to their old values, the nullifiers are taken back out of the
+    /// set and the note tree is truncated to its old size.
+    fn commit_staged(&mut self, transition: &StateTransition, mut staged: Vec<UserState>) -> CloakResult<()> {
+        let previous_leaves: Vec<([u8; 32], Option<Fr>)> = staged
+            .iter()
+            .map(|user_state| (user_state.sdkey_hash, self.merkle_tree.get_leaf(&user_state.sdkey_hash)))
+            .collect();
+        let previous_note_count = self.note_tree.len();
+        let mut supply = self.supply.clone();
+        supply.apply(transition)?;
+
+        let result = self.write_staged(transition, &mut staged, &supply);
+        if let Err(e) = result {
+            for (sdkey_hash, leaf) in previous_leaves {
+                match leaf {
+                    Some(leaf) => self.merkle_tree.update(&sdkey_hash, leaf),
+                    None => {
+                        self.merkle_tree.remove(&sdkey_hash);
+                    }
+                }
+            }
+            // `check_nullifiers` guaranteed none of these were spent before
+            for nullifier in transition.nullifiers() {
+                self.nullifiers.remove(&nullifier);
+            }
+            self.note_tree.truncate(previous_note_count);
+            return Err(e);
+        }
+
+        self.root_sequence += 1;
+        self.supply = supply;
+        for user_state in staged {
+            self.user_cache.put(user_state);
+        }
         Ok(())
     }
 
-    /// Registers a new user in the state
-    pub fn register_user(&mut self, sdkey_hash: [u8; 32]) -> CloakResult<()> {
-        if self.user_states.contains_key(&sdkey_hash) {
-            return Err(CloakError::State(format!(
-                "User already registered: {}",
-                hex::encode(sdkey_hash)
-            )));
+    /// Updates leaves, stamps the new root and block height, and writes the batch
+    fn write_staged(
+        &mut self,
+        transition: &StateTransition,
+        staged: &mut [UserState],
+        supply: &SupplyCounters,
+    ) -> CloakResult<()> {
+        let previous_root = self.merkle_tree.get_root();
+        let mut first_leaf_root = None;
+        for user_state in staged.iter() {
+            self.merkle_tree.update(&user_state.sdkey_hash, user_state.leaf_hash());
+            first_leaf_root.get_or_insert_with(|| self.merkle_tree.get_root());
+        }
+        for nullifier in transition.nullifiers() {
+            self.nullifiers.insert(&nullifier)?;
         }
+        let first_note_position = self.append_note_commitments(transition)?;
 
-        let user_state = UserState::new(sdkey_hash);
-        self.user_states.insert(sdkey_hash, user_state.clone());
+        let root = self.merkle_tree.get_root();
+        Self::check_proven_roots(transition, &first_leaf_root.unwrap_or(previous_root), &root)?;
+        let users_cf = schema::users_cf(&self.db)?;
+        let mut batch = WriteBatch::default();
+        for user_state in staged.iter_mut() {
+            user_state.merkle_root = root;
+            user_state.last_updated_block = self.block_height;
+            batch.put_cf(users_cf, user_state.sdkey_hash, user_state.encode()?);
+        }
+        Self::stage_merkle_paths(&self.db, &self.merkle_tree, &mut batch, staged.iter().map(|u| &u.sdkey_hash))?;
 
-        // Persist to RocksDB
-        self.persist_user_state(sdkey_hash, &user_state)?;
+        let sequence = self.root_sequence + 1;
+        let entry = JournalEntry {
+            sequence,
+            block_height: self.block_height,
+            transition: transition.clone(),
+            root_after: root,
+        };
+        Self::stage_journal_entry(&self.db, &mut batch, &entry)?;
+        Self::stage_nullifiers(&self.db, &mut batch, transition, sequence)?;
+        Self::stage_note_commitments(&self.db, &mut batch, transition, first_note_position)?;
+        Self::stage_supply(&self.db, &mut batch, supply)?;
+        self.stage_acquisitions(&mut batch, transition)?;
+        Self::stage_fee_ledger(&self.db, &mut batch, transition, sequence, self.block_height)?;
+        Self::stage_distribution(&self.db, &mut batch, transition)?;
 
-        info!("Registered user: {}", hex::encode(sdkey_hash));
+        let record = RootRecord {
+            sequence,
+            root,
+            previous_root,
+            nullifier_root: self.nullifiers.get_root(),
+            note_root: self.note_tree.get_root(),
+            block_height: self.block_height,
+            transitions: vec![transition.clone()],
+        };
+        Self::stage_root_record(&self.db, &mut batch, &record, staged)?;
+
+        self.db.write(batch)?;
         Ok(())
     }
 
-    /// Applies a state transition
-    /// TODO: Implement full ZK proof verification before applying transitions
-    pub fn apply_transition(&mut self, transition: StateTransition) -> CloakResult<()> {
+    /// Stages a transition against user states resolved through `lookup`
+    ///
+    /// Shared by live application and journal replay so both follow exactly
+    /// the same validation rules.
+    fn stage_with<F>(transition: &StateTransition, lookup: F) -> CloakResult<Vec<UserState>>
+    where
+        F: Fn(&[u8; 32]) -> Option<UserState>,
+    {
+        let staged_user = |sdkey_hash: &[u8; 32]| {
+            lookup(sdkey_hash).ok_or_else(|| CloakError::user_not_found(sdkey_hash))
+        };
+
         match transition {
+            StateTransition::Register { user_sdkey_hash } => {
+                if lookup(user_sdkey_hash).is_some() {
+                    return Err(CloakError::State(format!(
+                        "User already registered: {}",
+                        hex::encode(user_sdkey_hash)
+                    )));
+                }
+                Ok(vec![UserState::new(*user_sdkey_hash)])
+            }
             StateTransition::Deposit {
                 user_sdkey_hash,
                 token_id,
                 amount,
             } => {
-                let user_state = self.user_states.get_mut(&user_sdkey_hash)
-                    .ok_or_else(|| CloakError::user_not_found(&user_sdkey_hash))?;
-                
-                let current = user_state.get_balance(&token_id);
-                user_state.update_balance(token_id.clone(), current + amount);
-                
-                // Persist updated state
-                self.persist_user_state(user_sdkey_hash, user_state)?;
-                
-                info!(
-                    "Deposit: user {} deposited {} of {}",
-                    hex::encode(user_sdkey_hash),
-                    amount,
-                    token_id
-                );
+                let mut user_state = staged_user(user_sdkey_hash)?;
+                Self::credit(&mut user_state, token_id, *amount)?;
+                Ok(vec![user_state])
             }
             StateTransition::Trade {
                 user_a_sdkey_hash,
@@ -273,80 +559,148 @@ impl StateManager {
                 token_b_id,
                 amount_a,
                 amount_b,
+                maker_fee,
+                taker_fee,
+                ..
             } => {
-                // TODO: Verify ZK proof before executing trade
-                let user_a = self.user_states.get_mut(&user_a_sdkey_hash)
-                    .ok_or_else(|| CloakError::user_not_found(&user_a_sdkey_hash))?;
-                
-                let balance_a = user_a.get_balance(&token_a_id);
-                if balance_a < amount_a {
-                    return Err(CloakError::InsufficientBalance {
-                        required: amount_a,
-                        available: balance_a,
-                    });
+                if user_a_sdkey_hash == user_b_sdkey_hash {
+                    return Err(CloakError::invalid_input("Trade counterparties must be different users"));
                 }
-                
-                user_a.update_balance(token_a_id.clone(), balance_a - amount_a);
-                let balance_b = user_a.get_balance(&token_b_id);
-                user_a.update_balance(token_b_id.clone(), balance_b + amount_b);
-                self.persist_user_state(user_a_sdkey_hash, user_a)?;
-                
-                let user_b = self.user_states.get_mut(&user_b_sdkey_hash)
-                    .ok_or_else(|| CloakError::user_not_found(&user_b_sdkey_hash))?;
-                
-                let balance_b = user_b.get_balance(&token_b_id);
-                if balance_b < amount_b {
-                    return Err(CloakError::InsufficientBalance {
-                        required: amount_b,
-                        available: balance_b,
-                    });
+                let net_a = amount_a
+                    .checked_sub(*taker_fee)
+                    .ok_or_else(|| CloakError::invalid_input("Taker fee exceeds the traded amount"))?;
+                let net_b = amount_b
+                    .checked_sub(*maker_fee)
+                    .ok_or_else(|| CloakError::invalid_input("Maker fee exceeds the traded amount"))?;
+
+                let mut user_a = staged_user(user_a_sdkey_hash)?;
+                let mut user_b = staged_user(user_b_sdkey_hash)?;
+
+                // Leg 1: A gives token A to B, less the taker fee
+                Self::debit(&mut user_a, token_a_id, *amount_a)?;
+                Self::credit(&mut user_b, token_a_id, net_a)?;
+
+                // Leg 2: B gives token B to A, less the maker fee
+                Self::debit(&mut user_b, token_b_id, *amount_b)?;
+                Self::credit(&mut user_a, token_b_id, net_b)?;
+
+                let mut staged = vec![user_a, user_b];
+                if transition.charges_fees() {
+                    let mut fee_account = Self::staged_fee_account(&lookup, &staged)?;
+                    Self::credit(&mut fee_account, token_a_id, *taker_fee)?;
+                    Self::credit(&mut fee_account, token_b_id, *maker_fee)?;
+                    staged.push(fee_account);
                 }
-                
-                user_b.update_balance(token_b_id.clone(), balance_b - amount_b);
-                let balance_a = user_b.get_balance(&token_a_id);
-                user_b.update_balance(token_a_id.clone(), balance_a + amount_a);
-                self.persist_user_state(user_b_sdkey_hash, user_b)?;
-                
-                info!(
-                    "Trade: user {} and {} executed trade",
-                    hex::encode(user_a_sdkey_hash),
-                    hex::encode(user_b_sdkey_hash)
-                );
+                Ok(staged)
             }
             StateTransition::Withdrawal {
                 user_sdkey_hash,
                 token_id,
                 amount,
+                fee,
+                ..
+            } => {
+                let mut user_state = staged_user(user_sdkey_hash)?;
+                let total = amount
+                    .checked_add(*fee)
+                    .ok_or_else(|| CloakError::invalid_input("Withdrawal amount plus fee overflows"))?;
+                Self::debit(&mut user_state, token_id, total)?;
+
+                let mut staged = vec![user_state];
+                if transition.charges_fees() {
+                    let mut fee_account = Self::staged_fee_account(&lookup, &staged)?;
+                    Self::credit(&mut fee_account, token_id, *fee)?;
+                    staged.push(fee_account);
+                }
+                Ok(staged)
+            }
+            StateTransition::Shield {
+                user_sdkey_hash,
+                token_id,
+                amount,
+                ..
+            } => {
+                // `check_notes` has checked the opening binds the commitment to the debit
+                let mut user_state = staged_user(user_sdkey_hash)?;
+                Self::debit(&mut user_state, token_id, *amount)?;
+                Ok(vec![user_state])
+            }
+            // `check_notes` refuses spends until note spend proofs are verified
+            StateTransition::PrivateTransfer { .. } => Ok(Vec::new()),
+            StateTransition::Unshield {
+                user_sdkey_hash,
+                token_id,
+                amount,
+                ..
             } => {
-                let user_state = self.user_states.get_mut(&user_sdkey_hash)
-                    .ok_or_else(|| CloakError::user_not_found(&user_sdkey_hash))?;
-                
-                let current = user_state.get_balance(&token_id);
-                if current < amount {
-                    return Err(CloakError::InsufficientBalance {
-                        required: amount,
-                        available: current,
-                    });
+                let mut user_state = staged_user(user_sdkey_hash)?;
+                Self::credit(&mut user_state, token_id, *amount)?;
+                Ok(vec![user_state])
+            }
+            StateTransition::Distribution { report } => {
+                if report.compute_hash() != report.report_hash {
+                    return Err(CloakError::invalid_input(format!(
+                        "Distribution report hash mismatch: {}",
+                        report.id()
+                    )));
                 }
-                
-                user_state.update_balance(token_id.clone(), current - amount);
-                self.persist_user_state(user_sdkey_hash, user_state)?;
-                
-                info!(
-                    "Withdrawal: user {} withdrew {} of {}",
-                    hex::encode(user_sdkey_hash),
-                    amount,
-                    token_id
-                );
+                report
+                    .entitlements
+                    .iter()
+                    .map(|entitlement| {
+                        let mut user_state = staged_user(&entitlement.sdkey_hash)?;
+                        Self::credit(&mut user_state, &report.action.payout_token_id, entitlement.amount)?;
+                        Ok(user_state)
+                    })
+                    .collect()
             }
         }
+    }
 
+    /// Gets the protocol fee account to credit, creating it on first use
+    ///
+    /// The fee account cannot pay fees to itself, so it must not already be
+    /// one of the staged users.
+    fn staged_fee_account<F>(lookup: &F, staged: &[UserState]) -> CloakResult<UserState>
+    where
+        F: Fn(&[u8; 32]) -> Option<UserState>,
+    {
+        let fee_account = fees::protocol_fee_account();
+        if staged.iter().any(|user_state| user_state.sdkey_hash == fee_account) {
+            return Err(CloakError::invalid_input("The protocol fee account cannot pay fees"));
+        }
+        Ok(lookup(&fee_account).unwrap_or_else(|| UserState::new(fee_account)))
+    }
+
+    /// Adds `amount` of a token to a staged user state
+    fn credit(user_state: &mut UserState, token_id: &str, amount: u128) -> CloakResult<()> {
+        let current = user_state.get_balance(token_id);
+        let updated = current.checked_add(amount).ok_or_else(|| {
+            CloakError::invalid_input(format!("Balance overflow crediting {} of {}", amount, token_id))
+        })?;
+        user_state.update_balance(token_id.to_string(), updated);
         Ok(())
     }
 
-    /// Gets a user's state
+    /// Removes `amount` of a token from a staged user state
+    fn debit(user_state: &mut UserState, token_id: &str, amount: u128) -> CloakResult<()> {
+        let current = user_state.get_balance(token_id);
+        if current < amount {
+            return Err(CloakError::InsufficientBalance {
+                required: amount,
+                available: current,
+            });
+        }
+        user_state.update_balance(token_id.to_string(), current - amount);
+        Ok(())
+    }
+
+    /// Gets a user's state, reading through to RocksDB on a cache miss
     pub fn get_user_state(&self, sdkey_hash: [u8; 32]) -> Option<UserState> {
-        self.user_states.get(&sdkey_hash).cloned()
+        self.load_user(&sdkey_hash).unwrap_or_else(|e| {
+            error!("Failed to load user {}: {}", hex::encode(sdkey_hash), e);
+            None
+        })
     }
 
     /// Gets the current Merkle root
@@ -354,15 +708,80 @@ impl StateManager {
         self.merkle_tree.get_root()
     }
 
+    /// Generates a Merkle proof for a user against the current root
+    ///
+    /// Returns an inclusion proof (the `merkle_path` / `merkle_path_indices`
+    /// witness for the balance circuit) when the user is in the tree, and a
+    /// non-membership proof for unregistered keys.
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if a user holding the key's slot cannot
+    /// be loaded.
+    pub fn generate_merkle_proof(&self, sdkey_hash: [u8; 32]) -> CloakResult<MerkleProof> {
+        self.merkle_tree
+            .prove(&sdkey_hash, |occupant| self.get_user_state(*occupant).map(|user| user.leaf_body()))
+    }
+
+    /// Sets the current Psy block height used to stamp state updates
+    pub fn set_block_height(&mut self, height: u64) {
+        self.block_height = height;
+    }
+
+    /// Gets the current Psy block height
+    pub fn get_block_height(&self) -> u64 {
+        self.block_height
+    }
+
     /// Gets the number of registered users
     pub fn get_user_count(&self) -> usize {
-        self.user_states.len()
+        self.merkle_tree.len()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::testing::{temp_manager, test_token};
     use super::*;
+    use rocksdb::IteratorMode;
+
+    /// Registers two users and funds A with USDC and B with RWA-CREDIT
+    fn funded_pair(manager: &mut StateManager) -> ([u8; 32], [u8; 32]) {
+        let (a, b) = ([0xAAu8; 32], [0xBBu8; 32]);
+        manager.register_user(a).unwrap();
+        manager.register_user(b).unwrap();
+        manager.apply_transition(StateTransition::Deposit {
+            user_sdkey_hash: a,
+            token_id: "USDC".to_string(),
+            amount: 1_000,
+        }).unwrap();
+        manager.apply_transition(StateTransition::Deposit {
+            user_sdkey_hash: b,
+            token_id: "RWA-CREDIT".to_string(),
+            amount: 10,
+        }).unwrap();
+        (a, b)
+    }
+
+    fn trade(a: [u8; 32], b: [u8; 32], amount_a: u128, amount_b: u128) -> StateTransition {
+        StateTransition::Trade {
+            user_a_sdkey_hash: a,
+            user_b_sdkey_hash: b,
+            token_a_id: "USDC".to_string(),
+            token_b_id: "RWA-CREDIT".to_string(),
+            amount_a,
+            amount_b,
+            nullifiers: Vec::new(),
+            maker_fee: 0,
+            taker_fee: 0,
+            settlement: None,
+        }
+    }
+
+    /// Applies a trade the way `apply_epoch` does, leaving its settlement to
+    /// a batch proof these tests do not build
+    fn settle(manager: &mut StateManager, transition: StateTransition) -> CloakResult<()> {
+        manager.apply_checked(transition, true)
+    }
 
     #[test]
     fn test_user_state_creation() {
@@ -384,12 +803,981 @@ mod tests {
 
     #[test]
     fn test_merkle_tree_operations() {
-        let mut tree = SimpleMerkleTree::new();
+        let mut tree = SparseMerkleTree::new();
         assert!(tree.is_empty());
 
-        let leaf = [2u8; 32];
-        tree.add_leaf(leaf);
+        let user = UserState::new([2u8; 32]);
+        tree.update(&user.sdkey_hash, user.leaf_hash());
         assert_eq!(tree.len(), 1);
         assert!(!tree.is_empty());
+        assert_eq!(tree.get_leaf(&user.sdkey_hash), Some(user.leaf_hash()));
+    }
+
+    #[test]
+    fn test_trade_moves_both_legs() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+
+        settle(&mut manager, trade(a, b, 950, 10)).unwrap();
+
+        let user_a = manager.get_user_state(a).unwrap();
+        let user_b = manager.get_user_state(b).unwrap();
+        assert_eq!(user_a.get_balance("USDC"), 50);
+        assert_eq!(user_a.get_balance("RWA-CREDIT"), 10);
+        assert_eq!(user_b.get_balance("USDC"), 950);
+        assert_eq!(user_b.get_balance("RWA-CREDIT"), 0);
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_failed_second_leg_leaves_both_users_untouched() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let before_a = manager.get_user_state(a).unwrap();
+        let before_b = manager.get_user_state(b).unwrap();
+
+        // A can pay, but B only holds 10 RWA-CREDIT
+        let result = settle(&mut manager, trade(a, b, 500, 11));
+        assert!(matches!(result, Err(CloakError::InsufficientBalance { required: 11, available: 10 })));
+
+        let after_a = manager.get_user_state(a).unwrap();
+        let after_b = manager.get_user_state(b).unwrap();
+        assert_eq!(after_a.balances, before_a.balances);
+        assert_eq!(after_a.nonce, before_a.nonce);
+        assert_eq!(after_b.balances, before_b.balances);
+        assert_eq!(after_b.nonce, before_b.nonce);
+
+        // Nothing leaked into RocksDB either
+        drop(manager);
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_user_state(a).unwrap().balances, before_a.balances);
+        assert_eq!(reopened.get_user_state(b).unwrap().balances, before_b.balances);
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_trade_with_missing_counterparty_leaves_user_untouched() {
+        let (mut manager, path) = temp_manager();
+        let (a, _) = funded_pair(&mut manager);
+        let before_a = manager.get_user_state(a).unwrap();
+        let unknown = [0xCCu8; 32];
+
+        let result = settle(&mut manager, trade(a, unknown, 100, 1));
+        assert!(matches!(result, Err(CloakError::UserNotFound(_))));
+
+        let after_a = manager.get_user_state(a).unwrap();
+        assert_eq!(after_a.balances, before_a.balances);
+        assert_eq!(after_a.nonce, before_a.nonce);
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_transitions_update_merkle_root_and_user_roots() {
+        let (mut manager, path) = temp_manager();
+        let empty_root = manager.get_merkle_root();
+
+        manager.set_block_height(42);
+        let (a, b) = funded_pair(&mut manager);
+        let after_deposits = manager.get_merkle_root();
+        assert_ne!(after_deposits, empty_root);
+
+        manager.set_block_height(43);
+        settle(&mut manager, trade(a, b, 100, 5)).unwrap();
+        let root = manager.get_merkle_root();
+        assert_ne!(root, after_deposits);
+
+        for sdkey_hash in [a, b] {
+            let user = manager.get_user_state(sdkey_hash).unwrap();
+            assert_eq!(user.merkle_root, root);
+            assert_eq!(user.last_updated_block, 43);
+
+            let proof = manager.generate_merkle_proof(sdkey_hash).unwrap();
+            assert!(proof.is_inclusion());
+            assert_eq!(proof.leaf, poseidon::field_to_bytes(&user.leaf_hash()));
+            assert!(verify_proof(&proof, &root));
+        }
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_failed_transition_keeps_merkle_root() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let root = manager.get_merkle_root();
+
+        assert!(settle(&mut manager, trade(a, b, 500, 11)).is_err());
+        assert_eq!(manager.get_merkle_root(), root);
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_merkle_tree_rebuilt_on_startup() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(7);
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 1, 1)).unwrap();
+        let root = manager.get_merkle_root();
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_merkle_root(), root);
+        assert_eq!(reopened.get_block_height(), 7);
+        assert!(reopened.generate_merkle_proof(a).unwrap().is_inclusion());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_root_history_point_in_time_queries() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(100);
+        let (a, b) = funded_pair(&mut manager);
+        let root_100 = manager.get_merkle_root();
+
+        manager.set_block_height(105);
+        settle(&mut manager, trade(a, b, 400, 4)).unwrap();
+        let root_105 = manager.get_merkle_root();
+
+        assert!(manager.get_root_at_block(99).unwrap().is_none());
+        let at_102 = manager.get_root_at_block(102).unwrap().unwrap();
+        assert_eq!(at_102.root, root_100);
+        assert_eq!(at_102.block_height, 100);
+
+        let record = manager.get_root_record(&root_105).unwrap().unwrap();
+        assert_eq!(record.previous_root, root_100);
+        assert_eq!(record.block_height, 105);
+        assert!(matches!(record.transitions.as_slice(), [StateTransition::Trade { .. }]));
+
+        let a_then = manager.get_user_state_at_root(a, &root_100).unwrap().unwrap();
+        assert_eq!(a_then.get_balance("USDC"), 1_000);
+        let a_at_block = manager.get_user_state_at_block(a, 104).unwrap().unwrap();
+        assert_eq!(a_at_block.get_balance("USDC"), 1_000);
+        let a_now = manager.get_user_state_at_block(a, 105).unwrap().unwrap();
+        assert_eq!(a_now.get_balance("USDC"), 600);
+
+        // Sequence numbering survives restarts
+        let sequence = record.sequence;
+        drop(manager);
+        let mut reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        reopened.register_user([0xCCu8; 32]).unwrap();
+        let latest = reopened.get_root_record(&reopened.get_merkle_root()).unwrap().unwrap();
+        assert_eq!(latest.sequence, sequence + 1);
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_journal_records_every_transition() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 10, 1)).unwrap();
+
+        let entries = manager.get_journal_entries(1, 100).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert!(entries.iter().enumerate().all(|(i, entry)| entry.sequence == i as u64 + 1));
+        assert!(matches!(entries[0].transition, StateTransition::Register { .. }));
+        assert_eq!(entries[4].root_after, manager.get_merkle_root());
+
+        // A rejected transition leaves no journal entry behind
+        assert!(settle(&mut manager, trade(a, b, 10, 1_000)).is_err());
+        assert_eq!(manager.last_journal_entry().unwrap().unwrap().sequence, 5);
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_replay_from_genesis_and_checkpoint_matches_snapshot() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 10, 1)).unwrap();
+
+        let outcome = manager.verify_snapshot().unwrap();
+        assert_eq!(outcome.entries_replayed, 5);
+        assert_eq!(outcome.root, manager.get_merkle_root());
+
+        let checkpoint = manager.checkpoint_journal().unwrap();
+        settle(&mut manager, trade(a, b, 5, 1)).unwrap();
+        let outcome = manager.replay_journal(Some(&checkpoint)).unwrap();
+        assert_eq!(outcome.entries_replayed, 1);
+        assert_eq!(outcome.root, manager.get_merkle_root());
+        assert_eq!(outcome.user_states[&a].balances, manager.get_user_state(a).unwrap().balances);
+        assert!(manager.verify_snapshot().is_ok());
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_corrupted_snapshot_recovered_from_journal_on_startup() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 10, 1)).unwrap();
+        let root = manager.get_merkle_root();
+        let expected = manager.get_user_state(a).unwrap();
+
+        // Simulate a torn snapshot: user A's record is overwritten out-of-band
+        let mut corrupted = expected.clone();
+        corrupted.balances.insert("USDC".to_string(), 1_000_000);
+        let users_cf = schema::users_cf(&manager.db).unwrap();
+        manager.db.put_cf(users_cf, a, corrupted.encode().unwrap()).unwrap();
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_merkle_root(), root);
+        assert_eq!(reopened.get_user_state(a).unwrap().balances, expected.balances);
+        assert!(reopened.verify_snapshot().is_ok());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_rollback_restores_balances_nonces_and_root() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(1);
+        let (a, b) = funded_pair(&mut manager);
+        let root_at_1 = manager.get_merkle_root();
+        let a_at_1 = manager.get_user_state(a).unwrap();
+
+        manager.set_block_height(2);
+        settle(&mut manager, trade(a, b, 100, 2)).unwrap();
+        manager.set_block_height(3);
+        manager.register_user([0xCC; 32]).unwrap();
+
+        let orphaned = manager.rollback_to_height(2).unwrap();
+        assert_eq!(orphaned.len(), 2);
+        assert_eq!(manager.get_block_height(), 1);
+        assert_eq!(manager.get_merkle_root(), root_at_1);
+        let a_now = manager.get_user_state(a).unwrap();
+        assert_eq!(a_now.balances, a_at_1.balances);
+        assert_eq!(a_now.nonce, a_at_1.nonce);
+        assert!(manager.get_user_state([0xCC; 32]).is_none());
+
+        // History and journal no longer mention the orphaned blocks
+        assert_eq!(manager.get_root_at_block(3).unwrap().unwrap().root, root_at_1);
+        assert_eq!(manager.last_journal_entry().unwrap().unwrap().root_after, root_at_1);
+        assert!(manager.verify_snapshot().is_ok());
+
+        drop(manager);
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_merkle_root(), root_at_1);
+        assert_eq!(reopened.get_user_count(), 2);
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_rollback_repoints_root_index_at_surviving_record() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(1);
+        funded_pair(&mut manager);
+        let root = manager.get_merkle_root();
+        let sequence = manager.get_root_record(&root).unwrap().unwrap().sequence;
+
+        // A commit that touches no user keeps the root, taking over its index
+        manager.set_block_height(2);
+        let keeps_root = StateTransition::PrivateTransfer {
+            nullifiers: Vec::new(),
+            output_commitments: Vec::new(),
+            anchor: [0u8; 32],
+        };
+        manager.commit_staged(&keeps_root, Vec::new()).unwrap();
+        assert_eq!(manager.get_merkle_root(), root);
+        assert_eq!(manager.get_root_record(&root).unwrap().unwrap().sequence, sequence + 1);
+
+        // Rolling it back hands the root back to the record that first produced it
+        assert_eq!(manager.rollback_to_height(2).unwrap().len(), 1);
+        assert_eq!(manager.get_root_record(&root).unwrap().unwrap().sequence, sequence);
+        assert!(manager.get_user_state_at_root([0xAA; 32], &root).unwrap().is_some());
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_rollback_refuses_final_blocks() {
+        let (mut manager, path) = temp_manager();
+        manager.set_finality_depth(2);
+        manager.set_block_height(1);
+        let (a, b) = funded_pair(&mut manager);
+        manager.set_block_height(10);
+        settle(&mut manager, trade(a, b, 5, 1)).unwrap();
+        let root = manager.get_merkle_root();
+
+        assert!(manager.rollback_to_height(5).is_err());
+        assert_eq!(manager.get_merkle_root(), root);
+        assert_eq!(manager.get_block_height(), 10);
+        assert_eq!(manager.rollback_to_height(9).unwrap().len(), 1);
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_reorg_applies_new_canonical_transitions() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(1);
+        let (a, b) = funded_pair(&mut manager);
+        manager.set_block_height(2);
+        settle(&mut manager, trade(a, b, 100, 2)).unwrap();
+
+        let orphaned = manager
+            .handle_reorg(2, vec![(2, withdraw(a, 30, [0x11; 32])), (3, withdraw(a, 20, [0x12; 32]))])
+            .unwrap();
+        assert_eq!(orphaned.len(), 1);
+        assert_eq!(manager.get_block_height(), 3);
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("USDC"), 950);
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("RWA-CREDIT"), 10);
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 0);
+        assert_eq!(manager.get_root_at_block(3).unwrap().unwrap().root, manager.get_merkle_root());
+        assert!(manager.verify_snapshot().is_ok());
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    fn withdraw(user: [u8; 32], amount: u128, nullifier: [u8; 32]) -> StateTransition {
+        StateTransition::Withdrawal {
+            user_sdkey_hash: user,
+            token_id: "USDC".to_string(),
+            amount,
+            nullifier: Some(nullifier),
+            fee: 0,
+            proof: None,
+        }
+    }
+
+    #[test]
+    fn test_spent_nullifier_rejected_and_persisted() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let empty_nullifier_root = manager.get_nullifier_root();
+
+        manager.apply_transition(withdraw(a, 100, [0x11; 32])).unwrap();
+        assert!(manager.is_nullifier_spent(&[0x11; 32]));
+        let nullifier_root = manager.get_nullifier_root();
+        assert_ne!(nullifier_root, empty_nullifier_root);
+        let record = manager.get_root_record(&manager.get_merkle_root()).unwrap().unwrap();
+        assert_eq!(record.nullifier_root, nullifier_root);
+
+        // Reusing the nullifier is rejected before any state changes
+        let root = manager.get_merkle_root();
+        let result = manager.apply_transition(withdraw(a, 100, [0x11; 32]));
+        assert!(matches!(result, Err(CloakError::NullifierSpent(_))));
+        let mut repeated = trade(a, b, 10, 1);
+        if let StateTransition::Trade { nullifiers, .. } = &mut repeated {
+            *nullifiers = vec![[0x22; 32], [0x22; 32]];
+        }
+        assert!(matches!(settle(&mut manager, repeated), Err(CloakError::NullifierSpent(_))));
+        assert_eq!(manager.get_merkle_root(), root);
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("USDC"), 900);
+        assert!(!manager.is_nullifier_spent(&[0x22; 32]));
+
+        // A failed transition does not burn its nullifier
+        let result = manager.apply_transition(withdraw(a, 10_000, [0x33; 32]));
+        assert!(matches!(result, Err(CloakError::InsufficientBalance { .. })));
+        assert!(!manager.is_nullifier_spent(&[0x33; 32]));
+
+        drop(manager);
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert!(reopened.is_nullifier_spent(&[0x11; 32]));
+        assert_eq!(reopened.get_nullifier_root(), nullifier_root);
+        assert!(verify_proof(&reopened.generate_nullifier_proof([0x11; 32]).unwrap(), &nullifier_root));
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_rollback_unspends_orphaned_nullifiers() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(1);
+        let (a, _) = funded_pair(&mut manager);
+        let nullifier_root = manager.get_nullifier_root();
+
+        manager.set_block_height(2);
+        manager.apply_transition(withdraw(a, 100, [0x11; 32])).unwrap();
+        manager.rollback_to_height(2).unwrap();
+        assert!(!manager.is_nullifier_spent(&[0x11; 32]));
+        assert_eq!(manager.get_nullifier_root(), nullifier_root);
+
+        // The note can be spent again on the new canonical chain
+        manager.apply_transition(withdraw(a, 100, [0x11; 32])).unwrap();
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_shield_needs_opening_and_unbacked_spends_refused() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let (alice_key, bob_key) = ([0x51u8; 32], [0x52u8; 32]);
+
+        let alice_note = Note::new("USDC", 300, notes::owner_key(&alice_key), [1u8; 32]);
+        let shield = |opening: Option<Note>| StateTransition::Shield {
+            user_sdkey_hash: a,
+            token_id: "USDC".to_string(),
+            amount: 300,
+            commitment: alice_note.commitment(),
+            opening,
+        };
+
+        // The commitment must open to exactly the shielded amount
+        let inflated = Note::new("USDC", 3_000, notes::owner_key(&alice_key), [1u8; 32]);
+        assert!(manager.apply_transition(shield(None)).is_err());
+        assert!(manager.apply_transition(shield(Some(inflated))).is_err());
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("USDC"), 1_000);
+
+        manager.apply_transition(shield(Some(alice_note.clone()))).unwrap();
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("USDC"), 700);
+        assert!(manager.generate_note_proof(0).unwrap().verify(&manager.get_note_root()));
+
+        // Spending against an anchor the node never produced is rejected
+        let bob_note = Note::new("USDC", 300, notes::owner_key(&bob_key), [2u8; 32]);
+        let alice_nullifier = alice_note.nullifier(&alice_key).unwrap();
+        let transfer = |anchor| StateTransition::PrivateTransfer {
+            nullifiers: vec![alice_nullifier],
+            output_commitments: vec![bob_note.commitment()],
+            anchor,
+        };
+        assert!(matches!(manager.apply_transition(transfer([0xEE; 32])), Err(CloakError::State(_))));
+
+        // Without a spend proof nothing backs a spend, so none is accepted
+        let anchor = manager.get_note_root();
+        assert!(matches!(
+            manager.apply_transition(transfer(anchor)),
+            Err(CloakError::ProofVerification(_))
+        ));
+        assert!(!manager.is_nullifier_spent(&alice_nullifier));
+        assert_eq!(manager.get_note_count(), 1);
+
+        // An unshield of a note nobody created would mint public balance
+        let unbacked = StateTransition::Unshield {
+            user_sdkey_hash: b,
+            token_id: "USDC".to_string(),
+            amount: 1_000_000,
+            nullifier: [0x77; 32],
+            anchor,
+        };
+        assert!(matches!(manager.apply_transition(unbacked), Err(CloakError::ProofVerification(_))));
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 0);
+        assert!(!manager.is_nullifier_spent(&[0x77; 32]));
+        assert!(manager.verify_snapshot().is_ok());
+
+        let note_root = manager.get_note_root();
+        drop(manager);
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_note_root(), note_root);
+        assert_eq!(reopened.get_note_count(), 1);
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_rollback_drops_orphaned_note_commitments() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(1);
+        let (a, _) = funded_pair(&mut manager);
+        let empty_note_root = manager.get_note_root();
+
+        manager.set_block_height(2);
+        let note = Note::new("USDC", 10, notes::owner_key(&[0x51; 32]), [1u8; 32]);
+        manager.apply_transition(StateTransition::Shield {
+            user_sdkey_hash: a,
+            token_id: "USDC".to_string(),
+            amount: 10,
+            commitment: note.commitment(),
+            opening: Some(note.clone()),
+        }).unwrap();
+        let orphaned_anchor = manager.get_note_root();
+
+        manager.rollback_to_height(2).unwrap();
+        assert_eq!(manager.get_note_count(), 0);
+        assert_eq!(manager.get_note_root(), empty_note_root);
+        assert!(!manager.is_known_note_anchor(&orphaned_anchor).unwrap());
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_legacy_json_records_migrated_on_startup() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let root = manager.get_merkle_root();
+
+        // Rewrite the latest records in the pre-binary JSON format
+        for sdkey_hash in [a, b] {
+            let user_state = manager.get_user_state(sdkey_hash).unwrap();
+            let users_cf = schema::users_cf(&manager.db).unwrap();
+            manager.db.put_cf(users_cf, sdkey_hash, serde_json::to_vec(&user_state).unwrap()).unwrap();
+        }
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        let stored = reopened.db.get_cf(schema::users_cf(&reopened.db).unwrap(), a).unwrap().unwrap();
+        assert!(!encoding::is_legacy_encoding(&stored));
+        assert_eq!(UserState::decode(&stored).unwrap().get_balance("USDC"), 1_000);
+        assert_eq!(reopened.get_merkle_root(), root);
+        assert!(reopened.verify_snapshot().is_ok());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_schema_version_written_and_mismatch_refused() {
+        let (manager, path) = temp_manager();
+        assert_eq!(manager.get_schema_version().unwrap(), Some(schema::SCHEMA_VERSION));
+
+        let metadata = schema::metadata_cf(&manager.db).unwrap();
+        manager
+            .db
+            .put_cf(metadata, schema::SCHEMA_VERSION_KEY, (schema::SCHEMA_VERSION + 1).to_be_bytes())
+            .unwrap();
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap());
+        assert!(matches!(reopened, Err(CloakError::Config(_))));
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_merkle_nodes_persisted_and_reloaded() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 10, 1)).unwrap();
+        let root = manager.get_merkle_root();
+        let merkle = schema::merkle_cf(&manager.db).unwrap();
+        assert!(manager.db.iterator_cf(merkle, IteratorMode::Start).next().is_some());
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_merkle_root(), root);
+        assert!(reopened.verify_snapshot().is_ok());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_unversioned_default_keyspace_migrated_on_startup() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let root = manager.get_merkle_root();
+
+        // Move everything back into the pre-split layout
+        let users = schema::users_cf(&manager.db).unwrap();
+        let roots = schema::roots_cf(&manager.db).unwrap();
+        let merkle = schema::merkle_cf(&manager.db).unwrap();
+        let mut batch = WriteBatch::default();
+        for item in manager.db.iterator_cf(users, IteratorMode::Start) {
+            let (key, value) = item.unwrap();
+            batch.put(format!("{}{}", schema::LEGACY_USER_PREFIX, hex::encode(&key)), &value);
+            batch.delete_cf(users, &key);
+        }
+        for item in manager.db.iterator_cf(roots, IteratorMode::Start) {
+            let (key, value) = item.unwrap();
+            batch.put(&key, &value);
+            batch.delete_cf(roots, &key);
+        }
+        for item in manager.db.iterator_cf(merkle, IteratorMode::Start) {
+            batch.delete_cf(merkle, item.unwrap().0);
+        }
+        batch.delete_cf(schema::metadata_cf(&manager.db).unwrap(), schema::SCHEMA_VERSION_KEY);
+        manager.db.write(batch).unwrap();
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_schema_version().unwrap(), Some(schema::SCHEMA_VERSION));
+        assert_eq!(reopened.get_merkle_root(), root);
+        assert_eq!(reopened.get_user_state(b).unwrap().get_balance("RWA-CREDIT"), 10);
+        assert!(reopened.get_user_state(a).is_some());
+        assert!(reopened.db.iterator(IteratorMode::Start).next().is_none());
+        assert!(reopened.verify_snapshot().is_ok());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_evicted_users_read_through_from_rocksdb() {
+        let (mut manager, path) = temp_manager();
+        manager.set_user_cache_capacity(1);
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 10, 1)).unwrap();
+        assert_eq!(manager.get_user_cache_stats().len, 1);
+
+        // A was evicted by B, so this lookup misses and reloads it
+        let before = manager.get_user_cache_stats();
+        let user_a = manager.get_user_state(a).unwrap();
+        assert_eq!(user_a.get_balance("RWA-CREDIT"), 1);
+        assert_eq!(manager.get_user_cache_stats().misses, before.misses + 1);
+        assert!(manager.get_user_state(a).is_some());
+        assert_eq!(manager.get_user_cache_stats().hits, before.hits + 1);
+
+        // Transitions on evicted users still stage against the stored state
+        settle(&mut manager, trade(b, a, 1, 1)).unwrap();
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("RWA-CREDIT"), 10);
+        assert_eq!(manager.get_user_count(), 2);
+        assert!(manager.verify_snapshot().is_ok());
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_supply_counters_follow_transitions_and_rollbacks() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(1);
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 100, 2)).unwrap();
+        assert_eq!(manager.get_token_supply("USDC").public_supply(), Some(1_000));
+
+        manager.set_block_height(2);
+        manager.apply_transition(StateTransition::Withdrawal {
+            user_sdkey_hash: b,
+            token_id: "USDC".to_string(),
+            amount: 40,
+            nullifier: None,
+            fee: 0,
+            proof: None,
+        }).unwrap();
+        assert_eq!(manager.get_token_supply("USDC").withdrawn, 40);
+        assert!(manager.audit().unwrap().is_clean());
+
+        // A failed transition leaves the counters untouched
+        assert!(manager.apply_transition(StateTransition::Withdrawal {
+            user_sdkey_hash: b,
+            token_id: "USDC".to_string(),
+            amount: 1_000,
+            nullifier: None,
+            fee: 0,
+            proof: None,
+        }).is_err());
+        assert_eq!(manager.get_token_supply("USDC").withdrawn, 40);
+
+        manager.rollback_to_height(2).unwrap();
+        assert_eq!(manager.get_token_supply("USDC").withdrawn, 0);
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_token_supply("USDC").public_supply(), Some(1_000));
+        assert!(reopened.audit().unwrap().is_clean());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_audit_reports_minted_value_and_root_mismatch() {
+        let (mut manager, path) = temp_manager();
+        let (a, _) = funded_pair(&mut manager);
+
+        // Simulate a buggy write that credits value out of thin air
+        let mut minted = manager.get_user_state(a).unwrap();
+        minted.update_balance("USDC".to_string(), 1_500);
+        let users_cf = schema::users_cf(&manager.db).unwrap();
+        manager.db.put_cf(users_cf, a, minted.encode().unwrap()).unwrap();
+
+        let report = manager.audit().unwrap();
+        assert!(!report.is_clean());
+        let usdc = report.tokens.iter().find(|token| token.token_id == "USDC").unwrap();
+        assert_eq!((usdc.expected, usdc.actual), (Some(1_000), 1_500));
+        assert_ne!(report.recomputed_root, report.state_root);
+        assert_eq!(report.discrepancies.len(), 2);
+
+        drop(manager);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_transitions_reject_unknown_and_frozen_tokens() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let root = manager.get_merkle_root();
+
+        let typo = StateTransition::Deposit {
+            user_sdkey_hash: a,
+            token_id: "usdc".to_string(),
+            amount: 1,
+        };
+        assert!(matches!(manager.apply_transition(typo), Err(CloakError::InvalidInput(_))));
+
+        manager.set_token_status("RWA-CREDIT", TokenStatus::Frozen).unwrap();
+        assert!(settle(&mut manager, trade(a, b, 10, 1)).is_err());
+        assert_eq!(manager.get_merkle_root(), root);
+        drop(manager);
+
+        // The registry survives a restart, including the frozen status
+        let mut reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.list_tokens().len(), 2);
+        assert_eq!(reopened.get_token("RWA-CREDIT").unwrap().status, TokenStatus::Frozen);
+        reopened.set_token_status("RWA-CREDIT", TokenStatus::Active).unwrap();
+        settle(&mut reopened, trade(a, b, 10, 1)).unwrap();
+        assert!(reopened.register_token(test_token("USDC", AssetClass::Treasury)).is_err());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_transfer_policy_rules_gate_every_transfer() {
+        let (mut manager, path) = temp_manager();
+        let (a, b) = funded_pair(&mut manager);
+        let c = [0xCCu8; 32];
+        manager.register_user(c).unwrap();
+        let rule_of = |result: CloakResult<()>| match result {
+            Err(CloakError::TransferRestricted { rule, .. }) => Some(rule),
+            _ => None,
+        };
+        let deposit = |user_sdkey_hash: [u8; 32], amount: u128| StateTransition::Deposit {
+            user_sdkey_hash,
+            token_id: "RWA-CREDIT".to_string(),
+            amount,
+        };
+
+        // Whitelist: only B may receive RWA-CREDIT
+        manager
+            .set_transfer_policy(
+                "RWA-CREDIT",
+                TransferPolicy {
+                    whitelist: Some([hex::encode(b)].into()),
+                    ..TransferPolicy::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(rule_of(settle(&mut manager, trade(a, b, 100, 1))), Some(RestrictionRule::Whitelist));
+
+        // Jurisdiction: recipients need a recorded, unblocked jurisdiction
+        manager
+            .set_transfer_policy(
+                "RWA-CREDIT",
+                TransferPolicy {
+                    blocked_jurisdictions: ["KP".to_string()].into(),
+                    ..TransferPolicy::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(rule_of(manager.apply_transition(deposit(a, 1))), Some(RestrictionRule::Jurisdiction));
+        manager.set_user_jurisdiction(a, "kp").unwrap();
+        assert_eq!(rule_of(manager.apply_transition(deposit(a, 1))), Some(RestrictionRule::Jurisdiction));
+        manager.set_user_jurisdiction(a, "US").unwrap();
+        manager.set_user_jurisdiction(c, "US").unwrap();
+
+        // Lockup: C cannot pass RWA-CREDIT on until 10 blocks after acquiring it
+        manager
+            .set_transfer_policy(
+                "RWA-CREDIT",
+                TransferPolicy {
+                    lockup_blocks: 10,
+                    max_holders: Some(2),
+                    ..TransferPolicy::default()
+                },
+            )
+            .unwrap();
+        manager.set_block_height(100);
+        manager.apply_transition(deposit(c, 5)).unwrap();
+        let c_sells = StateTransition::Trade {
+            user_a_sdkey_hash: c,
+            user_b_sdkey_hash: b,
+            token_a_id: "RWA-CREDIT".to_string(),
+            token_b_id: "USDC".to_string(),
+            amount_a: 5,
+            amount_b: 0,
+            nullifiers: Vec::new(),
+            maker_fee: 0,
+            taker_fee: 0,
+            settlement: None,
+        };
+        manager.set_block_height(109);
+        assert_eq!(rule_of(settle(&mut manager, c_sells.clone())), Some(RestrictionRule::Lockup));
+
+        // Withdrawing or shielding it passes it on just the same
+        let c_withdraws = StateTransition::Withdrawal {
+            user_sdkey_hash: c,
+            token_id: "RWA-CREDIT".to_string(),
+            amount: 1,
+            nullifier: Some([0xC1; 32]),
+            fee: 0,
+            proof: None,
+        };
+        assert_eq!(rule_of(manager.apply_transition(c_withdraws)), Some(RestrictionRule::Lockup));
+        let note = Note::new("RWA-CREDIT", 1, notes::owner_key(&[0xC2; 32]), [2u8; 32]);
+        let c_shields = StateTransition::Shield {
+            user_sdkey_hash: c,
+            token_id: "RWA-CREDIT".to_string(),
+            amount: 1,
+            commitment: note.commitment(),
+            opening: Some(note),
+        };
+        assert_eq!(rule_of(manager.apply_transition(c_shields)), Some(RestrictionRule::Lockup));
+
+        // An acquisition rolled back by a reorg no longer restarts the lockup
+        manager.apply_transition(deposit(c, 1)).unwrap();
+        manager.rollback_to_height(109).unwrap();
+        manager.set_block_height(109);
+
+        // Max holders: B and C already hold it, A would be a third holder
+        assert_eq!(manager.get_holder_count("RWA-CREDIT"), 2);
+        assert_eq!(rule_of(manager.apply_transition(deposit(a, 1))), Some(RestrictionRule::MaxHolders));
+
+        // Once unlocked, C can exit, which frees a slot for A; B's lockup
+        // restarts because it just acquired more
+        manager.set_block_height(110);
+        settle(&mut manager, c_sells).unwrap();
+        assert_eq!(manager.get_holder_count("RWA-CREDIT"), 1);
+        assert_eq!(rule_of(settle(&mut manager, trade(a, b, 100, 1))), Some(RestrictionRule::Lockup));
+        manager.set_block_height(120);
+        settle(&mut manager, trade(a, b, 100, 1)).unwrap();
+        assert_eq!(manager.get_holder_count("RWA-CREDIT"), 2);
+        drop(manager);
+
+        // Policies, jurisdictions and holder counts survive a restart
+        let mut reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_transfer_policy("RWA-CREDIT").max_holders, Some(2));
+        assert_eq!(reopened.get_user_jurisdiction(&a).unwrap().as_deref(), Some("US"));
+        assert_eq!(reopened.get_holder_count("RWA-CREDIT"), 2);
+        assert_eq!(rule_of(reopened.apply_transition(deposit(c, 1))), Some(RestrictionRule::MaxHolders));
+        assert!(reopened.set_transfer_policy("UNKNOWN", TransferPolicy::default()).is_err());
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_distribution_pays_holders_at_record_height() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(5);
+        let (a, b) = funded_pair(&mut manager);
+        settle(&mut manager, trade(a, b, 100, 4)).unwrap();
+
+        // After the record height B sells the rest, which must not count
+        manager.set_block_height(8);
+        settle(&mut manager, trade(a, b, 100, 6)).unwrap();
+        let action = CorporateAction {
+            token_id: "RWA-CREDIT".to_string(),
+            payout_token_id: "USDC".to_string(),
+            record_height: 5,
+            total_payout: 1_001,
+            rounding: RoundingPolicy::Floor,
+        };
+        manager.set_block_height(9);
+        let report = manager.distribute(&action).unwrap();
+
+        // 4/10 and 6/10 of 1001, rounded down
+        assert_eq!(report.eligible_supply, 10);
+        let paid: Vec<_> = report.entitlements.iter().map(|e| (e.sdkey_hash, e.amount)).collect();
+        assert_eq!(paid, vec![(a, 400), (b, 600)]);
+        assert_eq!((report.distributed, report.remainder), (1_000, 1));
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("USDC"), 800 + 400);
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 200 + 600);
+        assert_eq!(manager.get_token_supply("USDC").distributed, 1_000);
+        assert!(manager.audit().unwrap().is_clean());
+
+        // Replays and altered reports are refused
+        let replay = StateTransition::Distribution { report: report.clone() };
+        assert!(manager.apply_transition(replay).is_err());
+        let mut altered = manager.compute_distribution(&CorporateAction { total_payout: 2_002, ..action.clone() }).unwrap();
+        altered.entitlements[0].amount += 1;
+        altered.report_hash = altered.compute_hash();
+        assert!(manager.apply_transition(StateTransition::Distribution { report: altered }).is_err());
+
+        // Largest remainder hands the leftover unit to B (0.6 > 0.4)
+        let largest = manager
+            .compute_distribution(&CorporateAction { rounding: RoundingPolicy::LargestRemainder, ..action.clone() })
+            .unwrap();
+        assert_eq!(largest.entitlements[1].amount, 601);
+        assert_eq!(largest.remainder, 0);
+        drop(manager);
+
+        // The report stays retrievable by hash and is dropped by a rollback
+        let mut reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_distribution_report(&report.report_hash).unwrap(), Some(report.clone()));
+        reopened.rollback_to_height(9).unwrap();
+        assert_eq!(reopened.get_distribution_report(&report.report_hash).unwrap(), None);
+        assert_eq!(reopened.get_token_supply("USDC").distributed, 0);
+        assert_eq!(reopened.get_user_state(a).unwrap().get_balance("USDC"), 800);
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
+    }
+
+    #[test]
+    fn test_fees_move_to_protocol_account_and_ledger() {
+        let (mut manager, path) = temp_manager();
+        manager.set_block_height(3);
+        let (a, b) = funded_pair(&mut manager);
+        let usdc = FeeSchedule { maker_bps: 0, taker_bps: 30, withdrawal_bps: 50 };
+        manager.set_fee_schedule("USDC", usdc).unwrap();
+        manager.set_fee_schedule("RWA-CREDIT", FeeSchedule { maker_bps: 100, ..FeeSchedule::default() }).unwrap();
+        assert!(manager.set_fee_schedule("UNKNOWN", usdc).is_err());
+
+        // A (maker) pays 1% of 10 RWA-CREDIT rounded up, B (taker) 0.3% of 1000 USDC
+        manager.set_block_height(4);
+        settle(&mut manager, trade(a, b, 1_000, 10)).unwrap();
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("RWA-CREDIT"), 9);
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 997);
+
+        // The withdrawal fee is charged on top, whatever the caller asked for
+        manager.set_block_height(5);
+        manager
+            .apply_transition(StateTransition::Withdrawal {
+                user_sdkey_hash: b,
+                token_id: "USDC".to_string(),
+                amount: 100,
+                nullifier: None,
+                fee: 0,
+                proof: None,
+            })
+            .unwrap();
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 896);
+
+        let fees = manager.get_fee_balances();
+        assert_eq!(fees.get("USDC"), Some(&4));
+        assert_eq!(fees.get("RWA-CREDIT"), Some(&1));
+        let ledger = manager.get_fee_ledger(0, 10).unwrap();
+        let charged: Vec<_> = ledger.iter().map(|e| (e.kind, e.token_id.as_str(), e.amount)).collect();
+        assert_eq!(
+            charged,
+            vec![
+                (FeeKind::Maker, "RWA-CREDIT", 1),
+                (FeeKind::Taker, "USDC", 3),
+                (FeeKind::Withdrawal, "USDC", 1),
+            ]
+        );
+        assert_eq!(manager.get_fee_ledger(ledger[2].sequence, 10).unwrap().len(), 1);
+        // Fees only move value, and the fee account is not a token holder
+        assert!(manager.audit().unwrap().is_clean());
+        assert_eq!(manager.get_holder_count("RWA-CREDIT"), 1);
+
+        // Rolling back the withdrawal refunds its fee and drops its ledger entry
+        manager.rollback_to_height(5).unwrap();
+        assert_eq!(manager.get_fee_balances().get("USDC"), Some(&3));
+        assert_eq!(manager.get_fee_ledger(0, 10).unwrap().len(), 2);
+        drop(manager);
+
+        let reopened = StateManager::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.get_fee_schedule("USDC"), usdc);
+        assert_eq!(reopened.get_fee_balances().get("USDC"), Some(&3));
+
+        drop(reopened);
+        std::fs::remove_dir_all(path).ok();
     }
 }
@@ -0,0 +1,485 @@
+//! Shielded Notes
+//!
+//! Note-commitment (UTXO) model that runs alongside the account balances in
+//! `UserState`. A note is a commitment `H(asset || amount || owner_key ||
+//! blinding)` appended to an append-only Poseidon commitment tree; the node
+//! only ever stores the commitment. Spending a note reveals its nullifier,
+//! `H(spending_key || commitment)`, which goes into the nullifier set, plus
+//! an anchor: a commitment-tree root the spend was proven against.
+//!
+//! Notes enter the pool with `Shield` (debiting an account balance), move
+//! with `PrivateTransfer`, and leave with `Unshield` (crediting an account
+//! balance). A shield reveals the opening of its note, binding the
+//! commitment to the debited amount. Spends are refused until the node can
+//! verify a proof that the spent notes exist and cover the outputs.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{poseidon, schema, StateManager, StateTransition};
+use ark_bls12_381::Fr;
+use ark_ff::Zero;
+use rocksdb::{ColumnFamily, IteratorMode, WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Depth of the note-commitment tree (2^32 notes)
+pub const NOTE_TREE_DEPTH: usize = 32;
+
+/// Gets the note column family handle
+pub(super) fn notes_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    schema::column_family(db, schema::NOTES_CF)
+}
+
+/// Derives the public owner key for a spending key: `H(spending_key)`
+pub fn owner_key(spending_key: &[u8; 32]) -> [u8; 32] {
+    poseidon::field_to_bytes(&poseidon::hash(&[poseidon::bytes_to_field(spending_key)]))
+}
+
+/// Opening of a shielded note, known only to its owner
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Note {
+    /// Asset (token ID) held by the note
+    pub token_id: String,
+
+    /// Amount held by the note
+    pub amount: u128,
+
+    /// Owner key, derived from the owner's spending key with `owner_key`
+    pub owner_key: [u8; 32],
+
+    /// Random blinding factor that hides the note contents
+    pub blinding: [u8; 32],
+}
+
+impl Note {
+    /// Creates a note opening
+    pub fn new(token_id: impl Into<String>, amount: u128, owner_key: [u8; 32], blinding: [u8; 32]) -> Self {
+        Self {
+            token_id: token_id.into(),
+            amount,
+            owner_key,
+            blinding,
+        }
+    }
+
+    /// Computes the note commitment: `H(asset || amount || owner_key || blinding)`
+    pub fn commitment(&self) -> [u8; 32] {
+        poseidon::field_to_bytes(&poseidon::hash(&[
+            poseidon::string_to_field(&self.token_id),
+            Fr::from(self.amount),
+            poseidon::bytes_to_field(&self.owner_key),
+            poseidon::bytes_to_field(&self.blinding),
+        ]))
+    }
+
+    /// Computes the nullifier revealed when the note is spent
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if `spending_key` does not own the note.
+    pub fn nullifier(&self, spending_key: &[u8; 32]) -> CloakResult<[u8; 32]> {
+        if owner_key(spending_key) != self.owner_key {
+            return Err(CloakError::invalid_input("Spending key does not own this note"));
+        }
+        Ok(poseidon::field_to_bytes(&poseidon::hash_two(
+            poseidon::bytes_to_field(spending_key),
+            poseidon::bytes_to_field(&self.commitment()),
+        )))
+    }
+}
+
+/// Authentication path for a note commitment at a tree position
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteProof {
+    /// Position of the commitment in the tree
+    pub position: u64,
+
+    /// The note commitment
+    pub commitment: [u8; 32],
+
+    /// Sibling hashes from the leaf level up to just below the root
+    pub merkle_path: Vec<[u8; 32]>,
+
+    /// Direction bits for each level (`true` = running node is the right child)
+    pub merkle_path_indices: Vec<bool>,
+
+    /// Commitment-tree root the proof authenticates against
+    pub root: [u8; 32],
+}
+
+impl NoteProof {
+    /// Checks that the path hashes the commitment up to `root`
+    pub fn verify(&self, root: &[u8; 32]) -> bool {
+        if self.root != *root || self.merkle_path.len() != self.merkle_path_indices.len() {
+            return false;
+        }
+        let indices_match = self
+            .merkle_path_indices
+            .iter()
+            .enumerate()
+            .all(|(level, is_right)| ((self.position >> level) & 1 == 1) == *is_right);
+        if !indices_match {
+            return false;
+        }
+
+        let mut current = poseidon::bytes_to_field(&self.commitment);
+        for (sibling, is_right) in self.merkle_path.iter().zip(&self.merkle_path_indices) {
+            let sibling = poseidon::bytes_to_field(sibling);
+            current = if *is_right {
+                poseidon::hash_two(sibling, current)
+            } else {
+                poseidon::hash_two(current, sibling)
+            };
+        }
+        poseidon::field_to_bytes(&current) == *root
+    }
+}
+
+/// Append-only Poseidon Merkle tree of note commitments
+#[derive(Debug, Clone)]
+pub struct CommitmentTree {
+    /// Number of levels between the leaves and the root
+    depth: usize,
+
+    /// Roots of empty subtrees, indexed by height (0 = leaf)
+    empty_hashes: Vec<Fr>,
+
+    /// Non-empty nodes keyed by (height, index within level)
+    nodes: HashMap<(usize, u64), Fr>,
+
+    /// Number of commitments appended so far
+    size: u64,
+}
+
+impl CommitmentTree {
+    /// Creates an empty tree with the default depth
+    pub fn new() -> Self {
+        Self::with_depth(NOTE_TREE_DEPTH)
+    }
+
+    /// Creates an empty tree with a custom depth
+    ///
+    /// # Panics
+    /// Panics if `depth` is zero or greater than 63.
+    pub fn with_depth(depth: usize) -> Self {
+        assert!(depth > 0 && depth < 64, "note tree depth must be between 1 and 63");
+
+        let mut empty_hashes = Vec::with_capacity(depth + 1);
+        empty_hashes.push(Fr::zero());
+        for height in 0..depth {
+            let child = empty_hashes[height];
+            empty_hashes.push(poseidon::hash_two(child, child));
+        }
+
+        Self {
+            depth,
+            empty_hashes,
+            nodes: HashMap::new(),
+            size: 0,
+        }
+    }
+
+    /// Appends a commitment, returning its position
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if the tree is full.
+    pub fn append(&mut self, commitment: &[u8; 32]) -> CloakResult<u64> {
+        if self.size >= 1u64 << self.depth {
+            return Err(CloakError::state("Note commitment tree is full"));
+        }
+        let position = self.size;
+        self.set_path(position, poseidon::bytes_to_field(commitment));
+        self.size += 1;
+        Ok(position)
+    }
+
+    /// Drops every commitment at or after `len`
+    pub fn truncate(&mut self, len: u64) {
+        while self.size > len {
+            self.size -= 1;
+            self.set_path(self.size, self.empty_hashes[0]);
+        }
+    }
+
+    /// Gets the commitment at a position
+    pub fn get(&self, position: u64) -> Option<[u8; 32]> {
+        (position < self.size).then(|| poseidon::field_to_bytes(&self.node(0, position)))
+    }
+
+    /// Gets the current root hash
+    pub fn get_root(&self) -> [u8; 32] {
+        poseidon::field_to_bytes(&self.node(self.depth, 0))
+    }
+
+    /// Generates the authentication path for the commitment at a position
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if no commitment exists there.
+    pub fn prove(&self, position: u64) -> CloakResult<NoteProof> {
+        let commitment = self
+            .get(position)
+            .ok_or_else(|| CloakError::invalid_input(format!("No note commitment at position {}", position)))?;
+
+        let mut merkle_path = Vec::with_capacity(self.depth);
+        let mut merkle_path_indices = Vec::with_capacity(self.depth);
+        let mut index = position;
+        for height in 0..self.depth {
+            merkle_path.push(poseidon::field_to_bytes(&self.node(height, index ^ 1)));
+            merkle_path_indices.push(index & 1 == 1);
+            index >>= 1;
+        }
+
+        Ok(NoteProof {
+            position,
+            commitment,
+            merkle_path,
+            merkle_path_indices,
+            root: self.get_root(),
+        })
+    }
+
+    /// Gets the number of commitments in the tree
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Checks whether the tree has no commitments
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Gets a node by height and index, falling back to the empty subtree hash
+    fn node(&self, height: usize, index: u64) -> Fr {
+        self.nodes
+            .get(&(height, index))
+            .copied()
+            .unwrap_or(self.empty_hashes[height])
+    }
+
+    /// Writes a leaf and recomputes every ancestor up to the root
+    fn set_path(&mut self, index: u64, leaf: Fr) {
+        let mut index = index;
+        let mut current = leaf;
+        for height in 0..=self.depth {
+            if current == self.empty_hashes[height] {
+                self.nodes.remove(&(height, index));
+            } else {
+                self.nodes.insert((height, index), current);
+            }
+            if height == self.depth {
+                break;
+            }
+            let sibling = self.node(height, index ^ 1);
+            current = if index & 1 == 0 {
+                poseidon::hash_two(current, sibling)
+            } else {
+                poseidon::hash_two(sibling, current)
+            };
+            index >>= 1;
+        }
+    }
+}
+
+impl Default for CommitmentTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateTransition {
+    /// Gets the note commitments this transition appends, in order
+    pub fn note_commitments(&self) -> Vec<[u8; 32]> {
+        match self {
+            StateTransition::Shield { commitment, .. } => vec![*commitment],
+            StateTransition::PrivateTransfer { output_commitments, .. } => output_commitments.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Gets the commitment-tree root a note spend was proven against
+    pub fn note_anchor(&self) -> Option<[u8; 32]> {
+        match self {
+            StateTransition::PrivateTransfer { anchor, .. } | StateTransition::Unshield { anchor, .. } => {
+                Some(*anchor)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl StateManager {
+    /// Gets the current note-commitment tree root
+    pub fn get_note_root(&self) -> [u8; 32] {
+        self.note_tree.get_root()
+    }
+
+    /// Gets the number of note commitments in the tree
+    pub fn get_note_count(&self) -> u64 {
+        self.note_tree.len()
+    }
+
+    /// Generates the authentication path for a note against the current root
+    pub fn generate_note_proof(&self, position: u64) -> CloakResult<NoteProof> {
+        self.note_tree.prove(position)
+    }
+
+    /// Loads the note commitments from RocksDB and rebuilds their tree
+    pub(super) fn load_notes(&mut self) -> CloakResult<()> {
+        let mut tree = CommitmentTree::new();
+        for item in self.db.iterator_cf(notes_cf(&self.db)?, IteratorMode::Start) {
+            let (key, value) = item?;
+            let position = key
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| CloakError::state("Malformed note position key"))?;
+            let commitment: [u8; 32] = value
+                .as_ref()
+                .try_into()
+                .map_err(|_| CloakError::state(format!("Malformed note commitment at {}", position)))?;
+            if position != tree.len() {
+                return Err(CloakError::state(format!(
+                    "Note commitment gap: expected position {}, found {}",
+                    tree.len(),
+                    position
+                )));
+            }
+            tree.append(&commitment)?;
+        }
+
+        info!(
+            "Loaded {} note commitments, note root: {}",
+            tree.len(),
+            hex::encode(tree.get_root())
+        );
+        self.note_tree = tree;
+        Ok(())
+    }
+
+    /// Checks the note-model rules a transition must satisfy before staging
+    ///
+    /// Commitments must be canonical field encodings, a shield must carry
+    /// the opening of its commitment holding exactly the shielded amount and
+    /// token, spends must reference an anchor this node has produced, and
+    /// private transfers need at least one input and one output.
+    ///
+    /// Spending a note needs a proof that the nullifier derives from a note
+    /// in the tree whose value matches the outputs. This node cannot verify
+    /// one yet, so private transfers and unshields are refused.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for a malformed spend or a missing
+    /// or mismatched opening, `CloakError::ProofVerification` for any spend,
+    /// and `CloakError::State` for an unknown anchor.
+    pub(super) fn check_notes(&self, transition: &StateTransition) -> CloakResult<()> {
+        for commitment in transition.note_commitments() {
+            if poseidon::field_to_bytes(&poseidon::bytes_to_field(&commitment)) != commitment {
+                return Err(CloakError::invalid_input(format!(
+                    "Note commitment is not a canonical field element: {}",
+                    hex::encode(commitment)
+                )));
+            }
+        }
+        if let StateTransition::Shield {
+            token_id,
+            amount,
+            commitment,
+            opening,
+            ..
+        } = transition
+        {
+            let opening = opening
+                .as_ref()
+                .ok_or_else(|| CloakError::invalid_input("Shield carries no opening of its note commitment"))?;
+            if opening.token_id != *token_id || opening.amount != *amount || opening.commitment() != *commitment {
+                return Err(CloakError::invalid_input(format!(
+                    "Note commitment {} does not open to {} of {}",
+                    hex::encode(commitment),
+                    amount,
+                    token_id
+                )));
+            }
+        }
+        if let StateTransition::PrivateTransfer { nullifiers, output_commitments, .. } = transition {
+            if nullifiers.is_empty() || output_commitments.is_empty() {
+                return Err(CloakError::invalid_input(
+                    "Private transfer must spend at least one note and create at least one",
+                ));
+            }
+        }
+        if let Some(anchor) = transition.note_anchor() {
+            if !self.is_known_note_anchor(&anchor)? {
+                return Err(CloakError::state(format!("Unknown note anchor: {}", hex::encode(anchor))));
+            }
+            return Err(CloakError::proof_verification(
+                "Note spends need a spend proof, which this node cannot verify yet",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Appends a transition's note commitments to the tree
+    ///
+    /// Returns the tree size before the append so the caller can truncate
+    /// back to it if the commit fails.
+    pub(super) fn append_note_commitments(&mut self, transition: &StateTransition) -> CloakResult<u64> {
+        let previous_len = self.note_tree.len();
+        for commitment in transition.note_commitments() {
+            if let Err(e) = self.note_tree.append(&commitment) {
+                self.note_tree.truncate(previous_len);
+                return Err(e);
+            }
+        }
+        Ok(previous_len)
+    }
+
+    /// Adds a transition's note commitments to a batch, starting at `first_position`
+    pub(super) fn stage_note_commitments(
+        db: &DB,
+        batch: &mut WriteBatch,
+        transition: &StateTransition,
+        first_position: u64,
+    ) -> CloakResult<()> {
+        let cf = notes_cf(db)?;
+        for (offset, commitment) in transition.note_commitments().iter().enumerate() {
+            batch.put_cf(cf, (first_position + offset as u64).to_be_bytes(), commitment);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_commitment_hides_and_binds_contents() {
+        let owner = owner_key(&[1u8; 32]);
+        let note = Note::new("USDC", 100, owner, [9u8; 32]);
+        assert_ne!(note.commitment(), Note::new("USDC", 101, owner, [9u8; 32]).commitment());
+        assert_ne!(note.commitment(), Note::new("USDC", 100, owner, [8u8; 32]).commitment());
+
+        assert!(note.nullifier(&[1u8; 32]).is_ok());
+        assert!(note.nullifier(&[2u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_commitment_tree_append_prove_and_truncate() {
+        let mut tree = CommitmentTree::with_depth(8);
+        let empty_root = tree.get_root();
+
+        assert_eq!(tree.append(&[1u8; 32]).unwrap(), 0);
+        let root_after_one = tree.get_root();
+        assert_eq!(tree.append(&[2u8; 32]).unwrap(), 1);
+        assert_eq!(tree.append(&[3u8; 32]).unwrap(), 2);
+
+        let proof = tree.prove(1).unwrap();
+        assert!(proof.verify(&tree.get_root()));
+        assert!(!proof.verify(&root_after_one));
+        assert!(tree.prove(3).is_err());
+
+        tree.truncate(1);
+        assert_eq!(tree.get_root(), root_after_one);
+        tree.truncate(0);
+        assert_eq!(tree.get_root(), empty_root);
+    }
+}
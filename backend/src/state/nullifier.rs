@@ -0,0 +1,223 @@
+//! Nullifier Set
+//!
+//! Persistent set of spent-note nullifiers. Every transition that carries a
+//! nullifier is checked against the set before it is staged, and
+//! the nullifiers are inserted in the same `WriteBatch` as the state update.
+//! The set is also kept in its own Poseidon sparse Merkle tree so its root
+//! can be committed on-chain next to the state root, and so wallets can get
+//! non-membership proofs for notes they are about to spend.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{poseidon, schema, MerkleProof, SparseMerkleTree, StateManager, StateTransition};
+use ark_bls12_381::Fr;
+use ark_ff::One;
+use rocksdb::{ColumnFamily, IteratorMode, WriteBatch, DB};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Depth of the nullifier tree
+///
+/// Nullifiers are uniformly random, so the full 64-bit slot space keeps
+/// slot collisions negligible even for billions of spends; colliding
+/// nullifiers share a slot.
+pub const NULLIFIER_TREE_DEPTH: usize = 64;
+
+/// Computes the leaf body shared by every nullifier leaf
+fn nullifier_leaf_body() -> Fr {
+    Fr::one()
+}
+
+/// Computes the leaf commitment for a spent nullifier: `H(nullifier || 1)`
+pub fn nullifier_leaf(nullifier: &[u8; 32]) -> Fr {
+    poseidon::hash_two(poseidon::bytes_to_field(nullifier), nullifier_leaf_body())
+}
+
+/// Gets the nullifier column family handle
+pub(super) fn nullifier_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    schema::column_family(db, schema::NULLIFIER_CF)
+}
+
+/// In-memory view of the spent nullifiers and their Merkle tree
+#[derive(Debug, Clone)]
+pub struct NullifierSet {
+    tree: SparseMerkleTree,
+}
+
+impl NullifierSet {
+    /// Creates an empty nullifier set
+    pub fn new() -> Self {
+        Self {
+            tree: SparseMerkleTree::with_depth(NULLIFIER_TREE_DEPTH),
+        }
+    }
+
+    /// Checks whether a nullifier has been spent
+    pub fn contains(&self, nullifier: &[u8; 32]) -> bool {
+        self.tree.contains(nullifier)
+    }
+
+    /// Marks a nullifier as spent
+    ///
+    /// # Errors
+    /// Returns `CloakError::NullifierSpent` if it was already spent.
+    pub fn insert(&mut self, nullifier: &[u8; 32]) -> CloakResult<()> {
+        if self.contains(nullifier) {
+            return Err(CloakError::nullifier_spent(nullifier));
+        }
+        self.tree.update(nullifier, nullifier_leaf(nullifier));
+        Ok(())
+    }
+
+    /// Removes a nullifier, returning whether it was present
+    pub fn remove(&mut self, nullifier: &[u8; 32]) -> bool {
+        self.tree.remove(nullifier)
+    }
+
+    /// Gets the nullifier-set root
+    pub fn get_root(&self) -> [u8; 32] {
+        self.tree.get_root()
+    }
+
+    /// Generates a membership or non-membership proof for a nullifier
+    pub fn prove(&self, nullifier: &[u8; 32]) -> CloakResult<MerkleProof> {
+        self.tree.prove(nullifier, |_| Some(nullifier_leaf_body()))
+    }
+
+    /// Gets the number of spent nullifiers
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Checks whether no nullifier has been spent yet
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl Default for NullifierSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateTransition {
+    /// Gets the nullifiers this transition spends
+    pub fn nullifiers(&self) -> Vec<[u8; 32]> {
+        match self {
+            StateTransition::Register { .. }
+            | StateTransition::Deposit { .. }
+            | StateTransition::Shield { .. }
+            | StateTransition::Distribution { .. } => Vec::new(),
+            StateTransition::Trade { nullifiers, .. }
+            | StateTransition::PrivateTransfer { nullifiers, .. } => nullifiers.clone(),
+            StateTransition::Withdrawal { nullifier, .. } => nullifier.iter().copied().collect(),
+            StateTransition::Unshield { nullifier, .. } => vec![*nullifier],
+        }
+    }
+}
+
+impl StateManager {
+    /// Checks whether a nullifier has been spent
+    pub fn is_nullifier_spent(&self, nullifier: &[u8; 32]) -> bool {
+        self.nullifiers.contains(nullifier)
+    }
+
+    /// Gets the current nullifier-set root
+    pub fn get_nullifier_root(&self) -> [u8; 32] {
+        self.nullifiers.get_root()
+    }
+
+    /// Generates a membership or non-membership proof for a nullifier
+    pub fn generate_nullifier_proof(&self, nullifier: [u8; 32]) -> CloakResult<MerkleProof> {
+        self.nullifiers.prove(&nullifier)
+    }
+
+    /// Gets the number of spent nullifiers
+    pub fn get_nullifier_count(&self) -> usize {
+        self.nullifiers.len()
+    }
+
+    /// Loads the spent nullifiers from RocksDB and rebuilds their tree
+    pub(super) fn load_nullifiers(&mut self) -> CloakResult<()> {
+        let mut nullifiers = NullifierSet::new();
+        for item in self.db.iterator_cf(nullifier_cf(&self.db)?, IteratorMode::Start) {
+            let (key, _) = item?;
+            let nullifier: [u8; 32] = key
+                .as_ref()
+                .try_into()
+                .map_err(|_| CloakError::state(format!("Malformed nullifier key: {}", hex::encode(&key))))?;
+            nullifiers.insert(&nullifier)?;
+        }
+
+        info!(
+            "Loaded {} spent nullifiers, nullifier root: {}",
+            nullifiers.len(),
+            hex::encode(nullifiers.get_root())
+        );
+        self.nullifiers = nullifiers;
+        Ok(())
+    }
+
+    /// Rejects a transition that reuses a nullifier
+    ///
+    /// # Errors
+    /// Returns `CloakError::NullifierSpent` if a nullifier was already spent
+    /// or appears twice in the same transition.
+    pub(super) fn check_nullifiers(&self, transition: &StateTransition) -> CloakResult<()> {
+        let mut seen = HashSet::new();
+        for nullifier in transition.nullifiers() {
+            if self.nullifiers.contains(&nullifier) || !seen.insert(nullifier) {
+                return Err(CloakError::nullifier_spent(&nullifier));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the nullifiers spent by a transition to a batch
+    pub(super) fn stage_nullifiers(
+        db: &DB,
+        batch: &mut WriteBatch,
+        transition: &StateTransition,
+        sequence: u64,
+    ) -> CloakResult<()> {
+        let cf = nullifier_cf(db)?;
+        for nullifier in transition.nullifiers() {
+            batch.put_cf(cf, nullifier, sequence.to_be_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_changes_root_and_rejects_reuse() {
+        let mut set = NullifierSet::new();
+        let empty_root = set.get_root();
+
+        set.insert(&[7u8; 32]).unwrap();
+        assert!(set.contains(&[7u8; 32]));
+        assert_ne!(set.get_root(), empty_root);
+        assert!(matches!(set.insert(&[7u8; 32]), Err(CloakError::NullifierSpent(_))));
+
+        assert!(set.remove(&[7u8; 32]));
+        assert_eq!(set.get_root(), empty_root);
+    }
+
+    #[test]
+    fn test_nullifier_proofs_verify() {
+        let mut set = NullifierSet::new();
+        set.insert(&[7u8; 32]).unwrap();
+        let root = set.get_root();
+
+        let spent = set.prove(&[7u8; 32]).unwrap();
+        assert!(spent.is_inclusion());
+        assert!(crate::state::verify_proof(&spent, &root));
+
+        let unspent = set.prove(&[8u8; 32]).unwrap();
+        assert!(!unspent.is_inclusion());
+        assert!(crate::state::verify_proof(&unspent, &root));
+    }
+}
@@ -0,0 +1,128 @@
+//! Poseidon Hashing over BLS12-381
+//!
+//! Native Poseidon hash used for state commitments. The parameters match the
+//! circuits documented in TECHNICAL.md: width t=5 (rate 4, capacity 1),
+//! 8 full rounds, 56 partial rounds and an x^5 S-box over the BLS12-381
+//! scalar field.
+//!
+//! Every hash absorbs the number of inputs ahead of the inputs themselves,
+//! so hashes of different arity never collide (without it, trailing zero
+//! inputs would leave the sponge state unchanged).
+
+use ark_bls12_381::Fr;
+use ark_crypto_primitives::sponge::poseidon::{find_poseidon_ark_and_mds, PoseidonConfig, PoseidonSponge};
+use ark_crypto_primitives::sponge::CryptographicSponge;
+use ark_ff::{BigInteger, PrimeField};
+use once_cell::sync::Lazy;
+
+/// Number of field elements absorbed per permutation
+pub const POSEIDON_RATE: usize = 4;
+
+/// Number of full S-box rounds
+pub const POSEIDON_FULL_ROUNDS: usize = 8;
+
+/// Number of partial S-box rounds
+pub const POSEIDON_PARTIAL_ROUNDS: usize = 56;
+
+/// S-box exponent
+pub const POSEIDON_ALPHA: u64 = 5;
+
+/// Round constants and MDS matrix, derived once with the Grain LFSR
+static POSEIDON_CONFIG: Lazy<PoseidonConfig<Fr>> = Lazy::new(|| {
+    let (ark, mds) = find_poseidon_ark_and_mds::<Fr>(
+        Fr::MODULUS_BIT_SIZE as u64,
+        POSEIDON_RATE,
+        POSEIDON_FULL_ROUNDS as u64,
+        POSEIDON_PARTIAL_ROUNDS as u64,
+        0,
+    );
+    PoseidonConfig::new(
+        POSEIDON_FULL_ROUNDS,
+        POSEIDON_PARTIAL_ROUNDS,
+        POSEIDON_ALPHA,
+        mds,
+        ark,
+        POSEIDON_RATE,
+        1,
+    )
+});
+
+/// Gets the shared Poseidon configuration
+pub fn poseidon_config() -> &'static PoseidonConfig<Fr> {
+    &POSEIDON_CONFIG
+}
+
+/// Hashes a sequence of field elements to a single field element
+///
+/// The input length is absorbed first as a domain tag.
+pub fn hash(inputs: &[Fr]) -> Fr {
+    let mut sponge = PoseidonSponge::new(poseidon_config());
+    sponge.absorb(&Fr::from(inputs.len() as u64));
+    sponge.absorb(&inputs);
+    sponge.squeeze_field_elements::<Fr>(1)[0]
+}
+
+/// Hashes two child nodes into their parent
+pub fn hash_two(left: Fr, right: Fr) -> Fr {
+    hash(&[left, right])
+}
+
+/// Maps 32 bytes (big-endian) into the scalar field, reducing modulo q
+pub fn bytes_to_field(bytes: &[u8; 32]) -> Fr {
+    Fr::from_be_bytes_mod_order(bytes)
+}
+
+/// Encodes a field element as 32 big-endian bytes
+pub fn field_to_bytes(value: &Fr) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&value.into_bigint().to_bytes_be());
+    out
+}
+
+/// Maps an arbitrary string (e.g. a token ID) into the scalar field
+///
+/// The string is split into 31-byte chunks so every chunk fits in the field
+/// without reduction, and the byte length is absorbed first so that strings
+/// differing only in trailing zero bytes hash differently.
+pub fn string_to_field(value: &str) -> Fr {
+    let bytes = value.as_bytes();
+    let mut inputs = Vec::with_capacity(1 + bytes.len() / 31 + 1);
+    inputs.push(Fr::from(bytes.len() as u64));
+    for chunk in bytes.chunks(31) {
+        inputs.push(Fr::from_be_bytes_mod_order(chunk));
+    }
+    hash(&inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_deterministic_and_order_sensitive() {
+        let a = Fr::from(1u64);
+        let b = Fr::from(2u64);
+        assert_eq!(hash_two(a, b), hash_two(a, b));
+        assert_ne!(hash_two(a, b), hash_two(b, a));
+    }
+
+    #[test]
+    fn test_hash_separates_input_lengths() {
+        let zero = Fr::from(0u64);
+        assert_ne!(hash(&[]), hash(&[zero]));
+        assert_ne!(hash(&[zero]), hash(&[zero, zero]));
+        assert_ne!(hash(&[Fr::from(1u64)]), hash(&[Fr::from(1u64), zero]));
+    }
+
+    #[test]
+    fn test_field_bytes_roundtrip() {
+        let value = hash_two(Fr::from(7u64), Fr::from(9u64));
+        assert_eq!(bytes_to_field(&field_to_bytes(&value)), value);
+    }
+
+    #[test]
+    fn test_string_to_field_distinguishes_case() {
+        assert_ne!(string_to_field("USDC"), string_to_field("usdc"));
+        assert_ne!(string_to_field(""), string_to_field("\0"));
+    }
+}
@@ -0,0 +1,414 @@
+//! Proof Verification
+//!
+//! Groth16 proofs attached to transitions are checked natively before any
+//! state is staged. A proof is accepted only if it verifies against the
+//! stored verifying key for its circuit, its state-root public input equals
+//! the current Merkle root, and its remaining public inputs describe the
+//! transition it is attached to.
+//!
+//! A withdrawal may carry a balance proof showing the user can cover
+//! `amount + fee` of the token at the current root. Its new root must be the
+//! root once the user's leaf is debited, which is checked in `write_staged`
+//! the same way as a settlement proof's.
+//!
+//! A trade carries a settlement proof whose commitments must open to its
+//! legs under the attached blinding. The proof's new root is only known once
+//! the trade is staged, so it is checked against the updated tree in
+//! `write_staged`, and a mismatch rolls the trade back like any failed write.
+//! Trades settled in an epoch carry none; the batch proof covers them (see
+//! `epochs`).
+
+use super::{poseidon, SparseMerkleTree, StateManager, StateTransition, UserState};
+use crate::error::{CloakError, CloakResult};
+use crate::prover::settlement::check_commitments;
+use crate::prover::{CircuitKind, LeafUpdate, ProofBundle, SettlementWitness};
+use ark_bls12_381::{Bls12_381, Fr};
+use ark_groth16::VerifyingKey;
+use std::collections::HashMap;
+use tracing::info;
+
+impl StateManager {
+    /// Stores the verifying key proofs of `kind` are checked against
+    pub fn register_verifying_key(&mut self, kind: CircuitKind, key: &VerifyingKey<Bls12_381>) {
+        self.verifier.insert(kind, key);
+        info!("Registered verifying key for the {} circuit", kind);
+    }
+
+    /// Checks whether proofs of `kind` can be verified
+    pub fn has_verifying_key(&self, kind: CircuitKind) -> bool {
+        self.verifier.has_key(kind)
+    }
+
+    /// Verifies a proof and checks it was made against the current root
+    ///
+    /// # Errors
+    /// Returns `CloakError::ProofVerification` if the proof does not verify
+    /// or its state-root public input is not the current Merkle root.
+    pub fn verify_proof(&self, bundle: &ProofBundle) -> CloakResult<()> {
+        self.verifier.verify(bundle)?;
+
+        if let Some(index) = bundle.circuit.state_root_input() {
+            let current = self.get_merkle_root();
+            if bundle.public_inputs[index] != current {
+                return Err(CloakError::proof_verification(format!(
+                    "{} proof is against root {}, current root is {}",
+                    bundle.circuit,
+                    hex::encode(bundle.public_inputs[index]),
+                    hex::encode(current)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the settlement circuit witness for a trade at the current root
+    ///
+    /// Fees are assessed and the trade is staged exactly as
+    /// `apply_transition` would, then the maker, taker and fee account leaves
+    /// are replayed on a copy of the tree in that order.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the transition is not a trade or
+    /// a leaf holds too many balances for the circuit, and any error staging
+    /// the trade would raise.
+    pub fn settlement_witness(&self, transition: &StateTransition, blinding: [u8; 32]) -> CloakResult<SettlementWitness> {
+        let mut tree = self.merkle_tree.clone();
+        self.stage_settlement(transition, blinding, &mut tree, &mut HashMap::new())
+    }
+
+    /// Stages a trade on top of earlier staged trades and replays its leaves
+    ///
+    /// Users in `staged_users` are read from there instead of the store, and
+    /// the trade's staged users are written back into it, so consecutive
+    /// calls on the same `tree` chain trades the way applying them in order
+    /// would.
+    pub(super) fn stage_settlement(
+        &self,
+        transition: &StateTransition,
+        blinding: [u8; 32],
+        tree: &mut SparseMerkleTree,
+        staged_users: &mut HashMap<[u8; 32], UserState>,
+    ) -> CloakResult<SettlementWitness> {
+        let transition = self.assess_fees(transition.clone());
+        let StateTransition::Trade {
+            token_a_id,
+            token_b_id,
+            amount_a,
+            amount_b,
+            maker_fee,
+            taker_fee,
+            ..
+        } = &transition
+        else {
+            return Err(CloakError::invalid_input("Settlement proofs are only made for trades"));
+        };
+
+        let mut previous = self.load_affected_users(&transition)?;
+        for sdkey_hash in transition.affected_users() {
+            if let Some(user_state) = staged_users.get(&sdkey_hash) {
+                previous.insert(sdkey_hash, user_state.clone());
+            }
+        }
+        let staged = Self::stage_with(&transition, |sdkey_hash| previous.get(sdkey_hash).cloned())?;
+        let touched = [token_a_id.as_str(), token_b_id.as_str()];
+        let merkle_root_old = tree.get_root();
+        let mut updates = staged
+            .iter()
+            .map(|user_state| LeafUpdate::apply(tree, previous.get(&user_state.sdkey_hash), user_state, &touched))
+            .collect::<CloakResult<Vec<_>>>()?
+            .into_iter();
+        staged_users.extend(staged.into_iter().map(|user_state| (user_state.sdkey_hash, user_state)));
+
+        Ok(SettlementWitness {
+            token_a_id: token_a_id.clone(),
+            token_b_id: token_b_id.clone(),
+            amount_a: *amount_a,
+            amount_b: *amount_b,
+            maker_fee: *maker_fee,
+            taker_fee: *taker_fee,
+            blinding,
+            user_a: updates.next().expect("a trade stages both counterparties"),
+            user_b: updates.next().expect("a trade stages both counterparties"),
+            fee_account: updates.next(),
+            merkle_root_old,
+            merkle_root_new: tree.get_root(),
+        })
+    }
+
+    /// Verifies any proof attached to a transition
+    ///
+    /// Runs after `assess_fees`, so a withdrawal proof must cover the fee.
+    /// A trade settled by a batch proof needs no settlement proof of its own;
+    /// any other trade needs one, and is refused while no settlement
+    /// verifying key is registered to check it.
+    pub(super) fn check_proofs(&self, transition: &StateTransition, batch_settled: bool) -> CloakResult<()> {
+        if let StateTransition::Trade {
+            user_a_sdkey_hash,
+            user_b_sdkey_hash,
+            token_a_id,
+            token_b_id,
+            amount_a,
+            amount_b,
+            settlement,
+            ..
+        } = transition
+        {
+            let Some(settlement) = settlement else {
+                if !batch_settled {
+                    return Err(CloakError::proof_verification("Trade carries no settlement proof"));
+                }
+                return Ok(());
+            };
+            if settlement.proof.circuit != CircuitKind::Settlement {
+                return Err(CloakError::proof_verification(format!(
+                    "Trade carries a {} proof, expected a settlement proof",
+                    settlement.proof.circuit
+                )));
+            }
+            self.verify_proof(&settlement.proof)?;
+            return check_commitments(
+                settlement,
+                (user_a_sdkey_hash, token_a_id, *amount_a),
+                (user_b_sdkey_hash, token_b_id, *amount_b),
+            );
+        }
+
+        if let StateTransition::Withdrawal {
+            user_sdkey_hash,
+            token_id,
+            amount,
+            fee,
+            proof: Some(proof),
+            ..
+        } = transition
+        {
+            if proof.circuit != CircuitKind::Balance {
+                return Err(CloakError::proof_verification(format!(
+                    "Withdrawal carries a {} proof, expected a balance proof",
+                    proof.circuit
+                )));
+            }
+            self.verify_proof(proof)?;
+
+            let debit = amount.saturating_add(*fee);
+            let expected = [
+                (2, poseidon::string_to_field(token_id), "token"),
+                (3, Fr::from(debit), "amount"),
+                (4, poseidon::bytes_to_field(user_sdkey_hash), "user"),
+            ];
+            for (index, value, name) in expected {
+                if proof.public_inputs[index] != poseidon::field_to_bytes(&value) {
+                    return Err(CloakError::proof_verification(format!(
+                        "Balance proof {} does not match the withdrawal",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a transition's proof ends at the root the transition reached
+    ///
+    /// A settlement proof must end at `root`, the root after the whole
+    /// trade. A balance proof only covers the withdrawing user's leaf, so it
+    /// must end at `user_root`, the root once that leaf alone was updated and
+    /// before any fee was credited.
+    pub(super) fn check_proven_roots(
+        transition: &StateTransition,
+        user_root: &[u8; 32],
+        root: &[u8; 32],
+    ) -> CloakResult<()> {
+        match transition {
+            StateTransition::Trade { settlement: Some(settlement), .. } => {
+                if settlement.proof.public_inputs.get(1) != Some(root) {
+                    return Err(CloakError::proof_verification(format!(
+                        "Settlement proof does not end at the new root {}",
+                        hex::encode(root)
+                    )));
+                }
+            }
+            StateTransition::Withdrawal { proof: Some(proof), .. } => {
+                if proof.public_inputs.get(1) != Some(user_root) {
+                    return Err(CloakError::proof_verification(format!(
+                        "Balance proof does not end at the updated root {}",
+                        hex::encode(user_root)
+                    )));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prover::{BalanceWitness, Prover, SettlementProof};
+    use crate::state::testing::{empty_manager, keyed_prover, test_token};
+    use crate::state::{protocol_fee_account, AssetClass, FeeSchedule, TREE_DEPTH};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn withdrawal(user: [u8; 32], amount: u128, proof: Option<ProofBundle>) -> StateTransition {
+        StateTransition::Withdrawal {
+            user_sdkey_hash: user,
+            token_id: "USDC".to_string(),
+            amount,
+            nullifier: None,
+            fee: 0,
+            proof,
+        }
+    }
+
+    fn trade(a: [u8; 32], b: [u8; 32], amount_a: u128, settlement: Option<SettlementProof>) -> StateTransition {
+        StateTransition::Trade {
+            user_a_sdkey_hash: a,
+            user_b_sdkey_hash: b,
+            token_a_id: "USDC".to_string(),
+            token_b_id: "RWA-BOND".to_string(),
+            amount_a,
+            amount_b: 50,
+            nullifiers: Vec::new(),
+            maker_fee: 0,
+            taker_fee: 0,
+            settlement,
+        }
+    }
+
+    fn is_rejected(result: CloakResult<()>) -> bool {
+        matches!(result, Err(CloakError::ProofVerification(_)))
+    }
+
+    #[test]
+    fn test_withdrawal_proofs_verified_against_current_root() {
+        let (mut manager, path) = empty_manager();
+        manager
+            .register_token(test_token("USDC", AssetClass::Treasury))
+            .unwrap();
+        let user = [4u8; 32];
+        manager.register_user(user).unwrap();
+        manager
+            .apply_transition(StateTransition::Deposit {
+                user_sdkey_hash: user,
+                token_id: "USDC".to_string(),
+                amount: 1_000,
+            })
+            .unwrap();
+
+        let mut rng = StdRng::seed_from_u64(20);
+        let mut prover = Prover::new(TREE_DEPTH);
+        prover.setup(CircuitKind::Balance, &mut rng).unwrap();
+        let witness = BalanceWitness::from_state(&manager, user, "USDC", 300, 0).unwrap();
+        let proof = prover.prove_balance(&witness, &mut rng).unwrap();
+
+        // Without a stored key nothing can be verified
+        assert!(is_rejected(manager.verify_proof(&proof)));
+        assert!(is_rejected(manager.apply_transition(withdrawal(user, 300, Some(proof.clone())))));
+
+        manager.register_verifying_key(CircuitKind::Balance, prover.verifying_key(CircuitKind::Balance).unwrap());
+        assert!(manager.has_verifying_key(CircuitKind::Balance));
+        manager.verify_proof(&proof).unwrap();
+
+        // The proof must describe the withdrawal it is attached to
+        assert!(is_rejected(manager.apply_transition(withdrawal(user, 200, Some(proof.clone())))));
+
+        // Tampered proof bytes or public inputs do not verify
+        let mut tampered = proof.clone();
+        tampered.proof[0] ^= 1;
+        assert!(is_rejected(manager.verify_proof(&tampered)));
+        let mut tampered = proof.clone();
+        tampered.public_inputs[3] = poseidon::field_to_bytes(&Fr::from(1u64));
+        assert!(is_rejected(manager.verify_proof(&tampered)));
+        let mut tampered = proof.clone();
+        tampered.public_inputs.pop();
+        assert!(is_rejected(manager.verify_proof(&tampered)));
+
+        // A proof crediting part of the debit back ends at another root
+        let refunding = BalanceWitness::from_state(&manager, user, "USDC", 300, 100).unwrap();
+        let refunding = prover.prove_balance(&refunding, &mut rng).unwrap();
+        manager.verify_proof(&refunding).unwrap();
+        assert!(is_rejected(manager.apply_transition(withdrawal(user, 300, Some(refunding)))));
+        assert_eq!(manager.get_user_state(user).unwrap().get_balance("USDC"), 1_000);
+
+        manager.apply_transition(withdrawal(user, 300, Some(proof.clone()))).unwrap();
+        assert_eq!(manager.get_user_state(user).unwrap().get_balance("USDC"), 700);
+
+        // Once the root has moved on the same proof is stale
+        assert!(is_rejected(manager.verify_proof(&proof)));
+        assert!(is_rejected(manager.apply_transition(withdrawal(user, 300, Some(proof)))));
+        assert_eq!(manager.get_user_state(user).unwrap().get_balance("USDC"), 700);
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_trades_settle_only_with_a_matching_settlement_proof() {
+        let (mut manager, path) = empty_manager();
+        manager.register_token(test_token("USDC", AssetClass::Treasury)).unwrap();
+        manager.register_token(test_token("RWA-BOND", AssetClass::Treasury)).unwrap();
+        manager
+            .set_fee_schedule("USDC", FeeSchedule { maker_bps: 0, taker_bps: 100, withdrawal_bps: 0 })
+            .unwrap();
+        let (a, b) = ([5u8; 32], [6u8; 32]);
+        for (user, token_id) in [(a, "USDC"), (b, "RWA-BOND")] {
+            manager.register_user(user).unwrap();
+            manager
+                .apply_transition(StateTransition::Deposit {
+                    user_sdkey_hash: user,
+                    token_id: token_id.to_string(),
+                    amount: 1_000,
+                })
+                .unwrap();
+        }
+
+        // Unproven trades are refused, with or without a settlement key
+        assert!(is_rejected(manager.apply_transition(trade(a, b, 400, None))));
+        let mut rng = StdRng::seed_from_u64(21);
+        let prover = keyed_prover(&mut manager, Prover::new(TREE_DEPTH), &[CircuitKind::Settlement], &mut rng);
+        assert!(is_rejected(manager.apply_transition(trade(a, b, 400, None))));
+
+        let blinding = [7u8; 32];
+        let witness = manager.settlement_witness(&trade(a, b, 400, None), blinding).unwrap();
+        assert_eq!((witness.taker_fee, witness.maker_fee), (4, 0));
+        assert!(witness.fee_account.as_ref().is_some_and(|update| !update.exists));
+        let settlement = SettlementProof {
+            proof: prover.prove_settlement(&witness, &mut rng).unwrap(),
+            blinding,
+        };
+        assert_eq!(settlement.proof.public_inputs.len(), 4);
+
+        // The commitments must open to the trade the proof is attached to
+        assert!(is_rejected(manager.apply_transition(trade(a, b, 300, Some(settlement.clone())))));
+        assert!(is_rejected(manager.apply_transition(trade(b, a, 400, Some(settlement.clone())))));
+        let mut wrong_blinding = settlement.clone();
+        wrong_blinding.blinding[0] ^= 1;
+        assert!(is_rejected(manager.apply_transition(trade(a, b, 400, Some(wrong_blinding)))));
+
+        // A proof ending at another root is rolled back after staging
+        let mut wrong_root = witness.clone();
+        wrong_root.merkle_root_new = wrong_root.merkle_root_old;
+        assert!(prover.prove_settlement(&wrong_root, &mut rng).is_err());
+        let root_before = manager.get_merkle_root();
+        manager
+            .set_fee_schedule("USDC", FeeSchedule { maker_bps: 0, taker_bps: 200, withdrawal_bps: 0 })
+            .unwrap();
+        assert!(is_rejected(manager.apply_transition(trade(a, b, 400, Some(settlement.clone())))));
+        assert_eq!(manager.get_merkle_root(), root_before);
+        manager
+            .set_fee_schedule("USDC", FeeSchedule { maker_bps: 0, taker_bps: 100, withdrawal_bps: 0 })
+            .unwrap();
+
+        manager.apply_transition(trade(a, b, 400, Some(settlement.clone()))).unwrap();
+        assert_eq!(manager.get_merkle_root(), witness.merkle_root_new);
+        assert_eq!(manager.get_user_state(a).unwrap().get_balance("RWA-BOND"), 50);
+        assert_eq!(manager.get_user_state(b).unwrap().get_balance("USDC"), 396);
+        assert_eq!(manager.get_user_state(protocol_fee_account()).unwrap().get_balance("USDC"), 4);
+
+        // Replaying the proof against the new root fails
+        assert!(is_rejected(manager.apply_transition(trade(a, b, 400, Some(settlement)))));
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,228 @@
+//! Chain Reorg Rollback
+//!
+//! Undoes transitions applied on Psy blocks that were orphaned by a reorg.
+//! Rolling back to height `h` restores every user touched at or above `h` to
+//! their last snapshot below `h`, removes the orphaned journal entries and
+//! root history, un-spends their nullifiers, drops the note commitments they
+//! appended, reverts their supply counter changes and lockup acquisition
+//! heights, and checks that the restored Merkle root matches the root
+//! recorded for the last surviving sequence.
+//! Only blocks within the configured finality depth can be rolled back.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{fees, journal, notes, nullifier, schema, JournalEntry, SparseMerkleTree, StateManager, StateTransition};
+use rocksdb::WriteBatch;
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+/// Default number of blocks after which a state change is considered final
+pub const DEFAULT_FINALITY_DEPTH: u64 = 64;
+
+impl StateTransition {
+    /// Gets the SDKey hashes of every user this transition touches
+    pub fn affected_users(&self) -> Vec<[u8; 32]> {
+        let mut users = match self {
+            StateTransition::Register { user_sdkey_hash }
+            | StateTransition::Deposit { user_sdkey_hash, .. }
+            | StateTransition::Withdrawal { user_sdkey_hash, .. }
+            | StateTransition::Shield { user_sdkey_hash, .. }
+            | StateTransition::Unshield { user_sdkey_hash, .. } => vec![*user_sdkey_hash],
+            StateTransition::PrivateTransfer { .. } => Vec::new(),
+            StateTransition::Distribution { report } => {
+                report.entitlements.iter().map(|entitlement| entitlement.sdkey_hash).collect()
+            }
+            StateTransition::Trade {
+                user_a_sdkey_hash,
+                user_b_sdkey_hash,
+                ..
+            } => vec![*user_a_sdkey_hash, *user_b_sdkey_hash],
+        };
+        if self.charges_fees() {
+            users.push(fees::protocol_fee_account());
+        }
+        users
+    }
+}
+
+impl StateManager {
+    /// Sets how many blocks back a rollback may reach
+    pub fn set_finality_depth(&mut self, depth: u64) {
+        self.finality_depth = depth;
+    }
+
+    /// Gets the configured finality depth
+    pub fn get_finality_depth(&self) -> u64 {
+        self.finality_depth
+    }
+
+    /// Gets the lowest block height that can still be rolled back
+    pub fn rollback_floor(&self) -> u64 {
+        self.block_height.saturating_sub(self.finality_depth) + 1
+    }
+
+    /// Rolls back every transition applied at or above `height`
+    ///
+    /// Returns the orphaned journal entries, oldest first, so the caller can
+    /// re-apply those that also appear on the new canonical chain.
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if `height` is below the finality floor,
+    /// or if the restored root does not match the recorded history.
+    pub fn rollback_to_height(&mut self, height: u64) -> CloakResult<Vec<JournalEntry>> {
+        if height < self.rollback_floor() {
+            return Err(CloakError::state(format!(
+                "Cannot roll back to block {}: blocks below {} are final (finality depth {})",
+                height,
+                self.rollback_floor(),
+                self.finality_depth
+            )));
+        }
+
+        let target_sequence = match height.checked_sub(1) {
+            Some(last_kept) => self.get_root_at_block(last_kept)?.map_or(0, |record| record.sequence),
+            None => 0,
+        };
+        if target_sequence >= self.root_sequence {
+            self.block_height = height.saturating_sub(1);
+            return Ok(Vec::new());
+        }
+
+        let orphaned = self.rewind_to_sequence(target_sequence)?;
+        self.block_height = height.saturating_sub(1);
+
+        info!(
+            "Rolled back {} transitions to block {} (sequence {}), root {}",
+            orphaned.len(),
+            self.block_height,
+            target_sequence,
+            hex::encode(self.merkle_tree.get_root())
+        );
+        Ok(orphaned)
+    }
+
+    /// Undoes every transition after `target_sequence`
+    ///
+    /// Returns the undone journal entries, oldest first. The block height is
+    /// left to the caller.
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if the restored root does not match the
+    /// recorded history.
+    pub(super) fn rewind_to_sequence(&mut self, target_sequence: u64) -> CloakResult<Vec<JournalEntry>> {
+        let orphaned = self.get_journal_entries(target_sequence + 1, usize::MAX)?;
+        let touched: HashSet<[u8; 32]> = orphaned
+            .iter()
+            .flat_map(|entry| entry.transition.affected_users())
+            .collect();
+        let restored = self.user_states_at_sequence(target_sequence)?;
+        let expected_root = match self.get_root_record_by_sequence(target_sequence)? {
+            Some(record) => record.root,
+            None => SparseMerkleTree::with_depth(self.merkle_tree.depth()).get_root(),
+        };
+
+        // Rewind the tree first so the root can be checked before writing
+        let mut tree = self.merkle_tree.clone();
+        for sdkey_hash in &touched {
+            match restored.get(sdkey_hash) {
+                Some(user_state) => tree.update(sdkey_hash, user_state.leaf_hash()),
+                None => {
+                    tree.remove(sdkey_hash);
+                }
+            }
+        }
+        if tree.get_root() != expected_root {
+            return Err(CloakError::state(format!(
+                "Rollback to sequence {} produced root {}, expected {}",
+                target_sequence,
+                hex::encode(tree.get_root()),
+                hex::encode(expected_root)
+            )));
+        }
+
+        let orphaned_notes: u64 = orphaned
+            .iter()
+            .map(|entry| entry.transition.note_commitments().len() as u64)
+            .sum();
+        let note_count = self.note_tree.len().saturating_sub(orphaned_notes);
+        let mut supply = self.supply.clone();
+        for entry in orphaned.iter().rev() {
+            supply.revert(&entry.transition)?;
+        }
+
+        let users_cf = schema::users_cf(&self.db)?;
+        let mut batch = WriteBatch::default();
+        for sdkey_hash in &touched {
+            match restored.get(sdkey_hash) {
+                Some(user_state) => batch.put_cf(users_cf, sdkey_hash, user_state.encode()?),
+                None => batch.delete_cf(users_cf, sdkey_hash),
+            }
+        }
+        Self::stage_merkle_paths(&self.db, &tree, &mut batch, &touched)?;
+        Self::stage_supply(&self.db, &mut batch, &supply)?;
+        let mut records = Vec::new();
+        for entry in &orphaned {
+            batch.delete_cf(journal::journal_cf(&self.db)?, entry.sequence.to_be_bytes());
+            for nullifier in entry.transition.nullifiers() {
+                batch.delete_cf(nullifier::nullifier_cf(&self.db)?, nullifier);
+            }
+            Self::stage_distribution_removal(&self.db, &mut batch, &entry.transition)?;
+            Self::stage_fee_ledger_removal(&self.db, &mut batch, &entry.transition, entry.sequence)?;
+            records.extend(self.get_root_record_by_sequence(entry.sequence)?);
+        }
+        self.stage_root_records_removal(&mut batch, &records, target_sequence)?;
+        self.stage_acquisition_rollback(&mut batch, &orphaned)?;
+        for position in note_count..self.note_tree.len() {
+            batch.delete_cf(notes::notes_cf(&self.db)?, position.to_be_bytes());
+        }
+        if let Some(checkpoint) = self.get_journal_checkpoint()? {
+            if checkpoint.sequence > target_sequence {
+                warn!("Dropping journal checkpoint at orphaned sequence {}", checkpoint.sequence);
+                Self::stage_checkpoint_removal(&self.db, &mut batch)?;
+            }
+        }
+        self.db.write(batch)?;
+
+        for sdkey_hash in &touched {
+            match restored.get(sdkey_hash) {
+                Some(user_state) => self.user_cache.put(user_state.clone()),
+                None => self.user_cache.remove(sdkey_hash),
+            }
+        }
+        for entry in &orphaned {
+            for nullifier in entry.transition.nullifiers() {
+                self.nullifiers.remove(&nullifier);
+            }
+        }
+        self.note_tree.truncate(note_count);
+        self.supply = supply;
+        self.merkle_tree = tree;
+        self.root_sequence = target_sequence;
+        self.recount_holders()?;
+        Ok(orphaned)
+    }
+
+    /// Handles a Psy reorg that forked at `fork_height`
+    ///
+    /// Rolls back every transition at or above the fork, then applies the
+    /// transitions of the new canonical chain in order. Returns the orphaned
+    /// journal entries.
+    pub fn handle_reorg(
+        &mut self,
+        fork_height: u64,
+        canonical: Vec<(u64, StateTransition)>,
+    ) -> CloakResult<Vec<JournalEntry>> {
+        if canonical.iter().any(|(height, _)| *height < fork_height) {
+            return Err(CloakError::invalid_input(format!(
+                "Canonical transitions must be at or above fork height {}",
+                fork_height
+            )));
+        }
+
+        let orphaned = self.rollback_to_height(fork_height)?;
+        for (height, transition) in canonical {
+            self.block_height = self.block_height.max(height);
+            self.apply_transition(transition)?;
+        }
+        Ok(orphaned)
+    }
+}
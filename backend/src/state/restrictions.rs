@@ -0,0 +1,540 @@
+//! Transfer Restrictions
+//!
+//! Per-token policies for regulated real-world assets. A policy can limit
+//! who may receive a token (a holder whitelist and blocked jurisdictions),
+//! how soon a holder may pass it on (a lockup measured in blocks since the
+//! holder last acquired it), how many accounts may hold it at once, and
+//! how recently its parties must have proven compliance.
+//!
+//! Policies are evaluated by `StateManager::apply_transition` for every
+//! transition that credits or debits a token after it is staged, so the
+//! post-transition holder set is known. A violation is reported as
+//! `CloakError::TransferRestricted` naming the rule that failed.
+//!
+//! Records live in the `restrictions` column family:
+//!
+//! | Key                               | Value                     |
+//! |-----------------------------------|---------------------------|
+//! | `policy:<token>`                  | JSON `TransferPolicy`     |
+//! | `jurisdiction:<sdkey hex>`        | ISO 3166-1 alpha-2 code   |
+//! | `acquired:<token>:<sdkey hex>`    | block height (u64 BE)     |
+//!
+//! Acquisition heights are only recorded for tokens with a lockup and are
+//! written in the same batch as the transition. A rollback restores them
+//! from the surviving journal. Holder counts are derived
+//! from the stored balances on startup and kept up to date in memory.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{journal, protocol_fee_account, schema, JournalEntry, StateManager, StateTransition, UserState};
+use rocksdb::{Direction, IteratorMode, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use tracing::info;
+
+/// Key prefix of token policies
+const POLICY_PREFIX: &str = "policy:";
+
+/// Key prefix of user jurisdictions
+const JURISDICTION_PREFIX: &str = "jurisdiction:";
+
+/// Key prefix of lockup acquisition heights
+const ACQUIRED_PREFIX: &str = "acquired:";
+
+/// Rule of a transfer policy, named in `CloakError::TransferRestricted`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestrictionRule {
+    /// Recipient is not on the token's holder whitelist
+    Whitelist,
+    /// Recipient's jurisdiction is blocked or unknown
+    Jurisdiction,
+    /// Sender acquired the token too recently
+    Lockup,
+    /// Transfer would exceed the token's maximum number of holders
+    MaxHolders,
+    /// A party has no fresh compliance proof
+    Compliance,
+}
+
+impl RestrictionRule {
+    /// Gets the rule's name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestrictionRule::Whitelist => "whitelist",
+            RestrictionRule::Jurisdiction => "jurisdiction",
+            RestrictionRule::Lockup => "lockup",
+            RestrictionRule::MaxHolders => "max_holders",
+            RestrictionRule::Compliance => "compliance",
+        }
+    }
+}
+
+impl fmt::Display for RestrictionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Transfer restrictions of one token
+///
+/// Every field is optional; the default policy restricts nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferPolicy {
+    /// Hex-encoded SDKey hashes allowed to receive the token (`None` = anyone)
+    #[serde(default)]
+    pub whitelist: Option<BTreeSet<String>>,
+    /// Jurisdictions (ISO 3166-1 alpha-2) whose users may not receive the
+    /// token; if non-empty, recipients must have a recorded jurisdiction
+    #[serde(default)]
+    pub blocked_jurisdictions: BTreeSet<String>,
+    /// Blocks a holder must wait after acquiring the token before trading it away
+    #[serde(default)]
+    pub lockup_blocks: u64,
+    /// Maximum number of accounts holding a non-zero balance
+    #[serde(default)]
+    pub max_holders: Option<u64>,
+    /// Blocks a compliance proof stays fresh for; if set, every party to a
+    /// transition crediting or debiting the token needs one (see `state::compliance`)
+    #[serde(default)]
+    pub compliance_window_blocks: Option<u64>,
+}
+
+impl TransferPolicy {
+    /// Checks and canonicalizes the policy's fields
+    ///
+    /// Whitelist entries are lower-cased and jurisdictions upper-cased.
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for a malformed whitelist entry
+    /// or jurisdiction code.
+    pub fn normalize(mut self) -> CloakResult<Self> {
+        if let Some(whitelist) = self.whitelist.take() {
+            let whitelist = whitelist
+                .into_iter()
+                .map(|entry| {
+                    let entry = entry.to_ascii_lowercase();
+                    match hex::decode(&entry) {
+                        Ok(bytes) if bytes.len() == 32 => Ok(entry),
+                        _ => Err(CloakError::invalid_input(format!("Invalid whitelisted SDKey hash: {}", entry))),
+                    }
+                })
+                .collect::<CloakResult<_>>()?;
+            self.whitelist = Some(whitelist);
+        }
+        self.blocked_jurisdictions = self
+            .blocked_jurisdictions
+            .iter()
+            .map(|code| normalize_jurisdiction(code))
+            .collect::<CloakResult<_>>()?;
+        Ok(self)
+    }
+}
+
+/// Checks and upper-cases an ISO 3166-1 alpha-2 jurisdiction code
+pub(super) fn normalize_jurisdiction(code: &str) -> CloakResult<String> {
+    if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(CloakError::invalid_input(format!("Invalid jurisdiction code: {}", code)));
+    }
+    Ok(code.to_ascii_uppercase())
+}
+
+fn policy_key(token_id: &str) -> String {
+    format!("{}{}", POLICY_PREFIX, token_id)
+}
+
+fn jurisdiction_key(sdkey_hash: &[u8; 32]) -> String {
+    format!("{}{}", JURISDICTION_PREFIX, hex::encode(sdkey_hash))
+}
+
+fn acquired_key(token_id: &str, sdkey_hash: &[u8; 32]) -> String {
+    format!("{}{}:{}", ACQUIRED_PREFIX, token_id, hex::encode(sdkey_hash))
+}
+
+/// Gets the (recipient, token) pairs a transition credits
+fn receipts(transition: &StateTransition) -> Vec<([u8; 32], &str)> {
+    match transition {
+        StateTransition::Deposit { user_sdkey_hash, token_id, .. }
+        | StateTransition::Unshield { user_sdkey_hash, token_id, .. } => vec![(*user_sdkey_hash, token_id)],
+        StateTransition::Trade {
+            user_a_sdkey_hash,
+            user_b_sdkey_hash,
+            token_a_id,
+            token_b_id,
+            ..
+        } => vec![(*user_b_sdkey_hash, token_a_id), (*user_a_sdkey_hash, token_b_id)],
+        StateTransition::Distribution { report } => report
+            .entitlements
+            .iter()
+            .map(|entitlement| (entitlement.sdkey_hash, report.action.payout_token_id.as_str()))
+            .collect(),
+        StateTransition::Register { .. }
+        | StateTransition::Withdrawal { .. }
+        | StateTransition::Shield { .. }
+        | StateTransition::PrivateTransfer { .. } => Vec::new(),
+    }
+}
+
+/// Gets the (sender, token) pairs a transition debits from a public balance
+fn debits(transition: &StateTransition) -> Vec<([u8; 32], &str)> {
+    match transition {
+        StateTransition::Withdrawal { user_sdkey_hash, token_id, .. }
+        | StateTransition::Shield { user_sdkey_hash, token_id, .. } => vec![(*user_sdkey_hash, token_id)],
+        StateTransition::Trade {
+            user_a_sdkey_hash,
+            user_b_sdkey_hash,
+            token_a_id,
+            token_b_id,
+            ..
+        } => vec![(*user_a_sdkey_hash, token_a_id), (*user_b_sdkey_hash, token_b_id)],
+        StateTransition::Register { .. }
+        | StateTransition::Deposit { .. }
+        | StateTransition::Unshield { .. }
+        | StateTransition::PrivateTransfer { .. }
+        | StateTransition::Distribution { .. } => Vec::new(),
+    }
+}
+
+/// Adds a user's non-zero balances to per-token holder counts
+///
+/// The protocol fee account is not a holder.
+pub(super) fn count_holdings(holders: &mut BTreeMap<String, u64>, user_state: &UserState) {
+    if user_state.sdkey_hash == protocol_fee_account() {
+        return;
+    }
+    for (token_id, amount) in &user_state.balances {
+        if *amount > 0 {
+            *holders.entry(token_id.clone()).or_default() += 1;
+        }
+    }
+}
+
+/// Computes the per-token change in holder count between two sets of states
+///
+/// Users missing from `previous` are treated as holding nothing, and the
+/// protocol fee account is ignored.
+pub(super) fn holder_changes(
+    previous: &HashMap<[u8; 32], UserState>,
+    staged: &[UserState],
+) -> BTreeMap<String, i64> {
+    let mut changes: BTreeMap<String, i64> = BTreeMap::new();
+    for user_state in staged.iter().filter(|user_state| user_state.sdkey_hash != protocol_fee_account()) {
+        let before = previous.get(&user_state.sdkey_hash);
+        let token_ids: BTreeSet<&String> = user_state
+            .balances
+            .keys()
+            .chain(before.into_iter().flat_map(|state| state.balances.keys()))
+            .collect();
+        for token_id in token_ids {
+            let held_before = before.is_some_and(|state| state.get_balance(token_id) > 0);
+            let held_after = user_state.get_balance(token_id) > 0;
+            if held_before != held_after {
+                *changes.entry(token_id.clone()).or_default() += if held_after { 1 } else { -1 };
+            }
+        }
+    }
+    changes.retain(|_, change| *change != 0);
+    changes
+}
+
+impl StateManager {
+    /// Sets the transfer policy of a registered token
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the token is not registered or
+    /// the policy is malformed.
+    pub fn set_transfer_policy(&mut self, token_id: &str, policy: TransferPolicy) -> CloakResult<TransferPolicy> {
+        if self.tokens.get(token_id).is_none() {
+            return Err(CloakError::invalid_input(format!("Unknown token: {}", token_id)));
+        }
+        let policy = policy.normalize()?;
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        self.db.put_cf(cf, policy_key(token_id), serde_json::to_vec(&policy)?)?;
+        info!(
+            "Transfer policy for {}: whitelist {}, {} blocked jurisdictions, lockup {} blocks, max holders {:?}",
+            token_id,
+            policy.whitelist.as_ref().map_or("off".to_string(), |w| format!("{} entries", w.len())),
+            policy.blocked_jurisdictions.len(),
+            policy.lockup_blocks,
+            policy.max_holders
+        );
+        self.restrictions.insert(token_id.to_string(), policy.clone());
+        Ok(policy)
+    }
+
+    /// Gets the transfer policy of a token (unrestricted if none is set)
+    pub fn get_transfer_policy(&self, token_id: &str) -> TransferPolicy {
+        self.restrictions.get(token_id).cloned().unwrap_or_default()
+    }
+
+    /// Records the jurisdiction of a user
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for a malformed jurisdiction code.
+    pub fn set_user_jurisdiction(&mut self, sdkey_hash: [u8; 32], jurisdiction: &str) -> CloakResult<()> {
+        let jurisdiction = normalize_jurisdiction(jurisdiction)?;
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        self.db.put_cf(cf, jurisdiction_key(&sdkey_hash), jurisdiction.as_bytes())?;
+        info!("User {} is in jurisdiction {}", hex::encode(sdkey_hash), jurisdiction);
+        Ok(())
+    }
+
+    /// Gets the recorded jurisdiction of a user
+    pub fn get_user_jurisdiction(&self, sdkey_hash: &[u8; 32]) -> CloakResult<Option<String>> {
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        let value = self.db.get_cf(cf, jurisdiction_key(sdkey_hash))?;
+        value
+            .map(|bytes| String::from_utf8(bytes).map_err(|e| CloakError::state(e.to_string())))
+            .transpose()
+    }
+
+    /// Gets the number of accounts holding a non-zero balance of a token
+    pub fn get_holder_count(&self, token_id: &str) -> u64 {
+        self.holders.get(token_id).copied().unwrap_or(0)
+    }
+
+    /// Gets the block height at which a user last acquired a locked-up token
+    fn get_acquired_height(&self, token_id: &str, sdkey_hash: &[u8; 32]) -> CloakResult<Option<u64>> {
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        match self.db.get_cf(cf, acquired_key(token_id, sdkey_hash))? {
+            Some(bytes) => {
+                let bytes: [u8; 8] = bytes
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| CloakError::state(format!("Corrupt acquisition height for {}", token_id)))?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Loads the transfer policies from RocksDB
+    pub(super) fn load_restrictions(&mut self) -> CloakResult<()> {
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        let mut restrictions = BTreeMap::new();
+        for item in self.db.prefix_iterator_cf(cf, POLICY_PREFIX) {
+            let (key, value) = item?;
+            let Some(token_id) = key.strip_prefix(POLICY_PREFIX.as_bytes()) else {
+                break;
+            };
+            let token_id = String::from_utf8(token_id.to_vec()).map_err(|e| CloakError::state(e.to_string()))?;
+            restrictions.insert(token_id, serde_json::from_slice(&value)?);
+        }
+        self.restrictions = restrictions;
+        Ok(())
+    }
+
+    /// Recounts the holders of every token from the stored balances
+    pub(super) fn recount_holders(&mut self) -> CloakResult<()> {
+        let mut holders = BTreeMap::new();
+        self.for_each_stored_user(|user_state| {
+            count_holdings(&mut holders, &user_state);
+            Ok(())
+        })?;
+        self.holders = holders;
+        Ok(())
+    }
+
+    /// Applies holder count changes of a committed transition
+    pub(super) fn apply_holder_changes(&mut self, changes: &BTreeMap<String, i64>) {
+        for (token_id, change) in changes {
+            let count = self.holders.entry(token_id.clone()).or_default();
+            *count = count.saturating_add_signed(*change);
+        }
+    }
+
+    /// Rejects a transition that violates a token's transfer policy
+    ///
+    /// Rules are checked in order: whitelist and jurisdiction for every
+    /// recipient, compliance freshness for every party, lockup for every
+    /// sender (including withdrawals and shields), then the holder limit
+    /// against the post-transition holder count.
+    pub(super) fn check_restrictions(
+        &self,
+        transition: &StateTransition,
+        holder_changes: &BTreeMap<String, i64>,
+    ) -> CloakResult<()> {
+        let senders = debits(transition);
+
+        for (recipient, token_id) in receipts(transition) {
+            let Some(policy) = self.restrictions.get(token_id) else {
+                continue;
+            };
+            if let Some(whitelist) = &policy.whitelist {
+                if !whitelist.contains(&hex::encode(recipient)) {
+                    return Err(CloakError::transfer_restricted(
+                        token_id,
+                        RestrictionRule::Whitelist,
+                        format!("recipient {} is not whitelisted", hex::encode(recipient)),
+                    ));
+                }
+            }
+            if !policy.blocked_jurisdictions.is_empty() {
+                match self.get_user_jurisdiction(&recipient)? {
+                    Some(jurisdiction) if policy.blocked_jurisdictions.contains(&jurisdiction) => {
+                        return Err(CloakError::transfer_restricted(
+                            token_id,
+                            RestrictionRule::Jurisdiction,
+                            format!("recipient {} is in blocked jurisdiction {}", hex::encode(recipient), jurisdiction),
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        return Err(CloakError::transfer_restricted(
+                            token_id,
+                            RestrictionRule::Jurisdiction,
+                            format!("recipient {} has no recorded jurisdiction", hex::encode(recipient)),
+                        ));
+                    }
+                }
+            }
+        }
+
+        for (party, token_id) in receipts(transition).into_iter().chain(senders.iter().copied()) {
+            let Some(window) = self.restrictions.get(token_id).and_then(|policy| policy.compliance_window_blocks) else {
+                continue;
+            };
+            if let Some(reason) = self.compliance_lapse(&party, window)? {
+                return Err(CloakError::transfer_restricted(token_id, RestrictionRule::Compliance, reason));
+            }
+        }
+
+        for (sender, token_id) in senders {
+            let Some(policy) = self.restrictions.get(token_id) else {
+                continue;
+            };
+            if policy.lockup_blocks == 0 {
+                continue;
+            }
+            if let Some(acquired) = self.get_acquired_height(token_id, &sender)? {
+                let unlocked_at = acquired.saturating_add(policy.lockup_blocks);
+                if self.block_height < unlocked_at {
+                    return Err(CloakError::transfer_restricted(
+                        token_id,
+                        RestrictionRule::Lockup,
+                        format!(
+                            "sender {} acquired it at block {} and is locked up until block {}",
+                            hex::encode(sender),
+                            acquired,
+                            unlocked_at
+                        ),
+                    ));
+                }
+            }
+        }
+
+        for (token_id, change) in holder_changes {
+            let Some(max_holders) = self.restrictions.get(token_id).and_then(|policy| policy.max_holders) else {
+                continue;
+            };
+            let holders = self.get_holder_count(token_id).saturating_add_signed(*change);
+            if *change > 0 && holders > max_holders {
+                return Err(CloakError::transfer_restricted(
+                    token_id,
+                    RestrictionRule::MaxHolders,
+                    format!("{} holders would exceed the limit of {}", holders, max_holders),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records acquisition heights of locked-up tokens credited by a transition
+    pub(super) fn stage_acquisitions(&self, batch: &mut WriteBatch, transition: &StateTransition) -> CloakResult<()> {
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        for (recipient, token_id) in receipts(transition) {
+            if self.restrictions.get(token_id).is_some_and(|policy| policy.lockup_blocks > 0) {
+                batch.put_cf(cf, acquired_key(token_id, &recipient), self.block_height.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the acquisition heights overwritten by orphaned transitions
+    ///
+    /// Each height they touched goes back to the block of the latest
+    /// surviving transition crediting the same holder and token, or is
+    /// removed if there is none.
+    pub(super) fn stage_acquisition_rollback(&self, batch: &mut WriteBatch, orphaned: &[JournalEntry]) -> CloakResult<()> {
+        let Some(first) = orphaned.first() else {
+            return Ok(());
+        };
+        let cf = schema::column_family(&self.db, schema::RESTRICTIONS_CF)?;
+        let mut pending: BTreeSet<([u8; 32], String)> = BTreeSet::new();
+        for entry in orphaned {
+            for (recipient, token_id) in receipts(&entry.transition) {
+                if self.get_acquired_height(token_id, &recipient)?.is_some() {
+                    pending.insert((recipient, token_id.to_string()));
+                }
+            }
+        }
+
+        if let Some(last_kept) = first.sequence.checked_sub(1).filter(|_| !pending.is_empty()) {
+            let start = last_kept.to_be_bytes();
+            let iter = self
+                .db
+                .iterator_cf(journal::journal_cf(&self.db)?, IteratorMode::From(&start, Direction::Reverse));
+            for item in iter {
+                if pending.is_empty() {
+                    break;
+                }
+                let (_, value) = item?;
+                let entry: JournalEntry = serde_json::from_slice(&value)?;
+                for (recipient, token_id) in receipts(&entry.transition) {
+                    if pending.remove(&(recipient, token_id.to_string())) {
+                        batch.put_cf(cf, acquired_key(token_id, &recipient), entry.block_height.to_be_bytes());
+                    }
+                }
+            }
+        }
+        for (recipient, token_id) in pending {
+            batch.delete_cf(cf, acquired_key(&token_id, &recipient));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(sdkey_hash: [u8; 32], token_id: &str, amount: u128) -> UserState {
+        let mut user_state = UserState::new(sdkey_hash);
+        user_state.balances.insert(token_id.to_string(), amount);
+        user_state
+    }
+
+    #[test]
+    fn test_policy_normalizes_entries() {
+        let policy = TransferPolicy {
+            whitelist: Some(BTreeSet::from([hex::encode([0xABu8; 32]).to_uppercase()])),
+            blocked_jurisdictions: BTreeSet::from(["kp".to_string()]),
+            ..TransferPolicy::default()
+        }
+        .normalize()
+        .unwrap();
+        assert!(policy.whitelist.unwrap().contains(&hex::encode([0xABu8; 32])));
+        assert!(policy.blocked_jurisdictions.contains("KP"));
+
+        let bad_whitelist = TransferPolicy {
+            whitelist: Some(BTreeSet::from(["abcd".to_string()])),
+            ..TransferPolicy::default()
+        };
+        assert!(bad_whitelist.normalize().is_err());
+        let bad_jurisdiction = TransferPolicy {
+            blocked_jurisdictions: BTreeSet::from(["USA".to_string()]),
+            ..TransferPolicy::default()
+        };
+        assert!(bad_jurisdiction.normalize().is_err());
+    }
+
+    #[test]
+    fn test_holder_changes_track_entries_and_exits() {
+        let previous = HashMap::from([([1u8; 32], holding([1u8; 32], "USDC", 10))]);
+        let staged = vec![holding([1u8; 32], "USDC", 0), holding([2u8; 32], "USDC", 5)];
+        assert!(holder_changes(&previous, &staged).is_empty());
+
+        let staged = vec![holding([1u8; 32], "USDC", 4), holding([2u8; 32], "USDC", 6)];
+        assert_eq!(holder_changes(&previous, &staged), BTreeMap::from([("USDC".to_string(), 1)]));
+    }
+}
@@ -0,0 +1,361 @@
+//! Storage Schema
+//!
+//! Column-family layout of the state database. Each kind of record lives in
+//! its own column family, tuned for how it is accessed:
+//!
+//! | Column family  | Key                               | Value                |
+//! |----------------|-----------------------------------|----------------------|
+//! | `users`        | SDKey hash                        | encoded `UserState`  |
+//! | `merkle_nodes` | height (u8) ‖ index (u64 BE)      | node field element   |
+//! | `journal`      | sequence (u64 BE)                 | JSON `JournalEntry`  |
+//! | `roots`        | `root:` / `root_hash:` / ... keys | root history records |
+//! | `nullifiers`   | nullifier                         | spending sequence    |
+//! | `notes`        | position (u64 BE)                 | note commitment      |
+//! | `metadata`     | UTF-8 name                        | node metadata        |
+//! | `tokens`       | token ID                          | JSON `TokenInfo`     |
+//! | `restrictions` | `policy:` / `jurisdiction:` / ... | transfer policies    |
+//! | `distributions`| report hash                       | JSON `DistributionReport` |
+//! | `fees`         | `schedule:` / `ledger:` keys      | fee schedules, ledger |
+//! | `proof_jobs`   | job ID (u64 BE)                   | JSON `ProofJob`      |
+//!
+//! The schema version is stored in `metadata`; the node refuses to open a
+//! database written with a different version. Databases from before the
+//! split (everything in the default keyspace, no version) are migrated in
+//! place on first open.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{history, journal, poseidon, SparseMerkleTree, StateManager};
+use ark_bls12_381::Fr;
+use rocksdb::{
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DBCompressionType, IteratorMode, Options,
+    WriteBatch, DB,
+};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// Latest user state per SDKey hash
+pub const USERS_CF: &str = "users";
+
+/// Non-empty nodes of the state Merkle tree
+pub const MERKLE_CF: &str = "merkle_nodes";
+
+/// Append-only transition journal
+pub const JOURNAL_CF: &str = "journal";
+
+/// State root history, its indexes and versioned user snapshots
+pub const ROOTS_CF: &str = "roots";
+
+/// Spent nullifiers
+pub const NULLIFIER_CF: &str = "nullifiers";
+
+/// Shielded note commitments
+pub const NOTES_CF: &str = "notes";
+
+/// Node metadata (schema version, journal checkpoint, supply counters)
+pub const METADATA_CF: &str = "metadata";
+
+/// Registered tokens
+pub const TOKENS_CF: &str = "tokens";
+
+/// Transfer policies, user jurisdictions and lockup acquisition heights
+pub const RESTRICTIONS_CF: &str = "restrictions";
+
+/// Committed corporate-action distribution reports
+pub const DISTRIBUTIONS_CF: &str = "distributions";
+
+/// Fee schedules and the fee ledger
+pub const FEES_CF: &str = "fees";
+
+/// Proving job records
+pub const PROOF_JOBS_CF: &str = "proof_jobs";
+
+/// Every column family the state manager opens
+pub const COLUMN_FAMILIES: [&str; 12] = [
+    USERS_CF,
+    MERKLE_CF,
+    JOURNAL_CF,
+    ROOTS_CF,
+    NULLIFIER_CF,
+    NOTES_CF,
+    METADATA_CF,
+    TOKENS_CF,
+    RESTRICTIONS_CF,
+    DISTRIBUTIONS_CF,
+    FEES_CF,
+    PROOF_JOBS_CF,
+];
+
+/// Current storage schema version
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Metadata key holding the schema version (u32 BE)
+pub(super) const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Default-keyspace prefix of user records before the column-family split
+pub(super) const LEGACY_USER_PREFIX: &str = "user:";
+
+/// Gets a column family handle by name
+pub(super) fn column_family<'a>(db: &'a DB, name: &str) -> CloakResult<&'a ColumnFamily> {
+    db.cf_handle(name)
+        .ok_or_else(|| CloakError::state(format!("Missing column family: {}", name)))
+}
+
+/// Gets the user state column family handle
+pub(super) fn users_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    column_family(db, USERS_CF)
+}
+
+/// Gets the Merkle node column family handle
+pub(super) fn merkle_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    column_family(db, MERKLE_CF)
+}
+
+/// Gets the root history column family handle
+pub(super) fn roots_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    column_family(db, ROOTS_CF)
+}
+
+/// Gets the metadata column family handle
+pub(super) fn metadata_cf(db: &DB) -> CloakResult<&ColumnFamily> {
+    column_family(db, METADATA_CF)
+}
+
+/// Builds a block-based table with a bloom filter for point lookups
+fn bloom_table() -> BlockBasedOptions {
+    let mut table = BlockBasedOptions::default();
+    table.set_bloom_filter(10.0, false);
+    table
+}
+
+/// Builds the options for a column family
+fn cf_options(name: &str) -> Options {
+    let mut opts = Options::default();
+    match name {
+        // Point lookups on every transition, full scan on startup
+        USERS_CF => {
+            opts.set_block_based_table_factory(&bloom_table());
+            opts.set_compression_type(DBCompressionType::Lz4);
+        }
+        // Random point reads and writes of incompressible hashes
+        MERKLE_CF => {
+            opts.set_block_based_table_factory(&bloom_table());
+            opts.set_compression_type(DBCompressionType::None);
+            opts.set_write_buffer_size(128 * 1024 * 1024);
+        }
+        // Append-only with sequential keys, read back in order
+        JOURNAL_CF => {
+            opts.set_compression_type(DBCompressionType::Zstd);
+            opts.set_write_buffer_size(64 * 1024 * 1024);
+            opts.set_level_compaction_dynamic_level_bytes(true);
+        }
+        // Prefix scans and reverse seeks over string keys
+        ROOTS_CF => {
+            opts.set_compression_type(DBCompressionType::Lz4);
+            opts.set_level_compaction_dynamic_level_bytes(true);
+        }
+        // Membership checks on random keys
+        NULLIFIER_CF => {
+            opts.set_block_based_table_factory(&bloom_table());
+            opts.set_compression_type(DBCompressionType::None);
+        }
+        // Append-only with sequential keys and random values
+        NOTES_CF => {
+            opts.set_compression_type(DBCompressionType::None);
+        }
+        // Point lookups of jurisdictions and acquisition heights on every trade
+        RESTRICTIONS_CF => {
+            opts.set_block_based_table_factory(&bloom_table());
+        }
+        _ => {}
+    }
+    opts
+}
+
+/// Opens the state database with every column family
+///
+/// Column families present on disk but unknown to this version are opened
+/// with default options so the schema check can report the mismatch.
+pub(super) fn open_db(db_path: &str) -> CloakResult<DB> {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    opts.set_max_open_files(1000);
+    opts.set_write_buffer_size(64 * 1024 * 1024); // 64MB write buffer
+
+    let mut descriptors: Vec<ColumnFamilyDescriptor> = COLUMN_FAMILIES
+        .iter()
+        .map(|name| ColumnFamilyDescriptor::new(*name, cf_options(name)))
+        .collect();
+    if let Ok(existing) = DB::list_cf(&opts, db_path) {
+        for name in existing {
+            if name != "default" && !COLUMN_FAMILIES.contains(&name.as_str()) {
+                descriptors.push(ColumnFamilyDescriptor::new(name, Options::default()));
+            }
+        }
+    }
+
+    Ok(DB::open_cf_descriptors(&opts, db_path, descriptors)?)
+}
+
+/// Encodes a Merkle node position as a `merkle_nodes` key
+fn node_key(height: usize, index: u64) -> [u8; 9] {
+    let mut key = [0u8; 9];
+    key[0] = height as u8;
+    key[1..].copy_from_slice(&index.to_be_bytes());
+    key
+}
+
+/// Decodes a `merkle_nodes` key
+fn parse_node_key(key: &[u8]) -> Option<(usize, u64)> {
+    let (height, index) = key.split_first()?;
+    Some((*height as usize, u64::from_be_bytes(index.try_into().ok()?)))
+}
+
+impl StateManager {
+    /// Gets the schema version recorded in the database, if any
+    pub fn get_schema_version(&self) -> CloakResult<Option<u32>> {
+        match self.db.get_cf(metadata_cf(&self.db)?, SCHEMA_VERSION_KEY)? {
+            Some(value) => {
+                let bytes: [u8; 4] = value
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| CloakError::state("Malformed schema version"))?;
+                Ok(Some(u32::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the schema version, migrating unversioned databases
+    ///
+    /// # Errors
+    /// Returns `CloakError::Config` if the database was written with a
+    /// different schema version.
+    pub(super) fn check_schema(&self) -> CloakResult<()> {
+        match self.get_schema_version()? {
+            Some(SCHEMA_VERSION) => Ok(()),
+            Some(version) => Err(CloakError::Config(format!(
+                "Database schema version {} is not supported (expected {})",
+                version, SCHEMA_VERSION
+            ))),
+            None => {
+                let migrated = self.migrate_default_keyspace()?;
+                if migrated > 0 {
+                    info!("Migrated {} records into column families", migrated);
+                }
+                self.db.put_cf(metadata_cf(&self.db)?, SCHEMA_VERSION_KEY, SCHEMA_VERSION.to_be_bytes())?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Moves records from the default keyspace into their column families
+    fn migrate_default_keyspace(&self) -> CloakResult<usize> {
+        let mut batch = WriteBatch::default();
+        let mut migrated = 0;
+
+        for item in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = item?;
+            let key_str = std::str::from_utf8(&key).unwrap_or_default();
+
+            if let Some(sdkey_hex) = key_str.strip_prefix(LEGACY_USER_PREFIX) {
+                let sdkey_hash: [u8; 32] = hex::decode(sdkey_hex)?
+                    .try_into()
+                    .map_err(|_| CloakError::state(format!("Malformed legacy user key: {}", key_str)))?;
+                batch.put_cf(users_cf(&self.db)?, sdkey_hash, &value);
+            } else if history::HISTORY_PREFIXES.iter().any(|prefix| key_str.starts_with(prefix)) {
+                batch.put_cf(roots_cf(&self.db)?, &key, &value);
+            } else if key_str == journal::CHECKPOINT_KEY {
+                batch.put_cf(metadata_cf(&self.db)?, &key, &value);
+            } else {
+                warn!("Leaving unrecognised legacy key in default keyspace: {}", key_str);
+                continue;
+            }
+            batch.delete(&key);
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            self.db.write(batch)?;
+        }
+        Ok(migrated)
+    }
+
+    /// Adds the Merkle paths of the given keys to a batch
+    pub(super) fn stage_merkle_paths<'a, I>(
+        db: &DB,
+        tree: &SparseMerkleTree,
+        batch: &mut WriteBatch,
+        keys: I,
+    ) -> CloakResult<()>
+    where
+        I: IntoIterator<Item = &'a [u8; 32]>,
+    {
+        let cf = merkle_cf(db)?;
+        let mut nodes = HashMap::new();
+        for sdkey_hash in keys {
+            nodes.extend(tree.path_nodes(sdkey_hash));
+        }
+        for ((height, index), value) in nodes {
+            match value {
+                Some(value) => batch.put_cf(cf, node_key(height, index), poseidon::field_to_bytes(&value)),
+                None => batch.delete_cf(cf, node_key(height, index)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a full rewrite of the stored Merkle tree to a batch
+    pub(super) fn stage_merkle_rewrite(db: &DB, tree: &SparseMerkleTree, batch: &mut WriteBatch) -> CloakResult<()> {
+        let cf = merkle_cf(db)?;
+        for item in db.iterator_cf(cf, IteratorMode::Start) {
+            let (key, _) = item?;
+            batch.delete_cf(cf, key);
+        }
+        for ((height, index), value) in tree.nodes() {
+            batch.put_cf(cf, node_key(*height, *index), poseidon::field_to_bytes(value));
+        }
+        Ok(())
+    }
+
+    /// Loads the state Merkle tree from its stored nodes
+    ///
+    /// Every stored user's leaf is checked against the stored node. If the
+    /// nodes are missing or disagree with the user records, the tree is
+    /// rebuilt from the leaves and rewritten. Inner nodes are covered by the
+    /// root check against the journal that runs afterwards.
+    pub(super) fn load_merkle_tree(&mut self, leaves: &[([u8; 32], Fr)]) -> CloakResult<()> {
+        let mut nodes = HashMap::new();
+        for item in self.db.iterator_cf(merkle_cf(&self.db)?, IteratorMode::Start) {
+            let (key, value) = item?;
+            let position = parse_node_key(&key)
+                .ok_or_else(|| CloakError::state("Malformed Merkle node key"))?;
+            let value: [u8; 32] = value
+                .as_ref()
+                .try_into()
+                .map_err(|_| CloakError::state("Malformed Merkle node value"))?;
+            nodes.insert(position, poseidon::bytes_to_field(&value));
+        }
+
+        let depth = self.merkle_tree.depth();
+        let tree = SparseMerkleTree::from_nodes(depth, nodes, leaves.iter().map(|(sdkey_hash, leaf)| (sdkey_hash, *leaf)));
+        let consistent = tree.len() == leaves.len() && tree.slots_match_nodes();
+        if consistent {
+            self.merkle_tree = tree;
+            return Ok(());
+        }
+
+        if !leaves.is_empty() {
+            warn!("Stored Merkle nodes disagree with user records; rebuilding tree");
+        }
+        let mut rebuilt = SparseMerkleTree::with_depth(depth);
+        for (sdkey_hash, leaf) in leaves {
+            rebuilt.update(sdkey_hash, *leaf);
+        }
+        let mut batch = WriteBatch::default();
+        Self::stage_merkle_rewrite(&self.db, &rebuilt, &mut batch)?;
+        self.db.write(batch)?;
+        self.merkle_tree = rebuilt;
+        Ok(())
+    }
+}
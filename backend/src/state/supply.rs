@@ -0,0 +1,214 @@
+//! Token Supply Counters
+//!
+//! Per-token totals of value entering and leaving the public balances:
+//! deposits, unshields and corporate-action distributions add to them,
+//! withdrawals and shields take from them. Trades and private transfers only
+//! move value around, so for every token the sum of all user balances must
+//! equal `deposited + unshielded + distributed - withdrawn - shielded`. The counters are written
+//! in the same `WriteBatch` as the transition that changes them, and
+//! `StateManager::audit` checks the invariant against the stored balances.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{schema, StateManager, StateTransition};
+use rocksdb::{WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Key of the supply counters in the metadata column family
+pub(super) const SUPPLY_KEY: &str = "token_supply";
+
+/// Running totals for one token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenSupply {
+    /// Total deposited from Psy
+    pub deposited: u128,
+    /// Total withdrawn to Psy
+    pub withdrawn: u128,
+    /// Total moved from public balances into shielded notes
+    pub shielded: u128,
+    /// Total moved from shielded notes back into public balances
+    pub unshielded: u128,
+    /// Total paid out by corporate-action distributions
+    #[serde(default)]
+    pub distributed: u128,
+}
+
+impl TokenSupply {
+    /// Gets the amount that should be held in public balances
+    ///
+    /// Returns `None` if more value left the balances than ever entered.
+    pub fn public_supply(&self) -> Option<u128> {
+        self.deposited
+            .checked_add(self.unshielded)?
+            .checked_add(self.distributed)?
+            .checked_sub(self.withdrawn.checked_add(self.shielded)?)
+    }
+
+    /// Gets the amount that should be held in shielded notes
+    ///
+    /// Returns `None` if more was unshielded than was ever shielded.
+    pub fn shielded_supply(&self) -> Option<u128> {
+        self.shielded.checked_sub(self.unshielded)
+    }
+}
+
+/// Supply counters for every token, keyed by token ID
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupplyCounters {
+    tokens: BTreeMap<String, TokenSupply>,
+}
+
+/// Selects one of a token's counters
+type CounterFn = fn(&mut TokenSupply) -> &mut u128;
+
+/// Picks the counter a transition moves, with the moved token and amount
+fn supply_leg(transition: &StateTransition) -> Option<(&str, u128, CounterFn)> {
+    match transition {
+        StateTransition::Deposit { token_id, amount, .. } => Some((token_id, *amount, |s| &mut s.deposited)),
+        StateTransition::Withdrawal { token_id, amount, .. } => Some((token_id, *amount, |s| &mut s.withdrawn)),
+        StateTransition::Shield { token_id, amount, .. } => Some((token_id, *amount, |s| &mut s.shielded)),
+        StateTransition::Unshield { token_id, amount, .. } => Some((token_id, *amount, |s| &mut s.unshielded)),
+        StateTransition::Distribution { report } => {
+            Some((&report.action.payout_token_id, report.distributed, |s| &mut s.distributed))
+        }
+        StateTransition::Register { .. }
+        | StateTransition::Trade { .. }
+        | StateTransition::PrivateTransfer { .. } => None,
+    }
+}
+
+impl SupplyCounters {
+    /// Gets the counters of a token (all zero if never seen)
+    pub fn get(&self, token_id: &str) -> TokenSupply {
+        self.tokens.get(token_id).copied().unwrap_or_default()
+    }
+
+    /// Iterates over every token with counters
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &TokenSupply)> {
+        self.tokens.iter()
+    }
+
+    /// Adds the effect of a transition to the counters
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if a counter would overflow.
+    pub fn apply(&mut self, transition: &StateTransition) -> CloakResult<()> {
+        let Some((token_id, amount, counter)) = supply_leg(transition) else {
+            return Ok(());
+        };
+        let supply = self.tokens.entry(token_id.to_string()).or_default();
+        let total = counter(supply);
+        *total = total
+            .checked_add(amount)
+            .ok_or_else(|| CloakError::state(format!("Supply counter overflow for {}", token_id)))?;
+        Ok(())
+    }
+
+    /// Removes the effect of a previously applied transition
+    ///
+    /// # Errors
+    /// Returns `CloakError::State` if a counter would go negative.
+    pub fn revert(&mut self, transition: &StateTransition) -> CloakResult<()> {
+        let Some((token_id, amount, counter)) = supply_leg(transition) else {
+            return Ok(());
+        };
+        let supply = self.tokens.entry(token_id.to_string()).or_default();
+        let total = counter(supply);
+        *total = total
+            .checked_sub(amount)
+            .ok_or_else(|| CloakError::state(format!("Supply counter underflow for {}", token_id)))?;
+        Ok(())
+    }
+}
+
+impl StateManager {
+    /// Gets the supply counters of a token
+    pub fn get_token_supply(&self, token_id: &str) -> TokenSupply {
+        self.supply.get(token_id)
+    }
+
+    /// Gets the supply counters of every token
+    pub fn get_supply_counters(&self) -> &SupplyCounters {
+        &self.supply
+    }
+
+    /// Adds the supply counters to a batch
+    pub(super) fn stage_supply(db: &DB, batch: &mut WriteBatch, supply: &SupplyCounters) -> CloakResult<()> {
+        batch.put_cf(schema::metadata_cf(db)?, SUPPLY_KEY, serde_json::to_vec(supply)?);
+        Ok(())
+    }
+
+    /// Loads the supply counters, deriving them from the journal if missing
+    ///
+    /// Databases written before the counters existed have a journal but no
+    /// counters; replaying every journaled transition rebuilds them.
+    pub(super) fn load_supply(&mut self) -> CloakResult<()> {
+        if let Some(value) = self.db.get_cf(schema::metadata_cf(&self.db)?, SUPPLY_KEY)? {
+            self.supply = serde_json::from_slice(&value)?;
+            return Ok(());
+        }
+
+        let mut supply = SupplyCounters::default();
+        let entries = self.get_journal_entries(1, usize::MAX)?;
+        for entry in &entries {
+            supply.apply(&entry.transition)?;
+        }
+        if !entries.is_empty() {
+            info!("Derived supply counters for {} tokens from {} journal entries", supply.tokens.len(), entries.len());
+        }
+
+        let mut batch = WriteBatch::default();
+        Self::stage_supply(&self.db, &mut batch, &supply)?;
+        self.db.write(batch)?;
+        self.supply = supply;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(amount: u128) -> StateTransition {
+        StateTransition::Deposit {
+            user_sdkey_hash: [1u8; 32],
+            token_id: "USDC".to_string(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_counters_track_public_and_shielded_supply() {
+        let mut supply = SupplyCounters::default();
+        supply.apply(&deposit(1_000)).unwrap();
+        supply
+            .apply(&StateTransition::Withdrawal {
+                user_sdkey_hash: [1u8; 32],
+                token_id: "USDC".to_string(),
+                amount: 100,
+                nullifier: None,
+                fee: 0,
+                proof: None,
+            })
+            .unwrap();
+        supply
+            .apply(&StateTransition::Shield {
+                user_sdkey_hash: [1u8; 32],
+                token_id: "USDC".to_string(),
+                amount: 300,
+                commitment: [2u8; 32],
+                opening: None,
+            })
+            .unwrap();
+
+        let usdc = supply.get("USDC");
+        assert_eq!(usdc.public_supply(), Some(600));
+        assert_eq!(usdc.shielded_supply(), Some(300));
+        assert_eq!(supply.get("ETH"), TokenSupply::default());
+
+        supply.revert(&deposit(1_000)).unwrap();
+        assert_eq!(supply.get("USDC").public_supply(), None);
+        assert!(supply.revert(&deposit(1)).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+//! Test Fixtures
+//!
+//! Setup shared by the state, prover and node tests: state managers on
+//! throwaway databases, token registry entries, and provers keyed for the
+//! circuits a test exercises.
+
+use super::{AssetClass, StateManager, TokenInfo, TokenStatus};
+use crate::prover::{CircuitKind, Prover};
+use rand::rngs::StdRng;
+use std::path::PathBuf;
+
+/// Opens a state manager on a fresh temporary database
+pub(crate) fn empty_manager() -> (StateManager, PathBuf) {
+    let path = std::env::temp_dir().join(format!("cloak-state-test-{}", uuid::Uuid::new_v4()));
+    let manager = StateManager::new(path.to_str().unwrap()).unwrap();
+    (manager, path)
+}
+
+/// Opens a state manager on a fresh temporary database with USDC and
+/// RWA-CREDIT registered
+pub(crate) fn temp_manager() -> (StateManager, PathBuf) {
+    let (mut manager, path) = empty_manager();
+    for (token_id, asset_class) in [("USDC", AssetClass::Treasury), ("RWA-CREDIT", AssetClass::Credit)] {
+        manager.register_token(test_token(token_id, asset_class)).unwrap();
+    }
+    (manager, path)
+}
+
+/// Builds an active registry entry for a test token
+pub(crate) fn test_token(token_id: &str, asset_class: AssetClass) -> TokenInfo {
+    TokenInfo {
+        token_id: token_id.to_string(),
+        symbol: token_id.to_string(),
+        decimals: 6,
+        issuer: "Cloak Test Issuer".to_string(),
+        asset_class,
+        status: TokenStatus::Active,
+    }
+}
+
+/// Sets up `kinds` on `prover` and registers their verifying keys with
+/// `manager`
+pub(crate) fn keyed_prover(
+    manager: &mut StateManager,
+    mut prover: Prover,
+    kinds: &[CircuitKind],
+    rng: &mut StdRng,
+) -> Prover {
+    for kind in kinds {
+        prover.setup(*kind, rng).unwrap();
+        manager.register_verifying_key(*kind, prover.verifying_key(*kind).unwrap());
+    }
+    prover
+}
@@ -0,0 +1,244 @@
+//! Token Registry
+//!
+//! Persisted registry of the assets the protocol accepts. Every transition
+//! that moves a token is checked against it before staging, so a
+//! mistyped ID such as `usdc` is rejected instead of silently creating a
+//! new asset, and a frozen token cannot move at all. Token IDs are
+//! canonical: upper-case ASCII letters, digits and `-`.
+
+use crate::error::{CloakError, CloakResult};
+use crate::state::{schema, StateManager, StateTransition};
+use rocksdb::IteratorMode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+
+/// Maximum length of a token ID
+pub const MAX_TOKEN_ID_LEN: usize = 32;
+
+/// Maximum number of decimals (`u128` holds 38 full decimal digits)
+pub const MAX_DECIMALS: u8 = 38;
+
+/// Class of real-world asset a token represents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetClass {
+    Treasury,
+    Credit,
+    RealEstate,
+    Carbon,
+}
+
+/// Lifecycle status of a registered token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStatus {
+    /// Accepted in every transition
+    Active,
+    /// Registered but rejected in every transition
+    Frozen,
+}
+
+/// Registry entry for one token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    /// Canonical token ID used in transitions and balances
+    pub token_id: String,
+    /// Display symbol
+    pub symbol: String,
+    /// Number of decimals of the base unit
+    pub decimals: u8,
+    /// Issuer of the underlying asset
+    pub issuer: String,
+    pub asset_class: AssetClass,
+    pub status: TokenStatus,
+}
+
+impl TokenInfo {
+    /// Checks the entry's fields
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for a non-canonical ID, empty
+    /// symbol or issuer, or too many decimals.
+    pub fn validate(&self) -> CloakResult<()> {
+        let canonical = !self.token_id.is_empty()
+            && self.token_id.len() <= MAX_TOKEN_ID_LEN
+            && self
+                .token_id
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-');
+        if !canonical {
+            return Err(CloakError::invalid_input(format!(
+                "Token ID must be 1-{} upper-case letters, digits or '-': {}",
+                MAX_TOKEN_ID_LEN, self.token_id
+            )));
+        }
+        if self.symbol.trim().is_empty() || self.issuer.trim().is_empty() {
+            return Err(CloakError::invalid_input(format!(
+                "Token {} needs a symbol and an issuer",
+                self.token_id
+            )));
+        }
+        if self.decimals > MAX_DECIMALS {
+            return Err(CloakError::invalid_input(format!(
+                "Token {} has {} decimals, maximum is {}",
+                self.token_id, self.decimals, MAX_DECIMALS
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// In-memory view of the registered tokens, keyed by token ID
+#[derive(Debug, Clone, Default)]
+pub struct TokenRegistry {
+    tokens: BTreeMap<String, TokenInfo>,
+}
+
+impl TokenRegistry {
+    /// Gets a registered token
+    pub fn get(&self, token_id: &str) -> Option<&TokenInfo> {
+        self.tokens.get(token_id)
+    }
+
+    /// Iterates over every registered token in ID order
+    pub fn iter(&self) -> impl Iterator<Item = &TokenInfo> {
+        self.tokens.values()
+    }
+
+    /// Checks that a token is registered and active
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` for an unknown or frozen token.
+    pub fn check_active(&self, token_id: &str) -> CloakResult<&TokenInfo> {
+        let token = self
+            .get(token_id)
+            .ok_or_else(|| CloakError::invalid_input(format!("Unknown token: {}", token_id)))?;
+        if token.status == TokenStatus::Frozen {
+            return Err(CloakError::invalid_input(format!("Token is frozen: {}", token_id)));
+        }
+        Ok(token)
+    }
+}
+
+impl StateTransition {
+    /// Gets the IDs of every token this transition moves
+    pub fn token_ids(&self) -> Vec<&str> {
+        match self {
+            StateTransition::Register { .. } | StateTransition::PrivateTransfer { .. } => Vec::new(),
+            StateTransition::Deposit { token_id, .. }
+            | StateTransition::Withdrawal { token_id, .. }
+            | StateTransition::Shield { token_id, .. }
+            | StateTransition::Unshield { token_id, .. } => vec![token_id],
+            StateTransition::Trade { token_a_id, token_b_id, .. } => vec![token_a_id, token_b_id],
+            // The entitled token does not move, only the payout does
+            StateTransition::Distribution { report } => vec![&report.action.payout_token_id],
+        }
+    }
+}
+
+impl StateManager {
+    /// Registers a new token
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the entry is invalid or the ID
+    /// is already registered.
+    pub fn register_token(&mut self, token: TokenInfo) -> CloakResult<()> {
+        token.validate()?;
+        if self.tokens.get(&token.token_id).is_some() {
+            return Err(CloakError::invalid_input(format!(
+                "Token already registered: {}",
+                token.token_id
+            )));
+        }
+        self.store_token(token)
+    }
+
+    /// Changes the status of a registered token
+    ///
+    /// # Errors
+    /// Returns `CloakError::InvalidInput` if the token is not registered.
+    pub fn set_token_status(&mut self, token_id: &str, status: TokenStatus) -> CloakResult<()> {
+        let mut token = self
+            .tokens
+            .get(token_id)
+            .cloned()
+            .ok_or_else(|| CloakError::invalid_input(format!("Unknown token: {}", token_id)))?;
+        token.status = status;
+        self.store_token(token)
+    }
+
+    /// Gets a registered token
+    pub fn get_token(&self, token_id: &str) -> Option<TokenInfo> {
+        self.tokens.get(token_id).cloned()
+    }
+
+    /// Lists every registered token in ID order
+    pub fn list_tokens(&self) -> Vec<TokenInfo> {
+        self.tokens.iter().cloned().collect()
+    }
+
+    /// Writes a registry entry and updates the in-memory registry
+    fn store_token(&mut self, token: TokenInfo) -> CloakResult<()> {
+        let cf = schema::column_family(&self.db, schema::TOKENS_CF)?;
+        self.db.put_cf(cf, token.token_id.as_bytes(), serde_json::to_vec(&token)?)?;
+        info!("Token {} is {:?} ({:?})", token.token_id, token.status, token.asset_class);
+        self.tokens.tokens.insert(token.token_id.clone(), token);
+        Ok(())
+    }
+
+    /// Loads the token registry from RocksDB
+    pub(super) fn load_tokens(&mut self) -> CloakResult<()> {
+        let mut tokens = BTreeMap::new();
+        let cf = schema::column_family(&self.db, schema::TOKENS_CF)?;
+        for item in self.db.iterator_cf(cf, IteratorMode::Start) {
+            let (_, value) = item?;
+            let token: TokenInfo = serde_json::from_slice(&value)?;
+            tokens.insert(token.token_id.clone(), token);
+        }
+        self.tokens = TokenRegistry { tokens };
+
+        // Balances in unregistered tokens stay readable but cannot move
+        for (token_id, _) in self.supply.iter() {
+            if self.tokens.get(token_id).is_none() {
+                warn!("Token {} has supply but is not registered", token_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects a transition that moves an unknown or frozen token
+    pub(super) fn check_tokens(&self, transition: &StateTransition) -> CloakResult<()> {
+        for token_id in transition.token_ids() {
+            self.tokens.check_active(token_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::testing::test_token;
+
+    #[test]
+    fn test_token_ids_must_be_canonical() {
+        assert!(test_token("USDC", AssetClass::Treasury).validate().is_ok());
+        assert!(test_token("RWA-CREDIT", AssetClass::Treasury).validate().is_ok());
+        assert!(test_token("usdc", AssetClass::Treasury).validate().is_err());
+        assert!(test_token("", AssetClass::Treasury).validate().is_err());
+        assert!(TokenInfo { decimals: 39, ..test_token("USDC", AssetClass::Treasury) }.validate().is_err());
+        assert!(TokenInfo { issuer: " ".to_string(), ..test_token("USDC", AssetClass::Treasury) }.validate().is_err());
+    }
+
+    #[test]
+    fn test_registry_rejects_unknown_and_frozen_tokens() {
+        let mut registry = TokenRegistry::default();
+        registry.tokens.insert("USDC".to_string(), test_token("USDC", AssetClass::Treasury));
+        registry.tokens.insert("CARBON".to_string(), TokenInfo { status: TokenStatus::Frozen, ..test_token("CARBON", AssetClass::Treasury) });
+
+        assert!(registry.check_active("USDC").is_ok());
+        assert!(registry.check_active("usdc").is_err());
+        assert!(registry.check_active("CARBON").is_err());
+    }
+}
@@ -194,6 +194,18 @@ export class CloakApiClient {
     }, 0, requestId);
   }
 
+  // Get the token registry (accepted assets with decimals and metadata)
+  async getTokens(requestId?: string): Promise<ApiResponse<Array<{
+    token_id: string;
+    symbol: string;
+    decimals: number;
+    issuer: string;
+    asset_class: 'treasury' | 'credit' | 'real_estate' | 'carbon';
+    status: 'active' | 'frozen';
+  }>>> {
+    return this.request('/api/tokens', {}, 0, requestId);
+  }
+
   // Get orders
   async getOrders(requestId?: string): Promise<ApiResponse<any[]>> {
     return this.request('/api/orders', {}, 0, requestId);